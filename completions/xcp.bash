@@ -19,13 +19,25 @@ _xcp() {
     -r
     -v
     -w
+    -x
     -L
     "$(_parse_help "$1" -h)" # long options will be parsed from `--help`
   )
   local units='B K M G' # in line with most completions prefer M to MB/MiB
   local drivers='parfile parblock'
   local reflink='auto always never'
-  local backup='none numbered auto'
+  local backup='none numbered auto simple'
+  local preallocate='never always keep-size auto'
+  local perms='exact umask default'
+  local sparse='auto always never'
+  local encrypted='error skip'
+  local same_file='error skip'
+  local overwrite='always rename never if-newer'
+  local hard_links='copy warn skip preserve'
+  local symlinks='preserve follow skip'
+  local optimize='off auto'
+  local verify='off cached direct hash'
+  local preserve='mode ownership timestamps links xattr context acl all'
 
   case "$prev" in
   -h | --help) return ;;
@@ -41,6 +53,13 @@ _xcp() {
     return
     ;;
 
+  --sync-every | --max-buffer-memory | --bwlimit | --min-size | --max-size)
+    local num="${cur%%[^0-9]*}"
+    local unit="${cur##*[0-9]}"
+    COMPREPLY=($(compgen -P "$num" -W "$units" -- "$unit"))
+    return
+    ;;
+
   --reflink)
     COMPREPLY=($(compgen -W "$reflink" -- "$cur"))
     return
@@ -51,6 +70,60 @@ _xcp() {
     return
     ;;
 
+  --preallocate)
+    COMPREPLY=($(compgen -W "$preallocate" -- "$cur"))
+    return
+    ;;
+
+  --sparse)
+    COMPREPLY=($(compgen -W "$sparse" -- "$cur"))
+    return
+    ;;
+
+  --perms)
+    COMPREPLY=($(compgen -W "$perms" -- "$cur"))
+    return
+    ;;
+
+  --encrypted)
+    COMPREPLY=($(compgen -W "$encrypted" -- "$cur"))
+    return
+    ;;
+
+  --same-file)
+    COMPREPLY=($(compgen -W "$same_file" -- "$cur"))
+    return
+    ;;
+
+  --overwrite)
+    COMPREPLY=($(compgen -W "$overwrite" -- "$cur"))
+    return
+    ;;
+
+  --hard-links)
+    COMPREPLY=($(compgen -W "$hard_links" -- "$cur"))
+    return
+    ;;
+
+  --symlinks)
+    COMPREPLY=($(compgen -W "$symlinks" -- "$cur"))
+    return
+    ;;
+
+  --optimize)
+    COMPREPLY=($(compgen -W "$optimize" -- "$cur"))
+    return
+    ;;
+
+  --verify)
+    COMPREPLY=($(compgen -W "$verify" -- "$cur"))
+    return
+    ;;
+  --preserve)
+    COMPREPLY=($(compgen -W "$preserve" -- "$cur"))
+    return
+    ;;
+
   --driver)
     COMPREPLY=($(compgen -W "$drivers" -- "$cur"))
     return
@@ -13,6 +13,68 @@ set -l backup '
   none\t"no backups (default)"
   numbered\t"follow the semantics of cp numbered backups"
   auto\t"create a numbered backup if previous backup exists"
+  simple\t"append ~ to the name of the replaced file"
+'
+
+set -l preallocate '
+  never\t"only set the file length (default)"
+  always\t"reserve all blocks up front"
+  keep-size\t"reserve blocks before setting the length"
+  auto\t"reserve all blocks unless the source is sparse"
+'
+
+set -l perms '
+  exact\t"copy the source mode (default)"
+  umask\t"apply the umask to the source mode"
+  default\t"0644 for files, 0755 for directories"
+'
+
+set -l sparse '
+  auto\t"copy the holes of sparse sources (default)"
+  always\t"also leave blocks of zeros as holes"
+  never\t"write holes as zeros"
+'
+
+set -l encrypted '
+  error\t"abort if an encrypted source is locked (default)"
+  skip\t"skip encrypted sources that are locked"
+'
+
+set -l same_file '
+  error\t"abort if a source is the same file as its destination (default)"
+  skip\t"skip sources that are the same file as their destination"
+'
+
+set -l verify '
+  off\t"do not verify copies (default)"
+  cached\t"compare each copy with its source"
+  direct\t"compare each copy with its source, reading from disk"
+  hash\t"compare hashes of each copy and its source"
+'
+
+set -l optimize '
+  off\t"use the options as given (default)"
+  auto\t"tune for the source and destination filesystems"
+'
+
+set -l hard_links '
+  copy\t"copy multiply-linked files silently (default)"
+  warn\t"warn when copying multiply-linked files"
+  skip\t"skip multiply-linked files"
+  preserve\t"recreate hard links between copied files"
+'
+
+set -l symlinks '
+  preserve\t"recreate symlinks in the destination (default)"
+  follow\t"copy the files symlinks point to"
+  skip\t"skip symlinks"
+'
+
+set -l overwrite '
+  always\t"replace existing destination files (default)"
+  rename\t"copy to a new name such as file (1).txt"
+  never\t"skip existing destination files"
+  if-newer\t"replace destination files older than the source"
 '
 
 # short + long
@@ -20,6 +82,8 @@ complete -c xcp -s T -l no-target-directory -d 'Overwrite target directory, do n
 complete -c xcp -s g -l glob -d 'Expand (glob) filename patterns'
 complete -c xcp -s h -l help -f -d 'Print help'
 complete -c xcp -s n -l no-clobber -d 'Do not overwrite an existing file'
+complete -c xcp -s i -l interactive -d 'Prompt before overwriting an existing file'
+complete -c xcp -s x -l one-file-system -d 'Stay on the source filesystems'
 complete -c xcp -s r -l recursive -d 'Copy directories recursively'
 complete -c xcp -s v -l verbose -d 'Increase verbosity (can be repeated)'
 complete -c xcp -s w -l workers -d 'Workers for recursive copies (0=auto)' -x -a '(seq 0 (getconf _NPROCESSORS_ONLN))'
@@ -28,14 +92,66 @@ complete -c xcp -s L -l dereference -d 'Dereference symlinks in source'
 # long
 complete -c xcp -l fsync -d 'Sync each file to disk after it is written'
 complete -c xcp -l gitignore -d 'Use .gitignore if present'
+complete -c xcp -l exclude -d 'Exclude paths matching PATTERN from directory copies' -x
+complete -c xcp -l exclude-regex -d 'Exclude paths matching the regular expression REGEX' -x
 complete -c xcp -l no-perms -d 'Do not copy file permissions'
 complete -c xcp -l no-timestamps -d 'Do not copy file timestamps'
+complete -c xcp -l summary -d 'Print a one-line summary when complete'
 complete -c xcp -l no-progress -d 'Disable progress bar'
+complete -c xcp -l json -d 'Print progress as newline-delimited JSON'
 complete -c xcp -l block-size -d 'Block size for file operations' -x -a '(seq 1 16){B,K,M,G}'
 complete -c xcp -l driver -d 'Parallelise at the file or at the block level' -x -a "$drivers"
 complete -c xcp -l reflink -d 'Whether and how to use reflinks' -x -a "$reflinks"
 complete -c xcp -l backup -d 'Whether to create backups of overwritten files' -x -a "$backup"
 complete -c xcp -l dereference -d 'Dereference symlinks in source'
+complete -c xcp -l preallocate -d 'Preallocation strategy for destination files' -x -a "$preallocate"
+complete -c xcp -l sparse -d 'Control the creation of sparse files' -x -a "$sparse"
+complete -c xcp -l file-timeout -d 'Per-file timeout in seconds' -x
+complete -c xcp -l read-retries -d 'Retry reads failing with an I/O error N times' -x
+complete -c xcp -l newest -d 'Only copy the N most recently modified files' -x
+complete -c xcp -l read-holes -d 'Read through holes when copying to /dev/null'
+complete -c xcp -l readdir-order -d 'Create files in the source directory order'
+complete -c xcp -l reproducible -d 'Create files in sorted order, one at a time'
+complete -c xcp -l sync-every -d 'Start writeback every N bytes written' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l max-buffer-memory -d 'Maximum memory used for copy buffers' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l bwlimit -d 'Maximum copy rate in bytes per second' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l direct-io -d 'Copy file data with direct I/O, bypassing the page cache'
+complete -c xcp -l drop-cache -d 'Drop copied files from the page cache'
+complete -c xcp -l regular-only -d 'Only copy regular files (and directories)'
+complete -c xcp -l same-file -d 'Handling of sources that are the same file as the destination' -x -a "$same_file"
+complete -c xcp -l verify -d 'Re-read and compare each file after copying' -x -a "$verify"
+complete -c xcp -l verify-reflink -d 'Check that reflinked files share their data with the source'
+complete -c xcp -l optimize -d 'Tune the copy for the filesystems involved' -x -a "$optimize"
+complete -c xcp -l hard-links -d 'Handling of source files with multiple hard links' -x -a "$hard_links"
+complete -c xcp -l symlinks -d 'Handling of symlinks in source' -x -a "$symlinks"
+complete -c xcp -l overwrite -d 'Handling of existing destination files' -x -a "$overwrite"
+complete -c xcp -l resume -d 'Resume interrupted copies'
+complete -c xcp -l delta -d 'Update existing files in place, writing only changed blocks'
+complete -c xcp -l lock -d 'Lock destination files while copying'
+complete -c xcp -l atomic -d 'Write files to a temporary name and rename them into place'
+complete -c xcp -l ignore-existing -d 'Only copy files missing from the destination'
+complete -c xcp -l min-size -d 'Skip files smaller than this size' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l max-size -d 'Skip files larger than this size' -x -a '(seq 1 16){B,K,M,G}'
+complete -c xcp -l check-inodes -d 'Check the destination has enough free inodes'
+complete -c xcp -l pack -d 'Pack the files in a source directory into a single file'
+complete -c xcp -l strip-components -d 'Remove N leading components from destination paths' -x
+complete -c xcp -l chmod -d 'Modify the preserved file permissions' -x
+complete -c xcp -l perms -d 'How to set the mode of copied files' -x -a "$perms"
+complete -c xcp -l expect-hash -d 'Skip the copy if the destination already has this content' -x
+complete -c xcp -l keep-going -d 'Continue copying the remaining files after an error'
+complete -c xcp -l exit-codes -d 'Exit statuses to use with --keep-going' -x
+complete -c xcp -l status-file -d 'Periodically write the copy progress to this file' -r -F
+complete -c xcp -l status -d 'Display the progress recorded in a status file and exit' -r -F
+complete -c xcp -l xattr-include -d 'Only copy extended attributes matching PATTERN' -x
+complete -c xcp -l xattr-exclude -d 'Skip extended attributes matching PATTERN' -x
+complete -c xcp -l usermap -d 'Remap file owners; implies preserving ownership' -x
+complete -c xcp -l groupmap -d 'Remap file groups; implies preserving ownership' -x
+complete -c xcp -l preserve -d 'Preserve only the listed attributes' -x -a "mode ownership timestamps links xattr context acl all"
+complete -c xcp -l preserve-context -d 'Preserve the SELinux security context'
+complete -c xcp -l preserve-acl -d 'Preserve POSIX ACLs'
+complete -c xcp -l metrics -d 'Write Prometheus metrics to a file' -r -F
+complete -c xcp -l max-open-fds -d 'Maximum number of open file descriptors' -x
+complete -c xcp -l encrypted -d 'Handling of encrypted sources without a key' -x -a "$encrypted"
 
 # docs: https://fishshell.com/docs/current/completions.html
 # path: /usr/share/fish/vendor_completions.d/xcp.fish
@@ -18,35 +18,153 @@
 use log::{debug, warn};
 use rustix::fs::{fsync, ftruncate};
 use rustix::io::{pread, pwrite};
-use std::cmp;
-use std::fs::{File, FileTimes};
+use std::{cmp, io, mem, ptr};
+use std::ffi::{CString, OsStr};
+use std::fs::{File, FileTimes, Metadata};
 use std::io::{ErrorKind, Read, Write};
-use std::os::unix::fs::MetadataExt;
+use std::ops::Range;
+use std::os::unix::fs::{FileExt as UnixFileExt, MetadataExt};
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use xattr::FileExt;
 
 use crate::errors::{Result, Error};
-use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes};
+use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes, copy_file_offset, filesystem_type, map_extents};
 
-fn copy_xattr(infd: &File, outfd: &File) -> Result<()> {
-    // FIXME: Flag for xattr.
+/// File capabilities (e.g. `cap_net_bind_service`); writing these
+/// requires `CAP_SETFCAP`.
+const CAPABILITY_XATTR: &str = "security.capability";
+
+/// The SELinux security context.
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// The POSIX access ACL of a file or directory.
+const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+
+/// The POSIX default ACL of a directory, inherited by new entries.
+const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// Namespaces other than `user` may not be writable by this process or
+// on the target filesystem (e.g. `system.*` ACLs on a filesystem
+// without them).
+fn is_unwritable(attr: &OsStr, err: &std::io::Error) -> bool {
+    !attr.as_encoded_bytes().starts_with(b"user.")
+        && matches!(err.raw_os_error(), Some(libc::EPERM | libc::EACCES | libc::EOPNOTSUPP))
+}
+
+/// Copy the [xattrs](https://man7.org/linux/man-pages/man7/xattr.7.html)
+/// for which `include` returns true. Attributes in namespaces that
+/// can't be written are skipped. This is a no-op on platforms without
+/// xattr support; see [XATTR_SUPPORTED](crate::XATTR_SUPPORTED).
+pub fn copy_xattrs(infd: &File, outfd: &File, include: impl Fn(&OsStr) -> bool) -> Result<()> {
     if XATTR_SUPPORTED {
         debug!("Starting xattr copy...");
+        // Copy each attribute independently so one failure doesn't
+        // lose the rest; the first error is returned.
+        let mut result = Ok(());
         for attr in infd.list_xattr()? {
+            if !include(&attr) {
+                debug!("Skipping filtered xattr {:?}", attr);
+                continue;
+            }
             if let Some(val) = infd.get_xattr(&attr)? {
                 debug!("Copy xattr {:?}", attr);
-                outfd.set_xattr(attr, val.as_slice())?;
+                match outfd.set_xattr(&attr, val.as_slice()) {
+                    Err(e) if attr == CAPABILITY_XATTR && e.kind() == ErrorKind::PermissionDenied => {
+                        warn!("Unable to preserve file capabilities on {:?}; this requires CAP_SETFCAP", outfd);
+                    }
+                    Err(e) if is_unwritable(&attr, &e) => {
+                        debug!("Skipping unwritable xattr {:?}: {}", attr, e);
+                    }
+                    Err(e) if result.is_ok() => result = Err(e.into()),
+                    _ => {}
+                }
             }
         }
+        return result;
     }
     Ok(())
 }
 
+/// Copy the SELinux security context (`security.selinux`), as for
+/// `cp --preserve=context`. Returns `false` without changing the
+/// destination if the source has no context, e.g. when SELinux is not
+/// enabled.
+pub fn copy_security_context(infd: &File, outfd: &File) -> Result<bool> {
+    if !XATTR_SUPPORTED {
+        return Ok(false);
+    }
+    let context = match infd.get_xattr(SELINUX_XATTR) {
+        Ok(Some(context)) => context,
+        Ok(None) => return Ok(false),
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    debug!("Copy security context {:?}", String::from_utf8_lossy(&context));
+    outfd.set_xattr(SELINUX_XATTR, &context)?;
+    Ok(true)
+}
+
+/// Copy the [POSIX ACLs](https://man7.org/linux/man-pages/man5/acl.5.html)
+/// (`system.posix_acl_access`, and `system.posix_acl_default` for
+/// directories), as for `cp --preserve=mode`. ACLs the source doesn't
+/// have are removed from the destination, e.g. where it inherited a
+/// default ACL from its parent. Returns `false` if the filesystem
+/// doesn't support ACLs.
+pub fn copy_acls(infd: &File, outfd: &File) -> Result<bool> {
+    if !XATTR_SUPPORTED {
+        return Ok(false);
+    }
+    let mut names = vec![ACL_ACCESS_XATTR];
+    if infd.metadata()?.is_dir() {
+        names.push(ACL_DEFAULT_XATTR);
+    }
+    for name in names {
+        let r = match infd.get_xattr(name) {
+            Ok(Some(acl)) => {
+                debug!("Copy ACL {}", name);
+                outfd.set_xattr(name, &acl)
+            }
+            Ok(None) => match outfd.get_xattr(name) {
+                Ok(Some(_)) => outfd.remove_xattr(name),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match r {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// The process file mode creation mask. It is read by setting and
+/// restoring it, so files created concurrently by other threads may
+/// get the wrong mode; call this before starting any copies.
+pub fn umask() -> u32 {
+    unsafe {
+        let mask = libc::umask(0o022);
+        libc::umask(mask);
+        mask as u32
+    }
+}
+
 /// Copy file permissions. Will also copy
 /// [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)'s if
-/// possible.
+/// possible, including file capabilities (`security.capability`) when
+/// running with `CAP_SETFCAP`; otherwise these are skipped with a
+/// warning.
 pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
-    let xr = copy_xattr(infd, outfd);
+    copy_permissions_filtered(infd, outfd, |_| true)
+}
+
+/// As [copy_permissions], but only copying the xattrs for which
+/// `include` returns true.
+pub fn copy_permissions_filtered(infd: &File, outfd: &File, include: impl Fn(&OsStr) -> bool) -> Result<()> {
+    let xr = copy_xattrs(infd, outfd, include);
     if let Err(e) = xr {
         // FIXME: We don't have a way of detecting if the
         // target FS supports XAttr, so assume any error is
@@ -66,17 +184,66 @@ pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
 
 /// Copy file timestamps.
 pub fn copy_timestamps(infd: &File, outfd: &File) -> Result<()> {
-    let inmeta = infd.metadata()?;
-
     debug!("Performing timestamp copy");
+    set_timestamps(outfd, &infd.metadata()?, None)
+}
+
+/// As [copy_timestamps], but rounding the times down to a multiple
+/// of `granularity`; see [timestamp_granularity]. This sets the
+/// times the destination filesystem would store anyway, but
+/// consistently, so later comparisons with the source time rounded
+/// the same way are stable.
+pub fn copy_timestamps_rounded(infd: &File, outfd: &File, granularity: Duration) -> Result<()> {
+    debug!("Performing timestamp copy with {:?} granularity", granularity);
+    set_timestamps(outfd, &infd.metadata()?, Some(granularity))
+}
+
+/// Set the access and modification times of `outfd` to those in
+/// `meta`, to the nanosecond, or rounded down to a multiple of
+/// `granularity` if given. Taking the metadata from before a copy
+/// avoids picking up an access time updated by reading the source.
+pub fn set_timestamps(outfd: &File, meta: &Metadata, granularity: Option<Duration>) -> Result<()> {
+    let round = |time| match granularity {
+        Some(g) => round_time(time, g),
+        None => time,
+    };
     let ftime = FileTimes::new()
-        .set_accessed(inmeta.accessed()?)
-        .set_modified(inmeta.modified()?);
+        .set_accessed(round(meta.accessed()?))
+        .set_modified(round(meta.modified()?));
     outfd.set_times(ftime)?;
 
     Ok(())
 }
 
+/// Round `time` down to a multiple of `granularity` since the Unix
+/// epoch. Earlier times are returned unchanged.
+pub fn round_time(time: SystemTime, granularity: Duration) -> SystemTime {
+    let g = granularity.as_nanos();
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) if g > 1 => {
+            let nanos = since.as_nanos() / g * g;
+            let rounded = Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32);
+            UNIX_EPOCH + rounded
+        }
+        _ => time,
+    }
+}
+
+/// Return the resolution of the timestamps stored by the filesystem
+/// containing `path`, where it is known to be coarser than a
+/// nanosecond; e.g. 2 seconds for FAT. Returns `None` for other
+/// filesystems, including ext2/3 with small inodes (1 second), which
+/// can't be distinguished from ext4.
+pub fn timestamp_granularity(path: &Path) -> Result<Option<Duration>> {
+    let granularity = match filesystem_type(path)?.as_deref() {
+        Some("vfat") => Duration::from_secs(2),
+        Some("exfat") => Duration::from_millis(10),
+        Some("ntfs") | Some("smb2") | Some("cifs") => Duration::from_nanos(100),
+        _ => return Ok(None),
+    };
+    Ok(Some(granularity))
+}
+
 pub(crate) fn read_bytes(fd: &File, buf: &mut [u8], off: usize) -> Result<usize> {
     Ok(pread(fd, buf, off as u64)?)
 }
@@ -85,23 +252,23 @@ pub(crate) fn write_bytes(fd: &File, buf: &mut [u8], off: usize) -> Result<usize
     Ok(pwrite(fd, buf, off as u64)?)
 }
 
-/// Copy a block of bytes at an offset between files. Uses Posix pread/pwrite.
-pub(crate) fn copy_range_uspace(reader: &File, writer: &File, nbytes: usize, off: usize) -> Result<usize> {
+/// Copy a block of bytes between files, reading from offset `in_off`
+/// and writing at `out_off`. Uses Posix pread/pwrite.
+pub fn copy_range_uspace(reader: &File, writer: &File, nbytes: usize, in_off: usize, out_off: usize) -> Result<usize> {
     // FIXME: For larger buffers we should use a pre-allocated thread-local?
     let mut buf = vec![0; nbytes];
 
     let mut written: usize = 0;
     while written < nbytes {
         let next = cmp::min(nbytes - written, nbytes);
-        let noff = off + written;
 
-        let rlen = match read_bytes(reader, &mut buf[..next], noff) {
+        let rlen = match read_bytes(reader, &mut buf[..next], in_off + written) {
             Ok(0) => return Err(Error::InvalidSource("Source file ended prematurely.")),
             Ok(len) => len,
             Err(e) => return Err(e),
         };
 
-        let _wlen = match write_bytes(writer, &mut buf[..rlen], noff) {
+        let _wlen = match write_bytes(writer, &mut buf[..rlen], out_off + written) {
             Ok(len) if len < rlen => {
                 return Err(Error::InvalidSource("Failed write to file."))
             }
@@ -114,8 +281,94 @@ pub(crate) fn copy_range_uspace(reader: &File, writer: &File, nbytes: usize, off
     Ok(written)
 }
 
+/// Copy `len` bytes from offset `src_off` in `infd` to offset
+/// `dst_off` in `outfd`, without using or moving either file
+/// cursor. The offsets are independent, so this can copy any range
+/// of one file to any position in another; the destination is
+/// extended as needed. Unlike [copy_file_offset], which may copy
+/// less than requested, this only returns once the whole range is
+/// copied, returning `len`; it is an error if the source ends
+/// first. Ranges within the same file must not overlap.
+pub fn copy_range(infd: &File, outfd: &File, src_off: u64, dst_off: u64, len: u64) -> Result<u64> {
+    let mut copied = 0;
+    while copied < len {
+        let bytes = copy_file_offset(
+            infd,
+            outfd,
+            len - copied,
+            (src_off + copied) as i64,
+            (dst_off + copied) as i64,
+        )? as u64;
+        if bytes == 0 {
+            return Err(Error::InvalidSource("Source range extends beyond the end of the file."));
+        }
+        copied += bytes;
+    }
+    Ok(copied)
+}
+
+/// Blocks of this size, aligned in the file, that are entirely zero
+/// are left as holes by [copy_range_sparse].
+pub const ZERO_BLOCK_SIZE: usize = 4096;
+
+/// Copy up to `buf.len()` bytes at offset `off` between files through
+/// `buf`, without writing any aligned [ZERO_BLOCK_SIZE] blocks that
+/// are entirely zero, so they are left as holes in the destination;
+/// this should already have its final length. Returns the bytes read,
+/// which is only less than `buf.len()` at the end of the source, and
+/// the bytes written.
+pub fn copy_range_sparse(reader: &File, writer: &File, buf: &mut [u8], off: usize) -> Result<(usize, usize)> {
+    let mut len = 0;
+    while len < buf.len() {
+        match read_bytes(reader, &mut buf[len..], off + len)? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    // The end of the block containing `pos`, in buffer offsets.
+    let block_end = |pos: usize| cmp::min(len, (off + pos) / ZERO_BLOCK_SIZE * ZERO_BLOCK_SIZE + ZERO_BLOCK_SIZE - off);
+    let is_zero = |block: &[u8]| block.iter().all(|b| *b == 0);
+
+    let mut written = 0;
+    let mut pos = 0;
+    while pos < len {
+        let mut end = block_end(pos);
+        if is_zero(&buf[pos..end]) {
+            pos = end;
+            continue;
+        }
+        // Write runs of data blocks together.
+        while end < len && !is_zero(&buf[end..block_end(end)]) {
+            end = block_end(end);
+        }
+        writer.write_all_at(&buf[pos..end], (off + pos) as u64)?;
+        written += end - pos;
+        pos = end;
+    }
+    Ok((len, written))
+}
+
+/// Copy up to `buf.len()` bytes between files opened for direct I/O
+/// (see [open_direct](crate::open_direct)), reading from offset
+/// `in_off` and writing at `out_off`. The buffer (e.g. an
+/// [AlignedBuf]), offsets and length must be aligned to
+/// [DIRECT_IO_ALIGN]. Direct reads are only short at the end of the
+/// source, so this returns less than `buf.len()` only there.
+pub fn copy_range_aligned(reader: &File, writer: &File, buf: &mut [u8], in_off: usize, out_off: usize) -> Result<usize> {
+    let len = read_bytes(reader, buf, in_off)?;
+    let mut written = 0;
+    while written < len {
+        match write_bytes(writer, &mut buf[written..len], out_off + written)? {
+            0 => return Err(Error::InvalidSource("Failed write to file.")),
+            n => written += n,
+        }
+    }
+    Ok(len)
+}
+
 /// Slightly modified version of io::copy() that only copies a set amount of bytes.
-pub(crate) fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: usize) -> Result<usize> {
+pub fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: usize) -> Result<usize> {
     let mut buf = vec![0; nbytes];
 
     let mut written = 0;
@@ -133,12 +386,84 @@ pub(crate) fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: us
     Ok(written)
 }
 
+// Call a getpwnam_r(3)-style lookup, growing the buffer as needed.
+fn lookup_name<T>(name: &str, lookup: impl Fn(*const libc::c_char, &mut T, &mut [libc::c_char], &mut *mut T) -> libc::c_int) -> Result<Option<T>> {
+    let cname = match CString::new(name) {
+        Ok(cname) => cname,
+        Err(_) => return Ok(None),
+    };
+    let mut buf = vec![0; 4096];
+    loop {
+        // Safety: The lookup functions only require a writable
+        // struct of plain data, which they fill in.
+        let mut entry: T = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        match lookup(cname.as_ptr(), &mut entry, &mut buf, &mut result) {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(entry)),
+            libc::ERANGE if buf.len() < 1024 * 1024 => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err).into()),
+        }
+    }
+}
+
+/// Look up a user ID by name in the system user database. Returns
+/// `None` if there is no such user.
+pub fn user_id(name: &str) -> Result<Option<u32>> {
+    let pwd = lookup_name(name, |cname, pwd: &mut libc::passwd, buf, result| unsafe {
+        libc::getpwnam_r(cname, pwd, buf.as_mut_ptr(), buf.len(), result)
+    })?;
+    Ok(pwd.map(|p| p.pw_uid))
+}
+
+/// Look up a group ID by name in the system group database. Returns
+/// `None` if there is no such group.
+pub fn group_id(name: &str) -> Result<Option<u32>> {
+    let grp = lookup_name(name, |cname, grp: &mut libc::group, buf, result| unsafe {
+        libc::getgrnam_r(cname, grp, buf.as_mut_ptr(), buf.len(), result)
+    })?;
+    Ok(grp.map(|g| g.gr_gid))
+}
+
+/// Alignment of buffers, offsets and lengths for direct I/O. This is
+/// the largest logical block size in common use, so is safe for all
+/// devices.
+pub const DIRECT_IO_ALIGN: usize = 4096;
+
+/// A zeroed buffer whose start is aligned to [DIRECT_IO_ALIGN], for
+/// use with files opened by [open_direct](crate::open_direct).
+pub struct AlignedBuf {
+    buf: Vec<u8>,
+    off: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    /// Allocate a buffer of `len` bytes, rounded up to a multiple of
+    /// [DIRECT_IO_ALIGN].
+    pub fn new(len: usize) -> AlignedBuf {
+        let len = len.max(1).div_ceil(DIRECT_IO_ALIGN) * DIRECT_IO_ALIGN;
+        let buf = vec![0; len + DIRECT_IO_ALIGN];
+        let off = buf.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        AlignedBuf { buf, off, len }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[self.off..self.off + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.off..self.off + self.len]
+    }
+}
+
 /// Allocate file space on disk. Uses Posix ftruncate().
 pub fn allocate_file(fd: &File, len: u64) -> Result<()> {
     Ok(ftruncate(fd, len)?)
 }
 
-/// Merge any contiguous extents in a list. See [merge_extents].
+/// Merge any contiguous extents in a list. See [merge_extents]. Unwritten
+/// extents are not merged with written ones.
 pub fn merge_extents(extents: Vec<Extent>) -> Result<Vec<Extent>> {
     let mut merged: Vec<Extent> = vec![];
 
@@ -146,13 +471,15 @@ pub fn merge_extents(extents: Vec<Extent>) -> Result<Vec<Extent>> {
     for e in extents {
         match prev {
             Some(p) => {
-                if e.start == p.end + 1 {
+                if e.start == p.end + 1 && e.unwritten == p.unwritten {
                     // Current & prev are contiguous, merge & see what
                     // comes next.
                     prev = Some(Extent {
                         start: p.start,
                         end: e.end,
+                        physical: p.physical,
                         shared: p.shared & e.shared,
+                        unwritten: p.unwritten,
                     });
                 } else {
                     merged.push(p);
@@ -171,7 +498,10 @@ pub fn merge_extents(extents: Vec<Extent>) -> Result<Vec<Extent>> {
 }
 
 
-/// Determine if two files are the same by examining their inodes.
+/// Determine if two files are the same by examining their inodes
+/// (`st_dev` and `st_ino`). This also detects aliases via hard links
+/// and bind mounts, as a bind mount shares the device of the
+/// underlying filesystem.
 pub fn is_same_file(src: &Path, dest: &Path) -> Result<bool> {
     let sstat = src.metadata()?;
     let dstat = dest.metadata()?;
@@ -181,6 +511,78 @@ pub fn is_same_file(src: &Path, dest: &Path) -> Result<bool> {
     Ok(same)
 }
 
+/// Read buffer size for [files_equal].
+const COMPARE_BUFFER: usize = 256 * 1024;
+
+/// Compare the contents of two files. The lengths are compared
+/// first, then the data block by block, stopping at the first
+/// difference. If both files are sparse with the same layout of holes
+/// only the data regions are read; otherwise holes are compared as
+/// the zeros they read as, so a sparse file equals a dense copy.
+pub fn files_equal(a: &Path, b: &Path) -> Result<bool> {
+    let afd = File::open(a)?;
+    let bfd = File::open(b)?;
+    let len = afd.metadata()?.len();
+    if bfd.metadata()?.len() != len {
+        return Ok(false);
+    }
+    if is_same_file(a, b)? {
+        return Ok(true);
+    }
+
+    let mut ranges = None;
+    if probably_sparse(&afd)? && probably_sparse(&bfd)? {
+        let alayout = data_ranges(&afd, len)?;
+        if alayout.is_some() && alayout == data_ranges(&bfd, len)? {
+            debug!("Comparing data regions of {:?} and {:?}", a, b);
+            ranges = alayout;
+        }
+    }
+
+    let mut abuf = vec![0; COMPARE_BUFFER];
+    let mut bbuf = vec![0; COMPARE_BUFFER];
+    for range in ranges.unwrap_or_else(|| vec![Range { start: 0, end: len }]) {
+        let mut off = range.start;
+        while off < range.end {
+            let n = cmp::min(range.end - off, COMPARE_BUFFER as u64) as usize;
+            afd.read_exact_at(&mut abuf[..n], off)?;
+            bfd.read_exact_at(&mut bbuf[..n], off)?;
+            if abuf[..n] != bbuf[..n] {
+                return Ok(false);
+            }
+            off += n as u64;
+        }
+    }
+    Ok(true)
+}
+
+/// The written regions of the first `len` bytes of a file, from its
+/// extent map, with adjacent extents combined; `None` if the
+/// filesystem doesn't support extent maps. Unwritten extents read as
+/// zeros, so are treated as holes.
+pub fn data_ranges(fd: &File, len: u64) -> Result<Option<Vec<Range<u64>>>> {
+    let extents = match map_extents(fd)? {
+        Some(extents) => extents,
+        None => return Ok(None),
+    };
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for ext in extents.into_iter().filter(|e| !e.unwritten) {
+        let end = cmp::min(ext.end, len);
+        match ranges.last_mut() {
+            Some(last) if last.end >= ext.start => last.end = cmp::max(last.end, end),
+            _ => ranges.push(ext.start..end),
+        }
+    }
+    ranges.retain(|r| !r.is_empty());
+    Ok(Some(ranges))
+}
+
+/// Returns true if the path refers to `/dev/null` (by inode, so
+/// aliases such as `/proc/self/fd/N` are also detected).
+pub fn is_devnull(path: &Path) -> bool {
+    path.exists() && is_same_file(path, Path::new("/dev/null")).unwrap_or(false)
+}
+
 /// Copy a file. This differs from [std::fs::copy] in that it looks
 /// for sparse blocks and skips them.
 pub fn copy_file(from: &Path, to: &Path) -> Result<u64> {
@@ -207,7 +609,8 @@ pub fn sync(fd: &File) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::read;
+    use std::fs::{read, OpenOptions};
+    use std::io::Seek;
     use std::ops::Range;
     use tempfile::tempdir;
 
@@ -216,11 +619,25 @@ mod tests {
             Extent {
                 start: r.start,
                 end: r.end,
+                physical: 0,
                 shared: false,
+                unwritten: false,
             }
         }
     }
 
+    #[test]
+    fn test_is_devnull() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("file.txt");
+        File::create(&file).unwrap();
+
+        assert!(is_devnull(Path::new("/dev/null")));
+        assert!(!is_devnull(&file));
+        assert!(!is_devnull(&dir.path().join("missing")));
+        assert!(!is_devnull(Path::new("/dev/zero")));
+    }
+
     #[test]
     fn test_copy_bytes_uspace_large() {
         let dir = tempdir().unwrap();
@@ -272,7 +689,7 @@ mod tests {
             let mut written = 0;
 
             for off in (0..4).rev() {
-                written += copy_range_uspace(&infd, &outfd, blocksize, blocksize * off).unwrap();
+                written += copy_range_uspace(&infd, &outfd, blocksize, blocksize * off, blocksize * off).unwrap();
             }
 
             assert_eq!(written, size);
@@ -287,6 +704,93 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_copy_distinct_offsets() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&from, &data)?;
+        let infd = File::open(&from)?;
+        let out_off = 512 * 1024;
+
+        for (name, kernel) in [("uspace.bin", false), ("offset.bin", true)] {
+            let to = dir.path().join(name);
+            let outfd = File::create(&to)?;
+            let copied = if kernel {
+                copy_file_offset(&infd, &outfd, data.len() as u64, 0, out_off as i64)?
+            } else {
+                copy_range_uspace(&infd, &outfd, data.len(), 0, out_off)?
+            };
+            assert_eq!(data.len(), copied, "{}", name);
+
+            let out = read(&to)?;
+            assert_eq!(out_off + data.len(), out.len(), "{}", name);
+            assert!(out[..out_off].iter().all(|b| *b == 0), "{}", name);
+            assert_eq!(data, &out[out_off..], "{}", name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_range() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data: Vec<u8> = (0..16384u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&from, &data)?;
+        std::fs::write(&to, vec![0xffu8; 8192])?;
+
+        let infd = File::open(&from)?;
+        let outfd = OpenOptions::new().write(true).open(&to)?;
+        // The source offset ahead of the destination, and behind it.
+        assert_eq!(1000, copy_range(&infd, &outfd, 5000, 100, 1000)?);
+        assert_eq!(3000, copy_range(&infd, &outfd, 10, 7000, 3000)?);
+        assert_eq!(0, copy_range(&infd, &outfd, 0, 0, 0)?);
+
+        let out = read(&to)?;
+        assert_eq!(10000, out.len());
+        assert_eq!(&data[5000..6000], &out[100..1100]);
+        assert_eq!(&data[10..3010], &out[7000..10000]);
+        assert!(out[..100].iter().chain(&out[1100..7000]).all(|b| *b == 0xff));
+
+        // The cursors are unchanged.
+        assert_eq!(0, (&infd).stream_position()?);
+        assert_eq!(0, (&outfd).stream_position()?);
+
+        // Beyond the end of the source.
+        assert!(copy_range(&infd, &outfd, 16000, 0, 1000).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_range_sparse() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let block = ZERO_BLOCK_SIZE;
+        let mut data = vec![0u8; block * 8];
+        data[10] = 1;
+        data[block * 3..block * 5].fill(0xff);
+        // Partial blocks at either end of the copy.
+        data[block * 8 - 1] = 2;
+        std::fs::write(&from, &data)?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        allocate_file(&outfd, data.len() as u64)?;
+        let mut buf = vec![0; block * 4];
+        // Unaligned, and reading past the end of the source.
+        assert_eq!((block - 100, block - 100), copy_range_sparse(&infd, &outfd, &mut buf[..block - 100], 0)?);
+        assert_eq!((block * 4, block * 2 - 100), copy_range_sparse(&infd, &outfd, &mut buf, block - 100)?);
+        assert_eq!((block * 3 + 100, block + 100), copy_range_sparse(&infd, &outfd, &mut buf, block * 5 - 100)?);
+
+        assert_eq!(data, read(&to)?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_extent_merge() -> Result<()> {
         assert_eq!(merge_extents(vec!())?, vec!());
@@ -332,6 +836,12 @@ mod tests {
                     (51..60).into()))?,
             vec!((0..60).into())
         );
+
+        let unwritten = Extent { start: 11, end: 20, physical: 0, shared: false, unwritten: true };
+        assert_eq!(
+            merge_extents(vec!((0..10).into(), unwritten))?,
+            vec!((0..10).into(), Extent { start: 11, end: 20, physical: 0, shared: false, unwritten: true })
+        );
         Ok(())
     }
 
@@ -357,4 +867,15 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_id_lookup() {
+        assert_eq!(Some(0), user_id("root").unwrap());
+        // BSDs call group 0 'wheel'.
+        #[cfg(target_os = "linux")]
+        assert_eq!(Some(0), group_id("root").unwrap());
+        assert_eq!(None, user_id("no-such-user-xcp").unwrap());
+        assert_eq!(None, group_id("no-such-group-xcp").unwrap());
+        assert_eq!(None, user_id("bad\0name").unwrap());
+    }
 }
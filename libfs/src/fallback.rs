@@ -15,20 +15,25 @@
  */
 
 use std::fs::File;
+use std::io;
 use std::path::Path;
 
 use log::warn;
 
 use crate::Extent;
-use crate::common::{copy_bytes_uspace, copy_range_uspace};
+use crate::common::{allocate_file, copy_bytes_uspace, copy_range_uspace};
 use crate::errors::{Result, Error};
 
 pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<usize> {
     copy_bytes_uspace(infd, outfd, bytes as usize)
 }
 
-pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
-    copy_range_uspace(infd, outfd, bytes as usize, off as usize)
+pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, in_off: i64, out_off: i64) -> Result<usize> {
+    copy_range_uspace(infd, outfd, bytes as usize, in_off as usize, out_off as usize)
+}
+
+pub fn preallocate(_fd: &File, _len: u64, _keep_size: bool) -> Result<bool> {
+    Ok(false)
 }
 
 // No sparse file handling by default, needs to be implemented
@@ -42,13 +47,18 @@ pub fn map_extents(_fd: &File) -> Result<Option<Vec<Extent>>> {
     Ok(None)
 }
 
+pub fn map_extents_synced(_fd: &File) -> Result<Option<Vec<Extent>>> {
+    Ok(None)
+}
+
 pub fn next_sparse_segments(_infd: &File, _outfd: &File, _pos: u64) -> Result<(u64, u64)> {
-    // FIXME: Implement for *BSD with lseek?
     Err(Error::UnsupportedOperation {})
 }
 
 pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     let len = infd.metadata()?.len();
+    // Discard any existing data past the source length.
+    allocate_file(outfd, len)?;
     copy_file_bytes(&infd, &outfd, len)
         .map(|i| i as u64)
 }
@@ -59,6 +69,50 @@ pub fn copy_node(src: &Path, _dest: &Path) -> Result<()> {
     Ok(())
 }
 
+pub fn open_direct(_path: &Path) -> Result<Option<File>> {
+    Ok(None)
+}
+
+pub fn open_direct_write(_path: &Path) -> Result<Option<File>> {
+    Ok(None)
+}
+
 pub fn reflink(_infd: &File, _outfd: &File) -> Result<bool> {
     Ok(false)
 }
+
+pub fn clone_file(_from: &Path, _to: &Path) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn reflink_range(_infd: &File, _outfd: &File, _in_off: u64, _out_off: u64, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn sync_range(fd: &File) -> Result<()> {
+    Ok(fd.sync_data()?)
+}
+
+pub fn advise_sequential(_fd: &File) -> Result<()> {
+    Ok(())
+}
+
+pub fn drop_cache(_fd: &File) -> Result<()> {
+    Ok(())
+}
+
+pub fn is_encrypted(_fd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn is_missing_key(_err: &io::Error) -> bool {
+    false
+}
+
+pub fn filesystem_type(_path: &Path) -> Result<Option<String>> {
+    Ok(None)
+}
+
+pub fn free_inodes(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// FreeBSD uses the generic implementations, other than for sparse
+// files, which are handled with `SEEK_DATA`/`SEEK_HOLE` as on Linux.
+pub use crate::fallback::*;
+
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+
+use rustix::fs::{seek, SeekFrom};
+use rustix::io::Errno;
+
+use crate::common::allocate_file;
+use crate::errors::Result;
+
+/// Guestimate if file is sparse; if it has less blocks that would be
+/// expected for its stated size. This is the same test used by
+/// coreutils `cp`. Note that compressed ZFS files may also match.
+pub fn probably_sparse(fd: &File) -> Result<bool> {
+    const ST_NBLOCKSIZE: u64 = 512;
+    let stat = fd.metadata()?;
+    Ok(stat.blocks() < stat.size() / ST_NBLOCKSIZE)
+}
+
+// Seek, returning `None` if there is no further data/hole.
+fn lseek(fd: &File, from: SeekFrom) -> Result<Option<u64>> {
+    match seek(fd, from) {
+        Err(errno) if errno == Errno::NXIO => Ok(None),
+        Err(err) => Err(err.into()),
+        Ok(off) => Ok(Some(off)),
+    }
+}
+
+/// Search the file for the next non-sparse file section. Returns the
+/// start and end of the data segment.
+pub fn next_sparse_segments(infd: &File, outfd: &File, pos: u64) -> Result<(u64, u64)> {
+    let len = infd.metadata()?.len();
+    let next_data = lseek(infd, SeekFrom::Data(pos as i64))?.unwrap_or(len);
+    let next_hole = lseek(infd, SeekFrom::Hole(next_data as i64))?.unwrap_or(len);
+
+    seek(infd, SeekFrom::Start(next_data))?;
+    seek(outfd, SeekFrom::Start(next_data))?;
+
+    Ok((next_data, next_hole))
+}
+
+/// Copy data between files, looking for sparse blocks and skipping
+/// them. Any existing destination data is discarded and the
+/// destination is set to the source length, so holes in the source
+/// are holes in the destination.
+pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
+    let len = infd.metadata()?.len();
+
+    allocate_file(outfd, 0)?;
+    allocate_file(outfd, len)?;
+
+    let mut pos = 0;
+    while pos < len {
+        let (next_data, next_hole) = next_sparse_segments(infd, outfd, pos)?;
+        copy_file_bytes(infd, outfd, next_hole - next_data)?;
+        pos = next_hole;
+    }
+
+    Ok(len)
+}
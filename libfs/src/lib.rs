@@ -26,29 +26,72 @@ cfg_if! {
     if #[cfg(all(target_os = "linux", feature = "use_linux"))] {
         mod linux;
         use linux as backend;
+    } else if #[cfg(target_os = "macos")] {
+        mod fallback;
+        mod macos;
+        use macos as backend;
+    } else if #[cfg(target_os = "freebsd")] {
+        mod fallback;
+        mod freebsd;
+        use freebsd as backend;
     } else {
         mod fallback;
         use fallback as backend;
     }
 }
 pub use backend::{
+    advise_sequential,
+    clone_file,
     copy_file_bytes,
     copy_file_offset,
     copy_node,
     copy_sparse,
+    drop_cache,
+    filesystem_type,
+    free_inodes,
+    is_encrypted,
+    is_missing_key,
     probably_sparse,
     next_sparse_segments,
     map_extents,
+    map_extents_synced,
+    open_direct,
+    open_direct_write,
+    preallocate,
     reflink,
+    reflink_range,
+    sync_range,
 };
 pub use common::{
+    AlignedBuf,
+    DIRECT_IO_ALIGN,
+    ZERO_BLOCK_SIZE,
     allocate_file,
+    copy_acls,
+    copy_bytes_uspace,
     copy_file,
     copy_permissions,
+    copy_permissions_filtered,
+    copy_range,
+    copy_range_aligned,
+    copy_range_sparse,
+    copy_range_uspace,
+    copy_security_context,
     copy_timestamps,
+    copy_timestamps_rounded,
+    copy_xattrs,
+    data_ranges,
+    files_equal,
+    group_id,
+    is_devnull,
     is_same_file,
     merge_extents,
+    round_time,
+    set_timestamps,
     sync,
+    timestamp_granularity,
+    umask,
+    user_id,
 };
 pub use errors::Error;
 
@@ -110,10 +153,17 @@ pub struct Extent {
     pub start: u64,
     /// Extent logical end
     pub end: u64,
+    /// Physical start of the extent on the device, where known; 0
+    /// otherwise.
+    pub physical: u64,
     /// Whether extent is shared between multiple file. This generally
     /// only applies to reflinked files on filesystems that support
     /// CoW.
     pub shared: bool,
+    /// Whether the extent is allocated but has never been written
+    /// (e.g. reserved with `fallocate`). These read as zeros, so can
+    /// be treated as holes when copying.
+    pub unwritten: bool,
 }
 
 impl From<Extent> for Range<u64> {
@@ -14,18 +14,25 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{fs::File, path::Path};
+use std::alloc::{alloc_zeroed, handle_alloc_error, Layout};
+use std::{fs::{self, File, OpenOptions}, path::Path};
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::PermissionsExt;
 
-use linux_raw_sys::ioctl::{FS_IOC_FIEMAP, FIEMAP_EXTENT_LAST, FICLONE, FIEMAP_EXTENT_SHARED};
-use rustix::fs::CWD;
-use rustix::{fs::{copy_file_range, seek, mknodat, FileType, Mode, RawMode, SeekFrom}, io::Errno};
+use linux_raw_sys::general::{file_clone_range, fscrypt_policy_v1};
+use linux_raw_sys::ioctl::{
+    FS_IOC_FIEMAP, FS_IOC_GET_ENCRYPTION_POLICY, FIEMAP_EXTENT_LAST, FIEMAP_EXTENT_UNWRITTEN, FIEMAP_FLAG_SYNC,
+    FICLONE, FICLONERANGE, FIEMAP_EXTENT_SHARED,
+};
+use rustix::fs::{statfs, statvfs, CWD};
+use rustix::{fs::{copy_file_range, fadvise, fallocate, seek, Advice, mknodat, FallocateFlags, FileType, Mode, RawMode, SeekFrom}, io::Errno};
+
+use log::debug;
 
 use crate::Extent;
 use crate::errors::Result;
-use crate::common::{copy_bytes_uspace, copy_range_uspace};
+use crate::common::{allocate_file, copy_bytes_uspace, copy_range_uspace};
 
 // Wrapper for copy_file_range(2) that checks for non-fatal errors due
 // to limitations of the syscall.
@@ -60,21 +67,39 @@ pub fn copy_file_bytes(infd: &File, outfd: &File, bytes: u64) -> Result<usize> {
         .unwrap_or_else(|| copy_bytes_uspace(infd, outfd, bytes as usize))
 }
 
-/// File copy operation that that copies a block at offset`off`.  On
-/// Linux this attempts to use
+/// File copy operation that that copies a block from offset `in_off`
+/// in the source to offset `out_off` in the destination.  On Linux
+/// this attempts to use
 /// [copy_file_range](https://man7.org/linux/man-pages/man2/copy_file_range.2.html)
 /// and falls back to user-space if that is not available.
-pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
-    let mut off_in = off as u64;
-    let mut off_out = off as u64;
+pub fn copy_file_offset(infd: &File, outfd: &File, bytes: u64, in_off: i64, out_off: i64) -> Result<usize> {
+    let mut off_in = in_off as u64;
+    let mut off_out = out_off as u64;
     try_copy_file_range(infd, Some(&mut off_in), outfd, Some(&mut off_out), bytes)
-        .unwrap_or_else(|| copy_range_uspace(infd, outfd, bytes as usize, off as usize))
+        .unwrap_or_else(|| copy_range_uspace(infd, outfd, bytes as usize, in_off as usize, out_off as usize))
+}
+
+/// Reserve disk blocks for a file using
+/// [fallocate](https://man7.org/linux/man-pages/man2/fallocate.2.html). If
+/// `keep_size` is set the file length is not changed
+/// (`FALLOC_FL_KEEP_SIZE`). Returns `false` if the filesystem doesn't
+/// support preallocation.
+pub fn preallocate(fd: &File, len: u64, keep_size: bool) -> Result<bool> {
+    let flags = if keep_size {
+        FallocateFlags::KEEP_SIZE
+    } else {
+        FallocateFlags::empty()
+    };
+    match fallocate(fd, flags, 0, len) {
+        Ok(()) => Ok(true),
+        Err(Errno::OPNOTSUPP) => Ok(false),
+        Err(errno) => Err(errno.into()),
+    }
 }
 
 /// Guestimate if file is sparse; if it has less blocks that would be
 /// expected for its stated size. This is the same test used by
 /// coreutils `cp`.
-// FIXME: Should work on *BSD?
 pub fn probably_sparse(fd: &File) -> Result<bool> {
     use std::os::linux::fs::MetadataExt;
     const ST_NBLOCKSIZE: u64 = 512;
@@ -96,7 +121,8 @@ fn lseek(fd: &File, from: SeekFrom) -> Result<SeekOff> {
     }
 }
 
-const FIEMAP_PAGE_SIZE: usize = 32;
+/// Number of extents fetched per FIEMAP call.
+const FIEMAP_EXTENT_BATCH: usize = 512;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -108,18 +134,6 @@ struct FiemapExtent {
     fe_flags: u32, // FIEMAP_EXTENT_* flags for this extent
     fe_reserved: [u32; 3],
 }
-impl FiemapExtent {
-    fn new() -> FiemapExtent {
-        FiemapExtent {
-            fe_logical: 0,
-            fe_physical: 0,
-            fe_length: 0,
-            fe_reserved64: [0; 2],
-            fe_flags: 0,
-            fe_reserved: [0; 3],
-        }
-    }
-}
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -130,19 +144,25 @@ struct FiemapReq {
     fm_mapped_extents: u32, // Number of extents that were mapped (out)
     fm_extent_count: u32,   // Size of fm_extents array (in)
     fm_reserved: u32,
-    fm_extents: [FiemapExtent; FIEMAP_PAGE_SIZE], // Array of mapped extents (out)
+    fm_extents: [FiemapExtent; FIEMAP_EXTENT_BATCH], // Array of mapped extents (out)
 }
 impl FiemapReq {
-    fn new() -> FiemapReq {
-        FiemapReq {
-            fm_start: 0,
-            fm_length: u64::max_value(),
-            fm_flags: 0,
-            fm_mapped_extents: 0,
-            fm_extent_count: FIEMAP_PAGE_SIZE as u32,
-            fm_reserved: 0,
-            fm_extents: [FiemapExtent::new(); FIEMAP_PAGE_SIZE],
-        }
+    fn new(flags: u32) -> Box<FiemapReq> {
+        // The request is ~28KB, so allocate it directly on the heap
+        // rather than building it on the stack first. All fields are
+        // integers, so zeroed memory is a valid value.
+        let layout = Layout::new::<FiemapReq>();
+        let mut req = unsafe {
+            let ptr = alloc_zeroed(layout) as *mut FiemapReq;
+            if ptr.is_null() {
+                handle_alloc_error(layout);
+            }
+            Box::from_raw(ptr)
+        };
+        req.fm_length = u64::MAX;
+        req.fm_flags = flags;
+        req.fm_extent_count = FIEMAP_EXTENT_BATCH as u32;
+        req
     }
 }
 
@@ -167,8 +187,22 @@ fn fiemap(fd: &File, req: &mut FiemapReq) -> Result<bool> {
 /// [fiemap](https://docs.kernel.org/filesystems/fiemap.html). See
 /// [merge_extents](super::merge_extents) for a tool to merge contiguous extents.
 pub fn map_extents(fd: &File) -> Result<Option<Vec<Extent>>> {
-    let mut req = FiemapReq::new();
-    let mut extents = Vec::with_capacity(FIEMAP_PAGE_SIZE);
+    fiemap_extents(fd, 0)
+}
+
+/// As [map_extents], but flushes the file's dirty data first. Until
+/// data written to a preallocated extent has been written back the
+/// extent may still be reported as unwritten, so use this where
+/// unwritten extents are skipped as holes. Note that this forces
+/// writeback of the whole file, which can be slow if it has a lot of
+/// recently written data.
+pub fn map_extents_synced(fd: &File) -> Result<Option<Vec<Extent>>> {
+    fiemap_extents(fd, FIEMAP_FLAG_SYNC)
+}
+
+fn fiemap_extents(fd: &File, flags: u32) -> Result<Option<Vec<Extent>>> {
+    let mut req = FiemapReq::new(flags);
+    let mut extents = Vec::with_capacity(FIEMAP_EXTENT_BATCH);
 
     loop {
         if !fiemap(fd, &mut req)? {
@@ -180,10 +214,15 @@ pub fn map_extents(fd: &File) -> Result<Option<Vec<Extent>>> {
 
         for i in 0..req.fm_mapped_extents as usize {
             let e = req.fm_extents[i];
+            if e.fe_length == 0 {
+                continue;
+            }
             let ext = Extent {
                 start: e.fe_logical,
                 end: e.fe_logical + e.fe_length,
+                physical: e.fe_physical,
                 shared: e.fe_flags & FIEMAP_EXTENT_SHARED != 0,
+                unwritten: e.fe_flags & FIEMAP_EXTENT_UNWRITTEN != 0,
             };
             extents.push(ext);
         }
@@ -202,7 +241,6 @@ pub fn map_extents(fd: &File) -> Result<Option<Vec<Extent>>> {
 
 /// Search the file for the next non-sparse file section. Returns the
 /// start and end of the data segment.
-// FIXME: Should work on *BSD too?
 pub fn next_sparse_segments(infd: &File, outfd: &File, pos: u64) -> Result<(u64, u64)> {
     let next_data = match lseek(infd, SeekFrom::Data(pos as i64))? {
         SeekOff::Offset(off) => off,
@@ -220,10 +258,17 @@ pub fn next_sparse_segments(infd: &File, outfd: &File, pos: u64) -> Result<(u64,
 }
 
 /// Copy data between files, looking for sparse blocks and skipping
-/// them.
+/// them. Any existing destination data is discarded and the
+/// destination is set to the source length, so holes in the source
+/// are holes in the destination.
 pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     let len = infd.metadata()?.len();
 
+    // Truncating down releases any existing blocks; extending
+    // leaves the whole file as a hole to copy the data into.
+    allocate_file(outfd, 0)?;
+    allocate_file(outfd, len)?;
+
     let mut pos = 0;
     while pos < len {
         let (next_data, next_hole) = next_sparse_segments(infd, outfd, pos)?;
@@ -235,14 +280,15 @@ pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     Ok(len)
 }
 
-/// Create a clone of a special file (unix socket, char-device, etc.)
+/// Create a clone of a special file (FIFO, device node, unix socket,
+/// etc.), preserving the device number.
 pub fn copy_node(src: &Path, dest: &Path) -> Result<()> {
     use std::os::unix::fs::MetadataExt;
     let meta = src.metadata()?;
     let rmode = RawMode::from(meta.permissions().mode());
     let mode = Mode::from_raw_mode(rmode);
     let ftype = FileType::from_raw_mode(rmode);
-    let dev = meta.dev();
+    let dev = meta.rdev();
 
     mknodat(CWD, dest, ftype, mode, dev)?;
     Ok(())
@@ -251,9 +297,59 @@ pub fn copy_node(src: &Path, dest: &Path) -> Result<()> {
 /// Reflink a file. This will reuse the underlying data on disk for
 /// the target file, utilising copy-on-write for any future
 /// updates. Only certain filesystems support this; if not supported
-/// the function returns `false`.
+/// the function returns `false`. This is all-or-nothing; `FICLONE`
+/// either shares the whole file or leaves the destination unchanged.
 pub fn reflink(infd: &File, outfd: &File) -> Result<bool> {
     if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONE as u64, infd.as_raw_fd()) } != 0 {
+        let oserr = io::Error::last_os_error();
+        match oserr.raw_os_error() {
+            // Includes the source and destination being on different
+            // filesystems (EXDEV); the caller falls back to copying.
+            Some(libc::EOPNOTSUPP)
+                | Some(libc::EINVAL)
+                | Some(libc::EXDEV)
+                | Some(libc::ETXTBSY) => {
+                debug!("Reflink not possible: {}", oserr);
+                return Ok(false)
+            }
+            _ =>
+                return  Err(oserr.into()),
+        }
+    }
+    Ok(true)
+}
+
+/// Create `to` as a reflinked clone of `from`; `to` must not already
+/// exist. If reflinks are not supported `to` is removed again and
+/// `false` is returned.
+pub fn clone_file(from: &Path, to: &Path) -> Result<bool> {
+    let infd = File::open(from)?;
+    let outfd = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    if reflink(&infd, &outfd)? {
+        return Ok(true);
+    }
+    drop(outfd);
+    fs::remove_file(to)?;
+    Ok(false)
+}
+
+/// Reflink a range of a file into another file.  Offsets and length
+/// must generally be aligned to the filesystem block size (the length
+/// may be unaligned if the range ends at the source EOF). Only
+/// certain filesystems support this; if not supported, or the range
+/// is not suitably aligned, the function returns `false`.
+pub fn reflink_range(infd: &File, outfd: &File, in_off: u64, out_off: u64, len: u64) -> Result<bool> {
+    let mut req = file_clone_range {
+        src_fd: infd.as_raw_fd() as i64,
+        src_offset: in_off,
+        src_length: len,
+        dest_offset: out_off,
+    };
+    let req_ptr: *mut file_clone_range = &mut req;
+    if unsafe { libc::ioctl(outfd.as_raw_fd(), FICLONERANGE as u64, req_ptr) } != 0 {
         let oserr = io::Error::last_os_error();
         match oserr.raw_os_error() {
             Some(libc::EOPNOTSUPP)
@@ -268,6 +364,158 @@ pub fn reflink(infd: &File, outfd: &File) -> Result<bool> {
     Ok(true)
 }
 
+/// Start writeback of a file's dirty pages using
+/// [sync_file_range](https://man7.org/linux/man-pages/man2/sync_file_range.2.html),
+/// first waiting for any writeback already in progress. Calling this
+/// periodically during a large write bounds the amount of dirty data,
+/// smoothing out the final flush. This does not flush metadata or
+/// the disk cache, so a final [sync](crate::sync) is still needed
+/// for durability.
+pub fn sync_range(fd: &File) -> Result<()> {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE;
+    if unsafe { libc::sync_file_range(fd.as_raw_fd(), 0, 0, flags) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Advise the kernel that `fd` will be read sequentially, using
+/// [posix_fadvise](https://man7.org/linux/man-pages/man2/posix_fadvise.2.html).
+/// This increases readahead for the file.
+pub fn advise_sequential(fd: &File) -> Result<()> {
+    fadvise(fd, 0, 0, Advice::Sequential)?;
+    Ok(())
+}
+
+/// Remove a file's pages from the page cache, using
+/// [posix_fadvise](https://man7.org/linux/man-pages/man2/posix_fadvise.2.html).
+/// Dirty pages can't be dropped, so any written data is flushed to
+/// disk first; this does not flush metadata.
+pub fn drop_cache(fd: &File) -> Result<()> {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    if unsafe { libc::sync_file_range(fd.as_raw_fd(), 0, 0, flags) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    fadvise(fd, 0, 0, Advice::DontNeed)?;
+    Ok(())
+}
+
+/// Check whether a file or directory is encrypted with
+/// [fscrypt](https://docs.kernel.org/filesystems/fscrypt.html), using
+/// the `FS_IOC_GET_ENCRYPTION_POLICY` ioctl. This works whether or not
+/// the key is present. Filesystems without encryption support return
+/// `false`.
+pub fn is_encrypted(fd: &File) -> Result<bool> {
+    let mut policy = fscrypt_policy_v1 {
+        version: 0,
+        contents_encryption_mode: 0,
+        filenames_encryption_mode: 0,
+        flags: 0,
+        master_key_descriptor: [0; 8],
+    };
+    let policy_ptr: *mut fscrypt_policy_v1 = &mut policy;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), FS_IOC_GET_ENCRYPTION_POLICY as u64, policy_ptr) } != 0 {
+        let oserr = io::Error::last_os_error();
+        return match oserr.raw_os_error() {
+            // Encrypted, but with a v2 policy which this ioctl
+            // can't return.
+            Some(libc::EINVAL) => Ok(true),
+            Some(libc::ENODATA)
+                | Some(libc::ENOTTY)
+                | Some(libc::EOPNOTSUPP) =>
+                Ok(false),
+            _ =>
+                Err(oserr.into()),
+        };
+    }
+    Ok(true)
+}
+
+/// Returns true if the error indicates that an fscrypt key required
+/// to open a file is not present (`ENOKEY`).
+pub fn is_missing_key(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOKEY)
+}
+
+/// Open a file for reading with `O_DIRECT`, bypassing the page
+/// cache. Reads must use buffers, offsets and lengths aligned to
+/// [DIRECT_IO_ALIGN]; see [AlignedBuf](crate::AlignedBuf). Returns
+/// `None` if the filesystem doesn't support direct I/O (e.g. tmpfs).
+pub fn open_direct(path: &Path) -> Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match File::options().read(true).custom_flags(libc::O_DIRECT).open(path) {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Open an existing file for writing with `O_DIRECT`; as with
+/// [open_direct], writes must be aligned to [DIRECT_IO_ALIGN] and
+/// `None` is returned if the filesystem doesn't support direct I/O.
+pub fn open_direct_write(path: &Path) -> Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    match File::options().write(true).custom_flags(libc::O_DIRECT).open(path) {
+        Ok(fd) => Ok(Some(fd)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Map a `statfs(2)` `f_type` magic number to a filesystem name.
+fn fs_type_name(magic: u32) -> Option<&'static str> {
+    let name = match magic {
+        0x0000_9fa0 => "proc",
+        0x0000_6969 => "nfs",
+        0x0000_4d44 => "vfat",
+        0x0000_ef53 => "ext4",
+        0x00c3_6400 => "ceph",
+        0x0102_1994 => "tmpfs",
+        0x2011_bab0 => "exfat",
+        0x2fc1_2fc1 => "zfs",
+        0x5846_5342 => "xfs",
+        0x5346_544e => "ntfs",
+        0x6265_6572 => "sysfs",
+        0x6573_5546 => "fuse",
+        0x7371_7368 => "squashfs",
+        0x794c_7630 => "overlayfs",
+        0x8584_58f6 => "ramfs",
+        0x9123_683e => "btrfs",
+        0xca45_1a4e => "bcachefs",
+        0xf2f5_2010 => "f2fs",
+        0xfe53_4d42 => "smb2",
+        0xff53_4d42 => "cifs",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Return the type of the filesystem containing `path`, using
+/// `statfs(2)`. Common filesystems are returned by name (e.g. `ext4`,
+/// which also covers ext2/3, or `btrfs`); others as the hex magic
+/// number. Useful for diagnosing why reflinks or `copy_file_range`
+/// were not used.
+pub fn filesystem_type(path: &Path) -> Result<Option<String>> {
+    let st = statfs(path)?;
+    let magic = st.f_type as u32;
+    let name = fs_type_name(magic)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:#x}", magic));
+    Ok(Some(name))
+}
+
+/// Return the number of inodes available to unprivileged users on
+/// the filesystem containing `path`, using `statvfs(2)`. Returns
+/// `None` if the filesystem doesn't have a fixed inode count
+/// (e.g. btrfs reports zero total inodes).
+pub fn free_inodes(path: &Path) -> Result<Option<u64>> {
+    let st = statvfs(path)?;
+    if st.f_files == 0 {
+        return Ok(None);
+    }
+    Ok(Some(st.f_favail))
+}
+
 #[cfg(test)]
 #[allow(unused)]
 mod tests {
@@ -277,6 +525,7 @@ mod tests {
     use std::fs::{read, OpenOptions};
     use std::io::{self, Seek, Write};
     use std::iter;
+    use std::os::unix::fs::FileExt;
     use std::os::unix::net::UnixListener;
     use std::path::PathBuf;
     use std::process::Command;
@@ -309,7 +558,7 @@ mod tests {
         let to_fd = File::create(to)?;
 
         {
-            let mut from_map = FiemapReq::new();
+            let mut from_map = FiemapReq::new(0);
             assert!(fiemap(&from_fd, &mut from_map)?);
             assert!(from_map.fm_mapped_extents > 0);
             // Un-refed file, no shared extents
@@ -320,11 +569,11 @@ mod tests {
         assert!(worked);
 
         {
-            let mut from_map = FiemapReq::new();
+            let mut from_map = FiemapReq::new(0);
             assert!(fiemap(&from_fd, &mut from_map)?);
             assert!(from_map.fm_mapped_extents > 0);
 
-            let mut to_map = FiemapReq::new();
+            let mut to_map = FiemapReq::new(0);
             assert!(fiemap(&to_fd, &mut to_map)?);
             assert!(to_map.fm_mapped_extents > 0);
 
@@ -376,7 +625,7 @@ mod tests {
         {
             let mut fd = OpenOptions::new().write(true).append(false).open(&file)?;
             let s = "x".repeat(512*1024);
-            fd.write(s.as_bytes())?;
+            fd.write_all(s.as_bytes())?;
             assert!(probably_sparse(&fd)?);
         }
 
@@ -484,7 +733,7 @@ mod tests {
             let infd = File::open(&from)?;
             let outfd: File = OpenOptions::new().write(true).append(false).open(&file)?;
             let copied =
-                copy_file_offset(&infd, &outfd, data.len() as u64, offset as i64)?;
+                copy_file_offset(&infd, &outfd, data.len() as u64, offset as i64, offset as i64)?;
             assert_eq!(copied as usize, data.len());
         }
 
@@ -613,6 +862,109 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
+    fn test_preallocate() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("prealloc.bin");
+        let len = 4 * 1024 * 1024;
+
+        let fd = File::create(&file)?;
+        assert!(preallocate(&fd, len, false)?);
+        assert_eq!(len, file.metadata()?.len());
+        assert!(!probably_sparse(&fd)?);
+
+        let extents = map_extents(&fd)?.unwrap();
+        assert!(!extents.is_empty());
+        assert_eq!(len, extents.last().unwrap().end);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
+    fn test_preallocate_keep_size() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("prealloc.bin");
+        let len = 4 * 1024 * 1024;
+
+        let fd = File::create(&file)?;
+        assert!(preallocate(&fd, len, true)?);
+        assert_eq!(0, file.metadata()?.len());
+
+        allocate_file(&fd, len)?;
+        assert_eq!(len, file.metadata()?.len());
+        assert!(!probably_sparse(&fd)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
+    fn test_map_extents_unwritten() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("prealloc.bin");
+        let mut fd = File::create(&file)?;
+        fd.write_all(&[0xff; 64 * 1024])?;
+        assert!(preallocate(&fd, 1024 * 1024, false)?);
+
+        let extents = map_extents_synced(&fd)?.unwrap();
+        assert!(extents.iter().all(|e| e.end > e.start));
+        assert!(!extents[0].unwritten);
+        assert_eq!(0, extents[0].start);
+        let last = extents.last().unwrap();
+        assert!(last.unwritten);
+        assert!(last.end >= 1024 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_encrypted_plain_file() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("plain.txt");
+        File::create(&file)?.write_all(b"plaintext")?;
+
+        assert!(!is_encrypted(&File::open(&file)?)?);
+        assert!(!is_encrypted(&File::open(dir.path())?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_range() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sync.bin");
+        let mut fd = File::create(&file)?;
+        fd.write_all(&[0xff; 64 * 1024])?;
+
+        sync_range(&fd)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_cache() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("cached.bin");
+        let mut fd = File::create(&file)?;
+        fd.write_all(&[0xff; 64 * 1024])?;
+
+        drop_cache(&fd)?;
+        let infd = File::open(&file)?;
+        advise_sequential(&infd)?;
+        drop_cache(&infd)?;
+        assert_eq!(vec![0xff; 64 * 1024], read(&file)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_missing_key() {
+        assert!(is_missing_key(&io::Error::from_raw_os_error(libc::ENOKEY)));
+        assert!(!is_missing_key(&io::Error::from_raw_os_error(libc::EACCES)));
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
     fn test_empty_extent() -> Result<()> {
@@ -674,8 +1026,8 @@ mod tests {
         assert!(extents_p.is_some());
         let extents = extents_p.unwrap();
         assert_eq!(extents.len(), 1);
-        assert_eq!(extents[0].start, offset as u64);
-        assert_eq!(extents[0].end, offset as u64 + 4 * 1024); // FIXME: Assume 4k blocks
+        assert_eq!(extents[0].start, offset);
+        assert_eq!(extents[0].end, offset + 4 * 1024); // FIXME: Assume 4k blocks
         assert!(!extents[0].shared);
 
         Ok(())
@@ -695,7 +1047,7 @@ mod tests {
         let fsize = 1024 * 1024;
         // FIXME: Assumes 4k blocks
         let bsize = 4 * 1024;
-        let block = iter::repeat(0xff_u8).take(bsize).collect::<Vec<u8>>();
+        let block = vec![0xff_u8; bsize];
 
         let mut fd = OpenOptions::new().write(true).append(false).open(&file)?;
         // Skip every-other block
@@ -712,6 +1064,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
+    fn test_extent_fetch_batches() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+
+        // FIXME: Assumes 4k blocks
+        let bsize = 4 * 1024;
+        let count = FIEMAP_EXTENT_BATCH * 2 + 10;
+        let block = vec![0xff_u8; bsize];
+
+        let mut fd = File::create(&file)?;
+        for i in 0..count as u64 {
+            lseek(&fd, SeekFrom::Start(i * bsize as u64 * 2))?;
+            fd.write_all(block.as_slice())?;
+        }
+
+        let extents = map_extents(&fd)?.unwrap();
+        assert_eq!(count, extents.len());
+        for (i, ext) in extents.iter().enumerate() {
+            assert_eq!(i as u64 * bsize as u64 * 2, ext.start);
+            assert_eq!(bsize as u64, ext.end - ext.start);
+        }
+
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
     fn test_extent_not_sparse() -> Result<()> {
@@ -731,7 +1110,7 @@ mod tests {
         let extents = extents_p.unwrap();
 
         assert_eq!(1, extents.len());
-        assert_eq!(0 as u64, extents[0].start);
+        assert_eq!(0, extents[0].start);
         assert_eq!(size as u64, extents[0].end);
 
         Ok(())
@@ -772,6 +1151,36 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_copy_sparse_over_larger_file() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("dense.bin");
+        let mb = 1024 * 1024;
+        let data = vec![0xcc; 64 * 1024];
+
+        {
+            let fd = File::create(&from)?;
+            allocate_file(&fd, mb)?;
+            fd.write_all_at(&data, 0)?;
+        }
+        assert!(probably_sparse(&File::open(&from)?)?);
+        std::fs::write(&to, vec![0xff; 2 * mb as usize])?;
+
+        {
+            let infd = File::open(&from)?;
+            let outfd = OpenOptions::new().write(true).open(&to)?;
+            assert_eq!(mb, copy_sparse(&infd, &outfd)?);
+        }
+
+        assert_eq!(mb, to.metadata()?.len());
+        assert!(probably_sparse(&File::open(&to)?)?);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
     fn test_copy_socket() {
@@ -824,4 +1233,142 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_filesystem_type() -> Result<()> {
+        assert_eq!(Some("tmpfs"), fs_type_name(0x0102_1994));
+        assert_eq!(Some("btrfs"), fs_type_name(0x9123_683e));
+        assert_eq!(None, fs_type_name(0x1234));
+
+        // The test filesystem should be a known one.
+        let dir = tempdir()?;
+        let fstype = filesystem_type(dir.path())?.unwrap();
+        assert!(!fstype.starts_with("0x"), "Unknown test filesystem {}", fstype);
+
+        let shm = Path::new("/dev/shm");
+        if shm.is_dir() {
+            assert_eq!(Some("tmpfs".to_string()), filesystem_type(shm)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_free_inodes() -> Result<()> {
+        let dir = tempdir()?;
+        // Only filesystems with fixed inode tables report a count.
+        if let Some(free) = free_inodes(dir.path())? {
+            assert!(free > 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_time() {
+        use crate::round_time;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let t = UNIX_EPOCH + Duration::new(1_700_000_003, 123_456_789);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1_700_000_002), round_time(t, Duration::from_secs(2)));
+        assert_eq!(UNIX_EPOCH + Duration::new(1_700_000_003, 120_000_000), round_time(t, Duration::from_millis(10)));
+        assert_eq!(UNIX_EPOCH + Duration::new(1_700_000_003, 123_456_700), round_time(t, Duration::from_nanos(100)));
+        assert_eq!(t, round_time(t, Duration::from_nanos(1)));
+        assert_eq!(t, round_time(t, Duration::ZERO));
+
+        let early = UNIX_EPOCH - Duration::from_millis(1500);
+        assert_eq!(early, round_time(early, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_files_equal() -> Result<()> {
+        use crate::files_equal;
+
+        let dir = tempdir()?;
+        let a = dir.path().join("a.bin");
+        let b = dir.path().join("b.bin");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+
+        std::fs::write(&a, &data)?;
+        std::fs::write(&b, &data)?;
+        assert!(files_equal(&a, &b)?);
+        assert!(files_equal(&a, &a)?);
+
+        // Same size, differing in the last byte.
+        let mut other = data.clone();
+        *other.last_mut().unwrap() ^= 1;
+        std::fs::write(&b, &other)?;
+        assert!(!files_equal(&a, &b)?);
+
+        std::fs::write(&b, &data[..data.len() - 1])?;
+        assert!(!files_equal(&a, &b)?);
+
+        // Sparse files with the same layout, and a dense equivalent.
+        let mb = 1024 * 1024;
+        let sparse = |path: &Path, byte: u8| -> Result<()> {
+            let fd = File::create(path)?;
+            fd.set_len(8 * mb)?;
+            fd.write_all_at(&[byte; 4096], 4 * mb)?;
+            Ok(())
+        };
+        sparse(&a, 0xaa)?;
+        sparse(&b, 0xaa)?;
+        assert!(files_equal(&a, &b)?);
+        sparse(&b, 0xbb)?;
+        assert!(!files_equal(&a, &b)?);
+
+        let mut dense = vec![0; 8 * mb as usize];
+        dense[4 * mb as usize..4 * mb as usize + 4096].fill(0xaa);
+        std::fs::write(&b, &dense)?;
+        assert!(files_equal(&a, &b)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_file() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("file.bin");
+        let to = dir.path().join("clone.bin");
+        std::fs::write(&from, "X".repeat(128 * 1024))?;
+
+        if clone_file(&from, &to)? {
+            assert_eq!(read(&from)?, read(&to)?);
+        } else {
+            // The partial destination is cleaned up.
+            assert!(!to.exists());
+        }
+
+        // The destination must be new.
+        std::fs::write(&to, "existing")?;
+        assert!(clone_file(&from, &to).is_err());
+        assert_eq!(b"existing".to_vec(), read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_cross_device() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        // /dev/shm is normally tmpfs, so on a different filesystem
+        // to the build directory.
+        let dir = tempdir()?;
+        let shm = Path::new("/dev/shm");
+        if !shm.is_dir() || shm.metadata()?.dev() == dir.path().metadata()?.dev() {
+            println!("Skipping: no separate filesystem available");
+            return Ok(());
+        }
+        let src_dir = tempdir_in(shm)?;
+        let from = src_dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let len = 64 * 1024;
+        File::create(&from)?.write_all(&[0xaa; 64 * 1024])?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        assert!(!reflink(&infd, &outfd)?);
+        assert_eq!(len, copy_file_bytes(&infd, &outfd, len as u64)?);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
 }
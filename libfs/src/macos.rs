@@ -0,0 +1,54 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// macOS uses the generic implementations, other than for cloning.
+pub use crate::fallback::*;
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::errors::{Error, Result};
+
+// From <sys/clonefile.h>; not yet exported by libc.
+const CLONE_NOFOLLOW: u32 = 0x0001;
+
+/// Create `to` as a clone of `from` using
+/// [clonefile(2)](https://keith.github.io/xcode-man-pages/clonefile.2.html);
+/// `to` must not already exist. APFS can only clone to a new path,
+/// so unlike Linux there is no file-descriptor equivalent and
+/// [reflink](crate::reflink) always returns `false`. Returns `false`
+/// if the filesystem doesn't support cloning or the files are on
+/// different devices.
+pub fn clone_file(from: &Path, to: &Path) -> Result<bool> {
+    let cfrom = CString::new(from.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidPath(from.to_path_buf()))?;
+    let cto = CString::new(to.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidPath(to.to_path_buf()))?;
+
+    if unsafe { libc::clonefile(cfrom.as_ptr(), cto.as_ptr(), CLONE_NOFOLLOW) } != 0 {
+        let oserr = io::Error::last_os_error();
+        match oserr.raw_os_error() {
+            Some(libc::ENOTSUP)
+                | Some(libc::EXDEV) =>
+                return Ok(false),
+            _ =>
+                return Err(oserr.into()),
+        }
+    }
+    Ok(true)
+}
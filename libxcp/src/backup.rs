@@ -5,7 +5,7 @@ use std::{
 
 use regex::Regex;
 
-use crate::{errors::{Result, XcpError}, config::{Config, Backup}};
+use crate::{errors::{Result, XcpError}, config::{Config, Backup}, quoting::quote_path};
 
 const BAK_PATTTERN: &str = r"^\~(\d+)\~$";
 static BAK_REGEX: OnceLock<Regex> = OnceLock::new();
@@ -15,9 +15,11 @@ fn get_regex() -> &'static Regex {
     BAK_REGEX.get_or_init(|| Regex::new(BAK_PATTTERN).unwrap())
 }
 
-pub(crate) fn get_backup_path(file: &Path) -> Result<PathBuf> {
-    let num = next_backup_num(file)?;
-    let suffix = format!(".~{}~", num);
+pub(crate) fn get_backup_path(file: &Path, conf: &Config) -> Result<PathBuf> {
+    let suffix = match conf.backup {
+        Backup::Simple => "~".to_string(),
+        _ => format!(".~{}~", next_backup_num(file)?),
+    };
     // Messy but PathBuf has no concept of mulitiple extensions.
     let mut bstr = file.to_path_buf().into_os_string();
     bstr.push(suffix);
@@ -31,7 +33,7 @@ pub(crate) fn needs_backup(file: &Path, conf: &Config) -> Result<bool> {
         Backup::Auto if file.exists() => {
             has_backup(file)?
         }
-        Backup::Numbered if file.exists() => true,
+        Backup::Numbered | Backup::Simple if file.exists() => true,
         _ => false,
     };
     Ok(need)
@@ -52,7 +54,7 @@ fn ls_file_dir(file: &Path) -> Result<ReadDir> {
 
 fn filename(path: &Path) -> Result<String> {
     let fname = path.file_name()
-        .ok_or(XcpError::InvalidArguments(format!("Invalid path found: {:?}", path)))?
+        .ok_or(XcpError::InvalidArguments(format!("Invalid path found: {}", quote_path(path))))?
         .to_string_lossy();
     Ok(fname.to_string())
 }
@@ -153,11 +155,18 @@ mod tests {
             File::create(&base)?;
         }
 
-        let backup = get_backup_path(&base)?;
-        let mut bs = base.into_os_string();
+        let numbered = Config { backup: Backup::Numbered, ..Config::default() };
+        let backup = get_backup_path(&base, &numbered)?;
+        let mut bs = base.clone().into_os_string();
         bs.push(".~1~");
         assert_eq!(PathBuf::from(bs), backup);
 
+        let simple = Config { backup: Backup::Simple, ..Config::default() };
+        let backup = get_backup_path(&base, &simple)?;
+        assert_eq!(dir.join("file.txt~"), backup);
+        assert!(needs_backup(&base, &simple)?);
+        assert!(!needs_backup(&dir.join("missing.txt"), &simple)?);
+
         Ok(())
     }
 
@@ -16,10 +16,19 @@
 
 //! Driver configuration support.
 
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::result;
 use std::str::FromStr;
+use std::time::Duration;
+
+use ignore::gitignore::GitignoreBuilder;
+use regex::Regex;
 
 use crate::errors::XcpError;
+use crate::fdbudget;
+use crate::hash::Digest;
 
 /// Enum defining configuration options for handling
 /// [reflinks](https://btrfs.readthedocs.io/en/latest/Reflink.html). [FromStr]
@@ -63,7 +72,10 @@ pub enum Backup {
     Auto,
     /// Create numbered backups. Numbered backups follow the semantics
     /// of `cp` numbered backups (e.g. `file.txt.~123~`).
-    Numbered
+    Numbered,
+    /// Append `~` to the name (e.g. `file.txt~`), replacing any
+    /// previous simple backup, as with `cp --backup=simple`.
+    Simple,
 }
 
 impl FromStr for Backup {
@@ -74,11 +86,468 @@ impl FromStr for Backup {
             "none" | "off" => Ok(Backup::None),
             "auto" => Ok(Backup::Auto),
             "numbered" => Ok(Backup::Numbered),
+            "simple" | "never" => Ok(Backup::Simple),
             _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'backup': {}", s))),
         }
     }
 }
 
+/// Enum defining how to handle source files encrypted with
+/// [fscrypt](https://docs.kernel.org/filesystems/fscrypt.html) whose
+/// key is not loaded. Such files cannot be opened, and their names
+/// are only visible in encoded form. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encrypted {
+    /// Abort the copy with [XcpError::EncryptedSource].
+    #[default]
+    Error,
+    /// Warn and skip the file.
+    Skip,
+}
+
+impl FromStr for Encrypted {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(Encrypted::Error),
+            "skip" => Ok(Encrypted::Skip),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'encrypted': {}", s))),
+        }
+    }
+}
+
+/// Enum defining what to do when a source file and its destination
+/// are the same file, e.g. via a hard link or bind mount. Copying
+/// would truncate the source. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SameFile {
+    /// Abort the copy with [XcpError::SameFile].
+    #[default]
+    Error,
+    /// Skip the file.
+    Skip,
+}
+
+impl FromStr for SameFile {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(SameFile::Error),
+            "skip" => Ok(SameFile::Skip),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'same-file': {}", s))),
+        }
+    }
+}
+
+/// Enum selecting automatic tuning for the filesystems involved in a
+/// copy. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Optimize {
+    /// Use the configured settings as they are.
+    #[default]
+    Off,
+    /// Adjust the settings for the source and destination
+    /// filesystems; see [optimize](crate::optimize).
+    Auto,
+}
+
+impl FromStr for Optimize {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Optimize::Off),
+            "auto" => Ok(Optimize::Auto),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'optimize': {}", s))),
+        }
+    }
+}
+
+/// Enum defining how to handle source files with more than one hard
+/// link. Except with `Preserve`, copies are independent files, so
+/// links to the file from elsewhere are not reproduced. [FromStr] is
+/// supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HardLinks {
+    /// Copy the file silently.
+    #[default]
+    Copy,
+    /// Copy the file with a warning that the link is broken.
+    Warn,
+    /// Skip the file.
+    Skip,
+    /// Copy the file once, and recreate other links to it within the
+    /// copied tree as hard links to the copy.
+    Preserve,
+}
+
+impl FromStr for HardLinks {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "copy" => Ok(HardLinks::Copy),
+            "warn" => Ok(HardLinks::Warn),
+            "skip" => Ok(HardLinks::Skip),
+            "preserve" => Ok(HardLinks::Preserve),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'hard-links': {}", s))),
+        }
+    }
+}
+
+/// Enum defining how to handle symlinks in the source. [FromStr] is
+/// supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Symlinks {
+    /// Recreate the symlink with the same target.
+    #[default]
+    Preserve,
+    /// Copy the file the symlink points to.
+    Follow,
+    /// Don't copy the symlink.
+    Skip,
+}
+
+impl FromStr for Symlinks {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "preserve" => Ok(Symlinks::Preserve),
+            "follow" => Ok(Symlinks::Follow),
+            "skip" => Ok(Symlinks::Skip),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'symlinks': {}", s))),
+        }
+    }
+}
+
+/// Enum defining what to do when a destination file already
+/// exists. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overwrite {
+    /// Replace the existing file.
+    #[default]
+    Always,
+    /// Copy to the first free name of the form `file (1).txt`,
+    /// leaving the existing file untouched.
+    RenameOnConflict,
+    /// Skip the file.
+    Never,
+    /// Replace the existing file only if the source modification time
+    /// is newer; otherwise skip it.
+    IfNewer,
+}
+
+impl FromStr for Overwrite {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Overwrite::Always),
+            "rename" => Ok(Overwrite::RenameOnConflict),
+            "never" => Ok(Overwrite::Never),
+            "if-newer" => Ok(Overwrite::IfNewer),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'overwrite': {}", s))),
+        }
+    }
+}
+
+/// Enum defining the strategy used to allocate space for destination
+/// files before copying. [FromStr] is supported.
+///
+/// Which is best depends on the destination filesystem:
+///
+/// * ext4 and XFS allocate contiguous extents when the full size is
+///   requested up front, so `Always` can reduce fragmentation of
+///   large files, especially with the `parblock` driver.
+/// * On copy-on-write filesystems (btrfs, ZFS) preallocated blocks are
+///   replaced on first write, so preallocation gives little benefit;
+///   `Never` is recommended.
+/// * `KeepSize` reserves blocks without extending the file, then sets
+///   the length separately. The resulting layout is generally the
+///   same as `Always`; it is provided for filesystems that handle
+///   size-extending `fallocate` poorly.
+///
+/// Note that preallocating a sparse source will produce a fully
+/// allocated destination; `Auto` avoids this by not preallocating
+/// sparse sources.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Preallocate {
+    /// Only set the destination length; no blocks are reserved. This
+    /// is the default.
+    #[default]
+    Never,
+    /// Reserve blocks for the whole file in a single request.
+    Always,
+    /// Reserve blocks for the whole file without changing its length
+    /// (`FALLOC_FL_KEEP_SIZE`), then set the length.
+    KeepSize,
+    /// As `Always`, except for sparse sources, which only have their
+    /// length set so that the destination is also sparse.
+    Auto,
+}
+
+impl FromStr for Preallocate {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" | "none" | "off" => Ok(Preallocate::Never),
+            "always" => Ok(Preallocate::Always),
+            "keep-size" | "keepsize" => Ok(Preallocate::KeepSize),
+            "auto" => Ok(Preallocate::Auto),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'preallocate': {}", s))),
+        }
+    }
+}
+
+/// Enum defining how holes are created in destination files, as with
+/// `cp --sparse`. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Sparse {
+    /// Copy the holes of sources that appear sparse; see
+    /// [libfs::probably_sparse]. This is the default.
+    #[default]
+    Auto,
+    /// Additionally scan the data for runs of zeros, and leave these
+    /// as holes in the destination, e.g. for fully allocated disk
+    /// images. The data goes through a user-space buffer, and blocks
+    /// are not preallocated.
+    Always,
+    /// Write all data, including holes, so the destination is fully
+    /// allocated. Reflinks may still share the source's layout.
+    Never,
+}
+
+impl FromStr for Sparse {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Sparse::Auto),
+            "always" => Ok(Sparse::Always),
+            "never" => Ok(Sparse::Never),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'sparse': {}", s))),
+        }
+    }
+}
+
+/// Enum defining whether to re-read and compare files after
+/// copying. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Verify {
+    /// Don't verify copies.
+    #[default]
+    Off,
+    /// Compare the source and destination, reading through the page
+    /// cache.
+    Cached,
+    /// As for `Cached`, but read the destination with `O_DIRECT` so
+    /// the comparison reflects what is on disk. Falls back to a cached
+    /// read on filesystems without direct I/O.
+    Direct,
+    /// Compare SHA-256 hashes of the source and destination, reading
+    /// each in turn. Holes in both files are not read.
+    Hash,
+}
+
+impl FromStr for Verify {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Verify::Off),
+            "cached" => Ok(Verify::Cached),
+            "direct" => Ok(Verify::Direct),
+            "hash" => Ok(Verify::Hash),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'verify': {}", s))),
+        }
+    }
+}
+
+/// A remapping of user or group IDs, as with rsync's `--usermap` or
+/// tar's `--owner-map`. IDs not in the map are unchanged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IdMap(pub HashMap<u32, u32>);
+
+impl IdMap {
+    /// The ID that `id` maps to.
+    pub fn map(&self, id: u32) -> u32 {
+        self.0.get(&id).copied().unwrap_or(id)
+    }
+}
+
+/// A filter on the extended attributes copied with the
+/// permissions. Patterns are attribute names such as `user.foo`, or
+/// prefixes ending in `*` such as `security.*` for a whole namespace.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct XattrFilter {
+    /// If not empty, only attributes matching one of these are copied.
+    pub include: Vec<String>,
+    /// Attributes matching any of these are not copied.
+    pub exclude: Vec<String>,
+}
+
+impl XattrFilter {
+    /// Whether the attribute `name` passes the filter.
+    pub fn allows(&self, name: &OsStr) -> bool {
+        let name = name.as_bytes();
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix.as_bytes()),
+            None => name == pattern.as_bytes(),
+        };
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+/// A pattern for paths to leave out of a copy; see
+/// [Config::exclude]. Patterns are matched against the path relative
+/// to the source being copied.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    /// A shell-style glob, with the semantics of a `.gitignore` line;
+    /// e.g. `*.tmp` or `node_modules`. Globs without a `/` match at
+    /// any depth.
+    Glob(String),
+    /// A regular expression, matching anywhere in the path unless
+    /// anchored.
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Parse a glob, checking its syntax.
+    pub fn glob(s: &str) -> result::Result<Pattern, XcpError> {
+        GitignoreBuilder::new("")
+            .add_line(None, s)
+            .map_err(|e| XcpError::InvalidArguments(format!("Invalid exclude pattern '{}': {}", s, e)))?;
+        Ok(Pattern::Glob(s.to_string()))
+    }
+
+    /// Parse a regular expression.
+    pub fn regex(s: &str) -> result::Result<Pattern, XcpError> {
+        Regex::new(s)
+            .map(Pattern::Regex)
+            .map_err(|e| XcpError::InvalidArguments(format!("Invalid exclude regex '{}': {}", s, e)))
+    }
+}
+
+/// How the mode of copied files and directories is set.
+/// [FromStr] is supported; `umask` reads the current process umask.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Perms {
+    /// The source mode, as for `cp -p`. This is the default.
+    #[default]
+    Exact,
+    /// The source mode with this umask applied, as for `cp` without
+    /// `-p`.
+    Umask(u32),
+    /// `0644` for files and `0755` for directories, regardless of the
+    /// source.
+    Default,
+}
+
+impl Perms {
+    /// The mode to give a copy of a file or directory with
+    /// `source_mode`.
+    pub fn mode(&self, source_mode: u32, is_dir: bool) -> u32 {
+        match self {
+            Perms::Exact => source_mode,
+            Perms::Umask(mask) => source_mode & !mask,
+            Perms::Default if is_dir => 0o755,
+            Perms::Default => 0o644,
+        }
+    }
+}
+
+impl FromStr for Perms {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exact" => Ok(Perms::Exact),
+            "umask" => Ok(Perms::Umask(libfs::umask())),
+            "default" => Ok(Perms::Default),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'perms': {}", s))),
+        }
+    }
+}
+
+/// A transformation applied to the source permissions, as with
+/// rsync's `--chmod`. Bits in `remove` are cleared, then bits in
+/// `add` are set.
+///
+/// Parsed from a comma-separated list of symbolic clauses such as
+/// `go-w,u+x`; each is zero or more of `ugoa` (default `a`), `+` or
+/// `-`, and zero or more of `rwxst`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModeTransform {
+    pub add: u32,
+    pub remove: u32,
+}
+
+impl ModeTransform {
+    /// Apply the transformation to a permission mode.
+    pub fn apply(&self, mode: u32) -> u32 {
+        ((mode & !self.remove) | self.add) & 0o7777
+    }
+}
+
+impl FromStr for ModeTransform {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || XcpError::InvalidArguments(format!("Unexpected value for 'chmod': {}", s));
+        let mut transform = ModeTransform::default();
+        for clause in s.split(',') {
+            let opn = clause.find(['+', '-']).ok_or_else(invalid)?;
+            let (who, perms) = (&clause[..opn], &clause[opn + 1..]);
+
+            let mut whomask = 0;
+            for c in who.chars() {
+                whomask |= match c {
+                    'u' => 0o4700,
+                    'g' => 0o2070,
+                    'o' => 0o1007,
+                    'a' => 0o7777,
+                    _ => return Err(invalid()),
+                };
+            }
+            if whomask == 0 {
+                whomask = 0o7777;
+            }
+
+            let mut bits = 0;
+            for c in perms.chars() {
+                bits |= match c {
+                    'r' => 0o0444,
+                    'w' => 0o0222,
+                    'x' => 0o0111,
+                    's' => 0o6000,
+                    't' => 0o1000,
+                    _ => return Err(invalid()),
+                };
+            }
+            let bits = bits & whomask;
+
+            if clause.as_bytes()[opn] == b'+' {
+                transform.add |= bits;
+                transform.remove &= !bits;
+            } else {
+                transform.remove |= bits;
+                transform.add &= !bits;
+            }
+        }
+        Ok(transform)
+    }
+}
+
+/// The smallest useful [Config::block_size]; below this the
+/// per-call overhead dominates.
+pub const MIN_BLOCK_SIZE: u64 = 4096;
+
 /// A structure defining the runtime options for copy-drivers. This
 /// would normally be passed to `load_driver()`.
 #[derive(Clone, Debug)]
@@ -87,8 +556,12 @@ pub struct Config {
     /// CPUs (the default).
     pub workers: usize,
 
-    /// Block size for operations. Defaults to the full file size. Use
-    /// a smaller value for finer-grained feedback.
+    /// Block size for operations. This is the size of each
+    /// `copy_file_range()` call, or buffer, in a copy and so also the
+    /// granularity of progress updates; larger blocks may improve
+    /// throughput on fast storage while smaller blocks give smoother
+    /// feedback. Should be at least [MIN_BLOCK_SIZE]. Defaults to the
+    /// full file size.
     pub block_size: u64,
 
     /// Use .gitignore if present.
@@ -99,17 +572,48 @@ pub struct Config {
     /// `false`.
     pub gitignore: bool,
 
+    /// Paths to leave out of directory copies. A matching directory
+    /// is not descended into, so its contents are also excluded. The
+    /// source itself is never excluded. Default is empty.
+    pub exclude: Vec<Pattern>,
+
     /// Do not overwrite existing files. Default is `false`.
     pub no_clobber: bool,
 
+    /// Ask on the terminal before overwriting an existing destination
+    /// file, skipping it unless the answer is yes. Prompts from
+    /// parallel workers are asked one at a time. Not used with
+    /// [resume](Config::resume) or [Overwrite::RenameOnConflict],
+    /// which don't replace existing data. Default is `false`.
+    pub interactive: bool,
+
     /// Do not copy the file permissions. Default is `false`.
     pub no_perms: bool,
 
-    /// Do not copy the file permissions. Default is `false`.
+    /// Copy extended attributes, subject to
+    /// [xattr_filter](Config::xattr_filter). Ignored on platforms
+    /// without xattr support. Default is `true`.
+    pub preserve_xattrs: bool,
+
+    /// Copy the SELinux security context, even if
+    /// [xattr_filter](Config::xattr_filter) excludes it. Failing to
+    /// set it is only a warning. Default is `false`.
+    pub preserve_context: bool,
+
+    /// Copy POSIX ACLs, including the default ACLs of directories in
+    /// tree copies, even if [preserve_xattrs](Config::preserve_xattrs)
+    /// is unset or [xattr_filter](Config::xattr_filter) excludes
+    /// them. Failing to set them is only a warning. Default is
+    /// `false`.
+    pub preserve_acl: bool,
+
+    /// Do not copy the file access and modification times. Default
+    /// is `false`.
     pub no_timestamps: bool,
 
-    /// Dereference symlinks. Default is `false`.
-    pub dereference: bool,
+    /// How to handle symlinks in the source. Default is
+    /// [Symlinks::Preserve].
+    pub symlinks: Symlinks,
 
     /// Target should not be a directory.
     ///
@@ -136,6 +640,291 @@ pub struct Config {
     /// semantics of `cp` numbered backups
     /// (e.g. `file.txt.~123~`). Default is `None`.
     pub backup: Backup,
+
+    /// Preallocation strategy for destination files. Default is
+    /// `Never`. See [Preallocate] for per-filesystem
+    /// recommendations.
+    pub preallocate: Preallocate,
+
+    /// How holes are created in destination files. Default is `Auto`.
+    pub sparse: Sparse,
+
+    /// Maximum time allowed for copying a single file, from when a
+    /// worker starts on it. The timeout is checked between blocks,
+    /// and at least every 16MiB, so a smaller `block_size` gives
+    /// finer-grained cancellation. A watchdog also reports a file that
+    /// has run out of time while stuck in a syscall, e.g. on hung
+    /// storage, and moves on; the stuck thread is left blocked, and
+    /// the copy fails if the call ever returns. With the `parblock`
+    /// driver the timeout runs from the first block copied, and only
+    /// block copies are watched. Default is `None` (no timeout).
+    pub file_timeout: Option<Duration>,
+
+    /// Number of times a batch of data is retried after an I/O error
+    /// (`EIO`) reading the source, with a short increasing delay
+    /// between attempts, before the copy fails. The eventual error is
+    /// [XcpError::ReadFailed](crate::errors::XcpError::ReadFailed),
+    /// which records the offset of the failed batch. This can recover
+    /// data from failing media. I/O errors writing the destination are
+    /// not retried. Default is `0`.
+    pub read_retries: u32,
+
+    /// Maximum number of file descriptors held open by concurrent
+    /// copy operations across the process. Each file copy holds at
+    /// least 2. Default is `None`, which derives a limit from the
+    /// process `RLIMIT_NOFILE`; see [fdbudget](crate::fdbudget).
+    pub max_open_fds: Option<usize>,
+
+    /// Maximum memory, in bytes, used for copy buffers across the
+    /// process. This applies to user-space copies (see
+    /// [Config::basic_io]) and verification; buffers are made no
+    /// larger than the budget, and workers wait for memory to be
+    /// released rather than exceeding it. Default is `None` (no
+    /// limit).
+    pub max_buffer_memory: Option<u64>,
+
+    /// Maximum throughput, in bytes per second, across all copies in
+    /// the process. Reflinked files are not limited. Default is
+    /// `None` (no limit).
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// How to handle encrypted source files whose key is not
+    /// available. Default is `Error`.
+    pub encrypted: Encrypted,
+
+    /// When the destination is `/dev/null`, read through holes in
+    /// sparse files rather than skipping them. Default is `false`.
+    pub read_holes: bool,
+
+    /// Create destination entries in the order the source directory
+    /// returns them (`readdir(2)` order), so the destination's
+    /// internal directory layout mirrors the source; useful when
+    /// reproducing filesystem images. This forces the `parfile`
+    /// driver to use a single worker, so is slower for large trees;
+    /// `parblock` already creates files in order. Note that
+    /// filesystems using hashed directories (e.g. ext4 with
+    /// `dir_index`) return entries in hash order regardless of
+    /// creation order. Default is `false`.
+    pub readdir_order: bool,
+
+    /// Walk each source tree sorted by file name and create the
+    /// destination entries one at a time in that order, so repeated
+    /// copies of the same tree create files in the same sequence;
+    /// e.g. to get reproducible inode numbers when building
+    /// filesystem images. The walk, which creates the directories,
+    /// completes before any files are created, and as with
+    /// `readdir_order` the `parfile` driver uses a single
+    /// worker. This takes precedence over
+    /// `readdir_order`. Default is `false`.
+    pub reproducible: bool,
+
+    /// Start writeback of the destination every N bytes written,
+    /// rather than leaving all data to be flushed at the end; see
+    /// [libfs::sync_range]. This smooths out I/O latency for very
+    /// large files. Combine with `fsync` for durability. Default is
+    /// `None`.
+    pub sync_every: Option<u64>,
+
+    /// Keep bulk copies from flooding the page cache. Sources are read
+    /// with sequential readahead, and once each file is copied its
+    /// pages are dropped from the cache on both sides. This is a
+    /// no-op on platforms without `posix_fadvise`. Default is `false`.
+    pub drop_cache: bool,
+
+    /// Only copy regular files and directories; symlinks, FIFOs,
+    /// sockets and device nodes are skipped and reported via
+    /// [StatusUpdate::Skipped](crate::feedback::StatusUpdate::Skipped). Default
+    /// is `false`.
+    pub regular_only: bool,
+
+    /// What to do when a source and destination are the same
+    /// file. Default is `Error`.
+    pub same_file: SameFile,
+
+    /// What to do when a destination file already exists; this
+    /// includes files created earlier in the same copy, e.g. from
+    /// sources with the same name. Default is `Always`.
+    pub overwrite: Overwrite,
+
+    /// What to do with source files that have more than one hard
+    /// link. Default is `Copy`.
+    pub hard_links: HardLinks,
+
+    /// Resume interrupted copies. Existing destination files are
+    /// compared with the source by their data extents, and copying
+    /// restarts after the last region known to be complete; sparse
+    /// regions are taken into account, as the destination length is
+    /// set before any data is written. Without extent information,
+    /// destinations shorter than the source are resumed from their
+    /// end, and others copied from the start. If [Config::verify] is
+    /// set the kept data is first compared with the source, and the
+    /// copy restarted on a mismatch. Backups are not made of resumed
+    /// files. Default is `false`.
+    pub resume: bool,
+
+    /// Update existing destination files in place, rewriting only the
+    /// blocks that differ from the source; see
+    /// [delta](crate::delta). Both files are read in full to find the
+    /// differences, so this is only worthwhile where writes are
+    /// expensive, e.g. large, mostly unchanged, VM images. Reflinks
+    /// are not attempted for whole files, and backups are not
+    /// made. Default is `false`.
+    pub delta: bool,
+
+    /// Hold an exclusive advisory lock (`flock(2)`) on each
+    /// destination file while it is written, so concurrent copies to
+    /// the same target are serialised rather than interleaved. Only
+    /// other processes taking the lock, such as another `xcp` with
+    /// this option, are excluded. Default is `false`.
+    pub lock: bool,
+
+    /// Write each file to a temporary file beside the destination,
+    /// named `.xcp-tmp-*`, and rename it into place once the copy has
+    /// completed and been finalised, so an interrupted copy never
+    /// leaves a partial destination. Any [backup](Config::backup) is
+    /// made just before the rename. The temporary file is removed if
+    /// the copy fails. Destinations on a different filesystem to
+    /// their directory (e.g. bind-mounted files) are written in place
+    /// with a warning. Not used with [resume](Config::resume),
+    /// [delta](Config::delta), [lock](Config::lock) or
+    /// [Overwrite::RenameOnConflict]. Default is `false`.
+    pub atomic: bool,
+
+    /// Remove this many leading components from each path, as it
+    /// would appear under the destination, like `tar
+    /// --strip-components`. Entries with no components left are
+    /// skipped; a warning is logged for skipped files. Default is
+    /// `0`.
+    pub strip_components: usize,
+
+    /// Skip entries that already exist at the destination, rather
+    /// than overwriting them or failing as with `no_clobber`;
+    /// directories are still descended into. Skipped entries are
+    /// reported with
+    /// [StatusUpdate::Skipped](crate::feedback::StatusUpdate::Skipped). Default
+    /// is `false`.
+    pub ignore_existing: bool,
+
+    /// Skip files smaller than this many bytes. Skipped files are
+    /// reported with
+    /// [StatusUpdate::Skipped](crate::feedback::StatusUpdate::Skipped). Default
+    /// is `None`.
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this many bytes, as for `min_size`.
+    /// Default is `None`.
+    pub max_size: Option<u64>,
+
+    /// Copy only the N most recently modified files, e.g. to archive
+    /// the latest rotated logs. Files are chosen from those left after
+    /// the other filters, across all sources, once the whole tree has
+    /// been walked; older files are reported with
+    /// [StatusUpdate::Skipped](crate::feedback::StatusUpdate::Skipped).
+    /// Default is `None`.
+    pub newest: Option<usize>,
+
+    /// Before copying, count the source entries and fail with
+    /// [XcpError::InsufficientInodes](crate::errors::XcpError::InsufficientInodes)
+    /// if the destination filesystem doesn't have enough free inodes
+    /// for them. This requires an extra walk of the source
+    /// trees. Filesystems without fixed inode tables are not
+    /// checked. Default is `false`.
+    pub check_inodes: bool,
+
+    /// Don't descend into directories on other filesystems, as with
+    /// `cp -x`. Mount points are still copied, as empty
+    /// directories. Default is `false`.
+    pub one_file_system: bool,
+
+    /// Transform applied to the source permissions of copied files;
+    /// e.g. `go-w` preserves the mode but removes group and other
+    /// write access. Ignored if `no_perms` is set. Default is `None`.
+    pub chmod: Option<ModeTransform>,
+
+    /// How the mode of copied files is derived from the source, before
+    /// any [chmod](Config::chmod) is applied. Ignored if `no_perms`
+    /// is set. Default is `Exact`.
+    pub perms: Perms,
+
+    /// Skip copying a file if the destination already exists and its
+    /// SHA-256 digest matches this one. The source is not read. This
+    /// is intended for single-file copies where the expected content
+    /// is known in advance. Default is `None`.
+    pub expect_hash: Option<Digest>,
+
+    /// Continue with the remaining files after a copy error, rather
+    /// than aborting. This includes tree entries that can't be read,
+    /// and directories that can't be created, which are skipped with
+    /// their contents. Errors are still sent as
+    /// [StatusUpdate::Error](crate::feedback::StatusUpdate::Error).
+    /// Default is `false`.
+    pub keep_going: bool,
+
+    /// Copy with plain reads and writes, avoiding reflinks (unless
+    /// [Reflink::Always]), `copy_file_range`, extent mapping,
+    /// preallocation and xattrs. This is enabled automatically for
+    /// destinations on filesystems that commonly lack these, such as
+    /// FUSE, to avoid a failing syscall per file. Default is `false`.
+    pub basic_io: bool,
+
+    /// Copy file data with `O_DIRECT`, bypassing the page cache, via
+    /// aligned buffers rather than `copy_file_range`. Any unaligned
+    /// start or end of each block is copied through the cache. Sparse
+    /// files are copied in full, and the option has no effect on
+    /// filesystems or platforms without direct I/O. Default is `false`.
+    pub direct_io: bool,
+
+    /// Which extended attributes to copy with the permissions.
+    /// Attributes that can't be copied for lack of privileges, such
+    /// as file capabilities, are skipped regardless. Default is to
+    /// copy all.
+    pub xattr_filter: XattrFilter,
+
+    /// Don't use extent maps (`FIEMAP`) of the source to find sparse
+    /// regions, or to find where to resume; e.g. for network
+    /// filesystems where they are unreliable. Default is `false`.
+    pub no_extent_map: bool,
+
+    /// Tune the copy settings for the source and destination
+    /// filesystems. Default is `Off`.
+    pub optimize: Optimize,
+
+    /// Round preserved timestamps down to a multiple of this, for
+    /// destinations that store coarser times than the source; e.g. 2
+    /// seconds for FAT. Setting the rounded time, rather than leaving
+    /// the filesystem to truncate it, keeps later comparisons with
+    /// the source stable. If `None` this is detected from the
+    /// destination filesystem where known; see
+    /// [libfs::timestamp_granularity]. Default is `None`.
+    pub timestamp_granularity: Option<Duration>,
+
+    /// Copy the owner and group of files, remapped with
+    /// [Config::uid_map] and [Config::gid_map]. Changing the owner
+    /// requires root or `CAP_CHOWN`; without it only the group is
+    /// set, if the user is a member of it. Failures are warned about
+    /// but not fatal. Default is `false`.
+    pub preserve_ownership: bool,
+
+    /// Mapping of source to destination user IDs when preserving
+    /// ownership. Default is the identity.
+    pub uid_map: IdMap,
+
+    /// Mapping of source to destination group IDs when preserving
+    /// ownership. Default is the identity.
+    pub gid_map: IdMap,
+
+    /// Re-read each copied file and compare it with the source,
+    /// failing with [XcpError::VerificationFailed] on a
+    /// mismatch. Default is `Off`.
+    pub verify: Verify,
+
+    /// After each successful reflink, compare the extent maps of the
+    /// source and destination and fail with
+    /// [XcpError::ReflinkUnverified] unless every extent of the
+    /// destination shares physical storage with the source. Also
+    /// fails if the filesystem doesn't provide extent
+    /// maps. Default is `false`.
+    pub verify_reflink: bool,
 }
 
 impl Config {
@@ -146,22 +935,142 @@ impl Config {
             self.workers
         }
     }
+
+    /// Whether entries must be created in walk order.
+    pub(crate) fn ordered_creation(&self) -> bool {
+        self.readdir_order || self.reproducible
+    }
+
+    pub(crate) fn fd_limit(&self) -> usize {
+        self.max_open_fds.unwrap_or_else(fdbudget::default_limit)
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             workers: num_cpus::get(),
-            block_size: u64::max_value(),
+            block_size: u64::MAX,
             gitignore: false,
+            exclude: Vec::new(),
             no_clobber: false,
+            interactive: false,
             no_perms: false,
+            preserve_xattrs: true,
+            preserve_context: false,
+            preserve_acl: false,
             no_timestamps: false,
-            dereference: false,
+            symlinks: Symlinks::Preserve,
             no_target_directory: false,
             fsync: false,
             reflink: Reflink::Auto,
             backup: Backup::None,
+            preallocate: Preallocate::Never,
+            sparse: Sparse::Auto,
+            file_timeout: None,
+            read_retries: 0,
+            max_open_fds: None,
+            max_buffer_memory: None,
+            max_bytes_per_sec: None,
+            encrypted: Encrypted::Error,
+            read_holes: false,
+            readdir_order: false,
+            reproducible: false,
+            sync_every: None,
+            drop_cache: false,
+            regular_only: false,
+            same_file: SameFile::Error,
+            overwrite: Overwrite::Always,
+            hard_links: HardLinks::Copy,
+            resume: false,
+            delta: false,
+            lock: false,
+            atomic: false,
+            strip_components: 0,
+            ignore_existing: false,
+            min_size: None,
+            max_size: None,
+            newest: None,
+            check_inodes: false,
+            one_file_system: false,
+            chmod: None,
+            perms: Perms::Exact,
+            expect_hash: None,
+            keep_going: false,
+            basic_io: false,
+            direct_io: false,
+            xattr_filter: XattrFilter::default(),
+            no_extent_map: false,
+            optimize: Optimize::Off,
+            timestamp_granularity: None,
+            preserve_ownership: false,
+            uid_map: IdMap::default(),
+            gid_map: IdMap::default(),
+            verify: Verify::Off,
+            verify_reflink: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_transform() {
+        let go_w = ModeTransform::from_str("go-w").unwrap();
+        assert_eq!(0o755, go_w.apply(0o777));
+        assert_eq!(0o644, go_w.apply(0o664));
+
+        let t = ModeTransform::from_str("u+x,o-rwx").unwrap();
+        assert_eq!(0o740, t.apply(0o647));
+
+        // Later clauses override earlier ones.
+        let t = ModeTransform::from_str("-w,u+w").unwrap();
+        assert_eq!(0o644, t.apply(0o666));
+
+        // File type bits are dropped.
+        assert_eq!(0o755, go_w.apply(0o100777));
+
+        for bad in ["", "go", "q-w", "g-z", "g=w"] {
+            assert!(ModeTransform::from_str(bad).is_err(), "Parsed {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_perms() {
+        assert_eq!(0o100775, Perms::Exact.mode(0o100775, false));
+        assert_eq!(0o100755, Perms::Umask(0o022).mode(0o100775, false));
+        assert_eq!(0o750, Perms::Umask(0o027).mode(0o777, true));
+        assert_eq!(0o644, Perms::Default.mode(0o100700, false));
+        assert_eq!(0o755, Perms::Default.mode(0o40700, true));
+
+        assert_eq!(Perms::Default, Perms::from_str("Default").unwrap());
+        assert!(matches!(Perms::from_str("umask"), Ok(Perms::Umask(_))));
+        assert!(Perms::from_str("0644").is_err());
+    }
+
+    #[test]
+    fn test_xattr_filter() {
+        let name = |n: &'static str| OsStr::new(n);
+        assert!(XattrFilter::default().allows(name("security.selinux")));
+
+        let filter = XattrFilter {
+            include: vec!["user.*".to_string(), "trusted.keep".to_string()],
+            exclude: vec!["user.secret".to_string()],
+        };
+        assert!(filter.allows(name("user.foo")));
+        assert!(filter.allows(name("trusted.keep")));
+        assert!(!filter.allows(name("trusted.keeper")));
+        assert!(!filter.allows(name("user.secret")));
+        assert!(!filter.allows(name("security.bar")));
+        assert!(!filter.allows(name("userx")));
+
+        let filter = XattrFilter {
+            include: vec![],
+            exclude: vec!["security.*".to_string()],
+        };
+        assert!(filter.allows(name("user.foo")));
+        assert!(!filter.allows(name("security.capability")));
+    }
+}
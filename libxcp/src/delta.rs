@@ -0,0 +1,410 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! In-place updates of existing files using an rsync-style delta.
+//!
+//! The destination is split into fixed-size blocks, each summarised
+//! by a cheap rolling checksum and a SHA-256 digest ([Signature]). The
+//! source is then scanned with the rolling checksum, a byte at a
+//! time, for windows matching a destination block ([compute_delta]).
+//! The result is a list of [DeltaOp]s describing the source in terms
+//! of destination blocks and literal data.
+//!
+//! As both files are local, a match at a different offset still has
+//! to be written; [update_in_place] only leaves destination blocks
+//! that match at the same offset untouched, and copies everything else
+//! from the source with [copy_range]. This suits large files
+//! such as VM images or databases where most blocks are unchanged,
+//! and the destination is slow to write.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+use log::debug;
+
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+use crate::hash::{Digest, Hasher};
+use crate::ranges::copy_range;
+
+/// The default block size for delta updates.
+pub const DELTA_BLOCK_SIZE: usize = 64 * 1024;
+
+// Amount of source read ahead of the scan window.
+const READ_AHEAD: usize = 1024 * 1024;
+
+/// The rsync weak checksum; two 16-bit sums over a window that can be
+/// moved along by a byte in constant time.
+#[derive(Clone, Copy, Debug)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        RollingChecksum { a: a & 0xffff, b: b & 0xffff, len }
+    }
+
+    /// Move the window along by one byte, removing `out` from the
+    /// start and adding `inb` at the end.
+    pub fn roll(&mut self, out: u8, inb: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inb as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    pub fn value(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+/// The checksums of a destination block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Digest,
+}
+
+/// The block checksums of a file; the last block may be short.
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub block_size: usize,
+    pub len: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl Signature {
+    /// Read `fd` and checksum each block.
+    pub fn of_file(fd: &File, block_size: usize) -> Result<Signature> {
+        let len = fd.metadata()?.len();
+        let mut buf = vec![0; block_size];
+        let mut blocks = Vec::new();
+        let mut off = 0;
+        while off < len {
+            let n = cmp::min(block_size as u64, len - off) as usize;
+            fd.read_exact_at(&mut buf[..n], off)?;
+            blocks.push(BlockSignature {
+                weak: RollingChecksum::new(&buf[..n]).value(),
+                strong: strong_hash(&buf[..n]),
+            });
+            off += n as u64;
+        }
+        Ok(Signature { block_size, len, blocks })
+    }
+
+    fn block_range(&self, block: usize) -> Range<u64> {
+        let start = (block * self.block_size) as u64;
+        start..cmp::min(start + self.block_size as u64, self.len)
+    }
+}
+
+/// A part of the source, in terms of the destination.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeltaOp {
+    /// The source range starting at `src` matches the destination
+    /// block at `dst`.
+    Match { src: u64, dst: u64, len: u64 },
+    /// The source range has no match in the destination.
+    Literal(Range<u64>),
+}
+
+impl DeltaOp {
+    /// Whether the source already matches the destination here.
+    fn in_place(&self) -> bool {
+        matches!(self, DeltaOp::Match { src, dst, .. } if src == dst)
+    }
+
+    fn source(&self) -> Range<u64> {
+        match self {
+            DeltaOp::Match { src, len, .. } => *src..src + len,
+            DeltaOp::Literal(range) => range.clone(),
+        }
+    }
+}
+
+/// The result of [update_in_place].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeltaStats {
+    /// Bytes written to the destination.
+    pub written: u64,
+    /// Bytes left as they were.
+    pub unchanged: u64,
+}
+
+fn strong_hash(data: &[u8]) -> Digest {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+// A sliding view of the source, read in large chunks.
+struct Window<'a> {
+    fd: &'a File,
+    len: u64,
+    data: Vec<u8>,
+    start: u64,
+}
+
+impl<'a> Window<'a> {
+    // The `len` bytes at `off`, which must be within the file. Data
+    // before `off` may be discarded, so offsets must not decrease.
+    fn get(&mut self, off: u64, len: usize) -> Result<&[u8]> {
+        let end = off + len as u64;
+        if end > self.start + self.data.len() as u64 {
+            let keep = (off - self.start) as usize;
+            self.data.drain(..keep);
+            self.start = off;
+            let want = cmp::min(cmp::max(len, READ_AHEAD) as u64, self.len - off) as usize;
+            let have = self.data.len();
+            self.data.resize(want, 0);
+            self.fd.read_exact_at(&mut self.data[have..], off + have as u64)?;
+        }
+        let pos = (off - self.start) as usize;
+        Ok(&self.data[pos..pos + len])
+    }
+}
+
+// Find a block matching `window`, preferring one at offset `pos`.
+fn find_block(sig: &Signature, index: &HashMap<u32, Vec<usize>>, weak: u32, window: &[u8], pos: u64) -> Option<usize> {
+    let candidates = index.get(&weak)?;
+    let mut strong = None;
+    let mut found = None;
+    for &block in candidates {
+        let range = sig.block_range(block);
+        if range.end - range.start != window.len() as u64 {
+            continue;
+        }
+        let digest = *strong.get_or_insert_with(|| strong_hash(window));
+        if sig.blocks[block].strong == digest {
+            if range.start == pos {
+                return Some(block);
+            }
+            found.get_or_insert(block);
+        }
+    }
+    found
+}
+
+/// Describe the source `infd` in terms of the destination blocks in
+/// `sig`; the returned operations cover the source in order.
+pub fn compute_delta(infd: &File, sig: &Signature) -> Result<Vec<DeltaOp>> {
+    let len = infd.metadata()?.len();
+    let bs = sig.block_size as u64;
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in sig.blocks.iter().enumerate() {
+        index.entry(block.weak).or_default().push(i);
+    }
+
+    let mut window = Window { fd: infd, len, data: Vec::new(), start: 0 };
+    let mut ops = Vec::new();
+    let mut literal = 0;
+    let mut pos = 0;
+    let mut rolling: Option<RollingChecksum> = None;
+
+    let push_match = |ops: &mut Vec<DeltaOp>, literal: u64, pos: u64, block: usize| {
+        if literal < pos {
+            ops.push(DeltaOp::Literal(literal..pos));
+        }
+        let range = sig.block_range(block);
+        ops.push(DeltaOp::Match { src: pos, dst: range.start, len: range.end - range.start });
+    };
+
+    while pos + bs <= len {
+        let data = window.get(pos, sig.block_size)?;
+        let weak = rolling.get_or_insert_with(|| RollingChecksum::new(data));
+        if let Some(block) = find_block(sig, &index, weak.value(), data, pos) {
+            push_match(&mut ops, literal, pos, block);
+            pos += bs;
+            literal = pos;
+            rolling = None;
+            continue;
+        }
+        if pos + bs < len {
+            // Read both ends in one view; a refill for the incoming
+            // byte alone would discard the outgoing one.
+            let data = window.get(pos, sig.block_size + 1)?;
+            let (out, inb) = (data[0], data[sig.block_size]);
+            if let Some(r) = rolling.as_mut() {
+                r.roll(out, inb);
+            }
+        }
+        pos += 1;
+    }
+
+    // A short tail can only match the short last block.
+    let tail = len - pos;
+    if tail > 0 && pos == literal {
+        let data = window.get(pos, tail as usize)?;
+        if let Some(block) = find_block(sig, &index, RollingChecksum::new(data).value(), data, pos) {
+            push_match(&mut ops, literal, pos, block);
+            literal = len;
+        }
+    }
+    if literal < len {
+        ops.push(DeltaOp::Literal(literal..len));
+    }
+    Ok(ops)
+}
+
+/// Update the existing file `outfd` to match `infd`, rewriting only
+/// the blocks that differ; see the [module](self) documentation. The
+/// destination is truncated or extended to the source length.
+pub fn update_in_place(infd: &File, outfd: &File, block_size: usize, config: &Config) -> Result<DeltaStats> {
+    if block_size == 0 {
+        return Err(XcpError::InvalidArguments("Delta block size must be non-zero".to_string()).into());
+    }
+    let sig = Signature::of_file(outfd, block_size)?;
+    let ops = compute_delta(infd, &sig)?;
+    debug!("Delta of {} operations against {} blocks", ops.len(), sig.blocks.len());
+
+    let mut stats = DeltaStats::default();
+    for op in &ops {
+        let range = op.source();
+        let len = range.end - range.start;
+        if op.in_place() {
+            stats.unchanged += len;
+        } else {
+            copy_range(infd, outfd, range.start, range.start, len, config)?;
+            stats.written += len;
+        }
+    }
+    outfd.set_len(infd.metadata()?.len())?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use tempfile::TempDir;
+
+    const BS: usize = 4096;
+
+    fn pattern(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed.wrapping_mul(2654435761) | 1;
+        (0..len).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_rolling_checksum() {
+        let data = pattern(1000, 1);
+        let mut rolling = RollingChecksum::new(&data[..100]);
+        for i in 1..900 {
+            rolling.roll(data[i - 1], data[i + 99]);
+            assert_eq!(RollingChecksum::new(&data[i..i + 100]).value(), rolling.value());
+        }
+    }
+
+    #[test]
+    fn test_update_middle_region() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.img");
+        let to = dir.path().join("to.img");
+        let mut data = pattern(256 * BS + 123, 2);
+        fs::write(&to, &data)?;
+        // Change a region within two blocks in the middle.
+        for b in &mut data[100 * BS + 10..101 * BS + 20] {
+            *b = !*b;
+        }
+        fs::write(&from, &data)?;
+
+        let infd = File::open(&from)?;
+        let outfd = OpenOptions::new().read(true).write(true).open(&to)?;
+        let stats = update_in_place(&infd, &outfd, BS, &Config::default())?;
+        assert_eq!(2 * BS as u64, stats.written);
+        assert_eq!(data.len() as u64 - stats.written, stats.unchanged);
+        assert_eq!(data, fs::read(&to)?);
+
+        // Now identical.
+        let stats = update_in_place(&infd, &outfd, BS, &Config::default())?;
+        assert_eq!(0, stats.written);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_finds_shifted_blocks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.img");
+        let to = dir.path().join("to.img");
+        let old = pattern(8 * BS, 3);
+        let mut new = b"inserted".to_vec();
+        new.extend_from_slice(&old);
+        fs::write(&to, &old)?;
+        fs::write(&from, &new)?;
+
+        let sig = Signature::of_file(&File::open(&to)?, BS)?;
+        let ops = compute_delta(&File::open(&from)?, &sig)?;
+        assert_eq!(DeltaOp::Literal(0..8), ops[0]);
+        assert_eq!(DeltaOp::Match { src: 8, dst: 0, len: BS as u64 }, ops[1]);
+        assert_eq!(9, ops.len());
+
+        // Shifted data must still be written, as must a truncation.
+        let infd = File::open(&from)?;
+        let outfd = OpenOptions::new().read(true).write(true).open(&to)?;
+        let stats = update_in_place(&infd, &outfd, BS, &Config::default())?;
+        assert_eq!(new.len() as u64, stats.written);
+        assert_eq!(new, fs::read(&to)?);
+
+        fs::write(&from, &new[..3 * BS + 5])?;
+        let infd = File::open(&from)?;
+        let stats = update_in_place(&infd, &outfd, BS, &Config::default())?;
+        assert_eq!(3 * BS as u64 + 5, stats.written + stats.unchanged);
+        assert_eq!(&new[..3 * BS + 5], fs::read(&to)?.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_across_read_ahead() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.img");
+        let to = dir.path().join("to.img");
+        // Enough unmatched source that the rolling scan crosses
+        // several refills of the read-ahead window.
+        let len = 3 * READ_AHEAD + 17;
+        let old = pattern(len, 4);
+        let mut new = pattern(len, 5);
+        new[len - 4 * BS..].copy_from_slice(&old[len - 4 * BS..]);
+        fs::write(&to, &old)?;
+        fs::write(&from, &new)?;
+
+        let infd = File::open(&from)?;
+        let outfd = OpenOptions::new().read(true).write(true).open(&to)?;
+        let stats = update_in_place(&infd, &outfd, BS, &Config::default())?;
+        assert!(stats.unchanged >= 3 * BS as u64);
+        assert_eq!(len as u64, stats.written + stats.unchanged);
+        assert_eq!(new, fs::read(&to)?);
+
+        Ok(())
+    }
+}
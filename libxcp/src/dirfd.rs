@@ -0,0 +1,288 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copy operations relative to already-open directory file
+//! descriptors.
+//!
+//! Path-based copies resolve every component of the source and
+//! destination paths for each file, so a privileged copy can be
+//! redirected if a component is swapped for a symlink
+//! mid-operation. The functions here only ever resolve a single name
+//! relative to an open directory, using `openat(2)`, `mkdirat(2)`,
+//! etc. with `O_NOFOLLOW`, avoiding this class of TOCTOU race.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::fd::AsFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+
+use cfg_if::cfg_if;
+use libfs::{copy_file_bytes, copy_sparse, copy_timestamps, copy_xattrs, probably_sparse};
+use log::{debug, warn};
+use rustix::fs::{
+    fchmod, mkdirat, openat, readlinkat, statat, symlinkat,
+    AtFlags, Dir, FileType, Mode, OFlags, RawMode,
+};
+use rustix::io::Errno;
+
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+use crate::quoting::quote_path;
+
+/// Copy the entry `src_name` in the directory `src_dir` to `dst_name`
+/// in the directory `dst_dir`, recursing into directories. Returns the
+/// number of bytes copied.
+///
+/// Names must be a single path component; they are never resolved
+/// through symlinks. Symlinks are recreated rather than followed, and
+/// special files are recreated with `mknodat(2)` where supported.
+/// [Config::no_clobber], [Config::no_perms],
+/// [Config::preserve_xattrs] and [Config::no_timestamps] are
+/// honoured.
+pub fn copy_at<S, D>(src_dir: S, src_name: &Path, dst_dir: D, dst_name: &Path, config: &Config) -> Result<u64>
+where
+    S: AsFd,
+    D: AsFd,
+{
+    check_name(src_name)?;
+    check_name(dst_name)?;
+
+    let stat = statat(&src_dir, src_name, AtFlags::SYMLINK_NOFOLLOW)?;
+    let rmode = stat.st_mode as RawMode;
+    let mode = Mode::from_raw_mode(rmode);
+
+    match FileType::from_raw_mode(rmode) {
+        FileType::RegularFile => {
+            debug!("Copy file {:?} -> {:?}", src_name, dst_name);
+            let infd = File::from(openat(&src_dir, src_name, OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::CLOEXEC, Mode::empty())?);
+            let mut oflags = OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+            if config.no_clobber {
+                oflags |= OFlags::EXCL;
+            }
+            let outfd = match openat(&dst_dir, dst_name, oflags, Mode::RUSR | Mode::WUSR) {
+                Err(Errno::EXIST) => {
+                    return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", dst_name.to_path_buf()).into());
+                }
+                r => File::from(r?),
+            };
+
+            let copied = if probably_sparse(&infd)? {
+                copy_sparse(&infd, &outfd)?
+            } else {
+                copy_all(infd.metadata()?.len(), dst_name, |n| copy_file_bytes(&infd, &outfd, n).map_err(Into::into))?
+            };
+
+            if config.preserve_xattrs {
+                if let Err(e) = copy_xattrs(&infd, &outfd, |name| config.xattr_filter.allows(name)) {
+                    warn!("Failed to copy xattrs for {:?}: {}", dst_name, e);
+                }
+            }
+            if !config.no_perms {
+                fchmod(&outfd, Mode::from_raw_mode(config.perms.mode(rmode, false)))?;
+            }
+            if !config.no_timestamps {
+                copy_timestamps(&infd, &outfd)?;
+            }
+            Ok(copied)
+        }
+
+        FileType::Directory => {
+            debug!("Copy directory {:?} -> {:?}", src_name, dst_name);
+            let dflags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC;
+            let src_fd = openat(&src_dir, src_name, dflags, Mode::empty())?;
+            match mkdirat(&dst_dir, dst_name, Mode::RWXU) {
+                Ok(()) | Err(Errno::EXIST) => {}
+                Err(e) => return Err(e.into()),
+            }
+            // Fails if an existing destination is not a directory.
+            let dst_fd = openat(&dst_dir, dst_name, dflags, Mode::empty())?;
+
+            let mut copied = 0;
+            for entry in Dir::read_from(&src_fd)? {
+                let entry = entry?;
+                let name = Path::new(OsStr::from_bytes(entry.file_name().to_bytes()));
+                if name == Path::new(".") || name == Path::new("..") {
+                    continue;
+                }
+                copied += copy_at(&src_fd, name, &dst_fd, name, config)?;
+            }
+
+            // Apply the mode last so that read-only source
+            // directories can still be populated.
+            if !config.no_perms {
+                fchmod(&dst_fd, Mode::from_raw_mode(config.perms.mode(rmode, true)))?;
+            }
+            Ok(copied)
+        }
+
+        FileType::Symlink => {
+            debug!("Copy symlink {:?} -> {:?}", src_name, dst_name);
+            let target = readlinkat(&src_dir, src_name, Vec::new())?;
+            symlinkat(target.as_c_str(), &dst_dir, dst_name)?;
+            Ok(0)
+        }
+
+        ftype => {
+            cfg_if! {
+                if #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))] {
+                    debug!("Copy special file {:?} -> {:?}", src_name, dst_name);
+                    rustix::fs::mknodat(&dst_dir, dst_name, ftype, mode, stat.st_rdev as rustix::fs::Dev)?;
+                } else {
+                    log::warn!("Special file copy of {} ({:?}) not supported by this OS", quote_path(src_name), ftype);
+                }
+            }
+            Ok(0)
+        }
+    }
+}
+
+// Copy `len` bytes with repeated calls to `copy`, which may copy less
+// than requested; e.g. copy_file_range() is capped at around 2GiB.
+fn copy_all(len: u64, dst_name: &Path, mut copy: impl FnMut(u64) -> Result<usize>) -> Result<u64> {
+    let mut copied = 0;
+    while copied < len {
+        let bytes = copy(len - copied)? as u64;
+        if bytes == 0 {
+            return Err(XcpError::CopyError(format!("Source ended after {} of {} bytes copying to {}", copied, len, quote_path(dst_name))).into());
+        }
+        copied += bytes;
+    }
+    Ok(copied)
+}
+
+fn check_name(name: &Path) -> Result<()> {
+    let mut components = name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(XcpError::InvalidArguments(format!("Expected a single path component: {}", quote_path(name))).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_link, read_to_string, write};
+    use std::os::unix::fs::symlink;
+    use rustix::fs::{open, CWD};
+    use tempfile::TempDir;
+
+    fn open_dir(path: &Path) -> Result<rustix::fd::OwnedFd> {
+        Ok(open(path, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())?)
+    }
+
+    #[test]
+    fn test_copy_at_tree() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let src = tdir.path().join("src");
+        let dst = tdir.path().join("dst");
+        create_dir_all(src.join("tree/sub"))?;
+        create_dir_all(&dst)?;
+        write(src.join("tree/a.txt"), "file a")?;
+        write(src.join("tree/sub/b.txt"), "file b")?;
+        symlink("a.txt", src.join("tree/link"))?;
+
+        let src_fd = open_dir(&src)?;
+        let dst_fd = open_dir(&dst)?;
+        let copied = copy_at(&src_fd, Path::new("tree"), &dst_fd, Path::new("copy"), &Config::default())?;
+
+        assert_eq!(12, copied);
+        assert_eq!("file a", read_to_string(dst.join("copy/a.txt"))?);
+        assert_eq!("file b", read_to_string(dst.join("copy/sub/b.txt"))?);
+        assert_eq!(Path::new("a.txt"), read_link(dst.join("copy/link"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_at_is_relative_to_fds() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let src = tdir.path().join("src");
+        let dst = tdir.path().join("dst");
+        create_dir_all(&src)?;
+        create_dir_all(&dst)?;
+        write(src.join("file.txt"), "data")?;
+
+        // The names don't exist relative to the CWD.
+        let name = Path::new("file.txt");
+        assert!(statat(CWD, name, AtFlags::SYMLINK_NOFOLLOW).is_err());
+
+        let src_fd = open_dir(&src)?;
+        let dst_fd = open_dir(&dst)?;
+        copy_at(&src_fd, name, &dst_fd, name, &Config::default())?;
+        assert_eq!("data", read_to_string(dst.join(name))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_all_short_copies() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.txt");
+        let to = tdir.path().join("to.txt");
+        write(&from, "0123456789")?;
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+
+        let mut calls = 0;
+        let copied = copy_all(10, &to, |n| {
+            calls += 1;
+            Ok(copy_file_bytes(&infd, &outfd, n.min(3))?)
+        })?;
+        assert_eq!((10, 4), (copied, calls));
+        assert_eq!("0123456789", read_to_string(&to)?);
+
+        // No progress is an error rather than a short success.
+        assert!(copy_all(10, &to, |_| Ok(0)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_at_rejects_paths() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let fd = open_dir(tdir.path())?;
+
+        for name in ["a/b", "../a", "/a", "."] {
+            let r = copy_at(&fd, Path::new(name), &fd, Path::new("dest"), &Config::default());
+            assert!(r.is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_at_doesnt_follow_symlinks() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let src = tdir.path().join("src");
+        let dst = tdir.path().join("dst");
+        let other = tdir.path().join("other");
+        create_dir_all(&src)?;
+        create_dir_all(&dst)?;
+        create_dir_all(&other)?;
+        write(other.join("secret.txt"), "secret")?;
+        symlink(&other, src.join("dir"))?;
+
+        let src_fd = open_dir(&src)?;
+        let dst_fd = open_dir(&dst)?;
+        copy_at(&src_fd, Path::new("dir"), &dst_fd, Path::new("dir"), &Config::default())?;
+
+        assert!(dst.join("dir").symlink_metadata()?.file_type().is_symlink());
+        assert_eq!(other, read_link(dst.join("dir"))?);
+
+        Ok(())
+    }
+}
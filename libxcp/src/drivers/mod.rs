@@ -21,11 +21,14 @@
 //!   speed on modern NVME devices, but can bottleneck on larger files.
 //! * `parblock`: Parallelise copying at the block level. Block-size is
 //!   configurable. This can have better performance for large files,
-//!   but has a higher overhead.
+//!   but has a higher overhead. Unlike `parfile`, copies are not
+//!   restarted if a file handle goes stale (`ESTALE`) on NFS.
 //!
 //! Drivers are configured with the [Config] struct. A convenience
 //! function [load_driver()] is provided to load a dynamic-dispatched
-//! instance of each driver.
+//! instance of each driver, [copy_file_tree()] runs a complete copy
+//! without progress reporting, and [copy_files()] copies a list of
+//! individual files in parallel.
 //!
 //! # Example
 //!
@@ -38,11 +41,19 @@ pub mod parblock;
 use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+use crossbeam_channel as cbc;
+use log::{debug, error};
 
 use crate::config::Config;
 use crate::errors::{Result, XcpError};
-use crate::feedback::StatusUpdater;
+use crate::feedback::{CoalescingUpdater, StatusUpdate, StatusUpdater, COALESCE_BYTES, COALESCE_INTERVAL};
+use crate::operations::{copy_reopening, skip_error};
+use crate::paths::target_path;
+use crate::quoting::quote_path;
 
 /// The trait specifying driver operations; drivers should implement
 /// this.
@@ -90,3 +101,263 @@ pub fn load_driver(driver: Drivers, config: &Arc<Config>) -> Result<Box<dyn Copy
 
     Ok(driver_impl)
 }
+
+// Counts completed files and keeps the first error reported.
+#[derive(Default)]
+struct TreeUpdater {
+    files: AtomicU64,
+    error: Mutex<Option<XcpError>>,
+}
+
+impl StatusUpdater for TreeUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        match update {
+            StatusUpdate::Completed { .. } => {
+                self.files.fetch_add(1, Ordering::Relaxed);
+            }
+            StatusUpdate::Error(e) => {
+                let mut error = self.error.lock()
+                    .map_err(|_| XcpError::CopyError("Error lock poisoned".to_string()))?;
+                error.get_or_insert(e);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Recursively copy `source` to `dest` with the
+/// [parfile](Drivers::ParFile) driver, recreating directories and
+/// symlinks, and return the number of files copied. As with the `xcp`
+/// command, if `dest` is an existing directory the source is copied
+/// into it. This blocks until the copy is complete; the first error
+/// reported by the driver is returned, including with
+/// [Config::keep_going].
+pub fn copy_file_tree(source: &Path, dest: &Path, config: &Arc<Config>) -> Result<u64> {
+    let updater = Arc::new(TreeUpdater::default());
+    let driver = load_driver(Drivers::ParFile, config)?;
+    driver.copy(vec![source.to_path_buf()], dest, updater.clone())?;
+
+    let error = updater.error.lock()
+        .map_err(|_| XcpError::CopyError("Error lock poisoned".to_string()))?
+        .take();
+    match error {
+        Some(e) => Err(e.into()),
+        None => Ok(updater.files.load(Ordering::Relaxed)),
+    }
+}
+
+/// The outcome of [copy_files()].
+#[derive(Debug, Default)]
+pub struct CopyResults {
+    /// Files copied successfully.
+    pub files: u64,
+    /// Bytes of data copied.
+    pub bytes: u64,
+    /// Source files that failed to copy, with their errors, in the
+    /// order they failed.
+    pub errors: Vec<(PathBuf, anyhow::Error)>,
+}
+
+/// Copy each `(source, dest)` pair in `files` using `workers` threads;
+/// 0 uses [Config::workers]. A `dest` that is an existing directory
+/// receives a file of the source's name, as with `cp file dir/`. The
+/// files are put on a shared queue and each worker takes the next pair
+/// as soon as it finishes its current one, so a few large files don't
+/// hold up the rest. The total size is sent to `stats` before copying
+/// starts and the progress of all workers is sent to it as it happens.
+/// Failures are collected in the returned [CopyResults]; unless
+/// [Config::keep_going] is set no further copies are started after the
+/// first.
+pub fn copy_files(files: Vec<(PathBuf, PathBuf)>, workers: usize, config: &Arc<Config>, stats: Arc<dyn StatusUpdater>) -> Result<CopyResults> {
+    let total = files.iter()
+        .filter_map(|(from, _)| from.metadata().ok())
+        .map(|meta| meta.len())
+        .sum();
+    stats.send(StatusUpdate::Size(total))?;
+
+    let nworkers = match workers {
+        0 => config.num_workers(),
+        n => n,
+    }.min(files.len().max(1));
+
+    let (work_tx, work_rx) = cbc::unbounded();
+    for pair in files {
+        work_tx.send(pair)
+            .map_err(|_| XcpError::CopyError("Work queue closed".to_string()))?;
+    }
+    drop(work_tx);
+
+    let results = Mutex::new(CopyResults::default());
+    let abort = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let joins: Vec<_> = (0..nworkers)
+            .map(|_| {
+                let wrx = work_rx.clone();
+                let sc = stats.clone();
+                let (results, abort) = (&results, &abort);
+                scope.spawn(move || files_worker(wrx, config, sc, results, abort))
+            })
+            .collect();
+        joins.into_iter()
+            .try_for_each(|handle| handle.join()
+                          .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))?)
+    })?;
+
+    results.into_inner()
+        .map_err(|_| XcpError::CopyError("Results lock poisoned".to_string()).into())
+}
+
+fn files_worker(work: cbc::Receiver<(PathBuf, PathBuf)>, config: &Arc<Config>, updates: Arc<dyn StatusUpdater>,
+                results: &Mutex<CopyResults>, abort: &AtomicBool) -> Result<()>
+{
+    debug!("Starting file worker {:?}", thread::current().id());
+    let updates: Arc<dyn StatusUpdater> = Arc::new(CoalescingUpdater::new(updates, COALESCE_BYTES, COALESCE_INTERVAL));
+    for (from, to) in work {
+        if abort.load(Ordering::Relaxed) {
+            break;
+        }
+        updates.send(StatusUpdate::Started(from.clone()))?;
+        let r = target_path(&from, &to, config)
+            .and_then(|to| copy_reopening(&from, &to, config, &updates));
+
+        let mut results = results.lock()
+            .map_err(|_| XcpError::CopyError("Results lock poisoned".to_string()))?;
+        match r {
+            Ok(copied) => {
+                results.files += 1;
+                results.bytes += copied.copied;
+            }
+            Err(e) if skip_error(&e, config) => updates.send(StatusUpdate::Skipped(from))?,
+            Err(e) => {
+                error!("Error copying: {} -> {}: {}", quote_path(&from), quote_path(&to), e);
+                updates.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                results.errors.push((from, e));
+                if !config.keep_going {
+                    abort.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+    debug!("File worker {:?} shutting down", thread::current().id());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_file_tree() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub/empty"))?;
+        fs::write(source.join("a.txt"), "a")?;
+        fs::write(source.join("sub/b.txt"), "b")?;
+        symlink("../a.txt", source.join("sub/link"))?;
+
+        let dest = dir.path().join("dest");
+        let config = Arc::new(Config::default());
+        assert_eq!(2, copy_file_tree(&source, &dest, &config)?);
+        assert_eq!("a", fs::read_to_string(dest.join("a.txt"))?);
+        assert_eq!("b", fs::read_to_string(dest.join("sub/b.txt"))?);
+        assert!(dest.join("sub/empty").is_dir());
+        assert_eq!(Path::new("../a.txt"), fs::read_link(dest.join("sub/link"))?);
+
+        assert!(copy_file_tree(&dir.path().join("missing"), &dest, &config).is_err());
+
+        Ok(())
+    }
+
+    // Accumulates the sizes and progress sent by the workers.
+    #[derive(Default)]
+    struct Totals {
+        size: AtomicU64,
+        copied: AtomicU64,
+        copied_sends: AtomicU64,
+        completed: AtomicU64,
+    }
+
+    impl StatusUpdater for Totals {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            match update {
+                StatusUpdate::Size(v) => self.size.fetch_add(v, Ordering::Relaxed),
+                StatusUpdate::Copied(v) => {
+                    self.copied_sends.fetch_add(1, Ordering::Relaxed);
+                    self.copied.fetch_add(v, Ordering::Relaxed)
+                }
+                StatusUpdate::Completed { .. } => self.completed.fetch_add(1, Ordering::Relaxed),
+                _ => 0,
+            };
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mut files = Vec::new();
+        for i in 0..50 {
+            let from = dir.path().join(format!("source-{}", i));
+            fs::write(&from, vec![b'x'; i * 1000])?;
+            files.push((from, dir.path().join(format!("dest-{}", i))));
+        }
+        files.push((dir.path().join("missing"), dir.path().join("dest-missing")));
+        // Into an existing directory.
+        fs::create_dir(dir.path().join("into"))?;
+        files.push((dir.path().join("source-1"), dir.path().join("into/")));
+
+        let config = Arc::new(Config {
+            keep_going: true,
+            ..Config::default()
+        });
+        let totals = Arc::new(Totals::default());
+        let results = copy_files(files, 4, &config, totals.clone())?;
+
+        let expected: u64 = (0..50).map(|i| i * 1000).sum::<u64>() + 1000;
+        assert_eq!(51, results.files);
+        assert_eq!(expected, results.bytes);
+        assert_eq!(1, results.errors.len());
+        assert_eq!(dir.path().join("missing"), results.errors[0].0);
+        assert_eq!(expected, totals.size.load(Ordering::Relaxed));
+        assert_eq!(expected, totals.copied.load(Ordering::Relaxed));
+        assert_eq!(51, totals.completed.load(Ordering::Relaxed));
+        assert_eq!(1000, fs::metadata(dir.path().join("into/source-1"))?.len());
+        for i in 0..50 {
+            assert_eq!(i * 1000, fs::metadata(dir.path().join(format!("dest-{}", i)))?.len() as usize);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parfile_coalesces_updates() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source");
+        fs::create_dir(&source)?;
+        let nfiles = 400;
+        for i in 0..nfiles {
+            fs::write(source.join(format!("file-{}", i)), vec![b'x'; 100])?;
+        }
+
+        let config = Arc::new(Config {
+            workers: 4,
+            block_size: 64,
+            ..Config::default()
+        });
+        let totals = Arc::new(Totals::default());
+        let driver = load_driver(Drivers::ParFile, &config)?;
+        driver.copy(vec![source], &dir.path().join("dest"), totals.clone())?;
+
+        assert_eq!(nfiles * 100, totals.copied.load(Ordering::Relaxed));
+        assert_eq!(nfiles, totals.completed.load(Ordering::Relaxed));
+        // Uncoalesced this would be two sends per file, one per block.
+        let sends = totals.copied_sends.load(Ordering::Relaxed);
+        assert!(sends < nfiles / 4, "{} sends of copied bytes", sends);
+
+        Ok(())
+    }
+}
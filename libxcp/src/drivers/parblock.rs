@@ -19,7 +19,6 @@
 //! but has a higher overhead.
 
 use std::cmp;
-use std::fs::remove_file;
 use std::ops::Range;
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
@@ -28,16 +27,16 @@ use std::thread;
 
 use cfg_if::cfg_if;
 use crossbeam_channel as cbc;
-use libfs::copy_node;
-use log::{error, info};
+use log::{debug, error, info};
 use blocking_threadpool::{Builder, ThreadPool};
 
-use crate::config::Config;
+use crate::config::{Config, Sparse};
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
-use libfs::{copy_file_offset, map_extents, merge_extents, probably_sparse};
+use crate::feedback::{Heartbeat, StatusUpdate, StatusUpdater, HEARTBEAT_INTERVAL};
+use crate::operations::{copy_special, dest_config, finish_tree, skip_error, CopyHandle, Operation, tree_walker};
+use crate::quoting::quote_path;
+use libfs::{map_extents_synced, merge_extents, next_sparse_segments, probably_sparse};
 
 // ********************************************************************** //
 
@@ -81,10 +80,19 @@ impl Driver {
 impl CopyDriver for Driver {
     fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
         let (file_tx, file_rx) = cbc::unbounded::<Operation>();
+        let config = dest_config(&self.config, &sources, dest);
+
+        // Reproducible copies walk the tree, creating the
+        // directories, before any files are created.
+        if config.reproducible {
+            let deferred = tree_walker(sources, dest, &config, file_tx, stats.clone())?;
+            dispatch_worker(file_rx, &stats, config.clone())?;
+            return finish_tree(deferred, &config, &stats);
+        }
 
         // Start (single) dispatch worker
         let dispatcher = {
-            let q_config = self.config.clone();
+            let q_config = config.clone();
             let st = stats.clone();
             thread::spawn(move || dispatch_worker(file_rx, &st, q_config))
         };
@@ -95,16 +103,16 @@ impl CopyDriver for Driver {
         let walk_worker = {
             let sc = stats.clone();
             let d = dest.to_path_buf();
-            let c = self.config.clone();
+            let c = config.clone();
             thread::spawn(move || tree_walker(sources, &d, &c, file_tx, sc))
         };
 
-        walk_worker.join()
+        let deferred = walk_worker.join()
             .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
         dispatcher.join()
             .map_err(|_| XcpError::CopyError("Error dispatching copy operation".to_string()))??;
 
-        Ok(())
+        finish_tree(deferred, &config, &stats)
     }
 }
 
@@ -127,16 +135,17 @@ fn queue_file_range(
         let off = range.start + (blkn * bsize);
 
         pool.execute(move || {
-            let copy_result = copy_file_offset(&harc.infd, &harc.outfd, bytes, off as i64);
+            let copy_result = harc.copy_block_watched(bytes, off);
             let stat_result = match copy_result {
                 Ok(bytes) => {
                     stat_tx.send(StatusUpdate::Copied(bytes as u64))
                 }
                 Err(e) => {
+                    let e = harc.write_error(e);
                     error!("Error copying: aborting.");
                     stat_tx.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))
                 }
-            };
+            }.and_then(|_| release_handle(harc, &stat_tx));
             if let Err(e) = stat_result {
                 let msg = format!("Failed to send status update message. This should not happen; aborting. Error: {}", e);
                 error!("{}", msg);
@@ -147,6 +156,22 @@ fn queue_file_range(
     Ok(len)
 }
 
+// Drop a reference to a shared handle. The last reference closes and
+// finalises the file, so reports its completion.
+fn release_handle(handle: Arc<CopyHandle>, status_channel: &Arc<dyn StatusUpdater>) -> Result<()> {
+    if let Some(handle) = Arc::into_inner(handle) {
+        if let Err(e) = handle.verify().and_then(|_| handle.set_complete()) {
+            error!("{}", e);
+            let err = e.downcast::<XcpError>()
+                .unwrap_or_else(|e| XcpError::CopyError(e.to_string()));
+            return status_channel.send(StatusUpdate::Error(err));
+        }
+        drop(handle);
+        status_channel.send(StatusUpdate::Completed { reflinked: false })?;
+    }
+    Ok(())
+}
+
 fn queue_file_blocks(
     source: &Path,
     dest: &Path,
@@ -157,10 +182,25 @@ fn queue_file_blocks(
     let handle = CopyHandle::new(source, dest, config)?;
     let len = handle.metadata.len();
 
-    if handle.try_reflink()? {
+    if handle.is_discard() || handle.is_delta() || len == 0 {
+        // Reading to /dev/null is sequential; there are no writes to
+        // parallelise. Delta updates scan the source sequentially, and
+        // empty files have no blocks.
+        handle.copy_file(status_channel)?;
+        return Ok(len);
+    }
+
+    let resume = handle.resume_offset();
+    if resume == 0 && handle.try_reflink()? {
         info!("Reflinked, skipping rest of copy");
+        handle.verify()?;
+        handle.set_complete()?;
+        status_channel.send(StatusUpdate::Completed { reflinked: true })?;
         return Ok(len);
     }
+    if resume > 0 {
+        status_channel.send(StatusUpdate::Copied(resume))?;
+    }
 
     // Put the open files in an Arc, which we drop once work has been
     // queued. This will keep the files open until all work has been
@@ -169,23 +209,55 @@ fn queue_file_blocks(
     let harc = Arc::new(handle);
 
     let queue_whole_file = || {
-        queue_file_range(&harc, 0..len, pool, status_channel)
+        queue_file_range(&harc, resume..len, pool, status_channel)
     };
 
-    if probably_sparse(&harc.infd)? {
-        if let Some(extents) = map_extents(&harc.infd)? {
-            let sparse_map = merge_extents(extents)?;
-            let mut queued = 0;
-            for ext in sparse_map {
-                queued += queue_file_range(&harc, ext.into(), pool, status_channel)?;
+    // Preallocated files may contain unwritten extents without
+    // appearing sparse, so check the map whenever there is one.
+    let sparse = !config.basic_io && config.sparse != Sparse::Never;
+    let extents = if !sparse || config.no_extent_map {
+        None
+    } else {
+        map_extents_synced(&harc.infd)?
+    };
+    let queued = if let Some(extents) = extents {
+        let sparse_map = merge_extents(extents)?;
+        let mut queued = 0;
+        for ext in sparse_map {
+            if ext.unwritten {
+                // Reads as zeros; leave as a hole in the destination.
+                debug!("Skipping unwritten extent {}..{} of {:?}", ext.start, ext.end, source);
+                continue;
             }
-            Ok(queued)
-        } else {
-            queue_whole_file()
+            // Extents are block-aligned, so the last may extend past
+            // the end of the file.
+            let range = cmp::max(ext.start, resume)..cmp::min(ext.end, len);
+            if range.is_empty() {
+                continue;
+            }
+            queued += queue_file_range(&harc, range, pool, status_channel)?;
+        }
+        queued
+    } else if sparse && probably_sparse(&harc.infd)? {
+        // No extent map (e.g. on tmpfs), so find the data by seeking.
+        let mut queued = 0;
+        let mut pos = resume;
+        let mut heartbeat = Heartbeat::new(HEARTBEAT_INTERVAL);
+        while pos < len {
+            let (next_data, next_hole) = next_sparse_segments(&harc.infd, &harc.outfd, pos)?;
+            heartbeat.beat(status_channel)?;
+            if next_hole > next_data {
+                queued += queue_file_range(&harc, next_data..next_hole, pool, status_channel)?;
+            }
+            pos = next_hole;
         }
+        queued
     } else {
-        queue_whole_file()
-    }
+        queue_whole_file()?
+    };
+
+    release_handle(harc, status_channel)?;
+    Ok(queued)
 }
 
 // Dispatch worker; receives queued files and hands them to
@@ -203,35 +275,41 @@ fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdat
     for op in file_q {
         match op {
             Operation::Copy(from, to) => {
-                info!("Dispatch[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
+                info!("Dispatch[{:?}]: Copy {} -> {}", thread::current().id(), quote_path(&from), quote_path(&to));
+                stats.send(StatusUpdate::Started(from.clone()))?;
                 let r = queue_file_blocks(&from, &to, &copy_pool, stats, &config);
                 if let Err(e) = r {
+                    if skip_error(&e, &config) {
+                        stats.send(StatusUpdate::Skipped(from))?;
+                        continue;
+                    }
                     stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Dispatcher: Error copying {:?} -> {:?}.", from, to);
+                    error!("Dispatcher: Error copying {} -> {}.", quote_path(&from), quote_path(&to));
+                    if config.keep_going {
+                        continue;
+                    }
                     return Err(e)
                 }
             }
 
             // Inline the following operations as the should be near-instant.
             Operation::Link(from, to) => {
-                info!("Dispatch[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
+                info!("Dispatch[{:?}]: Symlink {} -> {}", thread::current().id(), quote_path(&from), quote_path(&to));
                 let r = symlink(&from, &to);
                 if let Err(e) = r {
                     stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Error symlinking: {:?} -> {:?}; aborting.", from, to);
+                    if config.keep_going {
+                        error!("Error symlinking: {} -> {}; continuing.", quote_path(&from), quote_path(&to));
+                        continue;
+                    }
+                    error!("Error symlinking: {} -> {}; aborting.", quote_path(&from), quote_path(&to));
                     return Err(e.into())
                 }
             }
 
             Operation::Special(from, to) => {
-                info!("Dispatch[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
-                    }
-                    remove_file(&to)?;
-                }
-                copy_node(&from, &to)?;
+                info!("Dispatch[{:?}]: Special file {} -> {}", thread::current().id(), quote_path(&from), quote_path(&to));
+                copy_special(&from, &to, &config, stats)?;
             }
         }
     }
@@ -19,8 +19,6 @@
 
 use crossbeam_channel as cbc;
 use log::{debug, error, info};
-use libfs::copy_node;
-use std::fs::remove_file;
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -29,8 +27,9 @@ use std::thread;
 use crate::config::Config;
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
+use crate::feedback::{CoalescingUpdater, StatusUpdate, StatusUpdater, COALESCE_BYTES, COALESCE_INTERVAL};
+use crate::operations::{copy_reopening, copy_special, dest_config, finish_tree, skip_error, Deferred, Operation, tree_walker};
+use crate::quoting::quote_path;
 
 // ********************************************************************** //
 
@@ -49,79 +48,104 @@ impl Driver {
 impl CopyDriver for Driver {
     fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
         let (work_tx, work_rx) = cbc::unbounded();
+        let config = dest_config(&self.config, &sources, dest);
 
         // Thread which walks the file tree and sends jobs to the
         // workers. The worker tx channel is moved to the walker so it is
         // closed, which will cause the workers to shutdown on completion.
+        //
+        // For reproducible copies the walk, which creates the
+        // directories, is finished before any files are created.
         let walk_worker = {
             let sc = stats.clone();
             let d = dest.to_path_buf();
-            let o = self.config.clone();
+            let o = config.clone();
             thread::spawn(move || tree_walker(sources, &d, &o, work_tx, sc))
         };
+        let (walk_worker, mut deferred) = if config.reproducible {
+            (None, join_walker(walk_worker)?)
+        } else {
+            (Some(walk_worker), Deferred::default())
+        };
 
         // Worker threads. Will consume work and then shutdown once the
         // queue is closed by the walker.
-        let nworkers = self.config.num_workers();
+        // A single worker consumes operations in walk order.
+        let nworkers = if self.config.ordered_creation() {
+            1
+        } else {
+            self.config.num_workers()
+        };
         let mut joins = Vec::with_capacity(nworkers);
         for _ in 0..nworkers {
             let copy_worker = {
                 let wrx = work_rx.clone();
                 let sc = stats.clone();
-                let conf = self.config.clone();
+                let conf = config.clone();
                 thread::spawn(move || copy_worker(wrx, &conf, sc))
             };
             joins.push(copy_worker);
         }
 
-        walk_worker.join()
-            .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
+        if let Some(walk_worker) = walk_worker {
+            deferred = join_walker(walk_worker)?;
+        }
         for handle in joins {
             handle.join()
                 .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
         }
 
-        Ok(())
+        finish_tree(deferred, &config, &stats)
     }
 
 }
 
+fn join_walker(walk_worker: thread::JoinHandle<Result<Deferred>>) -> Result<Deferred> {
+    walk_worker.join()
+        .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))?
+}
+
 // ********************************************************************** //
 
 fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Arc<dyn StatusUpdater>) -> Result<()> {
     debug!("Starting copy worker {:?}", thread::current().id());
+    // Accumulate progress locally to avoid contention between
+    // workers; flushed on drop.
+    let updates: Arc<dyn StatusUpdater> = Arc::new(CoalescingUpdater::new(updates, COALESCE_BYTES, COALESCE_INTERVAL));
     for op in work {
         debug!("Received operation {:?}", op);
 
         match op {
             Operation::Copy(from, to) => {
-                info!("Worker[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
+                info!("Worker[{:?}]: Copy {} -> {}", thread::current().id(), quote_path(&from), quote_path(&to));
                 // copy_file() sends back its own updates, but we should
                 // send back any errors as they may have occurred
                 // before the copy started..
-                let r = CopyHandle::new(&from, &to, config)
-                    .and_then(|hdl| hdl.copy_file(&updates));
+                updates.send(StatusUpdate::Started(from.clone()))?;
+                let r = copy_reopening(&from, &to, config, &updates);
                 if let Err(e) = r {
+                    if skip_error(&e, config) {
+                        updates.send(StatusUpdate::Skipped(from))?;
+                        continue;
+                    }
                     updates.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Error copying: {:?} -> {:?}; aborting.", from, to);
+                    if config.keep_going {
+                        error!("Error copying: {} -> {}; continuing.", quote_path(&from), quote_path(&to));
+                        continue;
+                    }
+                    error!("Error copying: {} -> {}; aborting.", quote_path(&from), quote_path(&to));
                     return Err(e)
                 }
             }
 
             Operation::Link(from, to) => {
-                info!("Worker[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
+                info!("Worker[{:?}]: Symlink {} -> {}", thread::current().id(), quote_path(&from), quote_path(&to));
                 let _r = symlink(&from, &to);
             }
 
             Operation::Special(from, to) => {
-                info!("Worker[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
-                    }
-                    remove_file(&to)?;
-                }
-                copy_node(&from, &to)?;
+                info!("Worker[{:?}]: Special file {} -> {}", thread::current().id(), quote_path(&from), quote_path(&to));
+                copy_special(&from, &to, config, &updates)?;
             }
 
         }
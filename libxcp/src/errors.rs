@@ -18,6 +18,8 @@
 
 use std::path::PathBuf;
 
+use crate::quoting::quote_path;
+
 pub use anyhow::Result;
 
 #[derive(Debug, thiserror::Error)]
@@ -25,12 +27,24 @@ pub enum XcpError {
     #[error("Error during copy: {0}")]
     CopyError(String),
 
-    #[error("Destination Exists: {0}, {1}")]
+    #[error("Copy timed out: {0}")]
+    CopyTimeout(String),
+
+    #[error("Destination Exists: {0}, {}", quote_path(.1))]
     DestinationExists(&'static str, PathBuf),
 
     #[error("Early shutdown: {0}")]
     EarlyShutdown(&'static str),
 
+    #[error("Source is encrypted and its key is not available: {}", quote_path(.0))]
+    EncryptedSource(PathBuf),
+
+    #[error("No free inodes on destination filesystem writing {}", quote_path(path))]
+    InodesExhausted { path: PathBuf },
+
+    #[error("Insufficient free inodes on destination: {needed} needed, {available} available")]
+    InsufficientInodes { needed: u64, available: u64 },
+
     #[error("Invalid arguments: {0}")]
     InvalidArguments(String),
 
@@ -40,15 +54,42 @@ pub enum XcpError {
     #[error("Invalid source: {0}")]
     InvalidSource(&'static str),
 
+    #[error("No space left on destination filesystem writing {}", quote_path(path))]
+    NoSpace { path: PathBuf },
+
+    #[error("Disk quota exceeded writing {}", quote_path(path))]
+    QuotaExceeded { path: PathBuf },
+
+    #[error("Overwrite of {} declined", quote_path(.0))]
+    OverwriteDeclined(PathBuf),
+
+    #[error("Permission denied: {}", quote_path(.0))]
+    PermissionDenied(PathBuf),
+
+    #[error("Not permitted to replace {}; it is owned by another user in a directory with the sticky bit set", quote_path(.0))]
+    StickyDirectory(PathBuf),
+
+    #[error("Source and destination are the same file: {}", quote_path(.0))]
+    SameFile(PathBuf),
+
+    #[error("Read error in {} at byte offset {offset}: {error}", quote_path(path))]
+    ReadFailed { path: PathBuf, offset: u64, error: String },
+
     #[error("Failed to reflink file and 'always' was specified: {0}")]
     ReflinkFailed(String),
 
+    #[error("Could not verify that the reflinked file shares its data with the source: {}", quote_path(.0))]
+    ReflinkUnverified(PathBuf),
+
     #[error("Unknown driver: {0}")]
     UnknownDriver(String),
 
-    #[error("Unknown file-type: {0}")]
+    #[error("Unknown file-type: {}", quote_path(.0))]
     UnknownFileType(PathBuf),
 
     #[error("Unsupported OS")]
     UnsupportedOS(&'static str),
+
+    #[error("Verification failed; destination differs from source: {}", quote_path(.0))]
+    VerificationFailed(PathBuf),
 }
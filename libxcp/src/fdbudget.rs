@@ -0,0 +1,155 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A process-wide budget on the number of file descriptors held open
+//! by copy operations.
+//!
+//! Each [CopyHandle](crate::operations::CopyHandle) holds at least
+//! two descriptors for its lifetime, which with many workers can
+//! exhaust the process limit. Handles acquire an [FdPermit] for the
+//! descriptors they hold and block until the budget allows it.
+
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use log::debug;
+use rustix::process::{getrlimit, Resource};
+
+/// Descriptors held by the process outside of copy operations
+/// (stdio, logging, channels, directory walking, etc.).
+const RESERVED_FDS: usize = 32;
+
+/// The smallest usable budget; a single copy needs a source and a
+/// destination.
+pub const MIN_FDS: usize = 2;
+
+#[derive(Debug, Default)]
+struct State {
+    in_use: usize,
+    peak: usize,
+}
+
+/// A counting semaphore over file descriptors.
+#[derive(Debug, Default)]
+pub(crate) struct FdBudget {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// Descriptors reserved from an [FdBudget]; they are returned to the
+/// budget when this is dropped.
+#[derive(Debug)]
+pub(crate) struct FdPermit {
+    budget: &'static FdBudget,
+    fds: usize,
+}
+
+impl FdBudget {
+    /// Block until `fds` descriptors are available under `limit`,
+    /// then reserve them. A request larger than the limit is allowed
+    /// once nothing else is held, to avoid deadlocking.
+    pub(crate) fn acquire(&'static self, fds: usize, limit: usize) -> FdPermit {
+        let limit = limit.max(MIN_FDS);
+        let mut state = self.state.lock().unwrap();
+        while state.in_use > 0 && state.in_use + fds > limit {
+            debug!("Waiting for {} fds ({} of {} in use)", fds, state.in_use, limit);
+            state = self.released.wait(state).unwrap();
+        }
+        state.in_use += fds;
+        state.peak = state.peak.max(state.in_use);
+        FdPermit { budget: self, fds }
+    }
+
+    /// The highest number of descriptors reserved at once.
+    #[cfg(test)]
+    pub(crate) fn peak(&self) -> usize {
+        self.state.lock().unwrap().peak
+    }
+
+    fn release(&self, fds: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use -= fds;
+        self.released.notify_all();
+    }
+}
+
+impl Drop for FdPermit {
+    fn drop(&mut self) {
+        self.budget.release(self.fds);
+    }
+}
+
+/// The budget shared by all copy operations in this process.
+pub(crate) fn global() -> &'static FdBudget {
+    static BUDGET: OnceLock<FdBudget> = OnceLock::new();
+    BUDGET.get_or_init(FdBudget::default)
+}
+
+/// The default budget, derived from the soft `RLIMIT_NOFILE` limit
+/// less a reserve for descriptors used outside of copies.
+pub fn default_limit() -> usize {
+    static LIMIT: OnceLock<usize> = OnceLock::new();
+    *LIMIT.get_or_init(|| {
+        let limit = match getrlimit(Resource::Nofile).current {
+            Some(soft) => usize::try_from(soft).unwrap_or(usize::MAX),
+            None => usize::MAX,
+        };
+        let budget = limit.saturating_sub(RESERVED_FDS).max(MIN_FDS);
+        debug!("Default fd budget is {} (RLIMIT_NOFILE {})", budget, limit);
+        budget
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_budget_is_enforced() {
+        let budget: &'static FdBudget = Box::leak(Box::default());
+        let limit = 6;
+
+        let threads: Vec<_> = (0..16)
+            .map(|_| thread::spawn(move || {
+                for _ in 0..8 {
+                    let _permit = budget.acquire(2, limit);
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }))
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert!(budget.peak() <= limit);
+        assert_eq!(0, budget.state.lock().unwrap().in_use);
+    }
+
+    #[test]
+    fn test_oversized_request_doesnt_deadlock() {
+        let budget: &'static FdBudget = Box::leak(Box::default());
+        let permit = budget.acquire(10, 4);
+        drop(permit);
+        let _permit = budget.acquire(10, 4);
+        assert_eq!(10, budget.peak());
+    }
+
+    #[test]
+    fn test_default_limit() {
+        assert!(default_limit() >= MIN_FDS);
+    }
+}
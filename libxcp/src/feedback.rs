@@ -23,10 +23,30 @@
 //!
 //! * [NoopUpdater]
 //! * [ChannelUpdater]
+//!
+//! Alternatively [CallbackUpdater] passes each update to a closure as
+//! a [ProgressEvent], for embedders with their own UI.
+//!
+//! [StatusFileUpdater](crate::status::StatusFileUpdater) can wrap
+//! either to persist progress to a file, and [PeriodicUpdater] to
+//! receive snapshots of the totals at a fixed interval. [Throughput]
+//! estimates the current copy rate and time remaining from such
+//! totals.
+//!
+//! Drivers wrap the supplied updater in a per-worker
+//! [CoalescingUpdater], so implementations receive batched
+//! [StatusUpdate::Copied] updates, which may arrive after the
+//! [StatusUpdate::Completed] of the files they belong to.
 
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use crossbeam_channel as cbc;
+use log::error;
 
 use crate::config::Config;
 use crate::errors::{Result, XcpError};
@@ -34,17 +54,87 @@ use crate::errors::{Result, XcpError};
 /// A struct representing an updated status.
 #[derive(Debug)]
 pub enum StatusUpdate {
+    /// Copying of the file at this source path has started.
+    Started(PathBuf),
     /// An update representing a successful copy of bytes between
     /// files.
     Copied(u64),
     /// An update representing that this number of bytes will need to be copied.
     Size(u64),
+    /// A source entry was deliberately not copied
+    /// (e.g. [Config::regular_only]).
+    Skipped(PathBuf),
+    /// A file has been copied. `reflinked` is set if the data was
+    /// shared with the source rather than copied; no
+    /// [StatusUpdate::Copied] updates are sent for reflinked files.
+    Completed { reflinked: bool },
     /// An error during a copy operation.
     Error(XcpError)
 }
 
+/// What a single file copy did; this distinguishes e.g. a large sparse
+/// file with little data from a dense copy of the same length.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CopyStats {
+    /// The length of the file.
+    pub len: u64,
+    /// Bytes of data copied.
+    pub copied: u64,
+    /// Bytes not copied; holes, and data kept from an interrupted
+    /// copy or unchanged by a delta update.
+    pub skipped: u64,
+    /// The data was shared with the source rather than copied.
+    pub reflinked: bool,
+    /// Data regions copied separately.
+    pub extents: u64,
+}
+
+impl CopyStats {
+    /// The length of the file, as previously returned for a copy.
+    pub fn bytes(&self) -> u64 {
+        self.len
+    }
+}
+
 pub trait StatusUpdater: Sync + Send {
     fn send(&self, update: StatusUpdate) -> Result<()>;
+
+    /// A heartbeat, sent while a copy is making progress without
+    /// copying data; e.g. while scanning a large sparse file for the
+    /// next data segment. This allows a UI to show activity rather
+    /// than appearing stalled. Updaters that wrap another should
+    /// forward it; the default ignores it.
+    fn tick(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Minimum interval between heartbeats while scanning sparse files.
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Calls [StatusUpdater::tick] at most once per `interval`, for use
+/// in loops that may be called many times in quick succession.
+pub(crate) struct Heartbeat {
+    interval: Duration,
+    last: Instant,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(interval: Duration) -> Heartbeat {
+        Heartbeat {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn beat(&mut self, updates: &Arc<dyn StatusUpdater>) -> Result<()> {
+        let now = Instant::now();
+        if now.duration_since(self.last) >= self.interval {
+            self.last = now;
+            updates.tick()?;
+        }
+        Ok(())
+    }
 }
 
 /// An implementation of [StatusUpdater] which will return
@@ -55,19 +145,15 @@ pub trait StatusUpdater: Sync + Send {
 pub struct ChannelUpdater {
     chan_tx: cbc::Sender<StatusUpdate>,
     chan_rx: cbc::Receiver<StatusUpdate>,
-    config: Arc<Config>,
-    sent: AtomicU64,
 }
 
 impl ChannelUpdater {
     /// Create a new ChannelUpdater, including the channels.
-    pub fn new(config: &Arc<Config>) -> ChannelUpdater {
+    pub fn new(_config: &Arc<Config>) -> ChannelUpdater {
         let (chan_tx, chan_rx) = cbc::unbounded();
         ChannelUpdater {
             chan_tx,
             chan_rx,
-            config: config.clone(),
-            sent: AtomicU64::new(0),
         }
     }
 
@@ -89,18 +175,361 @@ impl ChannelUpdater {
 }
 
 impl StatusUpdater for ChannelUpdater {
-    // Wrapper around channel-send that groups updates together
     fn send(&self, update: StatusUpdate) -> Result<()> {
-        if let StatusUpdate::Copied(bytes) = update {
-            // Avoid saturating the queue with small writes
-            let bsize = self.config.block_size;
-            let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
-            if ((prev_written + bytes) / bsize) > (prev_written / bsize) {
-                self.chan_tx.send(update)?;
+        self.chan_tx.send(update)?;
+        Ok(())
+    }
+
+    /// Heartbeats are sent as a zero-byte [StatusUpdate::Copied].
+    fn tick(&self) -> Result<()> {
+        self.send(StatusUpdate::Copied(0))
+    }
+}
+
+/// Bytes a worker's [CoalescingUpdater] accumulates before
+/// forwarding them.
+pub(crate) const COALESCE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Maximum time a worker's [CoalescingUpdater] holds bytes before
+/// forwarding them, so progress stays current while copying many
+/// small files.
+pub(crate) const COALESCE_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Pending {
+    bytes: u64,
+    since: Instant,
+}
+
+/// A [StatusUpdater] which accumulates [StatusUpdate::Copied] bytes
+/// locally and forwards them to a shared updater only once
+/// `threshold` bytes are pending or `interval` has passed since the
+/// last forward. This avoids contention on the shared updater when
+/// many workers copy small files. Each worker should have its own
+/// instance.
+///
+/// Other updates are forwarded immediately, so a file may be reported
+/// complete before all of its bytes are; pending bytes are flushed
+/// before an error is forwarded, on [StatusUpdater::tick], and when
+/// the updater is dropped.
+pub struct CoalescingUpdater {
+    inner: Arc<dyn StatusUpdater>,
+    threshold: u64,
+    interval: Duration,
+    pending: Mutex<Pending>,
+}
+
+impl CoalescingUpdater {
+    pub fn new(inner: Arc<dyn StatusUpdater>, threshold: u64, interval: Duration) -> CoalescingUpdater {
+        CoalescingUpdater {
+            inner,
+            threshold,
+            interval,
+            pending: Mutex::new(Pending { bytes: 0, since: Instant::now() }),
+        }
+    }
+
+    /// Forward any pending bytes to the shared updater.
+    pub fn flush(&self) -> Result<()> {
+        let bytes = {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            pending.since = Instant::now();
+            mem::take(&mut pending.bytes)
+        };
+        if bytes > 0 {
+            self.inner.send(StatusUpdate::Copied(bytes))?;
+        }
+        Ok(())
+    }
+}
+
+impl StatusUpdater for CoalescingUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        match update {
+            StatusUpdate::Copied(bytes) => {
+                let due = {
+                    let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+                    pending.bytes += bytes;
+                    pending.bytes >= self.threshold || pending.since.elapsed() >= self.interval
+                };
+                if due {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+            StatusUpdate::Error(_) => {
+                self.flush()?;
+                self.inner.send(update)
             }
+            _ => self.inner.send(update),
+        }
+    }
+
+    fn tick(&self) -> Result<()> {
+        self.flush()?;
+        self.inner.tick()
+    }
+}
+
+impl Drop for CoalescingUpdater {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to flush progress updates: {}", e);
+        }
+    }
+}
+
+/// A snapshot of the copy totals, as passed to the [PeriodicUpdater]
+/// callback.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub copied: u64,
+    /// Total bytes to copy, as discovered so far.
+    pub total: u64,
+    pub files: u64,
+    pub skipped: u64,
+    pub errors: u64,
+    /// Time since the updater was created.
+    pub elapsed: Duration,
+    /// Mean bytes copied per second over `elapsed`.
+    pub rate: f64,
+    /// Bytes copied per second over the last few seconds; see
+    /// [Throughput].
+    pub recent_rate: f64,
+    /// Estimated time until `copied` reaches `total` at the
+    /// `recent_rate`; `None` until a rate is known.
+    pub eta: Option<Duration>,
+}
+
+/// The period [PeriodicUpdater] averages the rate over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Samples closer together than this are merged.
+const RATE_SAMPLE_SPACING: Duration = Duration::from_millis(100);
+
+/// A moving average of the copy rate over a recent window, and the
+/// estimated time remaining at that rate. Unlike the mean over the
+/// whole copy this follows changes in speed, e.g. when moving from
+/// large files to many small ones.
+#[derive(Clone, Debug)]
+pub struct Throughput {
+    window: Duration,
+    // (time, total bytes copied); the oldest sample is the last one
+    // before the window, so the average covers all of it.
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    pub fn new(window: Duration) -> Throughput {
+        Throughput {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record that `copied` bytes in total had been copied at `now`.
+    pub fn record(&mut self, now: Instant, copied: u64) {
+        let len = self.samples.len();
+        if len > 1 && now.saturating_duration_since(self.samples[len - 2].0) < RATE_SAMPLE_SPACING {
+            self.samples[len - 1] = (now, copied);
+            return;
+        }
+        self.samples.push_back((now, copied));
+        while self.samples.len() > 2 && now.saturating_duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes copied per second over the window; 0 until two samples
+    /// have been recorded.
+    pub fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) if last.0 > first.0 => {
+                last.1.saturating_sub(first.1) as f64 / (last.0 - first.0).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// The time to copy the rest of `total` bytes at the current
+    /// rate, or `None` if nothing is being copied.
+    pub fn eta(&self, total: u64) -> Option<Duration> {
+        let rate = self.rate();
+        let copied = self.samples.back()?.1;
+        if rate > 0.0 {
+            Some(Duration::from_secs_f64(total.saturating_sub(copied) as f64 / rate))
         } else {
-            self.chan_tx.send(update)?;
+            None
         }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    copied: AtomicU64,
+    total: AtomicU64,
+    files: AtomicU64,
+    skipped: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self, start: Instant) -> StatsSnapshot {
+        let elapsed = start.elapsed();
+        let copied = self.copied.load(Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        StatsSnapshot {
+            copied,
+            total: self.total.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            elapsed,
+            rate: if secs > 0.0 { copied as f64 / secs } else { 0.0 },
+            ..StatsSnapshot::default()
+        }
+    }
+}
+
+/// A [StatusUpdater] that forwards updates to another updater while
+/// counting the totals, and calls `callback` with a [StatsSnapshot]
+/// every `interval` from a background thread. The callback runs
+/// regardless of copy progress, so a UI can refresh (e.g. the elapsed
+/// time and rate) while a long single operation such as a large
+/// reflink is in flight; the byte counts only change as updates
+/// arrive. Snapshots include the rate over the last 5 seconds and the
+/// resulting estimate of the time remaining. A final snapshot is
+/// delivered when the updater is dropped.
+pub struct PeriodicUpdater {
+    inner: Arc<dyn StatusUpdater>,
+    counters: Arc<Counters>,
+    stop: Option<cbc::Sender<()>>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl PeriodicUpdater {
+    pub fn new(inner: Arc<dyn StatusUpdater>, interval: Duration, callback: impl Fn(StatsSnapshot) + Send + 'static) -> PeriodicUpdater {
+        let counters = Arc::new(Counters::default());
+        let (stop, stopped) = cbc::bounded::<()>(0);
+        let start = Instant::now();
+        let timer = {
+            let counters = counters.clone();
+            thread::spawn(move || {
+                let mut throughput = Throughput::new(RATE_WINDOW);
+                throughput.record(start, 0);
+                let mut snapshot = || {
+                    let mut snap = counters.snapshot(start);
+                    throughput.record(Instant::now(), snap.copied);
+                    snap.recent_rate = throughput.rate();
+                    snap.eta = throughput.eta(snap.total);
+                    snap
+                };
+                // Disconnection of the stop channel ends the timer.
+                while let Err(cbc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    callback(snapshot());
+                }
+                callback(snapshot());
+            })
+        };
+        PeriodicUpdater {
+            inner,
+            counters,
+            stop: Some(stop),
+            timer: Some(timer),
+        }
+    }
+}
+
+impl StatusUpdater for PeriodicUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        let (counter, n) = match update {
+            StatusUpdate::Started(_) => return self.inner.send(update),
+            StatusUpdate::Copied(bytes) => (&self.counters.copied, bytes),
+            StatusUpdate::Size(bytes) => (&self.counters.total, bytes),
+            StatusUpdate::Skipped(_) => (&self.counters.skipped, 1),
+            StatusUpdate::Completed { .. } => (&self.counters.files, 1),
+            StatusUpdate::Error(_) => (&self.counters.errors, 1),
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+        self.inner.send(update)
+    }
+
+    fn tick(&self) -> Result<()> {
+        self.inner.tick()
+    }
+}
+
+impl Drop for PeriodicUpdater {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(timer) = self.timer.take() {
+            if timer.join().is_err() {
+                error!("Progress callback panicked");
+            }
+        }
+    }
+}
+
+/// A copy event, as passed to the [CallbackUpdater] callback.
+#[derive(Debug)]
+pub enum ProgressEvent {
+    /// Copying of the file at this source path has started.
+    FileStarted(PathBuf),
+    /// Bytes copied since the previous such event, across all files.
+    BytesCopied(u64),
+    /// Further bytes that will need to be copied.
+    TotalSize(u64),
+    /// A source entry was deliberately not copied.
+    FileSkipped(PathBuf),
+    /// A file has been copied; see [StatusUpdate::Completed].
+    FileCompleted { reflinked: bool },
+    /// An error during a copy operation.
+    Error(XcpError),
+}
+
+impl From<StatusUpdate> for ProgressEvent {
+    fn from(update: StatusUpdate) -> ProgressEvent {
+        match update {
+            StatusUpdate::Started(path) => ProgressEvent::FileStarted(path),
+            StatusUpdate::Copied(bytes) => ProgressEvent::BytesCopied(bytes),
+            StatusUpdate::Size(bytes) => ProgressEvent::TotalSize(bytes),
+            StatusUpdate::Skipped(path) => ProgressEvent::FileSkipped(path),
+            StatusUpdate::Completed { reflinked } => ProgressEvent::FileCompleted { reflinked },
+            StatusUpdate::Error(e) => ProgressEvent::Error(e),
+        }
+    }
+}
+
+/// A [StatusUpdater] that calls a closure with each update as a
+/// [ProgressEvent]; e.g:
+///
+///     use libxcp::feedback::{CallbackUpdater, ProgressEvent, StatusUpdater};
+///     # use std::sync::Arc;
+///
+///     let stats: Arc<dyn StatusUpdater> = Arc::new(CallbackUpdater::new(|event| {
+///         if let ProgressEvent::FileStarted(path) = event {
+///             println!("Copying {:?}", path);
+///         }
+///     }));
+///
+/// The callback is called directly from the copy workers, so may be
+/// called concurrently and should return quickly. As with other
+/// updaters, [ProgressEvent::BytesCopied] events are batched by the
+/// drivers to roughly one per [Config::block_size] per worker.
+pub struct CallbackUpdater {
+    callback: Box<dyn Fn(ProgressEvent) + Send + Sync>,
+}
+
+impl CallbackUpdater {
+    pub fn new(callback: impl Fn(ProgressEvent) + Send + Sync + 'static) -> CallbackUpdater {
+        CallbackUpdater {
+            callback: Box::new(callback),
+        }
+    }
+}
+
+impl StatusUpdater for CallbackUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        (self.callback)(update.into());
         Ok(())
     }
 }
@@ -113,3 +542,208 @@ impl StatusUpdater for NoopUpdater {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Shared updater that counts bytes behind a lock.
+    #[derive(Default)]
+    struct LockedUpdater {
+        state: Mutex<(u64, u64)>, // (bytes, sends)
+    }
+
+    impl StatusUpdater for LockedUpdater {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            if let StatusUpdate::Copied(bytes) = update {
+                let mut state = self.state.lock().unwrap();
+                state.0 += bytes;
+                state.1 += 1;
+            }
+            Ok(())
+        }
+    }
+
+    fn run_workers(shared: &Arc<LockedUpdater>, threshold: Option<u64>, workers: u64, sends: u64) {
+        let threads: Vec<_> = (0..workers)
+            .map(|_| {
+                let shared: Arc<dyn StatusUpdater> = shared.clone();
+                thread::spawn(move || {
+                    let updates: Arc<dyn StatusUpdater> = match threshold {
+                        Some(t) => Arc::new(CoalescingUpdater::new(shared, t, Duration::MAX)),
+                        None => shared,
+                    };
+                    for _ in 0..sends {
+                        updates.send(StatusUpdate::Copied(7)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_coalescing_flushes_all_bytes() {
+        let shared = Arc::new(LockedUpdater::default());
+        run_workers(&shared, Some(1000), 8, 10_001);
+
+        let (bytes, sends) = *shared.state.lock().unwrap();
+        assert_eq!(8 * 10_001 * 7, bytes);
+        // 70007 bytes per worker at 7 per update; flushed at each
+        // crossing of 1000 (every 143 updates) plus once on drop.
+        assert_eq!(8 * (10_001 / 143 + 1), sends);
+    }
+
+    #[test]
+    fn test_coalescing_flushes_on_interval() {
+        let shared = Arc::new(LockedUpdater::default());
+        let updater = CoalescingUpdater::new(shared.clone(), u64::MAX, Duration::from_millis(50));
+        updater.send(StatusUpdate::Copied(10)).unwrap();
+        assert_eq!((0, 0), *shared.state.lock().unwrap());
+        thread::sleep(Duration::from_millis(60));
+        updater.send(StatusUpdate::Copied(5)).unwrap();
+        assert_eq!((15, 1), *shared.state.lock().unwrap());
+    }
+
+    #[test]
+    fn test_coalescing_forwards_other_updates() {
+        let (tx, rx) = cbc::unbounded();
+        struct Fwd(cbc::Sender<StatusUpdate>);
+        impl StatusUpdater for Fwd {
+            fn send(&self, update: StatusUpdate) -> Result<()> {
+                self.0.send(update)?;
+                Ok(())
+            }
+        }
+
+        {
+            let updater = CoalescingUpdater::new(Arc::new(Fwd(tx)), u64::MAX, Duration::MAX);
+            updater.send(StatusUpdate::Copied(10)).unwrap();
+            updater.send(StatusUpdate::Completed { reflinked: false }).unwrap();
+            updater.send(StatusUpdate::Copied(5)).unwrap();
+            updater.send(StatusUpdate::Error(XcpError::CopyError("failed".to_string()))).unwrap();
+            updater.send(StatusUpdate::Copied(3)).unwrap();
+        }
+
+        // Bytes are held back past other updates, but not past errors.
+        let received = rx.iter()
+            .map(|u| format!("{:?}", u))
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["Completed { reflinked: false }", "Copied(15)",
+                        r#"Error(CopyError("failed"))"#, "Copied(3)"], received);
+    }
+
+    #[test]
+    fn test_heartbeat_ticks() {
+        let config = Arc::new(Config::default());
+        let channel = ChannelUpdater::new(&config);
+        let rx = channel.rx_channel();
+        let updates: Arc<dyn StatusUpdater> = Arc::new(CoalescingUpdater::new(Arc::new(channel), u64::MAX, Duration::MAX));
+
+        updates.send(StatusUpdate::Copied(10)).unwrap();
+        updates.tick().unwrap();
+        // Rate-limited; only the last of these is due.
+        let mut heartbeat = Heartbeat::new(Duration::from_millis(50));
+        heartbeat.beat(&updates).unwrap();
+        thread::sleep(Duration::from_millis(60));
+        heartbeat.beat(&updates).unwrap();
+        heartbeat.beat(&updates).unwrap();
+        drop(updates);
+
+        let received = rx.try_iter()
+            .map(|u| format!("{:?}", u))
+            .collect::<Vec<String>>();
+        assert_eq!(vec!["Copied(10)", "Copied(0)", "Copied(0)"], received);
+    }
+
+    #[test]
+    #[ignore = "Benchmark"]
+    fn bench_coalescing_contention() {
+        let (workers, sends) = (32, 200_000);
+        for threshold in [None, Some(1024 * 1024)] {
+            let shared = Arc::new(LockedUpdater::default());
+            let start = Instant::now();
+            run_workers(&shared, threshold, workers, sends);
+            let (bytes, count) = *shared.state.lock().unwrap();
+            println!("threshold {:?}: {:?}, {} shared sends", threshold, start.elapsed(), count);
+            assert_eq!(workers * sends * 7, bytes);
+        }
+    }
+
+    #[test]
+    fn test_periodic_updates_during_long_operation() {
+        let (tx, rx) = cbc::unbounded();
+        let interval = Duration::from_millis(20);
+        let start = Instant::now();
+        let updater = PeriodicUpdater::new(Arc::new(NoopUpdater), interval, move |snap| {
+            tx.send((start.elapsed(), snap)).unwrap();
+        });
+        updater.send(StatusUpdate::Size(1000)).unwrap();
+        updater.send(StatusUpdate::Copied(400)).unwrap();
+        // A single long-running operation with no updates.
+        thread::sleep(Duration::from_millis(300));
+        updater.send(StatusUpdate::Copied(600)).unwrap();
+        updater.send(StatusUpdate::Completed { reflinked: false }).unwrap();
+        drop(updater);
+
+        let snaps: Vec<(Duration, StatsSnapshot)> = rx.iter().collect();
+        // Roughly one per interval, allowing for scheduling delays.
+        assert!(snaps.len() >= 5, "Only {} snapshots", snaps.len());
+        let during = snaps.iter()
+            .filter(|(at, _)| *at < Duration::from_millis(250))
+            .collect::<Vec<_>>();
+        assert!(!during.is_empty());
+        assert!(during.iter().all(|(_, s)| s.copied == 400 && s.total == 1000));
+        assert!(snaps.windows(2).all(|w| w[0].1.elapsed <= w[1].1.elapsed));
+
+        let last = snaps.last().unwrap().1;
+        assert_eq!((1000, 1000, 1), (last.copied, last.total, last.files));
+        assert!(last.rate > 0.0 && last.recent_rate > 0.0);
+        assert_eq!(Some(Duration::ZERO), last.eta);
+    }
+
+    #[test]
+    fn test_throughput_window() {
+        let start = Instant::now();
+        let secs = |n: u64| start + Duration::from_secs(n);
+        let mut tp = Throughput::new(Duration::from_secs(5));
+        assert_eq!(0.0, tp.rate());
+        assert_eq!(None, tp.eta(1000));
+
+        // 100MB/s for 10 seconds.
+        for i in 0..=10 {
+            tp.record(secs(i), i * 100_000_000);
+        }
+        assert_eq!(100_000_000.0, tp.rate());
+        assert_eq!(Some(Duration::from_secs(10)), tp.eta(2_000_000_000));
+
+        // Slowing to 10MB/s; after a full window the average only
+        // reflects the new rate, unlike the overall mean.
+        for i in 1..=5 {
+            tp.record(secs(10 + i), 1_000_000_000 + i * 10_000_000);
+        }
+        assert_eq!(10_000_000.0, tp.rate());
+        assert_eq!(Some(Duration::from_secs(10)), tp.eta(1_150_000_000));
+        assert_eq!(Some(Duration::ZERO), tp.eta(0));
+
+        // Closely spaced samples are merged.
+        let mut tp = Throughput::new(Duration::from_secs(5));
+        tp.record(start, 0);
+        for i in 1..=99 {
+            tp.record(start + Duration::from_millis(i), i * 1000);
+        }
+        assert_eq!(2, tp.samples.len());
+        assert!((tp.rate() - 1_000_000.0).abs() < 1.0);
+
+        // Stalled.
+        tp.record(secs(10), 100_000);
+        tp.record(secs(16), 100_000);
+        assert_eq!(0.0, tp.rate());
+        assert_eq!(None, tp.eta(200_000));
+    }
+}
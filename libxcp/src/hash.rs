@@ -0,0 +1,144 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Content hashing of files.
+//!
+//! The hash used is SHA-256, as produced by `sha256sum`. Digests are
+//! written as 64 hex digits, optionally prefixed with `sha256:`.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::result;
+use std::str::FromStr;
+
+use sha2::{Digest as _, Sha256};
+
+use crate::errors::{Result, XcpError};
+
+const HASH_BUFFER: usize = 64 * 1024;
+
+/// A SHA-256 digest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Digest(pub [u8; 32]);
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Digest {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || XcpError::InvalidArguments(format!("Invalid SHA-256 digest: {}", s));
+        let hex = s.strip_prefix("sha256:").unwrap_or(s);
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut digest = [0; 32];
+        for (i, b) in digest.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Digest(digest))
+    }
+}
+
+/// Incremental SHA-256 hasher.
+#[derive(Clone, Default)]
+pub struct Hasher(Sha256);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> Digest {
+        Digest(self.0.finalize().into())
+    }
+}
+
+/// Hash the contents of a file.
+pub fn hash_file(path: &Path) -> Result<Digest> {
+    let mut fd = File::open(path)?;
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0; HASH_BUFFER];
+    loop {
+        let n = fd.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Hasher::new();
+        hasher.update(data);
+        hasher.finish().to_string()
+    }
+
+    #[test]
+    fn test_known_digests() {
+        assert_eq!("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855", sha256(b""));
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad", sha256(b"abc"));
+        assert_eq!("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+                   sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"));
+    }
+
+    #[test]
+    fn test_incremental_and_file() -> Result<()> {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Hasher::new();
+        for chunk in data.chunks(1000 + 7) {
+            hasher.update(chunk);
+        }
+        let digest = hasher.finish();
+        assert_eq!(sha256(&data), digest.to_string());
+
+        let dir = TempDir::new()?;
+        let file = dir.path().join("data");
+        std::fs::write(&file, &data)?;
+        assert_eq!(digest, hash_file(&file)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_digest() {
+        let hex = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let digest = Digest::from_str(hex).unwrap();
+        assert_eq!(hex, digest.to_string());
+        assert_eq!(digest, Digest::from_str(&format!("sha256:{}", hex.to_uppercase())).unwrap());
+        for bad in ["", "abc", &hex[1..], &format!("{}0", hex), &format!("md5:{}", hex)] {
+            assert!(Digest::from_str(bad).is_err(), "Parsed {:?}", bad);
+        }
+    }
+}
@@ -54,12 +54,21 @@
 //!     // moved to the driver call and will end when drained.
 //!     for stat in stat_rx {
 //!         match stat {
+//!             StatusUpdate::Started(p) => {
+//!                 println!("Copying {:?}", p);
+//!             },
 //!             StatusUpdate::Copied(v) => {
 //!                 println!("Copied {} bytes", v);
 //!             },
 //!             StatusUpdate::Size(v) => {
 //!                 println!("Size update: {}", v);
 //!             },
+//!             StatusUpdate::Skipped(p) => {
+//!                 println!("Skipped {:?}", p);
+//!             },
+//!             StatusUpdate::Completed { reflinked } => {
+//!                 println!("File complete, reflinked: {}", reflinked);
+//!             },
 //!             StatusUpdate::Error(e) => {
 //!                 panic!("Error during copy: {}", e);
 //!             }
@@ -77,14 +86,28 @@
 //! [xcp]: https://crates.io/crates/xcp/
 
 pub mod config;
+pub mod delta;
+pub mod dirfd;
 pub mod drivers;
 pub mod errors;
+pub mod fdbudget;
 pub mod feedback;
+pub mod hash;
+pub mod optimize;
+pub mod pack;
+pub mod quoting;
+pub mod ranges;
+pub mod status;
 
 // Internal
 mod backup;
+mod membudget;
 mod operations;
 mod paths;
+mod throttle;
+mod verify;
+
+pub use operations::CopyHandle;
 
 #[cfg(test)]
 #[allow(unused)]
@@ -97,7 +120,7 @@ mod tests {
 
     use crate::errors::{Result, XcpError};
     use crate::config::Config;
-    use crate::feedback::{ChannelUpdater, StatusUpdater, StatusUpdate};
+    use crate::feedback::{CallbackUpdater, ChannelUpdater, ProgressEvent, StatusUpdater, StatusUpdate};
     use crate::drivers::{Drivers, load_driver};
 
     #[test]
@@ -120,12 +143,21 @@ mod tests {
         // moved to the driver call and will end when drained.
         for stat in stat_rx {
             match stat {
+                StatusUpdate::Started(p) => {
+                    println!("Copying {:?}", p);
+                },
                 StatusUpdate::Copied(v) => {
                     println!("Copied {} bytes", v);
                 },
                 StatusUpdate::Size(v) => {
                     println!("Size update: {}", v);
                 },
+                StatusUpdate::Skipped(p) => {
+                    println!("Skipped {:?}", p);
+                },
+                StatusUpdate::Completed { reflinked } => {
+                    println!("File complete, reflinked: {}", reflinked);
+                },
                 StatusUpdate::Error(e) => {
                     println!("Error during copy: {}", e);
                     return Err(e.into());
@@ -140,4 +172,91 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn callback_usage_test() -> Result<()> {
+        use std::fs;
+        use std::sync::Mutex;
+
+        let source = TempDir::new()?;
+        for (name, len) in [("a", 1000), ("b", 0), ("c", 5000)] {
+            fs::write(source.path().join(name), vec![0x5a; len])?;
+        }
+        let dest = TempDir::new()?;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let stats: Arc<dyn StatusUpdater> = {
+            let events = events.clone();
+            Arc::new(CallbackUpdater::new(move |event| events.lock().unwrap().push(event)))
+        };
+        let config = Arc::new(Config::default());
+        let driver = load_driver(Drivers::ParFile, &config)?;
+        driver.copy(vec![source.path().to_path_buf()], dest.path(), stats)?;
+
+        let events = events.lock().unwrap();
+        let started = events.iter()
+            .filter(|e| matches!(e, ProgressEvent::FileStarted(_)))
+            .count();
+        let completed = events.iter()
+            .filter(|e| matches!(e, ProgressEvent::FileCompleted { .. }))
+            .count();
+        let copied: u64 = events.iter()
+            .map(|e| match e {
+                ProgressEvent::BytesCopied(n) => *n,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!((3, 3, 6000), (started, completed, copied));
+        assert!(!events.iter().any(|e| matches!(e, ProgressEvent::Error(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn readdir_order_test() -> Result<()> {
+        use std::fs;
+        use std::sync::Mutex;
+
+        // Records the order in which files are handed to a worker.
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<PathBuf>>);
+
+        impl StatusUpdater for Recorder {
+            fn send(&self, update: StatusUpdate) -> Result<()> {
+                if let StatusUpdate::Started(p) = update {
+                    self.0.lock().unwrap().push(p);
+                }
+                Ok(())
+            }
+        }
+
+        let drivers = [
+            Drivers::ParFile,
+            #[cfg(feature = "parblock")]
+            Drivers::ParBlock,
+        ];
+        for drv in drivers {
+            let source = TempDir::new()?;
+            for n in 0..32 {
+                fs::write(source.path().join(format!("file{:02}", (n * 7) % 32)), "data")?;
+            }
+            let dest = TempDir::new()?;
+
+            let config = Arc::new(Config {
+                workers: 8,
+                readdir_order: true,
+                ..Config::default()
+            });
+            let recorder = Arc::new(Recorder::default());
+            let driver = load_driver(drv, &config)?;
+            driver.copy(vec![source.path().to_path_buf()], dest.path(), recorder.clone())?;
+
+            let expected = fs::read_dir(source.path())?
+                .map(|e| Ok(e?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(expected, *recorder.0.lock().unwrap(), "{:?}", drv);
+        }
+
+        Ok(())
+    }
 }
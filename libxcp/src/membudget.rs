@@ -0,0 +1,151 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A process-wide budget on memory used for copy buffers.
+//!
+//! Copies that go through user space (see
+//! [Config::basic_io](crate::config::Config::basic_io)) and
+//! verification allocate a buffer per worker, so peak memory grows
+//! with workers × buffer size. When
+//! [Config::max_buffer_memory](crate::config::Config::max_buffer_memory)
+//! is set, buffers are sized to fit within it and each allocation
+//! waits for a [MemPermit] covering its size. Without a budget no
+//! accounting is done.
+
+use std::sync::{Condvar, Mutex, OnceLock};
+
+use log::debug;
+
+use crate::config::Config;
+
+#[derive(Debug, Default)]
+struct State {
+    in_use: u64,
+    peak: u64,
+}
+
+/// A counting semaphore over bytes of buffer memory.
+#[derive(Debug, Default)]
+pub(crate) struct MemBudget {
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+/// Bytes reserved from a [MemBudget]; they are returned to the budget
+/// when this is dropped, so it should outlive the buffer it covers.
+#[derive(Debug)]
+pub(crate) struct MemPermit {
+    budget: &'static MemBudget,
+    bytes: u64,
+}
+
+impl MemBudget {
+    /// Block until `bytes` are available under `limit`, then reserve
+    /// them. As with the fd budget, a request larger than the limit
+    /// is allowed once nothing else is held.
+    pub(crate) fn acquire(&'static self, bytes: u64, limit: u64) -> MemPermit {
+        let mut state = self.state.lock().unwrap();
+        while state.in_use > 0 && state.in_use + bytes > limit {
+            debug!("Waiting for {} buffer bytes ({} of {} in use)", bytes, state.in_use, limit);
+            state = self.released.wait(state).unwrap();
+        }
+        state.in_use += bytes;
+        state.peak = state.peak.max(state.in_use);
+        MemPermit { budget: self, bytes }
+    }
+
+    /// The highest number of bytes reserved at once.
+    #[cfg(test)]
+    pub(crate) fn peak(&self) -> u64 {
+        self.state.lock().unwrap().peak
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.in_use -= bytes;
+        self.released.notify_all();
+    }
+}
+
+impl Drop for MemPermit {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+/// The budget shared by all copy operations in this process.
+pub(crate) fn global() -> &'static MemBudget {
+    static BUDGET: OnceLock<MemBudget> = OnceLock::new();
+    BUDGET.get_or_init(MemBudget::default)
+}
+
+/// The size to use for a buffer of up to `want` bytes; i.e. capped
+/// at the configured budget, if any.
+pub(crate) fn buffer_size(want: u64, config: &Config) -> u64 {
+    match config.max_buffer_memory {
+        Some(limit) => want.min(limit.max(1)),
+        None => want,
+    }
+}
+
+/// Reserve `bytes` of buffer memory from the global budget, if one is
+/// configured.
+pub(crate) fn reserve(bytes: u64, config: &Config) -> Option<MemPermit> {
+    config.max_buffer_memory
+        .map(|limit| global().acquire(bytes, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_budget_is_enforced() {
+        let budget: &'static MemBudget = Box::leak(Box::default());
+        let limit = 3 * 1024 * 1024;
+
+        let threads: Vec<_> = (0..16)
+            .map(|i| thread::spawn(move || {
+                for _ in 0..8 {
+                    let _permit = budget.acquire(1024 * 1024 + i * 1000, limit);
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }))
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        assert!(budget.peak() <= limit);
+        assert_eq!(0, budget.state.lock().unwrap().in_use);
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let unlimited = Config::default();
+        assert_eq!(u64::MAX, buffer_size(u64::MAX, &unlimited));
+        assert!(reserve(100, &unlimited).is_none());
+
+        let limited = Config {
+            max_buffer_memory: Some(4096),
+            ..Config::default()
+        };
+        assert_eq!(4096, buffer_size(u64::MAX, &limited));
+        assert_eq!(100, buffer_size(100, &limited));
+    }
+}
@@ -14,24 +14,42 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{cmp, thread};
-use std::fs::{self, canonicalize, create_dir_all, read_link, File, Metadata};
+use std::{cmp, process, thread};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, canonicalize, create_dir_all, read_link, File, Metadata, OpenOptions, Permissions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{fchown, FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use crossbeam_channel as cbc;
 use libfs::{
-    allocate_file, copy_file_bytes, copy_permissions,
-    next_sparse_segments, probably_sparse, sync, reflink, FileType, copy_timestamps,
+    advise_sequential, allocate_file, copy_acls, copy_node, copy_bytes_uspace, copy_file_bytes, copy_file_offset, copy_range_aligned, copy_range_sparse, copy_range_uspace, copy_security_context, filesystem_type, free_inodes, copy_xattrs, drop_cache, is_devnull, is_encrypted, is_same_file, is_missing_key, map_extents, map_extents_synced, next_sparse_segments, open_direct, open_direct_write,
+    preallocate, probably_sparse, sync, sync_range, reflink, clone_file, AlignedBuf, Extent, DIRECT_IO_ALIGN, FileType, set_timestamps, timestamp_granularity,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rustix::fs::{flock, FlockOperation};
+use rustix::io::Errno;
+use rustix::process::geteuid;
 use walkdir::WalkDir;
 
 use crate::backup::{get_backup_path, needs_backup};
-use crate::config::{Config, Reflink};
+use crate::config::{Backup, Config, Encrypted, HardLinks, Optimize, Overwrite, Preallocate, Reflink, SameFile, Sparse, Symlinks, Verify};
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::paths::{parse_ignore, ignore_filter};
+use crate::delta::{update_in_place, DELTA_BLOCK_SIZE};
+use crate::fdbudget::{self, FdPermit};
+use crate::membudget;
+use crate::optimize::select_profile;
+use crate::feedback::{CopyStats, Heartbeat, StatusUpdate, StatusUpdater, HEARTBEAT_INTERVAL};
+use crate::hash::hash_file;
+use crate::paths::{exclude_filter, ignore_filter, parse_excludes, parse_ignore, target_path};
+use crate::quoting::quote_path;
+use crate::throttle;
+use crate::verify::{prefix_matches, verify_copy};
 
 #[derive(Debug)]
 pub struct CopyHandle {
@@ -39,67 +57,679 @@ pub struct CopyHandle {
     pub outfd: File,
     pub metadata: Metadata,
     pub config: Arc<Config>,
+    from: PathBuf,
+    to: PathBuf,
+    // When copying started; see check_timeout().
+    started: OnceLock<Instant>,
+    // Set by the watchdog once Config::file_timeout has expired; see
+    // abandon_after().
+    expired: Arc<AtomicBool>,
+    // The destination is /dev/null; only read the source.
+    discard: bool,
+    // Update the existing destination in place; see Config::delta.
+    delta: bool,
+    sync_cadence: SyncCadence,
+    // Offset to resume an interrupted copy from; see Config::resume.
+    resume_from: u64,
+    // The destination was created as a clone of the source.
+    cloned: bool,
+    // The source and destination opened for direct I/O; see
+    // Config::direct_io.
+    direct: Option<(File, File)>,
+    // The file written in place of the destination; see
+    // Config::atomic.
+    temp: Option<TempDest>,
+    // Set if any write fails; the temp file is then never renamed
+    // into place.
+    failed: AtomicBool,
+    // The descriptors were supplied by the caller; see
+    // CopyHandle::from_fds. There are no paths to reopen or remove.
+    by_fd: bool,
+    // Declared after the descriptors so they are closed before the
+    // permit is returned.
+    _fds: FdPermit,
 }
 
+/// `S_ISVTX`; restricts renaming and removal in a directory to the
+/// owners.
+const STICKY_BIT: u32 = 0o1000;
+
+/// Descriptors held by a [CopyHandle]; the source and destination.
+/// Direct I/O opens each a second time.
+const HANDLE_FDS: usize = 2;
+
+/// Largest buffer used for direct I/O.
+const MAX_DIRECT_BUFFER: u64 = 16 * 1024 * 1024;
+
+/// Whether reflinks are made by cloning to a new path, as with
+/// `clonefile(2)` on macOS, rather than into the open destination.
+const CLONE_BY_PATH: bool = cfg!(target_os = "macos");
+
+/// Attempts to find an unused temporary name for an atomic copy.
+const MAX_TEMP_NAMES: u32 = 100;
+
+/// Largest buffer used when scanning for zeros; see [Sparse::Always].
+const SPARSE_BUF_SIZE: u64 = 1024 * 1024;
+
+/// Read buffer size when discarding data.
+const DISCARD_BUF_SIZE: usize = 128 * 1024;
+
+/// Largest buffer used when copying from a pipe or other stream.
+const STREAM_BUF_SIZE: u64 = 1024 * 1024;
+
+/// Largest amount copied between checks of [Config::file_timeout].
+const TIMEOUT_CHUNK: u64 = 16 * 1024 * 1024;
+
+/// Delay before the first retry of a failed read; see
+/// [Config::read_retries]. Later retries wait proportionally longer.
+const READ_RETRY_DELAY: Duration = Duration::from_millis(100);
+
 impl CopyHandle {
     pub fn new(from: &Path, to: &Path, config: &Arc<Config>) -> Result<CopyHandle> {
-        let infd = File::open(from)?;
+        let nfds = if config.direct_io { HANDLE_FDS * 2 } else { HANDLE_FDS };
+        let fds = fdbudget::global().acquire(nfds, config.fd_limit());
+        let infd = open_source(from)?;
         let metadata = infd.metadata()?;
+        if config.drop_cache {
+            if let Err(e) = advise_sequential(&infd) {
+                debug!("Failed to advise sequential reads of {:?}: {}", from, e);
+            }
+        }
 
-        if needs_backup(to, config)? {
-            let backup = get_backup_path(to)?;
-            info!("Backup: Rename {:?} to {:?}", to, backup);
-            fs::rename(to, backup)?;
+        let discard = is_devnull(to);
+        // The tree walk checks this too, but the destination must
+        // never be truncated if it is the source.
+        if !discard && to.exists() && is_same_file(from, to)? {
+            return Err(XcpError::SameFile(to.to_path_buf()).into());
         }
+        // Ask before anything can truncate or replace it.
+        if config.interactive && !discard && !config.resume && config.overwrite != Overwrite::RenameOnConflict
+            && to.exists() && !confirm_overwrite(to)?
+        {
+            return Err(XcpError::OverwriteDeclined(to.to_path_buf()).into());
+        }
+        let mut resume_from = 0;
+        let mut delta = false;
+        let mut cloned = false;
+        let mut dest = to.to_path_buf();
+        let mut temp = None;
+        let denied = |e: io::Error| permission_denied(e.into(), to);
+        let outfd = if discard {
+            debug!("Destination is {:?}, discarding data from {:?}", to, from);
+            OpenOptions::new().write(true).open(to).map_err(denied)?
+        } else if config.resume && to.exists() {
+            let outfd = OpenOptions::new().read(true).write(true).open(to).map_err(denied)?;
+            if config.lock {
+                lock_dest(&outfd, to)?;
+            }
+            resume_from = if config.no_extent_map {
+                0
+            } else {
+                resume_point(&infd, &outfd)?
+            };
+            if resume_from > 0 && !prefix_matches(&infd, &outfd, resume_from, config)? {
+                warn!("Existing data in {} doesn't match the source; copying from the start", quote_path(to));
+                resume_from = 0;
+            }
+            info!("Resuming copy of {} to {} from offset {}", quote_path(from), quote_path(to), resume_from);
+            // Discard anything after the resume point, e.g. stale
+            // data from a larger file, so the source holes are holes.
+            allocate_file(&outfd, resume_from)?;
+            allocate_file(&outfd, metadata.len())?;
+            outfd
+        } else if config.overwrite == Overwrite::RenameOnConflict {
+            let (outfd, unique) = create_unique(to)
+                .map_err(|e| permission_denied(e, to))?;
+            if unique != to {
+                info!("Destination {} exists, copying to {}", quote_path(to), quote_path(&unique));
+                dest = unique;
+            }
+            if config.lock {
+                lock_dest(&outfd, &dest)?;
+            }
+            allocate_dest(&infd, &outfd, metadata.len(), config)
+                .map_err(|e| out_of_space(e, &dest))?;
+            outfd
+        } else if config.delta && to.is_file() {
+            let outfd = OpenOptions::new().read(true).write(true).open(to).map_err(denied)?;
+            if config.lock {
+                lock_dest(&outfd, to)?;
+            }
+            debug!("Updating {:?} in place from {:?}", to, from);
+            delta = true;
+            outfd
+        } else {
+            let tmp = create_temp(to, config).map_err(|e| permission_denied(e, to))?;
+            // An atomic copy leaves the destination in place until it
+            // is replaced; see set_complete().
+            if tmp.is_none() {
+                backup_dest(to, config)?;
+            }
+
+            let outfd = if let Some((outfd, tmp)) = tmp {
+                debug!("Writing {:?} via {:?}", to, tmp.path);
+                temp = Some(tmp);
+                outfd
+            } else if clone_new(from, to, config)? {
+                cloned = true;
+                OpenOptions::new().write(true).open(to).map_err(denied)?
+            } else if config.lock {
+                // Don't truncate until we hold the lock, or we may
+                // clobber a copy in progress.
+                let outfd = OpenOptions::new().write(true).create(true).truncate(false).open(to).map_err(denied)?;
+                lock_dest(&outfd, to)?;
+                outfd.set_len(0)?;
+                outfd
+            } else {
+                File::create(to)
+                    .map_err(|e| permission_denied(sticky_dir_error(out_of_inodes(e.into(), to), to), to))?
+            };
+            if !cloned {
+                let written = temp.as_ref().map_or(to, |t| &t.path);
+                allocate_dest(&infd, &outfd, metadata.len(), config)
+                    .map_err(|e| out_of_space(e, written))?;
+            }
+            outfd
+        };
 
-        let outfd = File::create(to)?;
-        allocate_file(&outfd, metadata.len())?;
+        let direct = if config.direct_io && !discard && !delta && !cloned && config.sparse != Sparse::Always {
+            open_direct_pair(from, temp.as_ref().map_or(&dest, |t| &t.path))?
+        } else {
+            None
+        };
 
         let handle = CopyHandle {
             infd,
             outfd,
             metadata,
             config: config.clone(),
+            from: from.to_path_buf(),
+            to: dest,
+            started: OnceLock::new(),
+            expired: Arc::default(),
+            discard,
+            delta,
+            sync_cadence: SyncCadence::new(config.sync_every),
+            resume_from,
+            cloned,
+            direct,
+            temp,
+            failed: AtomicBool::new(false),
+            by_fd: false,
+            _fds: fds,
         };
 
         Ok(handle)
     }
 
+    /// Create a handle from descriptors already opened by the caller,
+    /// e.g. for a source that can't be reopened by path. A regular
+    /// source is copied from its start as with [CopyHandle::new];
+    /// other sources, such as pipes, are copied until they end. The
+    /// destination must be a regular file open for writing, and is
+    /// truncated. Options that control how the destination is opened
+    /// (e.g. [Config::atomic], [Config::resume] and [Config::delta])
+    /// have no effect, and [Config::verify] is not supported as it
+    /// reopens the destination.
+    pub fn from_fds(infd: File, outfd: File, config: &Arc<Config>) -> Result<CopyHandle> {
+        if config.verify != Verify::Off {
+            return Err(XcpError::InvalidArguments("Verification requires a destination path".to_string()).into());
+        }
+        let fds = fdbudget::global().acquire(HANDLE_FDS, config.fd_limit());
+        let metadata = infd.metadata()?;
+        let outmeta = outfd.metadata()?;
+        let from = fd_path(&infd);
+        let to = fd_path(&outfd);
+        if !outmeta.is_file() {
+            return Err(XcpError::InvalidDestination("Destination descriptor is not a regular file.").into());
+        }
+        if (metadata.dev(), metadata.ino()) == (outmeta.dev(), outmeta.ino()) {
+            return Err(XcpError::SameFile(to).into());
+        }
+        if config.lock {
+            lock_dest(&outfd, &to)?;
+        }
+        outfd.set_len(0)?;
+        (&outfd).rewind()?;
+        if metadata.is_file() {
+            (&infd).rewind()?;
+            allocate_dest(&infd, &outfd, metadata.len(), config)?;
+        }
+
+        Ok(CopyHandle {
+            infd,
+            outfd,
+            metadata,
+            config: config.clone(),
+            from,
+            to,
+            started: OnceLock::new(),
+            expired: Arc::default(),
+            discard: false,
+            delta: false,
+            sync_cadence: SyncCadence::new(config.sync_every),
+            resume_from: 0,
+            cloned: false,
+            direct: None,
+            temp: None,
+            failed: AtomicBool::new(false),
+            by_fd: true,
+            _fds: fds,
+        })
+    }
+
     /// Copy len bytes from wherever the descriptor cursors are set.
     fn copy_bytes(&self, len: u64, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        let start = (&self.infd).stream_position()?;
         let mut written = 0u64;
         while written < len {
-            let bytes_to_copy = cmp::min(len - written, self.config.block_size);
-            let bytes = copy_file_bytes(&self.infd, &self.outfd, bytes_to_copy)? as u64;
+            self.check_timeout()?;
+            let bytes_to_copy = self.chunk_size(cmp::min(len - written, self.config.block_size));
+            let pos = start + written;
+            let bytes = if self.config.sparse == Sparse::Always {
+                let bytes = self.copy_block_sparse(bytes_to_copy, pos)?;
+                self.seek_to(pos + bytes)?;
+                bytes
+            } else {
+                self.retry_reads(pos, bytes_to_copy, |attempt| {
+                    // A failed batch may have moved the cursors.
+                    if attempt > 0 {
+                        (&self.infd).seek(SeekFrom::Start(pos))?;
+                        (&self.outfd).seek(SeekFrom::Start(pos))?;
+                    }
+                    let bytes = if self.config.basic_io {
+                        let bytes_to_copy = membudget::buffer_size(bytes_to_copy, &self.config);
+                        let _mem = membudget::reserve(bytes_to_copy, &self.config);
+                        copy_bytes_uspace(&self.infd, &self.outfd, bytes_to_copy as usize)?
+                    } else {
+                        copy_file_bytes(&self.infd, &self.outfd, bytes_to_copy)?
+                    };
+                    Ok(bytes as u64)
+                })?
+            };
+            if bytes == 0 {
+                return Err(self.source_ended(written, len));
+            }
             written += bytes;
+            throttle::throttle(bytes, &self.config);
+            if self.sync_cadence.record(bytes) {
+                debug!("Periodic sync of {:?}", self.outfd);
+                sync_range(&self.outfd)?;
+            }
             updates.send(StatusUpdate::Copied(bytes))?;
         }
 
         Ok(written)
     }
 
-    /// Wrapper around copy_bytes that looks for sparse blocks and skips them.
-    fn copy_sparse(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+    /// Copy a block of `len` bytes at offset `off`, leaving the
+    /// descriptor cursors untouched.
+    pub(crate) fn copy_block(&self, len: u64, off: u64) -> Result<usize> {
+        if let Some((din, dout)) = &self.direct {
+            return self.copy_block_direct(din, dout, len, off);
+        }
+        if self.config.sparse == Sparse::Always {
+            let copied = self.copy_block_sparse(len, off)?;
+            throttle::throttle(copied, &self.config);
+            return Ok(copied as usize);
+        }
+        // The buffer may be smaller than the block.
+        let bufsize = membudget::buffer_size(len, &self.config);
+        let _mem = if self.config.basic_io {
+            membudget::reserve(bufsize, &self.config)
+        } else {
+            None
+        };
+        // Either copy may be short, so loop until the block is done.
+        let mut copied = 0;
+        while copied < len {
+            self.check_timeout()?;
+            let pos = off + copied;
+            let want = self.chunk_size(len - copied);
+            let bytes = self.retry_reads(pos, want, |_| {
+                let bytes = if self.config.basic_io {
+                    let chunk = cmp::min(want, bufsize) as usize;
+                    copy_range_uspace(&self.infd, &self.outfd, chunk, pos as usize, pos as usize)?
+                } else {
+                    copy_file_offset(&self.infd, &self.outfd, want, pos as i64, pos as i64)?
+                };
+                Ok(bytes as u64)
+            })?;
+            if bytes == 0 {
+                return Err(self.source_ended(off + copied, off + len));
+            }
+            copied += bytes;
+        }
+        throttle::throttle(copied, &self.config);
+        Ok(copied as usize)
+    }
+
+    // Copy a block through a buffer, leaving any blocks of zeros as
+    // holes; see Sparse::Always.
+    fn copy_block_sparse(&self, len: u64, off: u64) -> Result<u64> {
+        let bufsize = membudget::buffer_size(cmp::min(len, SPARSE_BUF_SIZE), &self.config);
+        let _mem = membudget::reserve(bufsize, &self.config);
+        let mut buf = vec![0; bufsize as usize];
+        let mut copied = 0;
+        while copied < len {
+            self.check_timeout()?;
+            let chunk = cmp::min(len - copied, bufsize) as usize;
+            let pos = off + copied;
+            let read = self.retry_reads(pos, chunk as u64, |_| {
+                let (read, _) = copy_range_sparse(&self.infd, &self.outfd, &mut buf[..chunk], pos as usize)?;
+                Ok(read as u64)
+            })?;
+            if read == 0 {
+                return Err(self.source_ended(off + copied, off + len));
+            }
+            copied += read;
+        }
+        Ok(copied)
+    }
+
+    // Run `copy`, a batch of `len` bytes starting at offset `off`,
+    // retrying it after an I/O error reading the source up to
+    // Config::read_retries times. `copy` is passed the attempt
+    // number. If the error persists it is reported with the offset.
+    // An I/O error writing the destination is returned as it is.
+    fn retry_reads<F>(&self, off: u64, len: u64, mut copy: F) -> Result<u64>
+    where
+        F: FnMut(u32) -> Result<u64>,
+    {
+        let mut attempt = 0;
+        loop {
+            match copy(attempt) {
+                Err(e) if os_error(&e) == Some(Errno::IO) && self.source_unreadable(off, len) => {
+                    if attempt >= self.config.read_retries {
+                        return Err(XcpError::ReadFailed { path: self.from.clone(), offset: off, error: e.to_string() }.into());
+                    }
+                    attempt += 1;
+                    warn!("I/O error copying {} at offset {}; retrying ({}/{})",
+                          quote_path(&self.from), off, attempt, self.config.read_retries);
+                    thread::sleep(READ_RETRY_DELAY * attempt);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Whether reading the source range `off..off + len` fails. The
+    // copy syscalls report I/O errors reading the source and writing
+    // the destination alike, so this tells them apart.
+    fn source_unreadable(&self, off: u64, len: u64) -> bool {
+        let mut buf = vec![0; cmp::min(len, DISCARD_BUF_SIZE as u64) as usize];
+        let end = off + len;
+        let mut pos = off;
+        while pos < end {
+            let want = cmp::min(end - pos, buf.len() as u64) as usize;
+            match self.infd.read_at(&mut buf[..want], pos) {
+                Ok(0) => break,
+                Ok(bytes) => pos += bytes as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return true,
+            }
+        }
+        false
+    }
+
+    // Copy the aligned middle of a block with direct I/O, and any
+    // unaligned start or end through the page cache.
+    fn copy_block_direct(&self, din: &File, dout: &File, len: u64, off: u64) -> Result<usize> {
+        let align = DIRECT_IO_ALIGN as u64;
+        let end = off + len;
+        let mid_start = cmp::min(off.next_multiple_of(align), end);
+        let mid_end = cmp::max(end - end % align, mid_start);
+
+        let mut copied = 0;
+        if mid_start > off {
+            copied += copy_range_uspace(&self.infd, &self.outfd, (mid_start - off) as usize, off as usize, off as usize)? as u64;
+        }
+        if mid_end > mid_start {
+            let bufsize = direct_buffer_size(mid_end - mid_start, &self.config);
+            let _mem = membudget::reserve(bufsize, &self.config);
+            let mut buf = AlignedBuf::new(bufsize as usize);
+            let mut pos = mid_start;
+            while pos < mid_end {
+                let chunk = cmp::min(mid_end - pos, bufsize) as usize;
+                let bytes = copy_range_aligned(din, dout, &mut buf.as_mut_slice()[..chunk], pos as usize, pos as usize)? as u64;
+                if bytes == 0 {
+                    return Err(self.source_ended(pos, end));
+                }
+                pos += bytes;
+                copied += bytes;
+            }
+        }
+        if end > mid_end {
+            copied += copy_range_uspace(&self.infd, &self.outfd, (end - mid_end) as usize, mid_end as usize, mid_end as usize)? as u64;
+        }
+        throttle::throttle(copied, &self.config);
+        Ok(copied as usize)
+    }
+
+    /// Copy from the resume point with direct I/O, in aligned blocks;
+    /// see [Config::direct_io].
+    fn copy_direct(&self, updates: &Arc<dyn StatusUpdater>) -> Result<CopyStats> {
         let len = self.metadata.len();
-        let mut pos = 0;
+        let align = DIRECT_IO_ALIGN as u64;
+        let block = direct_buffer_size(self.config.block_size, &self.config);
+
+        let mut pos = self.resume_from;
+        while pos < len {
+            self.check_timeout()?;
+            // Keep the blocks aligned after an unaligned resume point.
+            let next = cmp::min(len, (pos + block) / align * align);
+            let bytes = self.copy_block(next - pos, pos)? as u64;
+            pos += bytes;
+            updates.send(StatusUpdate::Copied(bytes))?;
+        }
+
+        let copied = len - self.resume_from;
+        Ok(CopyStats { len, copied, skipped: len - copied, extents: 1, ..CopyStats::default() })
+    }
+
+    // A copy returned no data before the expected end, e.g. because
+    // the source was truncated during the copy. Treat this as an
+    // error rather than retrying forever.
+    fn source_ended(&self, copied: u64, len: u64) -> anyhow::Error {
+        XcpError::CopyError(format!("Source ended after {} of {} bytes copying to {}", copied, len, quote_path(&self.to))).into()
+    }
+
+    /// Wrapper around copy_bytes that looks for sparse blocks and
+    /// skips them. The source extent map is used if available, as it
+    /// needs far fewer syscalls than seeking for each data segment.
+    /// Seeking can be slow on large files, so the updater is sent
+    /// [StatusUpdater::tick] heartbeats between segments.
+    fn copy_sparse(&self, updates: &Arc<dyn StatusUpdater>) -> Result<CopyStats> {
+        if !self.config.no_extent_map {
+            if let Some(extents) = map_extents_synced(&self.infd)? {
+                return self.copy_extents(&extents, updates);
+            }
+        }
+
+        let len = self.metadata.len();
+        let mut pos = self.resume_from;
+        let mut stats = CopyStats { len, ..CopyStats::default() };
+        let mut heartbeat = Heartbeat::new(HEARTBEAT_INTERVAL);
 
         while pos < len {
             let (next_data, next_hole) = next_sparse_segments(&self.infd, &self.outfd, pos)?;
+            heartbeat.beat(updates)?;
 
-            let _written = self.copy_bytes(next_hole - next_data, updates)?;
+            if next_hole > next_data {
+                stats.copied += self.copy_bytes(next_hole - next_data, updates)?;
+                stats.extents += 1;
+            }
             pos = next_hole;
         }
 
+        stats.skipped = len - stats.copied;
+        Ok(stats)
+    }
+
+    /// Copy the data in `extents` of the source, after any resume
+    /// point. Unwritten extents read as zeros, so are left as holes.
+    fn copy_extents(&self, extents: &[Extent], updates: &Arc<dyn StatusUpdater>) -> Result<CopyStats> {
+        let len = self.metadata.len();
+        let mut stats = CopyStats { len, ..CopyStats::default() };
+        for ext in extents.iter().filter(|e| !e.unwritten) {
+            // Extents are block-aligned, so the last may extend past
+            // the end of the file.
+            let start = cmp::max(ext.start, self.resume_from);
+            let end = cmp::min(ext.end, len);
+            if start >= end {
+                continue;
+            }
+            (&self.infd).seek(SeekFrom::Start(start))?;
+            (&self.outfd).seek(SeekFrom::Start(start))?;
+            stats.copied += self.copy_bytes(end - start, updates)?;
+            stats.extents += 1;
+        }
+        stats.skipped = len - stats.copied;
+        Ok(stats)
+    }
+
+    /// Copy a source that isn't a regular file, e.g. a pipe given to
+    /// [CopyHandle::from_fds], until it ends.
+    fn copy_stream(&self, updates: &Arc<dyn StatusUpdater>) -> Result<CopyStats> {
+        let bufsize = membudget::buffer_size(cmp::min(self.config.block_size, STREAM_BUF_SIZE), &self.config);
+        let _mem = membudget::reserve(bufsize, &self.config);
+        let mut buf = vec![0u8; bufsize as usize];
+        let mut copied = 0u64;
+        loop {
+            self.check_timeout()?;
+            let want = throttle::chunk_size(buf.len() as u64, &self.config) as usize;
+            let bytes = match (&self.infd).read(&mut buf[..want]) {
+                Ok(0) => break,
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            (&self.outfd).write_all(&buf[..bytes])?;
+            copied += bytes as u64;
+            throttle::throttle(bytes as u64, &self.config);
+            updates.send(StatusUpdate::Copied(bytes as u64))?;
+        }
+        Ok(CopyStats { len: copied, copied, extents: 1, ..CopyStats::default() })
+    }
+
+    /// Read len bytes from the source cursor and throw them away.
+    fn read_discard(&self, len: u64, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        let bufsize = membudget::buffer_size(DISCARD_BUF_SIZE as u64, &self.config);
+        let _mem = membudget::reserve(bufsize, &self.config);
+        let mut buf = vec![0u8; bufsize as usize];
+        let mut read = 0u64;
+        while read < len {
+            self.check_timeout()?;
+            let want = cmp::min(len - read, buf.len() as u64) as usize;
+            let bytes = (&self.infd).read(&mut buf[..want])?;
+            if bytes == 0 {
+                // Source was truncated during the read.
+                break;
+            }
+            read += bytes as u64;
+            updates.send(StatusUpdate::Copied(bytes as u64))?;
+        }
+        Ok(read)
+    }
+
+    /// Read the source without writing anything; used when the
+    /// destination is /dev/null to measure read throughput. Holes
+    /// are skipped the same way as a normal copy unless
+    /// [Config::read_holes] is set.
+    fn copy_discard(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+        let len = self.metadata.len();
+        if self.config.read_holes || !probably_sparse(&self.infd)? {
+            return self.read_discard(len, updates);
+        }
+
+        let mut pos = 0;
+        while pos < len {
+            let (next_data, next_hole) = next_sparse_segments(&self.infd, &self.outfd, pos)?;
+            self.read_discard(next_hole - next_data, updates)?;
+            pos = next_hole;
+        }
         Ok(len)
     }
 
+    /// Whether this handle discards its data, i.e. the destination
+    /// is `/dev/null`.
+    pub fn is_discard(&self) -> bool {
+        self.discard
+    }
+
+    /// Whether the existing destination is updated in place with a
+    /// delta; see [Config::delta].
+    pub fn is_delta(&self) -> bool {
+        self.delta
+    }
+
+    /// Return an error if the per-file timeout has expired. The
+    /// timeout runs from the first check, when copying starts, so
+    /// time spent queued (e.g. for parblock workers) isn't counted.
+    pub(crate) fn check_timeout(&self) -> Result<()> {
+        let timeout = match self.config.file_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        let started = *self.started.get_or_init(Instant::now);
+        if self.expired.load(Ordering::Relaxed) || started.elapsed() >= timeout {
+            return Err(timeout_error(&self.to, timeout));
+        }
+        Ok(())
+    }
+
+    /// As [copy_block](Self::copy_block), but with a
+    /// [Config::file_timeout] the block is copied on a separate
+    /// thread, which is abandoned if the timeout expires first;
+    /// e.g. if a read hangs on unresponsive storage.
+    pub(crate) fn copy_block_watched(self: &Arc<Self>, len: u64, off: u64) -> Result<usize> {
+        let timeout = match self.config.file_timeout {
+            Some(timeout) => timeout,
+            None => return self.copy_block(len, off),
+        };
+        self.check_timeout()?;
+        let started = *self.started.get_or_init(Instant::now);
+        let handle = self.clone();
+        abandon_after(started + timeout, &self.expired, &self.to, timeout, move || handle.copy_block(len, off))
+    }
+
+    // The size of the next chunk to copy, of up to `want` bytes. With
+    // a timeout this is bounded, so it is checked regularly even with
+    // a large block size.
+    fn chunk_size(&self, want: u64) -> u64 {
+        let want = throttle::chunk_size(want, &self.config);
+        if self.config.file_timeout.is_some() {
+            cmp::min(want, TIMEOUT_CHUNK)
+        } else {
+            want
+        }
+    }
+
+    /// Reflink the whole file, as set by [Config::reflink], returning
+    /// whether it was done. This is all-or-nothing: if the reflink
+    /// fails no data has been written, so a fallback copy
+    /// (e.g. preserving holes) starts from an untouched destination.
     pub fn try_reflink(&self) -> Result<bool> {
+        if self.discard {
+            return Ok(false);
+        }
         match self.config.reflink {
+            Reflink::Auto if self.config.basic_io => {
+                Ok(false)
+            }
+
+            Reflink::Always | Reflink::Auto if self.cloned => {
+                debug!("Cloned {:?} on creation", self.to);
+                self.verify_reflink()?;
+                Ok(true)
+            }
+
             Reflink::Always | Reflink::Auto => {
                 debug!("Attempting reflink from {:?}->{:?}", self.infd, self.outfd);
                 let worked = reflink(&self.infd, &self.outfd)?;
                 if worked {
                     debug!("Reflink {:?} succeeded", self.outfd);
+                    self.verify_reflink()?;
                     Ok(true)
                 } else if self.config.reflink == Reflink::Always {
                     Err(XcpError::ReflinkFailed(format!("{:?}->{:?}", self.infd, self.outfd)).into())
@@ -115,145 +745,2415 @@ impl CopyHandle {
         }
     }
 
-    pub fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
-        if self.try_reflink()? {
-            return Ok(self.metadata.len());
+    // See Config::verify_reflink.
+    fn verify_reflink(&self) -> Result<()> {
+        if !self.config.verify_reflink {
+            return Ok(());
+        }
+        let shared = match (map_extents(&self.infd)?, map_extents(&self.outfd)?) {
+            (Some(source), Some(dest)) => extents_shared(&source, &dest),
+            _ => {
+                warn!("No extent map available to verify reflink of {}", quote_path(&self.to));
+                false
+            }
+        };
+        if !shared {
+            return Err(XcpError::ReflinkUnverified(self.to.clone()).into());
+        }
+        debug!("Verified {:?} shares extents with its source", self.to);
+        Ok(())
+    }
+
+    pub fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<CopyStats> {
+        let len = self.metadata.len();
+        if self.discard {
+            let copied = self.copy_discard(updates)?;
+            updates.send(StatusUpdate::Completed { reflinked: false })?;
+            return Ok(CopyStats { len, copied, skipped: len - copied, extents: 1, ..CopyStats::default() });
+        }
+        if self.delta {
+            let stats = update_in_place(&self.infd, &self.outfd, DELTA_BLOCK_SIZE, &self.config)?;
+            info!("Updated {}: {} bytes rewritten, {} unchanged", quote_path(&self.to), stats.written, stats.unchanged);
+            updates.send(StatusUpdate::Copied(stats.written + stats.unchanged))?;
+            self.verify()?;
+            self.set_complete()?;
+            updates.send(StatusUpdate::Completed { reflinked: false })?;
+            return Ok(CopyStats { len, copied: stats.written, skipped: stats.unchanged, ..CopyStats::default() });
+        }
+        if len == 0 && self.metadata.is_file() {
+            // Nothing to copy or clone; the destination was truncated
+            // when opened, and is finalised on drop as usual.
+            debug!("Source {:?} is empty", self.from);
+            self.verify()?;
+            self.set_complete()?;
+            updates.send(StatusUpdate::Completed { reflinked: false })?;
+            return Ok(CopyStats { len, ..CopyStats::default() });
+        }
+        if !self.metadata.is_file() {
+            // Only from_fds() accepts other sources.
+            let stats = self.copy_stream(updates).map_err(|e| self.write_error(e))?;
+            debug!("Copied {:?}: {:?}", self.to, stats);
+            self.set_complete()?;
+            updates.send(StatusUpdate::Completed { reflinked: false })?;
+            return Ok(stats);
+        }
+        if self.resume_from == 0 && self.try_reflink()? {
+            self.verify()?;
+            self.set_complete()?;
+            updates.send(StatusUpdate::Completed { reflinked: true })?;
+            return Ok(CopyStats { len, reflinked: true, ..CopyStats::default() });
+        }
+        if self.resume_from > 0 {
+            updates.send(StatusUpdate::Copied(self.resume_from))?;
         }
-        let total = if probably_sparse(&self.infd)? {
-            self.copy_sparse(updates)?
+        let stats = if self.direct.is_some() {
+            self.copy_direct(updates)
+        } else if self.config.sparse != Sparse::Never && !self.config.basic_io && probably_sparse(&self.infd)? {
+            self.copy_sparse(updates)
         } else {
-            self.copy_bytes(self.metadata.len(), updates)?
+            self.seek_to(self.resume_from)
+                .and_then(|_| self.copy_bytes(len - self.resume_from, updates))
+                .map(|copied| CopyStats { len, copied, skipped: len - copied, extents: 1, ..CopyStats::default() })
         };
 
-        Ok(total)
+        let stats = stats.map_err(|e| self.write_error(e))?;
+        debug!("Copied {:?}: {:?}", self.to, stats);
+        self.verify()?;
+        self.set_complete()?;
+        updates.send(StatusUpdate::Completed { reflinked: false })?;
+        Ok(stats)
+    }
+
+    fn seek_to(&self, pos: u64) -> Result<()> {
+        if pos > 0 {
+            (&self.infd).seek(SeekFrom::Start(pos))?;
+            (&self.outfd).seek(SeekFrom::Start(pos))?;
+        }
+        Ok(())
+    }
+
+    /// Re-read the destination and compare it with the source, if
+    /// set by [Config::verify].
+    pub(crate) fn verify(&self) -> Result<()> {
+        if self.discard {
+            return Ok(());
+        }
+        verify_copy(&self.infd, self.written_path(), &self.config)
+    }
+
+    /// Record that the copy has completed successfully. An atomic
+    /// copy is finalised and renamed into place, after any backup, so
+    /// failures are reported before the copy is. A copy that finishes
+    /// after the watchdog has given up on it has already been
+    /// reported as timed out, so is not completed.
+    pub(crate) fn set_complete(&self) -> Result<()> {
+        if self.expired.load(Ordering::Relaxed) {
+            self.failed.store(true, Ordering::Relaxed);
+            return Err(timeout_error(&self.to, self.config.file_timeout.unwrap_or_default()));
+        }
+        match &self.temp {
+            Some(temp) if !self.failed.load(Ordering::Relaxed) => {
+                self.finalise_copy()?;
+                backup_dest(&self.to, &self.config)?;
+                debug!("Renaming {:?} to {:?}", temp.path, self.to);
+                temp.persist(&self.to)
+                    .map_err(|e| sticky_dir_error(e.into(), &self.to))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The path of the file being written.
+    fn written_path(&self) -> &Path {
+        self.temp.as_ref().map_or(&self.to, |t| &t.path)
+    }
+
+    /// The offset this copy resumes from; data before this is
+    /// already present in the destination.
+    pub fn resume_offset(&self) -> u64 {
+        self.resume_from
+    }
+
+    /// Classify an error writing the destination. If the destination
+    /// filesystem is out of space or quota the partial file is
+    /// removed.
+    pub(crate) fn write_error(&self, err: anyhow::Error) -> anyhow::Error {
+        self.failed.store(true, Ordering::Relaxed);
+        if self.discard || self.by_fd {
+            err
+        } else {
+            out_of_space(err, self.written_path())
+        }
     }
 
     fn finalise_copy(&self) -> Result<()> {
+        if self.discard {
+            // Never touch the permissions etc. of /dev/null.
+            return Ok(());
+        }
+        // Before the permissions, as chown clears setuid bits.
+        if self.config.preserve_ownership {
+            let uid = self.config.uid_map.map(self.metadata.uid());
+            let gid = self.config.gid_map.map(self.metadata.gid());
+            if let Err(e) = fchown(&self.outfd, Some(uid), Some(gid)) {
+                // As with cp, fall back to just the group; owners may
+                // set any group they are a member of.
+                if e.kind() == io::ErrorKind::PermissionDenied && fchown(&self.outfd, None, Some(gid)).is_ok() {
+                    warn!("Failed to set owner of {} to {}, only the group was preserved: {}", quote_path(&self.to), uid, e);
+                } else {
+                    warn!("Failed to set ownership of {} to {}:{}: {}", quote_path(&self.to), uid, gid, e);
+                }
+            }
+        }
+        // basic_io filesystems may not support xattrs.
+        if self.config.preserve_xattrs && !self.config.basic_io {
+            if let Err(e) = copy_xattrs(&self.infd, &self.outfd, |name| self.config.xattr_filter.allows(name)) {
+                warn!("Failed to copy xattrs to {}: {}", quote_path(&self.to), e);
+            }
+        }
+        if self.config.preserve_context && !self.config.basic_io {
+            if let Err(e) = copy_security_context(&self.infd, &self.outfd) {
+                warn!("Failed to preserve the security context of {}: {}", quote_path(&self.to), e);
+            }
+        }
+        if self.config.preserve_acl && !self.config.basic_io {
+            if let Err(e) = copy_acls(&self.infd, &self.outfd) {
+                warn!("Failed to copy ACLs to {}: {}", quote_path(&self.to), e);
+            }
+        }
         if !self.config.no_perms {
-            copy_permissions(&self.infd, &self.outfd)?;
+            let mut mode = self.config.perms.mode(self.metadata.permissions().mode(), false);
+            if let Some(chmod) = self.config.chmod {
+                mode = chmod.apply(mode);
+            }
+            self.outfd.set_permissions(Permissions::from_mode(mode))?;
         }
         if !self.config.no_timestamps {
-            copy_timestamps(&self.infd, &self.outfd)?;
+            // The times from before the copy, as reading the source
+            // may have updated its atime.
+            set_timestamps(&self.outfd, &self.metadata, self.config.timestamp_granularity)?;
         }
         if self.config.fsync {
             debug!("Syncing file {:?}", self.outfd);
             sync(&self.outfd)?;
         }
+        if self.config.drop_cache {
+            debug!("Dropping {:?} and {:?} from the page cache", self.infd, self.outfd);
+            drop_cache(&self.infd)?;
+            drop_cache(&self.outfd)?;
+        }
         Ok(())
     }
 }
 
-impl Drop for CopyHandle {
-    fn drop(&mut self) {
-        // FIXME: SHould we chcek for panicking() here?
-        if let Err(e) = self.finalise_copy() {
-            error!("Error during finalising copy operation {:?} -> {:?}: {}", self.infd, self.outfd, e);
-        }
+/// Take an exclusive advisory lock on the destination, waiting for
+/// any other holder. The lock is released when the file is closed.
+fn lock_dest(outfd: &File, to: &Path) -> Result<()> {
+    if flock(outfd, FlockOperation::NonBlockingLockExclusive).is_err() {
+        info!("Waiting for lock on {}", quote_path(to));
+        flock(outfd, FlockOperation::LockExclusive)?;
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub enum Operation {
-    Copy(PathBuf, PathBuf),
-    Link(PathBuf, PathBuf),
-    Special(PathBuf, PathBuf),
-}
-
-pub fn tree_walker(
-    sources: Vec<PathBuf>,
-    dest: &Path,
-    config: &Config,
-    work_tx: cbc::Sender<Operation>,
-    stats: Arc<dyn StatusUpdater>,
-) -> Result<()> {
-    debug!("Starting walk worker {:?}", thread::current().id());
+/// Maximum number of alternative names tried by [create_unique].
+const MAX_CONFLICT_RENAMES: u32 = 10_000;
 
-    for source in sources {
-        let sourcedir = source
-            .components()
-            .last()
-            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+// The nth alternative name for a conflicting destination,
+// e.g. `file (1).txt`.
+fn conflict_name(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+    let mut name = stem.to_owned();
+    name.push(format!(" ({})", n));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
 
-        let target_base = if dest.exists() && dest.is_dir() && !config.no_target_directory {
-            dest.join(sourcedir)
+// Create the first of `path` and its conflict names that doesn't
+// exist. Exclusive creation means concurrent copies never claim the
+// same name.
+fn create_unique(path: &Path) -> Result<(File, PathBuf)> {
+    for n in 0..=MAX_CONFLICT_RENAMES {
+        let candidate = if n == 0 {
+            path.to_path_buf()
         } else {
-            dest.to_path_buf()
+            conflict_name(path, n)
         };
-        debug!("Target base is {:?}", target_base);
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(fd) => return Ok((fd, candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(out_of_inodes(e.into(), &candidate)),
+        }
+    }
+    Err(XcpError::DestinationExists("No free name found for destination", path.to_path_buf()).into())
+}
 
-        let gitignore = parse_ignore(&source, config)?;
+/// Open a source file, identifying failures caused by a missing
+/// fscrypt key or permissions.
+fn open_source(from: &Path) -> Result<File> {
+    match File::open(from) {
+        Err(e) if is_missing_key(&e) && parent_encrypted(from) => {
+            Err(XcpError::EncryptedSource(from.to_path_buf()).into())
+        }
+        r => r.map_err(|e| permission_denied(e.into(), from)),
+    }
+}
 
-        for entry in WalkDir::new(&source)
-            .into_iter()
-            .filter_entry(|e| ignore_filter(e, &gitignore))
-        {
-            debug!("Got tree entry {:?}", entry);
-            let epath = entry?.into_path();
-            let from = if config.dereference {
-                let cpath = canonicalize(&epath)?;
-                debug!("Dereferencing {:?} into {:?}", epath, cpath);
-                cpath
-            } else {
-                epath.clone()
-            };
-            let meta = from.symlink_metadata()?;
-            let path = epath.strip_prefix(&source)?;
-            let target = if !empty_path(path) {
-                target_base.join(path)
-            } else {
-                target_base.clone()
-            };
+fn parent_encrypted(path: &Path) -> bool {
+    let parent = match path.parent() {
+        Some(p) if !empty_path(p) => p,
+        _ => Path::new("."),
+    };
+    match File::open(parent) {
+        Ok(fd) => is_encrypted(&fd).unwrap_or(false),
+        Err(_) => false,
+    }
+}
 
-            if config.no_clobber && target.exists() {
-                let msg = "Destination file exists and --no-clobber is set.";
-                stats.send(StatusUpdate::Error(
-                    XcpError::DestinationExists(msg, target)))?;
-                return Err(XcpError::EarlyShutdown(msg).into());
-            }
+/// A path naming a descriptor, for messages about handles created
+/// with [CopyHandle::from_fds].
+fn fd_path(fd: &File) -> PathBuf {
+    PathBuf::from(format!("/dev/fd/{}", fd.as_raw_fd()))
+}
 
-            let ft = FileType::from(meta.file_type());
-            match ft {
-                FileType::File => {
-                    debug!("Send copy operation {:?} to {:?}", from, target);
-                    stats.send(StatusUpdate::Size(meta.len()))?;
-                    work_tx.send(Operation::Copy(from, target))?;
-                }
+/// Returns true if a copy error means the file should be skipped,
+/// and reported as [StatusUpdate::Skipped] rather than an error: the
+/// source is encrypted with its key locked and the configuration says
+/// to skip such files, or an overwrite was declined at the
+/// [Config::interactive] prompt.
+pub(crate) fn skip_error(err: &anyhow::Error, config: &Config) -> bool {
+    match err.downcast_ref::<XcpError>() {
+        Some(XcpError::EncryptedSource(path)) if config.encrypted == Encrypted::Skip => {
+            warn!("Skipping encrypted file {}; key is not available", quote_path(path));
+            true
+        }
+        Some(XcpError::OverwriteDeclined(path)) => {
+            info!("Skipping {}; overwrite declined", quote_path(path));
+            true
+        }
+        _ => false,
+    }
+}
 
-                FileType::Symlink => {
-                    let lfile = read_link(from)?;
-                    debug!("Send symlink operation {:?} to {:?}", lfile, target);
-                    work_tx.send(Operation::Link(lfile, target))?;
-                }
+/// Serialises the [confirm_overwrite] prompts of parallel workers.
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
 
-                FileType::Dir => {
-                    // Create dir tree immediately as we can't
-                    // guarantee a worker will action the creation
-                    // before a subsequent copy operation requires it.
-                    debug!("Creating target directory {:?}", target);
-                    if let Err(err) = create_dir_all(&target) {
-                        let msg = format!("Error creating target directory: {}", err);
-                        error!("{msg}");
-                        return Err(XcpError::CopyError(msg).into())
+/// Ask on the terminal whether to overwrite `to`; see
+/// [Config::interactive].
+fn confirm_overwrite(to: &Path) -> Result<bool> {
+    let _prompt = PROMPT_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    eprint!("overwrite {}? [y/N] ", quote_path(to));
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+/// Whether a prompt answer is yes; anything else, including no answer,
+/// is no.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Find the offset from which an interrupted copy of `infd` to
+/// `outfd` can safely be resumed.
+///
+/// The destination length is set up-front, so its length says
+/// nothing about progress. Instead the data extents of both files are
+/// compared; data up to the first source extent not fully present in
+/// the destination is assumed valid, less one block in case the last
+/// write was incomplete. This assumes the destination was written
+/// sequentially, and that the source hasn't changed. If extents are
+/// unavailable a shorter destination, e.g. from an interrupted copy by
+/// another tool, is resumed from its end; otherwise returns 0 (start
+/// again).
+fn resume_point(infd: &File, outfd: &File) -> Result<u64> {
+    let len = infd.metadata()?.len();
+    let outmeta = outfd.metadata()?;
+    if outmeta.len() > len {
+        return Ok(0);
+    }
+    let (src, dst) = match (map_extents_synced(infd)?, map_extents_synced(outfd)?) {
+        (Some(src), Some(dst)) => (src, dst),
+        _ if outmeta.len() < len => return Ok(outmeta.len()),
+        _ => return Ok(0),
+    };
+    // Preallocated but unwritten destination blocks are not data.
+    let dst = dst.into_iter()
+        .filter(|e| !e.unwritten)
+        .collect::<Vec<Extent>>();
+    let blksize = outmeta.blksize();
+
+    let mut prev: Option<(u64, u64)> = None;
+    for ext in src.iter().filter(|e| !e.unwritten) {
+        let (start, end) = (ext.start, cmp::min(ext.end, len));
+        if start >= end {
+            continue;
+        }
+        let covered = covered_until(&dst, start);
+        if covered >= end {
+            prev = Some((start, end));
+            continue;
+        }
+        let resume = if covered > start {
+            covered.saturating_sub(blksize).max(start)
+        } else {
+            match prev {
+                Some((pstart, pend)) => pend.saturating_sub(blksize).max(pstart),
+                None => start,
+            }
+        };
+        return Ok(resume);
+    }
+
+    Ok(len)
+}
+
+/// The end of the contiguous run of extents covering `pos`, or `pos`
+/// if it isn't covered. Extents must be sorted.
+fn covered_until(extents: &[Extent], pos: u64) -> u64 {
+    let mut end = pos;
+    for e in extents {
+        if e.start > end {
+            break;
+        }
+        end = cmp::max(end, e.end);
+    }
+    end
+}
+
+/// Remove the first `n` components of `path`, or `None` if nothing
+/// would remain.
+fn strip_components(path: &Path, n: usize) -> Option<PathBuf> {
+    let stripped = path.components()
+        .skip(n)
+        .collect::<PathBuf>();
+    if empty_path(&stripped) {
+        None
+    } else {
+        Some(stripped)
+    }
+}
+
+/// Whether every extent of `dest` overlaps physically with an extent
+/// of `source`, i.e. their data is shared.
+fn extents_shared(source: &[Extent], dest: &[Extent]) -> bool {
+    let overlaps = |a: &Extent, b: &Extent| {
+        a.physical < b.physical + (b.end - b.start) && b.physical < a.physical + (a.end - a.start)
+    };
+    dest.iter().all(|d| source.iter().any(|s| overlaps(s, d)))
+}
+
+/// Number of times a copy is restarted after a stale file handle.
+const STALE_RETRIES: u32 = 3;
+
+/// Copy `from` to `to`, reopening both and restarting the copy from
+/// the beginning if either file handle goes stale (`ESTALE`). This
+/// happens on NFS when the file is renamed or recreated on the server
+/// during a long copy, after which the open descriptors can never
+/// succeed. Only the parfile driver and
+/// [copy_files()](crate::drivers::copy_files) restart copies.
+///
+/// With a [Config::file_timeout] the copy runs on a separate thread,
+/// and is abandoned if it doesn't finish in time; see
+/// [abandon_after].
+pub(crate) fn copy_reopening(from: &Path, to: &Path, config: &Arc<Config>, updates: &Arc<dyn StatusUpdater>) -> Result<CopyStats> {
+    let timeout = match config.file_timeout {
+        Some(timeout) => timeout,
+        None => return copy_restarting(from, to, config, None, |hdl| hdl.copy_file(updates)),
+    };
+    // Watch the whole copy, including opening the files, as any
+    // syscall may hang on unresponsive storage. An abandoned copy
+    // must not keep the caller's updater, e.g. a channel, open.
+    let expired = Arc::new(AtomicBool::new(false));
+    let detachable = Arc::new(Detachable::new(updates.clone()));
+    let copy = {
+        let (from, to, config, expired) = (from.to_path_buf(), to.to_path_buf(), config.clone(), expired.clone());
+        let updates: Arc<dyn StatusUpdater> = detachable.clone();
+        move || copy_restarting(&from, &to, &config, Some(&expired), |hdl| hdl.copy_file(&updates))
+    };
+    let result = abandon_after(Instant::now() + timeout, &expired, to, timeout, copy);
+    if expired.load(Ordering::Relaxed) {
+        detachable.detach();
+    }
+    result
+}
+
+/// Open `from` and `to` and copy them with `copy`, restarting on a
+/// stale handle. Once the destination has been opened, restarts write
+/// to the same file; see [restart_config]. The handles share the
+/// watchdog's `expired` flag, if any; see [abandon_after].
+fn copy_restarting<F>(from: &Path, to: &Path, config: &Arc<Config>, expired: Option<&Arc<AtomicBool>>, mut copy: F) -> Result<CopyStats>
+where
+    F: FnMut(&CopyHandle) -> Result<CopyStats>,
+{
+    let mut opened: Option<(PathBuf, Arc<Config>)> = None;
+    retry_stale(|| {
+        let (dest, config) = opened.clone()
+            .unwrap_or_else(|| (to.to_path_buf(), config.clone()));
+        let mut handle = CopyHandle::new(from, &dest, &config)?;
+        if let Some(expired) = expired {
+            handle.expired = expired.clone();
+        }
+        if opened.is_none() {
+            opened = Some((handle.to.clone(), restart_config(&config, handle.temp.is_some())));
+        }
+        copy(&handle)
+    })
+}
+
+/// The options to restart a copy with after a stale handle. The
+/// destination has already been chosen and confirmed, and may have
+/// been created by the first attempt, so the options that decide
+/// whether and where to write are disabled. Any backup has already
+/// been made, unless it is deferred to the end of an atomic copy.
+fn restart_config(config: &Config, deferred_backup: bool) -> Arc<Config> {
+    Arc::new(Config {
+        backup: if deferred_backup { config.backup } else { Backup::None },
+        overwrite: Overwrite::Always,
+        interactive: false,
+        no_clobber: false,
+        ignore_existing: false,
+        ..config.clone()
+    })
+}
+
+fn retry_stale<F>(mut copy: F) -> Result<CopyStats>
+where
+    F: FnMut() -> Result<CopyStats>,
+{
+    let mut result = copy();
+    let mut retries = 0;
+    while retries < STALE_RETRIES {
+        match result {
+            Err(ref e) if os_error(e) == Some(Errno::STALE) => {
+                retries += 1;
+                warn!("Stale file handle; reopening and restarting copy (retry {}/{})", retries, STALE_RETRIES);
+                result = copy();
+            }
+            _ => break,
+        }
+    }
+    result
+}
+
+/// The error for a copy to `to` exceeding [Config::file_timeout].
+fn timeout_error(to: &Path, timeout: Duration) -> anyhow::Error {
+    XcpError::CopyTimeout(format!("{} after {:?}", quote_path(to), timeout)).into()
+}
+
+/// Run `op`, part of a copy to `to`, on a separate thread and wait
+/// for it until `deadline`. If it hasn't finished by then `expired`
+/// is set, so the copy fails at its next check once `op` returns, and
+/// a timeout error is returned without waiting further; a syscall
+/// stuck on hung storage cannot be interrupted, so the thread is left
+/// blocked until it returns.
+fn abandon_after<T, F>(deadline: Instant, expired: &AtomicBool, to: &Path, timeout: Duration, op: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let (tx, rx) = cbc::bounded(1);
+    thread::spawn(move || {
+        // The receiver is gone if the copy was abandoned.
+        let _ = tx.send(op());
+    });
+    match rx.recv_deadline(deadline) {
+        Ok(result) => result,
+        Err(cbc::RecvTimeoutError::Timeout) => {
+            expired.store(true, Ordering::Relaxed);
+            warn!("Copy to {} timed out; abandoning it", quote_path(to));
+            Err(timeout_error(to, timeout))
+        }
+        Err(cbc::RecvTimeoutError::Disconnected) => {
+            Err(XcpError::CopyError(format!("Copy to {} failed unexpectedly", quote_path(to))).into())
+        }
+    }
+}
+
+/// Forwards updates to another [StatusUpdater] until detached, after
+/// which they are dropped.
+struct Detachable(Mutex<Option<Arc<dyn StatusUpdater>>>);
+
+impl Detachable {
+    fn new(inner: Arc<dyn StatusUpdater>) -> Detachable {
+        Detachable(Mutex::new(Some(inner)))
+    }
+
+    fn detach(&self) {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).take();
+    }
+
+    fn inner(&self) -> Option<Arc<dyn StatusUpdater>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl StatusUpdater for Detachable {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        match self.inner() {
+            Some(inner) => inner.send(update),
+            None => Ok(()),
+        }
+    }
+
+    fn tick(&self) -> Result<()> {
+        match self.inner() {
+            Some(inner) => inner.tick(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The OS error number underlying an error, if any.
+fn os_error(err: &anyhow::Error) -> Option<Errno> {
+    if let Some(e) = err.downcast_ref::<io::Error>() {
+        return e.raw_os_error().map(Errno::from_raw_os_error);
+    }
+    match err.downcast_ref::<libfs::Error>() {
+        Some(libfs::Error::IOError(e)) => e.raw_os_error().map(Errno::from_raw_os_error),
+        Some(libfs::Error::OSError(errno)) => Some(*errno),
+        _ => None,
+    }
+}
+
+/// Remove the partial destination `to` if the error is due to
+/// running out of space (`ENOSPC`) or quota (`EDQUOT`). These are
+/// converted to [XcpError::QuotaExceeded], [XcpError::InodesExhausted]
+/// or [XcpError::NoSpace].
+fn out_of_space(err: anyhow::Error, to: &Path) -> anyhow::Error {
+    let errno = os_error(&err);
+    if errno != Some(Errno::DQUOT) && errno != Some(Errno::NOSPC) {
+        return err;
+    }
+
+    warn!("Out of space writing {}; removing partial file", quote_path(to));
+    if let Err(e) = fs::remove_file(to) {
+        debug!("Failed to remove partial file {:?}: {}", to, e);
+    }
+    if errno == Some(Errno::DQUOT) {
+        return XcpError::QuotaExceeded { path: to.to_path_buf() }.into();
+    }
+    let err = out_of_inodes(err, to);
+    if let Some(XcpError::InodesExhausted { .. }) = err.downcast_ref::<XcpError>() {
+        err
+    } else {
+        XcpError::NoSpace { path: to.to_path_buf() }.into()
+    }
+}
+
+/// Files in a directory with the sticky bit set (e.g. `/tmp`) can
+/// only be renamed or removed by their owner or the directory owner,
+/// and `fs.protected_regular` may also prevent opening them for
+/// writing. Convert the resulting permission errors for an existing
+/// `path` to [XcpError::StickyDirectory].
+pub(crate) fn sticky_dir_error(err: anyhow::Error, path: &Path) -> anyhow::Error {
+    if !matches!(os_error(&err), Some(Errno::PERM) | Some(Errno::ACCESS)) {
+        return err;
+    }
+    let dir = match path.parent() {
+        Some(p) if !empty_path(p) => p,
+        _ => Path::new("."),
+    };
+    let (dmeta, fmeta) = match (dir.metadata(), path.symlink_metadata()) {
+        (Ok(d), Ok(f)) => (d, f),
+        _ => return err,
+    };
+    let euid = geteuid().as_raw();
+    if dmeta.mode() & STICKY_BIT != 0 && fmeta.uid() != euid && dmeta.uid() != euid {
+        debug!("Permission error on {:?} in sticky directory: {}", path, err);
+        XcpError::StickyDirectory(path.to_path_buf()).into()
+    } else {
+        err
+    }
+}
+
+/// Convert `EACCES` and `EPERM` from opening or creating `path` to
+/// [XcpError::PermissionDenied], so callers can identify them; other
+/// errors are returned unchanged.
+pub(crate) fn permission_denied(err: anyhow::Error, path: &Path) -> anyhow::Error {
+    if matches!(os_error(&err), Some(Errno::PERM) | Some(Errno::ACCESS)) {
+        XcpError::PermissionDenied(path.to_path_buf()).into()
+    } else {
+        err
+    }
+}
+
+/// `ENOSPC` is returned for both block and inode exhaustion; convert
+/// the latter to [XcpError::InodesExhausted].
+fn out_of_inodes(err: anyhow::Error, to: &Path) -> anyhow::Error {
+    if os_error(&err) != Some(Errno::NOSPC) {
+        return err;
+    }
+    let dir = match to.parent() {
+        Some(p) if !empty_path(p) => p,
+        _ => Path::new("."),
+    };
+    match free_inodes(dir) {
+        Ok(Some(0)) => XcpError::InodesExhausted { path: to.to_path_buf() }.into(),
+        _ => err,
+    }
+}
+
+/// An error if a filesystem with `available` free inodes can't hold
+/// `needed` new entries.
+fn insufficient_inodes(needed: u64, available: Option<u64>) -> Option<XcpError> {
+    match available {
+        Some(available) if available < needed => {
+            Some(XcpError::InsufficientInodes { needed, available })
+        }
+        _ => None,
+    }
+}
+
+/// Count the entries that copying `sources` would create.
+fn count_entries(sources: &[PathBuf], config: &Config) -> Result<u64> {
+    let mut count = 0;
+    for source in sources {
+        let gitignore = parse_ignore(source, config)?;
+        let excludes = parse_excludes(source, config)?;
+        count += WalkDir::new(source)
+            .same_file_system(config.one_file_system)
+            .into_iter()
+            .filter_entry(|e| ignore_filter(e, &gitignore) && exclude_filter(e, &excludes))
+            .count() as u64;
+    }
+    Ok(count)
+}
+
+// Where reflinks are by path, try to create `to` as a clone of
+// `from`. This needs the destination to not exist, and isn't done
+// when locking as the lock must be held before the data is written.
+fn clone_new(from: &Path, to: &Path, config: &Config) -> Result<bool> {
+    if !CLONE_BY_PATH
+        || config.lock
+        || config.reflink == Reflink::Never
+        || (config.reflink == Reflink::Auto && config.basic_io)
+        || to.symlink_metadata().is_ok()
+    {
+        return Ok(false);
+    }
+    debug!("Attempting clone of {:?} to {:?}", from, to);
+    Ok(clone_file(from, to)?)
+}
+
+// Open second descriptors on the source and destination for direct
+// I/O, if both filesystems support it.
+fn open_direct_pair(from: &Path, to: &Path) -> Result<Option<(File, File)>> {
+    match (open_direct(from)?, open_direct_write(to)?) {
+        (Some(infd), Some(outfd)) => Ok(Some((infd, outfd))),
+        _ => {
+            debug!("Direct I/O not supported for {:?} -> {:?}", from, to);
+            Ok(None)
+        }
+    }
+}
+
+/// The size of a direct I/O buffer for `want` bytes; capped, and
+/// rounded down to the alignment.
+fn direct_buffer_size(want: u64, config: &Config) -> u64 {
+    let align = DIRECT_IO_ALIGN as u64;
+    let size = membudget::buffer_size(want.min(MAX_DIRECT_BUFFER), config);
+    cmp::max(size - size % align, align)
+}
+
+fn allocate_dest(infd: &File, outfd: &File, len: u64, config: &Config) -> Result<()> {
+    let reserve = match config.preallocate {
+        Preallocate::Never => false,
+        Preallocate::Always | Preallocate::KeepSize => true,
+        // Reserving blocks would fill in the holes of the copy.
+        Preallocate::Auto => !probably_sparse(infd)?,
+    };
+    // As would reserving blocks that may be left as holes.
+    if len > 0 && reserve && !config.basic_io && config.sparse != Sparse::Always {
+        let keep_size = config.preallocate == Preallocate::KeepSize;
+        if !preallocate(outfd, len, keep_size)? {
+            debug!("Preallocation not supported for {:?}", outfd);
+        }
+    }
+    // Always set the final length; this is a no-op if the
+    // preallocation already did so.
+    allocate_file(outfd, len)?;
+    Ok(())
+}
+
+// Filesystems known to often lack the accelerated copy operations.
+fn limited_fs(fstype: &str) -> bool {
+    fstype == "fuse"
+}
+
+/// Adapt the configuration to the destination filesystem. This
+/// switches to [Config::basic_io] if the filesystem is likely to lack
+/// the accelerated operations, rather than discovering each via a
+/// failed syscall per file, warning once if so. It also sets
+/// [Config::timestamp_granularity] for filesystems with coarse
+/// timestamps, and applies the [Config::optimize] profile for the
+/// first source and the destination.
+pub(crate) fn dest_config(config: &Arc<Config>, sources: &[PathBuf], dest: &Path) -> Arc<Config> {
+    if is_devnull(dest) {
+        return config.clone();
+    }
+    // The destination may not exist yet.
+    let dest_fs = dest.ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+
+    let mut adapted = None;
+    if !config.basic_io {
+        if let Ok(Some(fstype)) = filesystem_type(dest_fs) {
+            if limited_fs(&fstype) {
+                warn!("Destination {} is on a {} filesystem; disabling accelerated copies", quote_path(dest), fstype);
+                adapted.get_or_insert_with(|| (**config).clone()).basic_io = true;
+            }
+        }
+    }
+    if config.timestamp_granularity.is_none() && !config.no_timestamps {
+        if let Ok(Some(granularity)) = timestamp_granularity(dest_fs) {
+            debug!("Rounding timestamps on {:?} to {:?}", dest, granularity);
+            adapted.get_or_insert_with(|| (**config).clone()).timestamp_granularity = Some(granularity);
+        }
+    }
+    if config.optimize == Optimize::Auto {
+        let source = sources.first().map(|p| p.as_path()).unwrap_or(Path::new("."));
+        let same_fs = match (source.metadata(), dest_fs.metadata()) {
+            (Ok(s), Ok(d)) => s.dev() == d.dev(),
+            _ => false,
+        };
+        let source_type = filesystem_type(source).ok().flatten();
+        let dest_type = filesystem_type(dest_fs).ok().flatten();
+        let profile = select_profile(source_type.as_deref(), dest_type.as_deref(), same_fs);
+        info!("Using the {} optimisation profile for {:?} -> {:?}", profile.name, source_type, dest_type);
+        let current = adapted.as_ref().unwrap_or(&**config);
+        adapted = Some(profile.apply(current));
+    }
+    adapted.map(Arc::new).unwrap_or_else(|| config.clone())
+}
+
+/// Tracks bytes written to decide when a periodic sync is due.
+#[derive(Debug)]
+struct SyncCadence {
+    every: Option<u64>,
+    written: AtomicU64,
+}
+
+impl SyncCadence {
+    fn new(every: Option<u64>) -> Self {
+        SyncCadence {
+            every: every.filter(|n| *n > 0),
+            written: AtomicU64::new(0),
+        }
+    }
+
+    /// Record bytes written; returns true when this write crosses a
+    /// sync boundary.
+    fn record(&self, bytes: u64) -> bool {
+        match self.every {
+            Some(every) => {
+                let prev = self.written.fetch_add(bytes, Ordering::Relaxed);
+                (prev + bytes) / every > prev / every
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for CopyHandle {
+    fn drop(&mut self) {
+        // Atomic copies are finalised by set_complete(); an incomplete
+        // temp file is removed when dropped.
+        if self.temp.is_some() {
+            return;
+        }
+        // FIXME: SHould we chcek for panicking() here?
+        if let Err(e) = self.finalise_copy() {
+            error!("Error during finalising copy operation {:?} -> {:?}: {}", self.infd, self.outfd, e);
+        }
+    }
+}
+
+/// The temporary file written by an atomic copy; see
+/// [Config::atomic]. It is removed when dropped unless it has been
+/// renamed into place.
+#[derive(Debug)]
+struct TempDest {
+    path: PathBuf,
+    persisted: AtomicBool,
+}
+
+impl TempDest {
+    fn persist(&self, to: &Path) -> io::Result<()> {
+        fs::rename(&self.path, to)?;
+        self.persisted.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for TempDest {
+    fn drop(&mut self) {
+        if *self.persisted.get_mut() {
+            return;
+        }
+        debug!("Removing incomplete copy {:?}", self.path);
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to remove temporary file {}: {}", quote_path(&self.path), e);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Rename an existing `to` to its backup name, if set by
+/// [Config::backup].
+fn backup_dest(to: &Path, config: &Config) -> Result<()> {
+    if needs_backup(to, config)? {
+        let backup = get_backup_path(to, config)?;
+        info!("Backup: Rename {} to {}", quote_path(to), quote_path(&backup));
+        fs::rename(to, backup)
+            .map_err(|e| sticky_dir_error(e.into(), to))?;
+    }
+    Ok(())
+}
+
+/// Create a temporary file to write `to` atomically, if set by
+/// [Config::atomic]. It is created in the same directory, so it can
+/// be renamed over the destination, unless the destination is on a
+/// different filesystem to the directory.
+fn create_temp(to: &Path, config: &Config) -> Result<Option<(File, TempDest)>> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    if !config.atomic {
+        return Ok(None);
+    }
+    let dir = match to.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if let Ok(meta) = to.symlink_metadata() {
+        if meta.is_symlink() {
+            // Renaming would replace the link rather than its target.
+            debug!("Destination {:?} is a symlink, writing in place", to);
+            return Ok(None);
+        }
+        if meta.dev() != dir.metadata()?.dev() {
+            warn!("{} is on a different filesystem to its directory; copying non-atomically", quote_path(to));
+            return Ok(None);
+        }
+    }
+
+    for _ in 0..MAX_TEMP_NAMES {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!(".xcp-tmp-{}-{}", process::id(), n));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(fd) => return Ok(Some((fd, TempDest { path, persisted: AtomicBool::new(false) }))),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(sticky_dir_error(out_of_inodes(e.into(), &path), to)),
+        }
+    }
+    Err(XcpError::DestinationExists("No free temporary name found for destination", to.to_path_buf()).into())
+}
+
+/// Recreate the FIFO or device node `from` at `to`, replacing any
+/// existing destination. Creating device nodes needs `CAP_MKNOD`;
+/// without it they are skipped with a warning.
+pub(crate) fn copy_special(from: &Path, to: &Path, config: &Config, updates: &Arc<dyn StatusUpdater>) -> Result<()> {
+    if to.exists() {
+        if config.no_clobber {
+            return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to.to_path_buf()).into());
+        }
+        fs::remove_file(to)
+            .map_err(|e| sticky_dir_error(e.into(), to))?;
+    }
+    match copy_node(from, to) {
+        Err(libfs::Error::OSError(errno)) if errno == Errno::PERM => {
+            warn!("Not permitted to create device node {}; skipping", quote_path(to));
+            updates.send(StatusUpdate::Skipped(from.to_path_buf()))?;
+        }
+        r => r?,
+    }
+    Ok(())
+}
+
+/// A hard link to create once the file it links to has been copied;
+/// see [HardLinks::Preserve].
+#[derive(Debug)]
+pub struct HardLink {
+    /// The destination of the first copy of the file.
+    pub original: PathBuf,
+    pub link: PathBuf,
+}
+
+/// Work that [tree_walker] defers until all copies are complete; see
+/// [finish_tree].
+#[derive(Debug, Default)]
+pub struct Deferred {
+    pub hard_links: Vec<HardLink>,
+    /// The sources, created directories and metadata of their
+    /// sources, in walk order, i.e. parents before their contents.
+    pub dirs: Vec<(PathBuf, PathBuf, Metadata)>,
+}
+
+#[derive(Debug)]
+pub enum Operation {
+    Copy(PathBuf, PathBuf),
+    Link(PathBuf, PathBuf),
+    Special(PathBuf, PathBuf),
+}
+
+pub fn tree_walker(
+    sources: Vec<PathBuf>,
+    dest: &Path,
+    config: &Config,
+    work_tx: cbc::Sender<Operation>,
+    stats: Arc<dyn StatusUpdater>,
+) -> Result<Deferred> {
+    debug!("Starting walk worker {:?}", thread::current().id());
+
+    // Destinations of multiply-linked files, by source (dev, inode).
+    let mut linked = HashMap::new();
+    let mut deferred = Deferred::default();
+
+    // When copying to /dev/null every file is read into it, and
+    // there is no tree to create.
+    let discard = is_devnull(dest);
+
+    // Files held back until the walk is complete, to choose the
+    // newest; see Config::newest.
+    let mut candidates = Vec::new();
+
+    // Queue the copy of a regular file, or defer it as a link to an
+    // earlier copy with HardLinks::Preserve.
+    let queue_file = |from: PathBuf, target: PathBuf, meta: &Metadata, linked: &mut HashMap<(u64, u64), PathBuf>, deferred: &mut Deferred| -> Result<()> {
+        if meta.nlink() > 1 && config.hard_links == HardLinks::Warn {
+            warn!("{} has {} hard links; copying as an independent file", quote_path(&from), meta.nlink());
+        }
+        if meta.nlink() > 1 && config.hard_links == HardLinks::Preserve && !discard {
+            if let Some(original) = linked.get(&(meta.dev(), meta.ino())) {
+                debug!("Deferring hard link {:?} to {:?}", target, original);
+                deferred.hard_links.push(HardLink { original: PathBuf::clone(original), link: target });
+                return Ok(());
+            }
+            linked.insert((meta.dev(), meta.ino()), target.clone());
+        }
+        debug!("Send copy operation {:?} to {:?}", from, target);
+        stats.send(StatusUpdate::Size(meta.len()))?;
+        work_tx.send(Operation::Copy(from, target))?;
+        Ok(())
+    };
+
+    if config.check_inodes && !discard {
+        let needed = count_entries(&sources, config)?;
+        // The destination may not exist yet.
+        let dest_fs = dest.ancestors()
+            .find(|p| !empty_path(p) && p.exists())
+            .unwrap_or(Path::new("."));
+        debug!("Checking for {} free inodes on {:?}", needed, dest_fs);
+        if let Some(err) = insufficient_inodes(needed, free_inodes(dest_fs)?) {
+            stats.send(StatusUpdate::Error(err))?;
+            return Err(XcpError::EarlyShutdown("Insufficient free inodes on destination.").into());
+        }
+    }
+
+    for source in sources {
+        let target_base = target_path(&source, dest, config)?;
+        debug!("Target base is {:?}", target_base);
+
+        let gitignore = parse_ignore(&source, config)?;
+        let excludes = parse_excludes(&source, config)?;
+
+        // With one_file_system, mount points are still returned, and
+        // so created empty, but not descended into.
+        let mut walker = WalkDir::new(&source)
+            .same_file_system(config.one_file_system);
+        if config.reproducible {
+            walker = walker.sort_by_file_name();
+        }
+        let mut entries = walker
+            .into_iter()
+            .filter_entry(|e| ignore_filter(e, &gitignore) && exclude_filter(e, &excludes));
+        while let Some(entry) = entries.next() {
+            debug!("Got tree entry {:?}", entry);
+            let epath = match entry {
+                Ok(entry) => entry.into_path(),
+                Err(e) => {
+                    entry_error(e.into(), config, &stats)?;
+                    continue;
+                }
+            };
+            let from = if config.symlinks == Symlinks::Follow {
+                let cpath = match canonicalize(&epath) {
+                    Ok(cpath) => cpath,
+                    Err(e) => {
+                        entry_error(XcpError::CopyError(format!("Failed to dereference {}: {}", quote_path(&epath), e)).into(), config, &stats)?;
+                        continue;
+                    }
+                };
+                debug!("Dereferencing {:?} into {:?}", epath, cpath);
+                cpath
+            } else {
+                epath.clone()
+            };
+            let meta = match from.symlink_metadata() {
+                Ok(meta) => meta,
+                Err(e) => {
+                    entry_error(XcpError::CopyError(format!("Failed to read metadata of {}: {}", quote_path(&from), e)).into(), config, &stats)?;
+                    continue;
+                }
+            };
+            let path = epath.strip_prefix(&source)?;
+            let target = if discard {
+                dest.to_path_buf()
+            } else if config.strip_components > 0 {
+                // Strip from the path as it would appear under dest.
+                let rel = target_base.strip_prefix(dest)
+                    .unwrap_or(Path::new(""))
+                    .join(path);
+                match strip_components(&rel, config.strip_components) {
+                    Some(stripped) => dest.join(stripped),
+                    None => {
+                        if meta.is_dir() {
+                            debug!("Stripped directory {:?} entirely", rel);
+                        } else {
+                            warn!("Skipping {}; fewer than {} path components", quote_path(&rel), config.strip_components + 1);
+                            stats.send(StatusUpdate::Skipped(from))?;
+                        }
+                        continue;
+                    }
+                }
+            } else if !empty_path(path) {
+                target_base.join(path)
+            } else {
+                target_base.clone()
+            };
+
+            if meta.is_file() && !size_allowed(meta.len(), config) {
+                info!("Skipping {}; size {} is outside the limits", quote_path(&from), meta.len());
+                stats.send(StatusUpdate::Skipped(from))?;
+                continue;
+            }
+
+            if config.ignore_existing && !discard && !meta.is_dir() && target.symlink_metadata().is_ok() {
+                debug!("Skipping {:?}; already exists at destination", from);
+                stats.send(StatusUpdate::Skipped(from))?;
+                continue;
+            }
+
+            if !discard && meta.is_file() && !may_overwrite(&meta, &target, config.overwrite)? {
+                info!("Skipping {}; destination exists ({:?})", quote_path(&from), config.overwrite);
+                stats.send(StatusUpdate::Skipped(from))?;
+                continue;
+            }
+
+            if let Some(expected) = config.expect_hash {
+                if !discard && meta.is_file() && target.is_file() && hash_file(&target)? == expected {
+                    info!("Skipping {}; destination matches expected hash", quote_path(&from));
+                    stats.send(StatusUpdate::Skipped(from))?;
+                    continue;
+                }
+            }
+
+            if config.no_clobber && !discard && target.exists() {
+                let msg = "Destination file exists and --no-clobber is set.";
+                stats.send(StatusUpdate::Error(
+                    XcpError::DestinationExists(msg, target)))?;
+                return Err(XcpError::EarlyShutdown(msg).into());
+            }
+
+            let ft = FileType::from(meta.file_type());
+            match ft {
+                FileType::File if !discard && target.exists() && is_same_file(&from, &target)? => {
+                    match config.same_file {
+                        SameFile::Error => {
+                            stats.send(StatusUpdate::Error(XcpError::SameFile(target)))?;
+                            return Err(XcpError::EarlyShutdown("Source and destination are the same file.").into());
+                        }
+                        SameFile::Skip => {
+                            info!("Skipping {}; same file as {}", quote_path(&from), quote_path(&target));
+                            stats.send(StatusUpdate::Skipped(from))?;
+                        }
                     }
                 }
 
-                FileType::Socket | FileType::Char | FileType::Fifo => {
+                FileType::File if meta.nlink() > 1 && config.hard_links == HardLinks::Skip => {
+                    info!("Skipping {}; it has {} hard links", quote_path(&from), meta.nlink());
+                    stats.send(StatusUpdate::Skipped(from))?;
+                }
+
+                FileType::File if config.newest.is_some() => {
+                    candidates.push((meta.modified()?, from, target, meta));
+                }
+
+                FileType::File => {
+                    queue_file(from, target, &meta, &mut linked, &mut deferred)?;
+                }
+
+                _ if discard => {
+                    debug!("Discarding: skip non-file {:?}", from);
+                }
+
+                FileType::Symlink
+                    | FileType::Socket
+                    | FileType::Char
+                    | FileType::Fifo
+                    | FileType::Block
+                    | FileType::Other if config.regular_only =>
+                {
+                    debug!("Skipping non-regular file {:?} ({:?})", from, ft);
+                    stats.send(StatusUpdate::Skipped(from))?;
+                }
+
+                FileType::Symlink if config.symlinks == Symlinks::Skip => {
+                    debug!("Skipping symlink {:?}", from);
+                    stats.send(StatusUpdate::Skipped(from))?;
+                }
+
+                FileType::Symlink => {
+                    let lfile = match read_link(&from) {
+                        Ok(lfile) => lfile,
+                        Err(e) => {
+                            entry_error(XcpError::CopyError(format!("Failed to read link {}: {}", quote_path(&from), e)).into(), config, &stats)?;
+                            continue;
+                        }
+                    };
+                    debug!("Send symlink operation {:?} to {:?}", lfile, target);
+                    work_tx.send(Operation::Link(lfile, target))?;
+                }
+
+                FileType::Dir => {
+                    // Create dir tree immediately as we can't
+                    // guarantee a worker will action the creation
+                    // before a subsequent copy operation requires it.
+                    debug!("Creating target directory {:?}", target);
+                    if let Err(err) = create_dir_all(&target) {
+                        let msg = format!("Error creating target directory {}: {}", quote_path(&target), err);
+                        if !config.keep_going {
+                            error!("{msg}");
+                            return Err(XcpError::CopyError(msg).into())
+                        }
+                        // Nothing beneath it can be created either.
+                        entry_error(XcpError::CopyError(msg).into(), config, &stats)?;
+                        entries.skip_current_dir();
+                        continue;
+                    }
+                    deferred.dirs.push((from, target, meta));
+                }
+
+                FileType::Socket => {
+                    // A socket is only meaningful with its listener.
+                    warn!("Skipping socket {}", quote_path(&from));
+                    stats.send(StatusUpdate::Skipped(from))?;
+                }
+
+                FileType::Char | FileType::Block | FileType::Fifo => {
                     debug!("Special file found: {:?} to {:?}", from, target);
                     work_tx.send(Operation::Special(from, target))?;
                 }
 
-                FileType::Block | FileType::Other => {
-                    error!("Unsupported filetype found: {:?} -> {:?}", target, ft);
-                    return Err(XcpError::UnknownFileType(target).into());
+                FileType::Other => {
+                    error!("Unsupported filetype found: {} -> {:?}", quote_path(&target), ft);
+                    return Err(XcpError::UnknownFileType(target).into());
+                }
+            };
+        }
+    }
+
+    if let Some(n) = config.newest {
+        // Newest first; the sort is stable, so ties keep walk order.
+        candidates.sort_by_key(|(mtime, ..)| Reverse(*mtime));
+        let older = candidates.split_off(cmp::min(n, candidates.len()));
+        for (_, from, target, meta) in candidates {
+            queue_file(from, target, &meta, &mut linked, &mut deferred)?;
+        }
+        for (_, from, ..) in older {
+            info!("Skipping {}; not among the {} newest files", quote_path(&from), n);
+            stats.send(StatusUpdate::Skipped(from))?;
+        }
+    }
+    debug!("Walk-worker finished: {:?}", thread::current().id());
+
+    Ok(deferred)
+}
+
+/// Handle a failure with a single entry of the tree walk; with
+/// [Config::keep_going] it is reported and the walk continues,
+/// otherwise the walk is aborted.
+fn entry_error(err: anyhow::Error, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    if !config.keep_going {
+        return Err(err);
+    }
+    error!("{}; continuing.", err);
+    stats.send(StatusUpdate::Error(XcpError::CopyError(err.to_string())))
+}
+
+/// Complete a tree copy once the operations sent by [tree_walker] have
+/// been performed: the deferred hard links are created, then each
+/// directory is given its source's metadata with
+/// [copy_directory_metadata], contents before their parents.
+pub fn finish_tree(deferred: Deferred, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    create_hard_links(deferred.hard_links, config, stats)?;
+    for (from, dir, meta) in deferred.dirs.iter().rev() {
+        if let Err(e) = copy_directory_metadata(from, dir, meta, config) {
+            stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+            if !config.keep_going {
+                error!("Error setting metadata of directory {}; aborting.", quote_path(dir));
+                return Err(e);
+            }
+            error!("Error setting metadata of directory {}; continuing.", quote_path(dir));
+        }
+    }
+    Ok(())
+}
+
+/// Apply the mode and timestamps in `meta`, from the source directory
+/// `from`, to the directory `dir`, subject to the same settings as
+/// files (e.g. [Config::no_perms], [Config::perms], [Config::chmod] and
+/// [Config::no_timestamps]). With [Config::preserve_acl] the access
+/// and default ACLs of `from` are also copied.
+///
+/// This must only be called once everything inside `dir` has been
+/// written: creating entries updates the modification time, a
+/// read-only mode would prevent their creation, and a default ACL
+/// would be inherited by them. Nested directories should therefore be
+/// finalised innermost first.
+pub fn copy_directory_metadata(from: &Path, dir: &Path, meta: &Metadata, config: &Config) -> Result<()> {
+    debug!("Setting metadata of directory {:?}", dir);
+    let fd = File::open(dir)?;
+    if config.preserve_acl && !config.basic_io {
+        if let Err(e) = File::open(from).map_err(libfs::Error::from).and_then(|infd| copy_acls(&infd, &fd)) {
+            warn!("Failed to copy ACLs to {}: {}", quote_path(dir), e);
+        }
+    }
+    if !config.no_timestamps {
+        set_timestamps(&fd, meta, config.timestamp_granularity)?;
+    }
+    if !config.no_perms {
+        let mut mode = config.perms.mode(meta.permissions().mode(), true);
+        if let Some(chmod) = config.chmod {
+            mode = chmod.apply(mode);
+        }
+        fd.set_permissions(Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Create the hard links deferred by [tree_walker], once all copies
+/// are complete; existing files at the link paths are replaced.
+pub fn create_hard_links(hard_links: Vec<HardLink>, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    for HardLink { original, link } in hard_links {
+        info!("Hard link {} -> {}", quote_path(&link), quote_path(&original));
+        let r = remove_existing(&link)
+            .and_then(|_| Ok(fs::hard_link(&original, &link)?));
+        match r {
+            Ok(()) => stats.send(StatusUpdate::Completed { reflinked: false })?,
+            Err(e) => {
+                stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                if !config.keep_going {
+                    error!("Error linking: {} -> {}; aborting.", quote_path(&link), quote_path(&original));
+                    return Err(e);
                 }
-            };
+                error!("Error linking: {} -> {}; continuing.", quote_path(&link), quote_path(&original));
+            }
         }
     }
-    debug!("Walk-worker finished: {:?}", thread::current().id());
-
     Ok(())
 }
 
+fn remove_existing(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(sticky_dir_error(e.into(), path)),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a file of `len` bytes is within [Config::min_size] and
+/// [Config::max_size].
+fn size_allowed(len: u64, config: &Config) -> bool {
+    config.min_size.map_or(true, |min| len >= min)
+        && config.max_size.map_or(true, |max| len <= max)
+}
+
+// Whether `overwrite` allows replacing `target`, if it exists, with a
+// source file with metadata `meta`.
+fn may_overwrite(meta: &Metadata, target: &Path, overwrite: Overwrite) -> Result<bool> {
+    let tmeta = match (overwrite, target.metadata()) {
+        (Overwrite::Never | Overwrite::IfNewer, Ok(tmeta)) => tmeta,
+        _ => return Ok(true),
+    };
+    Ok(overwrite == Overwrite::IfNewer && meta.modified()? > tmeta.modified()?)
+}
+
 fn empty_path(path: &Path) -> bool {
     *path == PathBuf::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::fs::FileTimes;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    use crate::config::{IdMap, Verify};
+    use crate::feedback::{ChannelUpdater, NoopUpdater};
+
+    #[test]
+    fn test_file_timeout() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        fs::write(&from, [0xff; 64 * 1024])?;
+
+        // As with --no-progress, so the whole file is one block.
+        let timeout = Duration::from_millis(200);
+        let config = Arc::new(Config {
+            block_size: u64::MAX,
+            reflink: Reflink::Never,
+            file_timeout: Some(timeout),
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        // The timeout runs from the start of the copy, not when the
+        // files are opened.
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        thread::sleep(timeout + Duration::from_millis(50));
+        handle.copy_file(&updates)?;
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+
+        // A slow source, trickling data into a pipe until it's closed.
+        let mut child = process::Command::new("sh")
+            .args(["-c", "while echo slow; do sleep 0.05; done"])
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        let pipe = File::from(std::os::fd::OwnedFd::from(child.stdout.take().unwrap()));
+        let start = Instant::now();
+        let handle = CopyHandle::from_fds(pipe, File::create(&to)?, &config)?;
+        let err = handle.copy_file(&updates).unwrap_err();
+        let elapsed = start.elapsed();
+        drop(handle);
+        child.wait()?;
+
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::CopyTimeout(_))));
+        assert!(elapsed >= timeout && elapsed < timeout * 5, "{:?}", elapsed);
+        assert!(fs::metadata(&to)?.len() > 0);
+
+        // A source that blocks indefinitely; the pipe has a writer
+        // that never writes. The watchdog gives up on the copy, and
+        // releases the updater.
+        let fifo = dir.path().join("fifo");
+        let to = dir.path().join("from-fifo");
+        rustix::fs::mknodat(rustix::fs::CWD, &fifo, rustix::fs::FileType::Fifo, rustix::fs::Mode::RUSR | rustix::fs::Mode::WUSR, 0)?;
+        let writer = OpenOptions::new().read(true).write(true).open(&fifo)?;
+        let config = Arc::new(Config {
+            atomic: true,
+            ..Config::clone(&config)
+        });
+        let channel = ChannelUpdater::new(&config);
+        let rx = channel.rx_channel();
+        let updates: Arc<dyn StatusUpdater> = Arc::new(channel);
+        let start = Instant::now();
+        let err = copy_reopening(&fifo, &to, &config, &updates).unwrap_err();
+        let elapsed = start.elapsed();
+        drop(updates);
+
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::CopyTimeout(_))));
+        assert!(elapsed >= timeout && elapsed < timeout * 5, "{:?}", elapsed);
+        loop {
+            match rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(StatusUpdate::Completed { .. }) => panic!("Timed out copy completed"),
+                Ok(_) => {}
+                Err(e) => {
+                    assert_eq!(cbc::RecvTimeoutError::Disconnected, e);
+                    break;
+                }
+            }
+        }
+
+        // Once unblocked the abandoned copy fails rather than being
+        // renamed into place.
+        drop(writer);
+        thread::sleep(Duration::from_millis(200));
+        assert!(!to.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_error() {
+        let err: anyhow::Error = XcpError::EncryptedSource(PathBuf::from("locked/file")).into();
+        let skip = Config {
+            encrypted: Encrypted::Skip,
+            ..Config::default()
+        };
+        assert!(skip_error(&err, &skip));
+        assert!(!skip_error(&err, &Config::default()));
+
+        let other: anyhow::Error = XcpError::CopyError("other".to_string()).into();
+        assert!(!skip_error(&other, &skip));
+
+        let declined: anyhow::Error = XcpError::OverwriteDeclined(PathBuf::from("dest")).into();
+        assert!(skip_error(&declined, &Config::default()));
+    }
+
+    #[test]
+    fn test_is_yes() {
+        for yes in ["y\n", "Y\n", "yes\n", " YES "] {
+            assert!(is_yes(yes), "{:?}", yes);
+        }
+        for no in ["", "\n", "n\n", "no", "yep", "y y"] {
+            assert!(!is_yes(no), "{:?}", no);
+        }
+    }
+
+    #[test]
+    fn test_copy_from_fds() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        fs::write(&from, "source data")?;
+        fs::write(&to, "a longer existing destination")?;
+        fs::set_permissions(&from, Permissions::from_mode(0o640))?;
+
+        let config = Arc::new(Config::default());
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        // The source is copied from its start, wherever its cursor is.
+        let mut infd = File::open(&from)?;
+        infd.seek(SeekFrom::Start(7))?;
+        let outfd = OpenOptions::new().write(true).open(&to)?;
+        let stats = CopyHandle::from_fds(infd, outfd, &config)?.copy_file(&updates)?;
+        assert_eq!(11, stats.len);
+        assert_eq!("source data", fs::read_to_string(&to)?);
+        assert_eq!(0o640, to.metadata()?.permissions().mode() & 0o777);
+
+        // Streams are copied until they end.
+        let mut child = process::Command::new("echo")
+            .arg("piped")
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        let pipe = File::from(std::os::fd::OwnedFd::from(child.stdout.take().unwrap()));
+        let handle = CopyHandle::from_fds(pipe, File::create(&to)?, &config)?;
+        let stats = handle.copy_file(&updates)?;
+        drop(handle);
+        child.wait()?;
+        assert_eq!(6, stats.copied);
+        assert_eq!("piped\n", fs::read_to_string(&to)?);
+
+        let verify = Arc::new(Config { verify: Verify::Cached, ..Config::default() });
+        assert!(CopyHandle::from_fds(File::open(&from)?, File::create(&to)?, &verify).is_err());
+        assert!(CopyHandle::from_fds(File::open(&from)?, File::open(dir.path())?, &config).is_err());
+        let err = CopyHandle::from_fds(File::open(&from)?, OpenOptions::new().write(true).open(&from)?, &config).unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::SameFile(_))));
+        assert_eq!("source data", fs::read_to_string(&from)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_source_plain_error() -> Result<()> {
+        let dir = TempDir::new()?;
+        let err = open_source(&dir.path().join("missing")).unwrap_err();
+        assert!(err.downcast_ref::<std::io::Error>().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_cadence() {
+        let mb = 1024 * 1024;
+        let cadence = SyncCadence::new(Some(4 * mb));
+        let syncs = (0..16)
+            .map(|_| cadence.record(mb))
+            .collect::<Vec<bool>>();
+        let due = syncs.iter()
+            .enumerate()
+            .filter(|(_, s)| **s)
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+        // Every 4th 1MB write.
+        assert_eq!(vec![3, 7, 11, 15], due);
+
+        // Writes larger than the cadence sync every time.
+        let cadence = SyncCadence::new(Some(mb));
+        assert!(cadence.record(3 * mb));
+        assert!(cadence.record(3 * mb));
+
+        let cadence = SyncCadence::new(None);
+        assert!(!cadence.record(u64::MAX / 2));
+        let cadence = SyncCadence::new(Some(0));
+        assert!(!cadence.record(mb));
+    }
+
+    #[test]
+    fn test_copy_with_sync_every() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        {
+            let mut fd = File::create(&from)?;
+            fd.write_all(&[0xaa; 256 * 1024])?;
+        }
+
+        let config = Arc::new(Config {
+            block_size: 16 * 1024,
+            reflink: Reflink::Never,
+            sync_every: Some(64 * 1024),
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        let copied = CopyHandle::new(&from, &to, &config)?.copy_file(&updates)?;
+        assert_eq!(256 * 1024, copied.copied);
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota_exceeded_cleanup() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&[0xff; 4096])?;
+
+        let config = Arc::new(Config::default());
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        assert!(to.exists());
+
+        let injected = libfs::Error::OSError(Errno::DQUOT).into();
+        let err = handle.write_error(injected);
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::QuotaExceeded { path }) => assert_eq!(&to, path),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(!to.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_space_cleanup() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&[0xff; 4096])?;
+
+        let config = Arc::new(Config::default());
+        let handle = CopyHandle::new(&from, &to, &config)?;
+
+        let err = handle.write_error(io::Error::from_raw_os_error(Errno::NOSPC.raw_os_error()).into());
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::NoSpace { path }) => assert_eq!(&to, path),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(!to.exists());
+
+        // Other errors leave the file in place.
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        handle.write_error(io::Error::from_raw_os_error(Errno::IO.raw_os_error()).into());
+        assert!(to.exists());
+
+        Ok(())
+    }
+
+    fn temp_files(dir: &Path) -> Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with(".xcp-tmp-"))
+            .collect())
+    }
+
+    #[test]
+    fn test_same_file_not_truncated() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.txt");
+        let link = dir.path().join("link.txt");
+        fs::write(&from, b"data")?;
+        fs::hard_link(&from, &link)?;
+
+        let config = Arc::new(Config::default());
+        for to in [&from, &link] {
+            let err = CopyHandle::new(&from, to, &config).unwrap_err();
+            match err.downcast_ref::<XcpError>() {
+                Some(XcpError::SameFile(path)) => assert_eq!(to, path),
+                e => panic!("Unexpected error {:?}", e),
+            }
+        }
+        assert_eq!(b"data", fs::read(&from)?.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_copy() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        fs::write(&from, [0xff; 4096])?;
+        fs::write(&to, b"original")?;
+
+        let config = Arc::new(Config {
+            atomic: true,
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        // The destination is untouched until the copy completes.
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        assert_eq!(b"original", fs::read(&to)?.as_slice());
+        assert_eq!(1, temp_files(dir.path())?.len());
+        handle.copy_file(&updates)?;
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+        assert!(temp_files(dir.path())?.is_empty());
+        drop(handle);
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+
+        // Incomplete and failed copies are discarded.
+        fs::write(&to, b"original")?;
+        drop(CopyHandle::new(&from, &to, &config)?);
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        handle.write_error(io::Error::from_raw_os_error(Errno::IO.raw_os_error()).into());
+        handle.copy_file(&updates)?;
+        drop(handle);
+        assert_eq!(b"original", fs::read(&to)?.as_slice());
+        assert!(temp_files(dir.path())?.is_empty());
+
+        // A failure to replace the destination fails the copy, and
+        // it isn't reported as completed.
+        let (tx, rx) = cbc::unbounded();
+        struct Fwd(cbc::Sender<StatusUpdate>);
+        impl StatusUpdater for Fwd {
+            fn send(&self, update: StatusUpdate) -> Result<()> {
+                self.0.send(update)?;
+                Ok(())
+            }
+        }
+        let sender: Arc<dyn StatusUpdater> = Arc::new(Fwd(tx));
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        fs::remove_file(&to)?;
+        fs::create_dir(&to)?;
+        fs::write(to.join("file"), b"occupied")?;
+        assert!(handle.copy_file(&sender).is_err());
+        drop(handle);
+        drop(sender);
+        assert!(rx.iter().all(|u| !matches!(u, StatusUpdate::Completed { .. })));
+        assert!(temp_files(dir.path())?.is_empty());
+
+        // Backups are made only as the copy is renamed into place.
+        let config = Arc::new(Config {
+            atomic: true,
+            backup: Backup::Simple,
+            ..Config::default()
+        });
+        let to = dir.path().join("backed-up.bin");
+        fs::write(&to, b"original")?;
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        assert_eq!(b"original", fs::read(&to)?.as_slice());
+        handle.copy_file(&updates)?;
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+        assert_eq!(b"original", fs::read(dir.path().join("backed-up.bin~"))?.as_slice());
+
+        Ok(())
+    }
+
+    fn write_at(path: &Path, off: u64, data: &[u8]) -> Result<()> {
+        let mut fd = OpenOptions::new().write(true).open(path)?;
+        fd.seek(SeekFrom::Start(off))?;
+        fd.write_all(data)?;
+        fd.sync_all()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_partial_sparse() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mb = 1024 * 1024;
+        let data = vec![0xcc; 64 * 1024];
+
+        File::create(&from)?.set_len(2 * mb)?;
+        write_at(&from, 0, &data)?;
+        write_at(&from, mb, &data)?;
+
+        // Interrupted copy; first extent complete, half the second.
+        File::create(&to)?.set_len(2 * mb)?;
+        write_at(&to, 0, &data)?;
+        write_at(&to, mb, &data[..32 * 1024])?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::open(&to)?;
+        if map_extents(&infd)?.is_none() {
+            // No FIEMAP on this filesystem.
+            return Ok(());
+        }
+        let resume = resume_point(&infd, &outfd)?;
+        assert!(resume >= mb && resume < mb + 32 * 1024, "Resume at {}", resume);
+
+        let config = Arc::new(Config {
+            resume: true,
+            reflink: Reflink::Never,
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        assert_eq!(resume, handle.resume_offset());
+        assert_eq!(2 * mb, handle.copy_file(&updates)?.bytes());
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_complete_and_longer() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&[0xdd; 64 * 1024])?;
+        fs::copy(&from, &to)?;
+
+        let infd = File::open(&from)?;
+        if map_extents(&infd)?.is_none() {
+            return Ok(());
+        }
+        assert_eq!(64 * 1024, resume_point(&infd, &File::open(&to)?)?);
+
+        // A longer destination isn't a partial copy of this source.
+        write_at(&to, 64 * 1024, &[0xdd; 4096])?;
+        assert_eq!(0, resume_point(&infd, &File::open(&to)?)?);
+
+        Ok(())
+    }
+
+    fn stale() -> anyhow::Error {
+        io::Error::from_raw_os_error(Errno::STALE.raw_os_error()).into()
+    }
+
+    #[test]
+    fn test_retry_stale_reopens() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&[0xee; 8192])?;
+        File::create(&to)?.write_all(b"old")?;
+
+        let config = Arc::new(Config {
+            backup: Backup::Numbered,
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let mut attempts = Vec::new();
+        let copied = copy_restarting(&from, &to, &config, None, |handle| {
+            attempts.push(handle.config.backup);
+            if attempts.len() == 1 {
+                // Injected; the handle is discarded and the files reopened.
+                return Err(stale());
+            }
+            handle.copy_file(&updates)
+        })?;
+
+        assert_eq!(8192, copied.bytes());
+        assert_eq!(vec![Backup::Numbered, Backup::None], attempts);
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+        // Only the original destination was backed up.
+        let backups = fs::read_dir(dir.path())?.count() - 2;
+        assert_eq!(1, backups);
+
+        // A new name chosen for a conflict is reused, rather than
+        // leaving the first attempt behind.
+        let config = Arc::new(Config {
+            overwrite: Overwrite::RenameOnConflict,
+            ..Config::default()
+        });
+        let mut attempts = 0;
+        copy_restarting(&from, &to, &config, None, |handle| {
+            attempts += 1;
+            if attempts == 1 {
+                return Err(stale());
+            }
+            handle.copy_file(&updates)
+        })?;
+        assert_eq!(2, attempts);
+        assert_eq!(fs::read(&from)?, fs::read(dir.path().join("to (1).bin"))?);
+        assert!(!dir.path().join("to (2).bin").exists());
+
+        // A deferred atomic backup is still made.
+        let config = Arc::new(Config {
+            atomic: true,
+            backup: Backup::Simple,
+            ..Config::default()
+        });
+        let to = dir.path().join("atomic.bin");
+        fs::write(&to, b"original")?;
+        let mut attempts = 0;
+        copy_restarting(&from, &to, &config, None, |handle| {
+            attempts += 1;
+            if attempts == 1 {
+                return Err(stale());
+            }
+            handle.copy_file(&updates)
+        })?;
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+        assert_eq!(b"original", fs::read(dir.path().join("atomic.bin~"))?.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_stale_limits() {
+        let mut attempts = 0;
+        let err = retry_stale(|| {
+            attempts += 1;
+            Err(stale())
+        }).unwrap_err();
+        assert_eq!(Some(Errno::STALE), os_error(&err));
+        assert_eq!(1 + STALE_RETRIES, attempts);
+
+        // Other errors are not retried.
+        let mut attempts = 0;
+        let _ = retry_stale(|| {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(Errno::IO.raw_os_error()).into())
+        });
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_read_retries() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&[0xff; 4096])?;
+
+        let eio = || -> anyhow::Error { io::Error::from_raw_os_error(Errno::IO.raw_os_error()).into() };
+
+        // The low pages of our own address space are unmapped, so
+        // reading them fails with EIO.
+        let unreadable = Path::new("/proc/self/mem");
+
+        let config = Arc::new(Config { read_retries: 2, ..Config::default() });
+        let handle = CopyHandle::new(unreadable, &to, &config)?;
+        let mut attempts = Vec::new();
+        let copied = handle.retry_reads(1024, 100, |attempt| {
+            attempts.push(attempt);
+            if attempt < 2 { Err(eio()) } else { Ok(100) }
+        })?;
+        assert_eq!(100, copied);
+        assert_eq!(vec![0, 1, 2], attempts);
+
+        let config = Arc::new(Config { read_retries: 1, ..Config::default() });
+        let handle = CopyHandle::new(unreadable, &to, &config)?;
+        let mut calls = 0;
+        let err = handle.retry_reads(2048, 100, |_| { calls += 1; Err(eio()) }).unwrap_err();
+        assert_eq!(2, calls);
+        match err.downcast_ref::<XcpError>() {
+            Some(XcpError::ReadFailed { path, offset, .. }) => assert_eq!((unreadable, 2048), (path.as_path(), *offset)),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // An I/O error with a readable source is from the
+        // destination, so isn't retried or reported as a read failure.
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        let mut calls = 0;
+        let err = handle.retry_reads(0, 4096, |_| { calls += 1; Err(eio()) }).unwrap_err();
+        assert_eq!(1, calls);
+        assert!(err.downcast_ref::<XcpError>().is_none());
+        assert_eq!(Some(Errno::IO), os_error(&err));
+
+        // Other errors are not retried.
+        let mut calls = 0;
+        let err = handle.retry_reads(0, 4096, |_| { calls += 1; Err(io::Error::from_raw_os_error(Errno::NOSPC.raw_os_error()).into()) }).unwrap_err();
+        assert_eq!(1, calls);
+        assert!(err.downcast_ref::<XcpError>().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extents_shared() {
+        let ext = |start, end, physical| Extent { start, end, physical, shared: true, unwritten: false };
+        let source = [ext(0, 4096, 1 << 20), ext(4096, 8192, 1 << 30)];
+
+        assert!(extents_shared(&source, &[ext(0, 4096, 1 << 20), ext(4096, 8192, 1 << 30)]));
+        // Partially shared, e.g. after a later write to the destination.
+        assert!(extents_shared(&source, &[ext(0, 2048, (1 << 20) + 2048)]));
+        assert!(extents_shared(&source, &[]));
+        // Adjacent but distinct storage.
+        assert!(!extents_shared(&source, &[ext(0, 4096, (1 << 20) + 4096)]));
+        assert!(!extents_shared(&source, &[ext(0, 4096, 1 << 20), ext(4096, 8192, 1 << 40)]));
+        assert!(!extents_shared(&[], &[ext(0, 4096, 1 << 20)]));
+    }
+
+    #[test]
+    fn test_insufficient_inodes() {
+        assert!(insufficient_inodes(10, Some(10)).is_none());
+        assert!(insufficient_inodes(10, None).is_none());
+        match insufficient_inodes(11, Some(10)) {
+            Some(XcpError::InsufficientInodes { needed: 11, available: 10 }) => {}
+            e => panic!("Unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_dest_config() -> Result<()> {
+        assert!(limited_fs("fuse"));
+        assert!(!limited_fs("ext4"));
+
+        let dir = TempDir::new()?;
+        let config = Arc::new(Config::default());
+        // The destination need not exist yet.
+        let dest = dir.path().join("missing/dest");
+        let fstype = filesystem_type(dir.path())?;
+        let adapted = dest_config(&config, &[], &dest);
+        assert_eq!(fstype.is_some_and(|t| limited_fs(&t)), adapted.basic_io);
+        assert_eq!(timestamp_granularity(dir.path())?, adapted.timestamp_granularity);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_coarse_timestamp_granularity() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        fs::write(&from, "data")?;
+        let mtime = UNIX_EPOCH + Duration::new(1_700_000_003, 123_456_789);
+        File::options().write(true).open(&from)?
+            .set_times(FileTimes::new().set_accessed(mtime).set_modified(mtime))?;
+
+        // Stand-in for a FAT destination.
+        let config = Arc::new(Config {
+            timestamp_granularity: Some(Duration::from_secs(2)),
+            ..Config::default()
+        });
+        // An explicit granularity is kept.
+        assert_eq!(config.timestamp_granularity, dest_config(&config, &[], &to).timestamp_granularity);
+
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        CopyHandle::new(&from, &to, &config)?.copy_file(&updates)?;
+        let expected = UNIX_EPOCH + Duration::from_secs(1_700_000_002);
+        assert_eq!(expected, fs::metadata(&to)?.modified()?);
+        assert_eq!(expected, fs::metadata(&to)?.accessed()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_basic_io_copy() -> Result<()> {
+        // Stand-in for a limited filesystem; with basic_io set the
+        // copy must not depend on holes, preallocation or xattrs.
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mb = 1024 * 1024;
+        let data = vec![0xcc; 64 * 1024];
+
+        File::create(&from)?.set_len(2 * mb)?;
+        write_at(&from, mb, &data)?;
+
+        let config = Arc::new(Config {
+            basic_io: true,
+            preallocate: Preallocate::Always,
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        assert!(!handle.try_reflink()?);
+        assert_eq!(2 * mb, handle.copy_file(&updates)?.bytes());
+        drop(handle);
+        assert_eq!(fs::read(&from)?, fs::read(&to)?);
+        // Holes in the source are written out as data.
+        if probably_sparse(&File::open(&from)?)? {
+            assert!(!probably_sparse(&File::open(&to)?)?);
+        }
+
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        assert_eq!(data.len(), handle.copy_block(data.len() as u64, mb)?);
+        drop(handle);
+        assert_eq!(data.as_slice(), &fs::read(&to)?[mb as usize..mb as usize + data.len()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_name() {
+        assert_eq!(PathBuf::from("/d/file (1).txt"), conflict_name(Path::new("/d/file.txt"), 1));
+        assert_eq!(PathBuf::from("/d/file (12)"), conflict_name(Path::new("/d/file"), 12));
+        assert_eq!(PathBuf::from("/d/.bashrc (2)"), conflict_name(Path::new("/d/.bashrc"), 2));
+        assert_eq!(PathBuf::from("a.tar (1).gz"), conflict_name(Path::new("a.tar.gz"), 1));
+    }
+
+    #[test]
+    fn test_create_unique_concurrent() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "existing")?;
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || create_unique(&path).map(|(_, p)| p))
+            })
+            .collect();
+        let mut names = threads.into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Result<Vec<PathBuf>>>()?;
+        names.sort();
+        names.dedup();
+
+        assert_eq!(8, names.len());
+        assert!(!names.contains(&path));
+        assert_eq!("existing", fs::read_to_string(&path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffer_memory_budget() -> Result<()> {
+        let dir = TempDir::new()?;
+        let mb = 1024 * 1024;
+        let limit = mb + 4096;
+        let config = Arc::new(Config {
+            basic_io: true,
+            verify: Verify::Cached,
+            max_buffer_memory: Some(limit),
+            ..Config::default()
+        });
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let from = dir.path().join(format!("from{}.bin", i));
+                let to = dir.path().join(format!("to{}.bin", i));
+                let config = config.clone();
+                thread::spawn(move || -> Result<()> {
+                    let data: Vec<u8> = (0..3 * mb as usize + i).map(|b| (b % 251) as u8).collect();
+                    fs::write(&from, &data)?;
+                    let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+                    let handle = CopyHandle::new(&from, &to, &config)?;
+                    handle.copy_file(&updates)?;
+                    assert_eq!(2 * mb as usize, handle.copy_block(2 * mb, 0)?);
+                    drop(handle);
+                    assert_eq!(data, fs::read(&to)?);
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap()?;
+        }
+
+        let peak = membudget::global().peak();
+        assert!(peak > 0 && peak <= limit, "Peak buffer memory {}", peak);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sticky_dir_error() -> Result<()> {
+        let dir = TempDir::new()?;
+        let sticky = dir.path().join("sticky");
+        fs::create_dir(&sticky)?;
+        fs::set_permissions(&sticky, Permissions::from_mode(0o1777))?;
+        let file = sticky.join("theirs.txt");
+        fs::write(&file, "data")?;
+
+        let eperm = || anyhow::Error::from(io::Error::from_raw_os_error(Errno::PERM.raw_os_error()));
+        // Our own files aren't affected.
+        assert!(sticky_dir_error(eperm(), &file).downcast_ref::<XcpError>().is_none());
+
+        if fchown(File::open(&file)?, Some(65534), Some(65534)).is_err() {
+            println!("Skipping: unable to chown test file");
+            return Ok(());
+        }
+        // The directory owner may also replace files, so give it to
+        // another user too.
+        std::os::unix::fs::chown(&sticky, Some(65533), None)?;
+        let err = sticky_dir_error(eperm(), &file);
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::StickyDirectory(p)) if *p == file));
+
+        // Other errors are unchanged.
+        let enoent = anyhow::Error::from(io::Error::from_raw_os_error(Errno::NOENT.raw_os_error()));
+        assert!(sticky_dir_error(enoent, &file).downcast_ref::<XcpError>().is_none());
+
+        // Without the sticky bit the error is a plain permission one.
+        fs::set_permissions(&sticky, Permissions::from_mode(0o777))?;
+        assert!(sticky_dir_error(eperm(), &file).downcast_ref::<XcpError>().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_permission_denied() -> Result<()> {
+        let path = PathBuf::from("/some/file");
+        for errno in [Errno::ACCESS, Errno::PERM] {
+            let err = anyhow::Error::from(io::Error::from_raw_os_error(errno.raw_os_error()));
+            let err = permission_denied(err, &path);
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::PermissionDenied(p)) if *p == path));
+        }
+        let enoent = anyhow::Error::from(io::Error::from_raw_os_error(Errno::NOENT.raw_os_error()));
+        assert!(permission_denied(enoent, &path).downcast_ref::<XcpError>().is_none());
+
+        // Root bypasses the mode checks.
+        if geteuid().is_root() {
+            println!("Skipping: running as root");
+            return Ok(());
+        }
+        let dir = TempDir::new()?;
+        let from = dir.path().join("unreadable.txt");
+        fs::write(&from, "data")?;
+        fs::set_permissions(&from, Permissions::from_mode(0o000))?;
+        let err = CopyHandle::new(&from, &dir.path().join("dest.txt"), &Arc::new(Config::default())).err().unwrap();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::PermissionDenied(p)) if *p == from));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_from_extent_map() -> Result<()> {
+        // The default tempdir may be tmpfs, which has no FIEMAP.
+        let dir = TempDir::new_in(".")?;
+        let from = dir.path().join("from.bin");
+        let mb = 1024 * 1024;
+        let data = vec![0xcc; 4096];
+
+        // Many small extents, with a trailing hole.
+        File::create(&from)?.set_len(8 * mb)?;
+        for i in 0..64 {
+            write_at(&from, i * 64 * 1024, &data)?;
+        }
+
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        for no_extent_map in [false, true] {
+            let to = dir.path().join(format!("to-{}.bin", no_extent_map));
+            let config = Arc::new(Config {
+                reflink: Reflink::Never,
+                no_extent_map,
+                ..Config::default()
+            });
+            let handle = CopyHandle::new(&from, &to, &config)?;
+            let stats = handle.copy_file(&updates)?;
+            assert_eq!(8 * mb, stats.bytes());
+            assert_eq!(8 * mb, stats.copied + stats.skipped);
+            assert!(!stats.reflinked);
+            drop(handle);
+            assert_eq!(fs::read(&from)?, fs::read(&to)?);
+            if probably_sparse(&File::open(&from)?)? {
+                assert!(probably_sparse(&File::open(&to)?)?);
+                // Only the data is copied, a block at a time.
+                assert_eq!(64 * 4096, stats.copied);
+                assert_eq!(64, stats.extents);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_timestamps() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.txt");
+        fs::write(&from, "data")?;
+        let atime = UNIX_EPOCH + Duration::new(1_600_000_000, 987_654_321);
+        let mtime = UNIX_EPOCH + Duration::new(1_700_000_003, 123_456_789);
+        File::options().write(true).open(&from)?
+            .set_times(FileTimes::new().set_accessed(atime).set_modified(mtime))?;
+
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let to = dir.path().join("to.txt");
+        let config = Arc::new(Config::default());
+        CopyHandle::new(&from, &to, &config)?.copy_file(&updates)?;
+        // Reading the source mustn't leak into the copied atime.
+        assert_eq!(atime, fs::metadata(&to)?.accessed()?);
+        assert_eq!(mtime, fs::metadata(&to)?.modified()?);
+
+        let to = dir.path().join("untimed.txt");
+        let config = Arc::new(Config {
+            no_timestamps: true,
+            ..Config::default()
+        });
+        CopyHandle::new(&from, &to, &config)?.copy_file(&updates)?;
+        assert_ne!(mtime, fs::metadata(&to)?.modified()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_empty_source() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("empty");
+        File::create(&from)?;
+        fs::set_permissions(&from, Permissions::from_mode(0o604))?;
+        let mtime = UNIX_EPOCH + Duration::new(1_700_000_003, 0);
+        File::options().write(true).open(&from)?
+            .set_times(FileTimes::new().set_modified(mtime))?;
+
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        let configs = [
+            Config::default(),
+            Config { reflink: Reflink::Never, preallocate: Preallocate::Always, ..Config::default() },
+            Config { reflink: Reflink::Never, sparse: Sparse::Always, ..Config::default() },
+            Config { reflink: Reflink::Never, direct_io: true, verify: Verify::Cached, ..Config::default() },
+            Config { basic_io: true, atomic: true, ..Config::default() },
+            Config { delta: true, ..Config::default() },
+            Config { resume: true, ..Config::default() },
+        ];
+        for config in configs {
+            // Replacing existing data, as well as creating the file.
+            let to = dir.path().join("to");
+            fs::write(&to, "stale data")?;
+            let config = Arc::new(config);
+            let stats = CopyHandle::new(&from, &to, &config)?.copy_file(&updates)?;
+            assert_eq!(0, stats.len);
+            assert_eq!(0, stats.copied);
+
+            let meta = fs::metadata(&to)?;
+            assert_eq!(0, meta.len(), "{:?}", config);
+            assert_eq!(0o604, meta.permissions().mode() & 0o777, "{:?}", config);
+            assert_eq!(mtime, meta.modified()?, "{:?}", config);
+            fs::remove_file(&to)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_ownership_unprivileged() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.txt");
+        let to = dir.path().join("to.txt");
+        fs::write(&from, "data")?;
+        let meta = fs::metadata(&from)?;
+
+        // Giving the file away needs CAP_CHOWN; without it the group
+        // is still set and the copy succeeds.
+        let config = Arc::new(Config {
+            preserve_ownership: true,
+            uid_map: IdMap(HashMap::from([(meta.uid(), 65534)])),
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        CopyHandle::new(&from, &to, &config)?.copy_file(&updates)?;
+
+        let outmeta = fs::metadata(&to)?;
+        let expected = if geteuid().is_root() { 65534 } else { meta.uid() };
+        assert_eq!(expected, outmeta.uid());
+        assert_eq!(meta.gid(), outmeta.gid());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_checks_prefix() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&from, &data)?;
+
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        for verify in [Verify::Off, Verify::Cached, Verify::Hash] {
+            // An interrupted copy, with corrupted kept data.
+            let mut partial = data[..128 * 1024].to_vec();
+            partial[100] ^= 0xff;
+            fs::write(&to, &partial)?;
+
+            let config = Arc::new(Config {
+                resume: true,
+                reflink: Reflink::Never,
+                verify,
+                ..Config::default()
+            });
+            let handle = CopyHandle::new(&from, &to, &config)?;
+            if verify == Verify::Off {
+                // Trusted as-is.
+                assert!(handle.resume_offset() > 100);
+                continue;
+            }
+            assert_eq!(0, handle.resume_offset(), "{:?}", verify);
+            handle.copy_file(&updates)?;
+            drop(handle);
+            assert_eq!(data, fs::read(&to)?);
+
+            // An intact prefix is kept.
+            fs::write(&to, &data[..128 * 1024])?;
+            let handle = CopyHandle::new(&from, &to, &config)?;
+            assert!(handle.resume_offset() > 0);
+            handle.copy_file(&updates)?;
+            drop(handle);
+            assert_eq!(data, fs::read(&to)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_direct() -> Result<()> {
+        // tmpfs doesn't support O_DIRECT.
+        let dir = TempDir::new_in(".")?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data: Vec<u8> = (0..(3 * DIRECT_IO_ALIGN + 123)).map(|i| (i % 251) as u8).collect();
+        fs::write(&from, &data)?;
+
+        let config = Arc::new(Config {
+            direct_io: true,
+            reflink: Reflink::Never,
+            block_size: 2 * DIRECT_IO_ALIGN as u64,
+            ..Config::default()
+        });
+        let updates: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        if handle.direct.is_none() {
+            println!("Skipping: no direct I/O support");
+            return Ok(());
+        }
+        assert_eq!(data.len() as u64, handle.copy_file(&updates)?.copied);
+        assert_eq!(data, fs::read(&to)?);
+
+        // Unaligned blocks are split around the aligned middle.
+        let handle = CopyHandle::new(&from, &to, &config)?;
+        let (off, len) = (100, 2 * DIRECT_IO_ALIGN as u64);
+        assert_eq!(len as usize, handle.copy_block(len, off)?);
+        drop(handle);
+        let copied = fs::read(&to)?;
+        assert_eq!(data[100..100 + len as usize], copied[100..100 + len as usize]);
+        assert!(copied[..100].iter().all(|b| *b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preallocate_auto() -> Result<()> {
+        // tmpfs doesn't support preallocation.
+        let dir = TempDir::new_in(".")?;
+        let dense = dir.path().join("dense.bin");
+        let sparse = dir.path().join("sparse.bin");
+        let mb = 1024 * 1024;
+        fs::write(&dense, vec![0xcc; mb as usize])?;
+        File::create(&sparse)?.set_len(4 * mb)?;
+        write_at(&sparse, mb, &[0xcc; 4096])?;
+
+        let config = Arc::new(Config {
+            preallocate: Preallocate::Auto,
+            ..Config::default()
+        });
+
+        // Only the dense source gets its blocks reserved; the check
+        // is before any data is copied.
+        let to = dir.path().join("dense.out");
+        let _handle = CopyHandle::new(&dense, &to, &config)?;
+        assert!(to.metadata()?.blocks() * 512 >= mb);
+
+        let to = dir.path().join("sparse.out");
+        let _handle = CopyHandle::new(&sparse, &to, &config)?;
+        assert_eq!(4 * mb, to.metadata()?.len());
+        assert_eq!(0, to.metadata()?.blocks());
+
+        Ok(())
+    }
+}
+
@@ -0,0 +1,179 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Per-filesystem optimisation profiles, for
+//! [Optimize::Auto](crate::config::Optimize::Auto).
+//!
+//! A [Profile] is selected from the source and destination
+//! filesystem types (as named by [libfs::filesystem_type]):
+//!
+//! | Profile     | Filesystems                                | Settings |
+//! |-------------|--------------------------------------------|----------|
+//! | `reflink`   | btrfs, xfs or bcachefs, source and destination the same filesystem | reflink `auto`, no preallocation |
+//! | `memory`    | tmpfs or ramfs destination                 | reflink `never`, blocks of at least 16MB, no preallocation |
+//! | `network`   | nfs, cifs, smb2 or ceph, either side       | no extent mapping |
+//! | `fuse`      | fuse destination                           | [basic_io](crate::config::Config::basic_io) |
+//! | `removable` | vfat or exfat destination                  | reflink `never`, preallocate `always` |
+//! | `default`   | anything else                              | unchanged |
+//!
+//! Extent maps (`FIEMAP`) from network filesystems are often missing
+//! or don't reflect the server's layout, so are not used for sparse
+//! copies or resuming. Preallocating FAT files avoids fragmentation.
+//! The settings in a profile take precedence over the corresponding
+//! options.
+
+use std::cmp;
+
+use crate::config::{Config, Preallocate, Reflink};
+
+/// Block size used for memory-backed destinations.
+const MEMORY_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Copy settings tuned for a combination of filesystems; see the
+/// [module](self) documentation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Profile {
+    pub name: &'static str,
+    pub reflink: Option<Reflink>,
+    pub preallocate: Option<Preallocate>,
+    pub min_block_size: Option<u64>,
+    pub no_extent_map: bool,
+    pub basic_io: bool,
+}
+
+impl Profile {
+    const DEFAULT: Profile = Profile {
+        name: "default",
+        reflink: None,
+        preallocate: None,
+        min_block_size: None,
+        no_extent_map: false,
+        basic_io: false,
+    };
+
+    /// `config` with this profile's settings applied.
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        if let Some(reflink) = self.reflink {
+            config.reflink = reflink;
+        }
+        if let Some(preallocate) = self.preallocate {
+            config.preallocate = preallocate;
+        }
+        if let Some(block_size) = self.min_block_size {
+            config.block_size = cmp::max(config.block_size, block_size);
+        }
+        config.no_extent_map |= self.no_extent_map;
+        config.basic_io |= self.basic_io;
+        config
+    }
+}
+
+fn is_network(fstype: Option<&str>) -> bool {
+    matches!(fstype, Some("nfs" | "cifs" | "smb2" | "ceph"))
+}
+
+/// Select the profile for copying from a `source` filesystem to a
+/// `dest` filesystem; `same_fs` is set if they are the same
+/// filesystem instance, not just the same type.
+pub fn select_profile(source: Option<&str>, dest: Option<&str>, same_fs: bool) -> Profile {
+    match dest {
+        Some("btrfs" | "xfs" | "bcachefs") if same_fs => Profile {
+            name: "reflink",
+            reflink: Some(Reflink::Auto),
+            preallocate: Some(Preallocate::Never),
+            ..Profile::DEFAULT
+        },
+        Some("tmpfs" | "ramfs") => Profile {
+            name: "memory",
+            reflink: Some(Reflink::Never),
+            preallocate: Some(Preallocate::Never),
+            min_block_size: Some(MEMORY_BLOCK_SIZE),
+            ..Profile::DEFAULT
+        },
+        _ if is_network(source) || is_network(dest) => Profile {
+            name: "network",
+            no_extent_map: true,
+            ..Profile::DEFAULT
+        },
+        Some("fuse") => Profile {
+            name: "fuse",
+            basic_io: true,
+            ..Profile::DEFAULT
+        },
+        Some("vfat" | "exfat") => Profile {
+            name: "removable",
+            reflink: Some(Reflink::Never),
+            preallocate: Some(Preallocate::Always),
+            ..Profile::DEFAULT
+        },
+        _ => Profile::DEFAULT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_profile() {
+        let cases = [
+            (Some("btrfs"), Some("btrfs"), true, "reflink"),
+            (Some("xfs"), Some("xfs"), true, "reflink"),
+            // Reflinks don't cross filesystems.
+            (Some("btrfs"), Some("btrfs"), false, "default"),
+            (Some("ext4"), Some("btrfs"), false, "default"),
+            (Some("tmpfs"), Some("tmpfs"), true, "memory"),
+            (Some("nfs"), Some("tmpfs"), false, "memory"),
+            (Some("ext4"), Some("ramfs"), false, "memory"),
+            (Some("nfs"), Some("ext4"), false, "network"),
+            (Some("ext4"), Some("cifs"), false, "network"),
+            (Some("nfs"), Some("fuse"), false, "network"),
+            (Some("ext4"), Some("fuse"), false, "fuse"),
+            (Some("ext4"), Some("vfat"), false, "removable"),
+            (Some("btrfs"), Some("exfat"), false, "removable"),
+            (Some("ext4"), Some("ext4"), true, "default"),
+            (None, None, false, "default"),
+        ];
+        for (source, dest, same, expected) in cases {
+            assert_eq!(expected, select_profile(source, dest, same).name, "{:?} -> {:?} (same: {})", source, dest, same);
+        }
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let config = Config {
+            reflink: Reflink::Always,
+            preallocate: Preallocate::KeepSize,
+            block_size: 1024 * 1024,
+            ..Config::default()
+        };
+
+        let memory = select_profile(None, Some("tmpfs"), false).apply(&config);
+        assert_eq!(Reflink::Never, memory.reflink);
+        assert_eq!(Preallocate::Never, memory.preallocate);
+        assert_eq!(MEMORY_BLOCK_SIZE, memory.block_size);
+
+        let network = select_profile(Some("nfs"), Some("ext4"), false).apply(&config);
+        assert!(network.no_extent_map);
+        assert_eq!(Reflink::Always, network.reflink);
+        assert_eq!(1024 * 1024, network.block_size);
+
+        let default = select_profile(Some("ext4"), Some("ext4"), true).apply(&config);
+        assert_eq!(config.preallocate, default.preallocate);
+        assert!(!default.no_extent_map && !default.basic_io);
+    }
+}
@@ -0,0 +1,234 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Packing a directory into a single file.
+//!
+//! [pack_dir] concatenates the regular files under a directory, in
+//! sorted path order, into one destination file, and writes a sidecar
+//! index recording the offset and length of each. Files can be
+//! extracted again with [read_index] and
+//! [copy_partial](crate::ranges::copy_partial).
+//!
+//! NOTE: This is not a standard archive format, and no metadata
+//! (permissions, timestamps, etc.) is recorded; use `tar` or similar
+//! where that matters. The index is a text file:
+//!
+//! ```text
+//! xcp-pack 1
+//! <offset> <length> <relative path>
+//! ...
+//! ```
+//!
+//! Newlines and backslashes in paths are escaped as `\n` and `\\`.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use libfs::copy_file_bytes;
+use log::debug;
+use walkdir::WalkDir;
+
+use crate::config::{Config, Symlinks};
+use crate::errors::{Result, XcpError};
+use crate::quoting::quote_path;
+
+const INDEX_HEADER: &str = "xcp-pack 1";
+
+/// The location of a file within a pack.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackEntry {
+    /// Path relative to the packed directory.
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The path of the index for the pack file `pack`, i.e. with `.idx`
+/// appended.
+pub fn index_path(pack: &Path) -> PathBuf {
+    let mut idx = pack.as_os_str().to_owned();
+    idx.push(".idx");
+    PathBuf::from(idx)
+}
+
+/// Pack the regular files under `dir` into the file `to`, writing
+/// the index to [index_path]. Symlinks are followed if
+/// [Config::symlinks] is [Symlinks::Follow], otherwise they and other
+/// special files are skipped. Returns the entries written to the index.
+pub fn pack_dir(dir: &Path, to: &Path, config: &Config) -> Result<Vec<PackEntry>> {
+    if !dir.is_dir() {
+        return Err(XcpError::InvalidSource("Pack source must be a directory.").into());
+    }
+    let outfd = File::create(to)?;
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    let walker = WalkDir::new(dir)
+        .follow_links(config.symlinks == Symlinks::Follow)
+        .sort_by_file_name();
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            debug!("Pack: skipping non-file {:?}", entry.path());
+            continue;
+        }
+        let infd = File::open(entry.path())?;
+        let len = infd.metadata()?.len();
+        let mut copied = 0;
+        while copied < len {
+            let bytes = copy_file_bytes(&infd, &outfd, len - copied)? as u64;
+            if bytes == 0 {
+                return Err(XcpError::CopyError(format!("{} was truncated while packing", quote_path(entry.path()))).into());
+            }
+            copied += bytes;
+        }
+        debug!("Packed {:?} at {}+{}", entry.path(), offset, len);
+
+        entries.push(PackEntry {
+            path: entry.path().strip_prefix(dir)?.to_path_buf(),
+            offset,
+            len,
+        });
+        offset += len;
+    }
+    if config.fsync {
+        outfd.sync_all()?;
+    }
+
+    write_index(&index_path(to), &entries)?;
+    Ok(entries)
+}
+
+fn write_index(path: &Path, entries: &[PackEntry]) -> Result<()> {
+    let mut out = Vec::new();
+    writeln!(out, "{}", INDEX_HEADER)?;
+    for e in entries {
+        write!(out, "{} {} ", e.offset, e.len)?;
+        for b in e.path.as_os_str().as_bytes() {
+            match b {
+                b'\n' => out.extend_from_slice(b"\\n"),
+                b'\\' => out.extend_from_slice(b"\\\\"),
+                b => out.push(*b),
+            }
+        }
+        out.push(b'\n');
+    }
+    fs::write(path, out)?;
+    Ok(())
+}
+
+/// Read a pack index written by [pack_dir].
+pub fn read_index(path: &Path) -> Result<Vec<PackEntry>> {
+    let invalid = |msg: &str| XcpError::InvalidArguments(format!("Invalid pack index {}: {}", quote_path(path), msg));
+
+    let mut lines = BufReader::new(File::open(path)?).split(b'\n');
+    match lines.next() {
+        Some(Ok(header)) if header == INDEX_HEADER.as_bytes() => {}
+        _ => return Err(invalid("unknown format").into()),
+    }
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        let mut fields = line.splitn(3, |b| *b == b' ');
+        let mut number = || -> Option<u64> {
+            std::str::from_utf8(fields.next()?).ok()?.parse().ok()
+        };
+        let (offset, len) = match (number(), number()) {
+            (Some(offset), Some(len)) => (offset, len),
+            _ => return Err(invalid("bad entry").into()),
+        };
+        let name = fields.next().ok_or_else(|| invalid("missing path"))?;
+
+        let mut path = Vec::with_capacity(name.len());
+        let mut bytes = name.iter();
+        while let Some(b) = bytes.next() {
+            match (b, bytes.as_slice().first()) {
+                (b'\\', Some(b'n')) => path.push(b'\n'),
+                (b'\\', Some(b'\\')) => path.push(b'\\'),
+                (b'\\', _) => return Err(invalid("bad escape").into()),
+                (b, _) => {
+                    path.push(*b);
+                    continue;
+                }
+            }
+            bytes.next();
+        }
+
+        entries.push(PackEntry {
+            path: PathBuf::from(OsString::from_vec(path)),
+            offset,
+            len,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read};
+    use tempfile::TempDir;
+
+    use crate::ranges::copy_partial;
+
+    #[test]
+    fn test_pack_three_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let source = dir.path().join("source");
+        let pack = dir.path().join("out.pack");
+        create_dir_all(source.join("sub"))?;
+        fs::write(source.join("b.txt"), "second file")?;
+        fs::write(source.join("a.txt"), "first")?;
+        fs::write(source.join("sub/c\nnewline"), vec![0xab; 10_000])?;
+
+        let entries = pack_dir(&source, &pack, &Config::default())?;
+        let expected = vec![
+            PackEntry { path: PathBuf::from("a.txt"), offset: 0, len: 5 },
+            PackEntry { path: PathBuf::from("b.txt"), offset: 5, len: 11 },
+            PackEntry { path: PathBuf::from("sub/c\nnewline"), offset: 16, len: 10_000 },
+        ];
+        assert_eq!(expected, entries);
+        assert_eq!(expected, read_index(&index_path(&pack))?);
+
+        let data = read(&pack)?;
+        assert_eq!(10_016, data.len());
+        assert_eq!(b"firstsecond file", &data[..16]);
+        assert!(data[16..].iter().all(|b| *b == 0xab));
+
+        // Round-trip extraction.
+        let extracted = dir.path().join("b.out");
+        let b = &entries[1];
+        copy_partial(&pack, &extracted, b.offset, b.len, &Config::default())?;
+        assert_eq!(b"second file", read(&extracted)?.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_bad_index() -> Result<()> {
+        let dir = TempDir::new()?;
+        let idx = dir.path().join("bad.idx");
+        for text in ["not a pack\n", "xcp-pack 1\nten 5 file\n", "xcp-pack 1\n0 5\n", "xcp-pack 1\n0 5 bad\\escape\n"] {
+            fs::write(&idx, text)?;
+            assert!(read_index(&idx).is_err(), "Parsed {:?}", text);
+        }
+        Ok(())
+    }
+}
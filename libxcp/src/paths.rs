@@ -14,19 +14,22 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::info;
+use log::{debug, info};
+use regex::Regex;
 use walkdir::DirEntry;
 
-use crate::config::Config;
-use crate::errors::Result;
+use crate::config::{Config, Pattern};
+use crate::errors::{Result, XcpError};
+use crate::quoting::quote_path;
 
 /// Parse a git ignore file.
 pub fn parse_ignore(source: &Path, config: &Config) -> Result<Option<Gitignore>> {
     let gitignore = if config.gitignore {
         let gifile = source.join(".gitignore");
-        info!("Using .gitignore file {:?}", gifile);
+        info!("Using .gitignore file {}", quote_path(&gifile));
         let mut builder = GitignoreBuilder::new(source);
         builder.add(&gifile);
         let ignore = builder.build()?;
@@ -49,3 +52,70 @@ pub fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
         }
     }
 }
+
+/// The [Config::exclude] patterns, compiled for one source.
+pub struct Excludes {
+    root: PathBuf,
+    globs: Option<Gitignore>,
+    regexes: Vec<Regex>,
+}
+
+/// Compile the exclude patterns for copying `source`.
+pub fn parse_excludes(source: &Path, config: &Config) -> Result<Excludes> {
+    let mut builder = GitignoreBuilder::new(source);
+    let mut globs = false;
+    let mut regexes = Vec::new();
+    for pattern in &config.exclude {
+        match pattern {
+            Pattern::Glob(glob) => {
+                builder.add_line(None, glob)?;
+                globs = true;
+            }
+            Pattern::Regex(regex) => regexes.push(regex.clone()),
+        }
+    }
+    let globs = if globs {
+        Some(builder.build()?)
+    } else {
+        None
+    };
+    Ok(Excludes { root: source.to_path_buf(), globs, regexes })
+}
+
+/// Filter to return whether a given entry is not excluded. The root
+/// of the walk is always included.
+pub fn exclude_filter(entry: &DirEntry, excludes: &Excludes) -> bool {
+    if entry.depth() == 0 {
+        return true;
+    }
+    let path = entry.path();
+    let rel = path.strip_prefix(&excludes.root).unwrap_or(path);
+    let excluded = excludes.globs.as_ref()
+        .is_some_and(|gi| gi.matched(rel, entry.file_type().is_dir()).is_ignore())
+        || excludes.regexes.iter().any(|re| re.is_match(&rel.to_string_lossy()));
+    if excluded {
+        debug!("Excluding {:?}", path);
+    }
+    !excluded
+}
+
+/// The path `source` is copied to for the destination `dest`. As with
+/// `cp`, if `dest` is an existing directory the source is copied into
+/// it under its own name, unless [Config::no_target_directory] is
+/// set. When copying a file, a `dest` ending in `/` must be an
+/// existing directory; a directory is copied to a new one.
+pub fn target_path(source: &Path, dest: &Path, config: &Config) -> Result<PathBuf> {
+    let is_dir = dest.is_dir();
+    if !is_dir && dest.as_os_str().as_bytes().ends_with(b"/") && !source.is_dir() {
+        return Err(XcpError::InvalidDestination("Destination ends with '/' but is not a directory.").into());
+    }
+    if is_dir && !config.no_target_directory {
+        let name = source
+            .components()
+            .next_back()
+            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+        Ok(dest.join(name))
+    } else {
+        Ok(dest.to_path_buf())
+    }
+}
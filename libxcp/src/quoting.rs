@@ -0,0 +1,103 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Safe display of paths in user-facing output.
+//!
+//! File names may contain newlines, terminal escape sequences or
+//! bytes that are not valid UTF-8, which can corrupt terminal output
+//! or confuse log parsers. [quote_path] renders a path in double
+//! quotes with such characters escaped, similar to `ls
+//! --quoting-style=c`.
+
+use std::fmt::{self, Display, Formatter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// A path that is escaped when displayed; see [quote_path].
+pub struct QuotedPath<'a>(&'a Path);
+
+/// Wrap a path for safe display. Control characters are written as C
+/// escapes (`\n`, `\t`, `\x1b`, ...), quotes and backslashes are
+/// escaped, and invalid UTF-8 bytes are written as `\xNN`.
+pub fn quote_path<P: AsRef<Path> + ?Sized>(path: &P) -> QuotedPath<'_> {
+    QuotedPath(path.as_ref())
+}
+
+impl Display for QuotedPath<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        let mut bytes = self.0.as_os_str().as_bytes();
+        while !bytes.is_empty() {
+            let (valid, invalid) = match std::str::from_utf8(bytes) {
+                Ok(s) => (s, &[][..]),
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    let bad = e.error_len().unwrap_or(rest.len());
+                    // Safe: checked by from_utf8() above.
+                    (std::str::from_utf8(valid).unwrap(), &rest[..bad])
+                }
+            };
+            for c in valid.chars() {
+                write_char(f, c)?;
+            }
+            for b in invalid {
+                write!(f, "\\x{:02x}", b)?;
+            }
+            bytes = &bytes[valid.len() + invalid.len()..];
+        }
+        f.write_char('"')
+    }
+}
+
+fn write_char(f: &mut Formatter<'_>, c: char) -> fmt::Result {
+    match c {
+        '\n' => f.write_str("\\n"),
+        '\r' => f.write_str("\\r"),
+        '\t' => f.write_str("\\t"),
+        '"' => f.write_str("\\\""),
+        '\\' => f.write_str("\\\\"),
+        c if c.is_control() && (c as u32) < 0x100 => write!(f, "\\x{:02x}", c as u32),
+        c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32),
+        c => f.write_char(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_plain_path() {
+        assert_eq!("\"dir/file.txt\"", quote_path("dir/file.txt").to_string());
+        assert_eq!("\"caf\u{e9}/\u{1f980}\"", quote_path("caf\u{e9}/\u{1f980}").to_string());
+    }
+
+    #[test]
+    fn test_control_chars() {
+        let quoted = quote_path("evil\nname\x1b[31mred\x07").to_string();
+        assert_eq!("\"evil\\nname\\x1b[31mred\\x07\"", quoted);
+        assert!(!quoted.chars().any(|c| c.is_control()));
+
+        assert_eq!("\"a\\\"b\\\\c\\td\\x7f\\x85\"", quote_path("a\"b\\c\td\x7f\u{85}").to_string());
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let path = Path::new(OsStr::from_bytes(b"bad\xff\xfename\xc3"));
+        assert_eq!("\"bad\\xff\\xfename\\xc3\"", quote_path(path).to_string());
+    }
+}
@@ -0,0 +1,344 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copy operations on byte ranges within files.
+//!
+//! These are intended for tools that manage file layout themselves
+//! (e.g. sparse or patched files), and use the same fallback chain as
+//! whole-file copies.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+use libfs::{reflink_range, sync};
+use log::debug;
+
+use crate::config::{Config, Reflink};
+use crate::errors::{Result, XcpError};
+use crate::membudget;
+use crate::quoting::quote_path;
+
+/// Copy `len` bytes from offset `src_offset` in `infd` to offset
+/// `dst_offset` in `outfd`, returning the number of bytes copied.
+///
+/// Depending on [Config::reflink] this first attempts to reflink the
+/// range, then falls back to `copy_file_range` where available, and
+/// finally to a user-space copy.
+///
+/// Caveats:
+///
+/// * Reflinking requires the offsets and length to be aligned to the
+///   filesystem block size. Unaligned ranges fall back to a data copy,
+///   or return an error with [Reflink::Always].
+/// * Overlapping ranges within the same file are not supported, and
+///   return an error, as do ranges whose end overflows a `u64`.
+/// * The destination is extended if the range ends beyond its current
+///   length; it is never truncated.
+pub fn copy_range(infd: &File, outfd: &File, src_offset: u64, dst_offset: u64, len: u64, config: &Config) -> Result<u64> {
+    if len == 0 {
+        return Ok(0);
+    }
+    if ranges_overlap(infd, outfd, src_offset, dst_offset, len)? {
+        return Err(XcpError::InvalidArguments("Source and destination ranges overlap within the same file".to_string()).into());
+    }
+
+    if config.reflink != Reflink::Never {
+        if reflink_range(infd, outfd, src_offset, dst_offset, len)? {
+            debug!("Reflinked range {}+{} -> {}", src_offset, len, dst_offset);
+            return Ok(len);
+        } else if config.reflink == Reflink::Always {
+            return Err(XcpError::ReflinkFailed(format!("{:?}->{:?}", infd, outfd)).into());
+        }
+    }
+
+    Ok(libfs::copy_range(infd, outfd, src_offset, dst_offset, len)?)
+}
+
+/// Copy `len` bytes starting at `src_offset` in the file `from` to a
+/// new file `to`, which will contain exactly those bytes; e.g. to
+/// extract a partition from a disk image. This uses [copy_range], so
+/// the same fallbacks and caveats apply.
+///
+/// Returns an error if the range extends beyond the end of the
+/// source. An existing destination is truncated, unless
+/// [Config::no_clobber] is set, in which case it is an error.
+pub fn copy_partial(from: &Path, to: &Path, src_offset: u64, len: u64, config: &Config) -> Result<u64> {
+    let infd = File::open(from)?;
+    let size = infd.metadata()?.len();
+    match src_offset.checked_add(len) {
+        Some(end) if end <= size => {}
+        _ => {
+            return Err(XcpError::InvalidArguments(format!(
+                "Range {}+{} exceeds the size of {} ({} bytes)", src_offset, len, quote_path(from), size)).into());
+        }
+    }
+
+    let outfd = if config.no_clobber {
+        match OpenOptions::new().write(true).create_new(true).open(to) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to.to_path_buf()).into());
+            }
+            r => r?,
+        }
+    } else {
+        File::create(to)?
+    };
+
+    let copied = copy_range(&infd, &outfd, src_offset, 0, len, config)?;
+    if config.fsync {
+        sync(&outfd)?;
+    }
+    Ok(copied)
+}
+
+/// Maximum buffer used by [broadcast_range].
+const BROADCAST_BUFFER: u64 = 1024 * 1024;
+
+/// Copy `len` bytes from offset `src_offset` in `infd` to each of
+/// `dst_offsets` in `outfd`, e.g. to write a boot sector to several
+/// locations in a disk image. The source is read once, in blocks of
+/// up to [Config::block_size] (capped at 1MB and
+/// [Config::max_buffer_memory]), and each block is
+/// written to every destination; reflinks are not attempted. Returns
+/// the total number of bytes written.
+///
+/// Destination ranges must not overlap each other, or the source
+/// range if the files are the same, and the source range must be
+/// within the file; otherwise an error is returned before anything is
+/// written. The destination is extended as necessary.
+pub fn broadcast_range(infd: &File, outfd: &File, src_offset: u64, len: u64, dst_offsets: &[u64], config: &Config) -> Result<u64> {
+    if len == 0 || dst_offsets.is_empty() {
+        return Ok(0);
+    }
+    match src_offset.checked_add(len) {
+        Some(end) if end <= infd.metadata()?.len() => {}
+        _ => return Err(XcpError::InvalidSource("Source range extends beyond the end of the file.").into()),
+    }
+
+    let mut sorted = dst_offsets.to_vec();
+    sorted.sort_unstable();
+    for pair in sorted.windows(2) {
+        if pair[0].checked_add(len).map_or(true, |end| end > pair[1]) {
+            return Err(XcpError::InvalidArguments(format!(
+                "Destination ranges at {} and {} overlap", pair[0], pair[1])).into());
+        }
+    }
+    for off in &sorted {
+        if off.checked_add(len).is_none() {
+            return Err(XcpError::InvalidArguments(format!("Destination range at {} overflows", off)).into());
+        }
+        if ranges_overlap(infd, outfd, src_offset, *off, len)? {
+            return Err(XcpError::InvalidArguments("Source and destination ranges overlap within the same file".to_string()).into());
+        }
+    }
+
+    let bufsize = membudget::buffer_size(cmp::min(cmp::min(config.block_size, BROADCAST_BUFFER), len), config);
+    let _mem = membudget::reserve(bufsize, config);
+    let mut buf = vec![0; bufsize as usize];
+    let mut pos = 0;
+    while pos < len {
+        let chunk_len = cmp::min(len - pos, buf.len() as u64) as usize;
+        let chunk = &mut buf[..chunk_len];
+        infd.read_exact_at(chunk, src_offset + pos)?;
+        for off in &sorted {
+            outfd.write_all_at(chunk, off + pos)?;
+        }
+        pos += chunk.len() as u64;
+    }
+    debug!("Broadcast {}+{} to {} offsets", src_offset, len, sorted.len());
+
+    Ok(len * sorted.len() as u64)
+}
+
+fn ranges_overlap(infd: &File, outfd: &File, src_offset: u64, dst_offset: u64, len: u64) -> Result<bool> {
+    let inmeta = infd.metadata()?;
+    let outmeta = outfd.metadata()?;
+    let same_file = inmeta.dev() == outmeta.dev() && inmeta.ino() == outmeta.ino();
+    let (src_end, dst_end) = match (src_offset.checked_add(len), dst_offset.checked_add(len)) {
+        (Some(src_end), Some(dst_end)) => (src_end, dst_end),
+        _ => {
+            return Err(XcpError::InvalidArguments(format!(
+                "Range of {} bytes at {} -> {} overflows", len, src_offset, dst_offset)).into());
+        }
+    };
+    Ok(same_file && src_offset < dst_end && dst_offset < src_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, OpenOptions};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_copy_middle_range() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data = pattern(16 * 1024);
+        File::create(&from)?.write_all(&data)?;
+        File::create(&to)?.write_all(&[b'z'; 16 * 1024])?;
+
+        {
+            let infd = File::open(&from)?;
+            let outfd = OpenOptions::new().write(true).open(&to)?;
+            let copied = copy_range(&infd, &outfd, 4096, 8192, 4096, &Config::default())?;
+            assert_eq!(4096, copied);
+        }
+
+        let result = read(&to)?;
+        assert_eq!(16 * 1024, result.len());
+        assert!(result[..8192].iter().all(|b| *b == b'z'));
+        assert_eq!(&data[4096..8192], &result[8192..12288]);
+        assert!(result[12288..].iter().all(|b| *b == b'z'));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_range_overlap() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.path().join("file.bin");
+        File::create(&file)?.write_all(&pattern(8192))?;
+
+        let infd = File::open(&file)?;
+        let outfd = OpenOptions::new().write(true).open(&file)?;
+        assert!(copy_range(&infd, &outfd, 0, 1024, 4096, &Config::default()).is_err());
+        assert_eq!(4096, copy_range(&infd, &outfd, 0, 4096, 4096, &Config::default())?);
+
+        let err = copy_range(&infd, &outfd, u64::MAX - 100, 0, 4096, &Config::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidArguments(_))));
+        let err = copy_range(&infd, &outfd, 0, u64::MAX - 100, 4096, &Config::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidArguments(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_range_past_eof() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        File::create(&from)?.write_all(&pattern(4096))?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        assert!(copy_range(&infd, &outfd, 2048, 0, 4096, &Config::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_partial() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("image.bin");
+        let to = dir.path().join("part.bin");
+        let data = pattern(64 * 1024);
+        File::create(&from)?.write_all(&data)?;
+        File::create(&to)?.write_all(&[b'z'; 128 * 1024])?;
+
+        let copied = copy_partial(&from, &to, 10_000, 20_000, &Config::default())?;
+        assert_eq!(20_000, copied);
+        assert_eq!(&data[10_000..30_000], read(&to)?.as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_partial_out_of_range() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("image.bin");
+        let to = dir.path().join("part.bin");
+        File::create(&from)?.write_all(&pattern(4096))?;
+
+        for (offset, len) in [(4000, 100), (5000, 1), (1, u64::MAX)] {
+            let err = copy_partial(&from, &to, offset, len, &Config::default()).unwrap_err();
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidArguments(_))));
+        }
+        assert!(!to.exists());
+
+        assert_eq!(96, copy_partial(&from, &to, 4000, 96, &Config::default())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_range() -> Result<()> {
+        let dir = TempDir::new()?;
+        let from = dir.path().join("boot.bin");
+        let to = dir.path().join("image.bin");
+        let data = pattern(4096);
+        File::create(&from)?.write_all(&data)?;
+        File::create(&to)?.write_all(&[b'z'; 4096])?;
+
+        let offsets = [0, 64 * 1024, 1024 * 1024];
+        let config = Config {
+            block_size: 100,
+            ..Config::default()
+        };
+        {
+            let infd = File::open(&from)?;
+            let outfd = OpenOptions::new().write(true).open(&to)?;
+            assert_eq!(3 * 512, broadcast_range(&infd, &outfd, 1024, 512, &offsets, &config)?);
+        }
+
+        let result = read(&to)?;
+        assert_eq!(1024 * 1024 + 512, result.len());
+        for off in offsets {
+            let off = off as usize;
+            assert_eq!(&data[1024..1536], &result[off..off + 512]);
+        }
+        assert!(result[512..4096].iter().all(|b| *b == b'z'));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_range_overlap() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.path().join("image.bin");
+        File::create(&file)?.write_all(&pattern(8192))?;
+        let infd = File::open(&file)?;
+        let outfd = OpenOptions::new().write(true).open(&file)?;
+        let config = Config::default();
+
+        // Destinations overlapping each other.
+        let err = broadcast_range(&infd, &outfd, 0, 512, &[8192, 4096, 4096 + 511], &config).unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::InvalidArguments(_))));
+        // Destination overlapping the source.
+        assert!(broadcast_range(&infd, &outfd, 0, 512, &[4096, 256], &config).is_err());
+        // Source beyond EOF.
+        assert!(broadcast_range(&infd, &outfd, 8000, 512, &[16384], &config).is_err());
+        assert_eq!(8192, infd.metadata()?.len());
+
+        // Adjacent ranges are fine.
+        assert_eq!(1024, broadcast_range(&infd, &outfd, 0, 512, &[4096, 4608], &config)?);
+        let result = read(&file)?;
+        assert_eq!(&result[..512], &result[4096..4608]);
+        assert_eq!(&result[..512], &result[4608..5120]);
+
+        Ok(())
+    }
+}
+
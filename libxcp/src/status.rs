@@ -0,0 +1,324 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Copy progress persisted to a status file.
+//!
+//! [StatusFileUpdater] wraps another [StatusUpdater], aggregating the
+//! updates and periodically replacing the status file with the
+//! current totals. Another process can then monitor the copy with
+//! [read_status]. The file is replaced atomically, so readers never
+//! see a partial write. It is a text file of `key=value` lines:
+//!
+//! ```text
+//! xcp-status 1
+//! pid=1234
+//! state=running
+//! updated=1718000000
+//! total=1048576
+//! copied=524288
+//! files=3
+//! skipped=0
+//! errors=0
+//! ```
+//!
+//! If the copying process dies the file is left in the `running`
+//! state; [CopyStatus::is_stale] detects this.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use rustix::process::{test_kill_process, Pid};
+use rustix::io::Errno;
+
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+use crate::quoting::quote_path;
+
+const STATUS_HEADER: &str = "xcp-status 1";
+
+/// Whether the copy recorded in a status file is still running.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RunState {
+    #[default]
+    Running,
+    Finished,
+}
+
+/// Aggregate copy progress, as recorded in a status file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyStatus {
+    /// The process performing the copy.
+    pub pid: u32,
+    pub state: RunState,
+    /// When the status was written, in seconds since the Unix epoch.
+    pub updated: u64,
+    /// Total bytes to copy, as discovered so far.
+    pub total: u64,
+    pub copied: u64,
+    pub files: u64,
+    pub skipped: u64,
+    pub errors: u64,
+}
+
+impl CopyStatus {
+    fn update(&mut self, update: &StatusUpdate) {
+        match update {
+            StatusUpdate::Started(_) => {}
+            StatusUpdate::Copied(v) => self.copied += v,
+            StatusUpdate::Size(v) => self.total += v,
+            StatusUpdate::Skipped(_) => self.skipped += 1,
+            StatusUpdate::Completed { .. } => self.files += 1,
+            StatusUpdate::Error(_) => self.errors += 1,
+        }
+    }
+
+    /// Whether the status is from a run that ended without
+    /// finishing, i.e. the recorded process no longer exists.
+    pub fn is_stale(&self) -> bool {
+        self.state == RunState::Running && !process_exists(self.pid)
+    }
+
+    fn render(&self) -> String {
+        let state = match self.state {
+            RunState::Running => "running",
+            RunState::Finished => "finished",
+        };
+        format!("{}\npid={}\nstate={}\nupdated={}\ntotal={}\ncopied={}\nfiles={}\nskipped={}\nerrors={}\n",
+                STATUS_HEADER, self.pid, state, self.updated,
+                self.total, self.copied, self.files, self.skipped, self.errors)
+    }
+}
+
+fn process_exists(pid: u32) -> bool {
+    let pid = match i32::try_from(pid).ok().and_then(Pid::from_raw) {
+        Some(pid) => pid,
+        None => return false,
+    };
+    // EPERM means it exists but belongs to another user.
+    matches!(test_kill_process(pid), Ok(()) | Err(Errno::PERM))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Replace the file via a rename so readers never see a partial write.
+fn write_atomic(path: &Path, status: &CopyStatus) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp.{}", status.pid));
+    let tmp = PathBuf::from(tmp);
+
+    let mut fd = fs::File::create(&tmp)?;
+    fd.write_all(status.render().as_bytes())?;
+    drop(fd);
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+struct WriterState {
+    status: CopyStatus,
+    last_write: Instant,
+}
+
+/// A [StatusUpdater] that records aggregate progress in a status
+/// file, at most once per `interval`, and forwards all updates to
+/// another updater. The final state is written when the updater is
+/// dropped, i.e. once the driver has finished with it.
+pub struct StatusFileUpdater {
+    inner: Arc<dyn StatusUpdater>,
+    path: PathBuf,
+    interval: Duration,
+    state: Mutex<WriterState>,
+}
+
+impl StatusFileUpdater {
+    /// Create the updater, writing an initial status to `path`.
+    pub fn new(path: &Path, inner: Arc<dyn StatusUpdater>, interval: Duration) -> Result<StatusFileUpdater> {
+        let status = CopyStatus {
+            pid: std::process::id(),
+            updated: unix_now(),
+            ..CopyStatus::default()
+        };
+        write_atomic(path, &status)?;
+        Ok(StatusFileUpdater {
+            inner,
+            path: path.to_path_buf(),
+            interval,
+            state: Mutex::new(WriterState {
+                status,
+                last_write: Instant::now(),
+            }),
+        })
+    }
+
+    fn record(&self, update: &StatusUpdate) -> Result<()> {
+        let mut state = self.state.lock()
+            .map_err(|_| XcpError::CopyError("Status file lock poisoned".to_string()))?;
+        state.status.update(update);
+        if state.last_write.elapsed() >= self.interval {
+            state.status.updated = unix_now();
+            write_atomic(&self.path, &state.status)?;
+            state.last_write = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl StatusUpdater for StatusFileUpdater {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        self.record(&update)?;
+        self.inner.send(update)
+    }
+
+    fn tick(&self) -> Result<()> {
+        self.inner.tick()
+    }
+}
+
+impl Drop for StatusFileUpdater {
+    fn drop(&mut self) {
+        let state = match self.state.get_mut() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        state.status.state = RunState::Finished;
+        state.status.updated = unix_now();
+        if let Err(e) = write_atomic(&self.path, &state.status) {
+            error!("Failed to write status file {}: {}", quote_path(&self.path), e);
+        }
+    }
+}
+
+/// Read a status file written by [StatusFileUpdater].
+pub fn read_status(path: &Path) -> Result<CopyStatus> {
+    let invalid = |msg: &str| XcpError::InvalidArguments(format!("Invalid status file {}: {}", quote_path(path), msg));
+
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines();
+    if lines.next() != Some(STATUS_HEADER) {
+        return Err(invalid("unknown format").into());
+    }
+
+    let mut status = CopyStatus::default();
+    for line in lines {
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid("bad line"))?;
+        if key == "state" {
+            status.state = match value {
+                "running" => RunState::Running,
+                "finished" => RunState::Finished,
+                _ => return Err(invalid("unknown state").into()),
+            };
+            continue;
+        }
+        let number = value.parse::<u64>().map_err(|_| invalid("bad number"))?;
+        match key {
+            "pid" => status.pid = u32::try_from(number).map_err(|_| invalid("bad pid"))?,
+            "updated" => status.updated = number,
+            "total" => status.total = number,
+            "copied" => status.copied = number,
+            "files" => status.files = number,
+            "skipped" => status.skipped = number,
+            "errors" => status.errors = number,
+            // Allow for additions.
+            _ => {}
+        }
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    use crate::feedback::NoopUpdater;
+
+    #[test]
+    fn test_status_file_reflects_progress() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("status");
+
+        let updater = StatusFileUpdater::new(&path, Arc::new(NoopUpdater), Duration::ZERO)?;
+        let initial = read_status(&path)?;
+        assert_eq!(std::process::id(), initial.pid);
+        assert_eq!(RunState::Running, initial.state);
+        assert_eq!(0, initial.total);
+
+        updater.send(StatusUpdate::Size(1000))?;
+        updater.send(StatusUpdate::Copied(400))?;
+        updater.send(StatusUpdate::Completed { reflinked: false })?;
+        updater.send(StatusUpdate::Skipped(PathBuf::from("other")))?;
+
+        let status = read_status(&path)?;
+        assert_eq!((1000, 400, 1, 1, 0), (status.total, status.copied, status.files, status.skipped, status.errors));
+        assert!(!status.is_stale());
+
+        drop(updater);
+        let status = read_status(&path)?;
+        assert_eq!(RunState::Finished, status.state);
+        assert_eq!(400, status.copied);
+        assert!(!status.is_stale());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_file_interval() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("status");
+
+        let updater = StatusFileUpdater::new(&path, Arc::new(NoopUpdater), Duration::from_secs(3600))?;
+        updater.send(StatusUpdate::Copied(400))?;
+        assert_eq!(0, read_status(&path)?.copied);
+        drop(updater);
+        assert_eq!(400, read_status(&path)?.copied);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_status() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("status");
+
+        // A process that has exited.
+        let mut child = Command::new("true").spawn()?;
+        let pid = child.id();
+        child.wait()?;
+
+        let status = CopyStatus { pid, copied: 10, ..CopyStatus::default() };
+        write_atomic(&path, &status)?;
+        let read = read_status(&path)?;
+        assert_eq!(status, read);
+        assert!(read.is_stale());
+
+        for text in ["", "not-status 1\n", "xcp-status 1\npid=abc\n", "xcp-status 1\nstate=paused\n", "xcp-status 1\nnokey\n"] {
+            fs::write(&path, text)?;
+            assert!(read_status(&path).is_err(), "Parsed {:?}", text);
+        }
+
+        Ok(())
+    }
+}
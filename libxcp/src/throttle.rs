@@ -0,0 +1,140 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! A process-wide limit on copy throughput.
+//!
+//! When [Config::max_bytes_per_sec](crate::config::Config::max_bytes_per_sec)
+//! is set, workers charge the bytes they copy to a shared token
+//! bucket and sleep off any deficit, so the limit applies to the copy
+//! as a whole rather than to each file. The bucket holds at most one
+//! second of tokens. Without a limit no accounting is done.
+
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+#[derive(Debug)]
+struct State {
+    /// Available tokens, in bytes; negative when workers have taken
+    /// more than has accrued and are sleeping it off.
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket over bytes copied.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    state: Mutex<State>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle {
+            state: Mutex::new(State {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Throttle {
+    /// Take `bytes` tokens at `rate` bytes per second, sleeping until
+    /// they have accrued.
+    pub(crate) fn take(&self, bytes: u64, rate: u64) {
+        let rate = rate.max(1) as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let accrued = now.duration_since(state.last).as_secs_f64() * rate;
+            state.tokens = (state.tokens + accrued).min(rate) - bytes as f64;
+            state.last = now;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate)
+        };
+        thread::sleep(wait);
+    }
+}
+
+/// The throttle shared by all copy operations in this process.
+pub(crate) fn global() -> &'static Throttle {
+    static THROTTLE: OnceLock<Throttle> = OnceLock::new();
+    THROTTLE.get_or_init(Throttle::default)
+}
+
+/// The size of the next chunk to copy, of up to `want` bytes; with a
+/// limit this is no more than a second's worth, to keep the rate
+/// smooth.
+pub(crate) fn chunk_size(want: u64, config: &Config) -> u64 {
+    match config.max_bytes_per_sec {
+        Some(rate) => want.min(rate.max(1)),
+        None => want,
+    }
+}
+
+/// Charge `bytes` copied to the global throttle, if a limit is
+/// configured.
+pub(crate) fn throttle(bytes: u64, config: &Config) {
+    if let Some(rate) = config.max_bytes_per_sec {
+        global().take(bytes, rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_rate_is_shared() {
+        let throttle = Arc::new(Throttle::default());
+        let rate = 10 * 1024 * 1024;
+
+        // 2MB from four threads at 10MB/s takes at least 200ms.
+        let start = Instant::now();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let throttle = throttle.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        throttle.take(100 * 1024, rate);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(190), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        let unlimited = Config::default();
+        assert_eq!(u64::MAX, chunk_size(u64::MAX, &unlimited));
+
+        let limited = Config {
+            max_bytes_per_sec: Some(4096),
+            ..Config::default()
+        };
+        assert_eq!(4096, chunk_size(u64::MAX, &limited));
+        assert_eq!(100, chunk_size(100, &limited));
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Verification of copies by re-reading them; see [Config::verify].
+
+use std::cmp;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use libfs::{data_ranges, open_direct, probably_sparse, AlignedBuf, DIRECT_IO_ALIGN};
+use log::debug;
+
+use crate::config::{Config, Verify};
+use crate::errors::{Result, XcpError};
+use crate::hash::{Digest, Hasher};
+use crate::membudget;
+
+/// Maximum size of the verification reads; a multiple of
+/// [DIRECT_IO_ALIGN].
+const VERIFY_BUFFER: usize = 1024 * 1024;
+
+/// Maximum size of the reads for [Verify::Hash]; otherwise the block
+/// size is used.
+const MAX_HASH_BUFFER: u64 = 16 * 1024 * 1024;
+
+/// Compare the copied file `to` with the source `infd`, as set by
+/// [Config::verify].
+pub(crate) fn verify_copy(infd: &File, to: &Path, config: &Config) -> Result<()> {
+    if config.verify == Verify::Off {
+        return Ok(());
+    }
+    debug!("Verifying {:?} ({:?})", to, config.verify);
+    if config.verify == Verify::Hash {
+        let bufsize = membudget::buffer_size(config.block_size.clamp(1, MAX_HASH_BUFFER), config);
+        let _mem = membudget::reserve(bufsize, config);
+        if !hashes_match(infd, &File::open(to)?, bufsize as usize)? {
+            return Err(XcpError::VerificationFailed(to.to_path_buf()).into());
+        }
+        return Ok(());
+    }
+    let (outfd, direct) = open_dest(to, config.verify == Verify::Direct)?;
+    // Two buffers; keep them aligned for direct reads.
+    let bufsize = membudget::buffer_size(2 * VERIFY_BUFFER as u64, config) as usize / 2;
+    let bufsize = cmp::max(bufsize - bufsize % DIRECT_IO_ALIGN, DIRECT_IO_ALIGN);
+    let _mem = membudget::reserve(2 * bufsize as u64, config);
+    if !contents_match(infd, &outfd, direct, bufsize)? {
+        return Err(XcpError::VerificationFailed(to.to_path_buf()).into());
+    }
+    Ok(())
+}
+
+fn open_dest(to: &Path, direct: bool) -> Result<(File, bool)> {
+    if direct {
+        if let Some(fd) = open_direct(to)? {
+            return Ok((fd, true));
+        }
+        debug!("Direct I/O not supported for {:?}, verifying through the cache", to);
+    }
+    Ok((File::open(to)?, false))
+}
+
+// Read at `off` until `buf` is full or EOF. Direct reads only stop
+// short at EOF, after which the offset is unaligned.
+fn read_full(fd: &File, buf: &mut [u8], off: u64, direct: bool) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match fd.read_at(&mut buf[n..], off + n as u64) {
+            Ok(0) => break,
+            Ok(r) => {
+                n += r;
+                if direct && n % DIRECT_IO_ALIGN != 0 {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(n)
+}
+
+fn contents_match(infd: &File, outfd: &File, direct: bool, bufsize: usize) -> Result<bool> {
+    if infd.metadata()?.len() != outfd.metadata()?.len() {
+        return Ok(false);
+    }
+    let mut inbuf = vec![0; bufsize];
+    let mut outbuf = AlignedBuf::new(bufsize);
+    let mut off = 0;
+    loop {
+        let outlen = read_full(outfd, outbuf.as_mut_slice(), off, direct)?;
+        let inlen = read_full(infd, &mut inbuf, off, false)?;
+        if inlen != outlen || inbuf[..inlen] != outbuf.as_slice()[..outlen] {
+            debug!("Verification mismatch at block offset {}", off);
+            return Ok(false);
+        }
+        if inlen < bufsize {
+            return Ok(true);
+        }
+        off += inlen as u64;
+    }
+}
+
+/// Whether the first `len` bytes of `outfd` match `infd`, e.g. the
+/// part of an interrupted copy that is to be kept when resuming. This
+/// is checked as for [Config::verify], by hash or by comparing the
+/// data read through the cache; with verification off the prefix is
+/// assumed to match.
+pub(crate) fn prefix_matches(infd: &File, outfd: &File, len: u64, config: &Config) -> Result<bool> {
+    if config.verify == Verify::Off {
+        return Ok(true);
+    }
+    debug!("Checking the first {} bytes of {:?} ({:?})", len, outfd, config.verify);
+    let ranges = compared_ranges(infd, outfd, len)?;
+    let bufsize = membudget::buffer_size(cmp::min(VERIFY_BUFFER as u64, len.max(1)), config);
+    let _mem = membudget::reserve(2 * bufsize, config);
+    let mut buf = vec![0; bufsize as usize];
+    if config.verify == Verify::Hash {
+        return Ok(hash_ranges(infd, &ranges, &mut buf)? == hash_ranges(outfd, &ranges, &mut buf)?);
+    }
+    let mut outbuf = vec![0; bufsize as usize];
+    for range in ranges {
+        let mut off = range.start;
+        while off < range.end {
+            let n = cmp::min(range.end - off, bufsize) as usize;
+            infd.read_exact_at(&mut buf[..n], off)?;
+            outfd.read_exact_at(&mut outbuf[..n], off)?;
+            if buf[..n] != outbuf[..n] {
+                debug!("Prefix mismatch at block offset {}", off);
+                return Ok(false);
+            }
+            off += n as u64;
+        }
+    }
+    Ok(true)
+}
+
+// The regions of the first `len` bytes to compare; those that are
+// holes in both files read as zeros in both, so are skipped.
+fn compared_ranges(infd: &File, outfd: &File, len: u64) -> Result<Vec<Range<u64>>> {
+    if probably_sparse(infd)? || probably_sparse(outfd)? {
+        if let (Some(a), Some(b)) = (data_ranges(infd, len)?, data_ranges(outfd, len)?) {
+            return Ok(union(a, b));
+        }
+    }
+    Ok(vec![Range { start: 0, end: len }])
+}
+
+fn hashes_match(infd: &File, outfd: &File, bufsize: usize) -> Result<bool> {
+    let len = infd.metadata()?.len();
+    if outfd.metadata()?.len() != len {
+        return Ok(false);
+    }
+    let ranges = compared_ranges(infd, outfd, len)?;
+
+    let mut buf = vec![0; bufsize];
+    let expected = hash_ranges(infd, &ranges, &mut buf)?;
+    let actual = hash_ranges(outfd, &ranges, &mut buf)?;
+    if expected != actual {
+        debug!("Verification hash mismatch: {} != {}", expected, actual);
+    }
+    Ok(expected == actual)
+}
+
+fn hash_ranges(fd: &File, ranges: &[Range<u64>], buf: &mut [u8]) -> Result<Digest> {
+    let mut hasher = Hasher::new();
+    for range in ranges {
+        let mut off = range.start;
+        while off < range.end {
+            let n = cmp::min(range.end - off, buf.len() as u64) as usize;
+            fd.read_exact_at(&mut buf[..n], off)?;
+            hasher.update(&buf[..n]);
+            off += n as u64;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+// Sorted, non-overlapping ranges covering both `a` and `b`.
+fn union(mut a: Vec<Range<u64>>, b: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    a.extend(b);
+    a.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(a.len());
+    for r in a {
+        match merged.last_mut() {
+            Some(last) if last.end >= r.start => last.end = cmp::max(last.end, r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn config(verify: Verify) -> Config {
+        Config {
+            verify,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_unaligned_length() -> Result<()> {
+        // The tempdir default may be tmpfs, which has no O_DIRECT.
+        let dir = TempDir::new_in(".")?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let data: Vec<u8> = (0..(2 * VERIFY_BUFFER + 123)).map(|i| (i % 251) as u8).collect();
+        fs::write(&from, &data)?;
+        fs::write(&to, &data)?;
+
+        let infd = File::open(&from)?;
+        for verify in [Verify::Off, Verify::Cached, Verify::Direct] {
+            verify_copy(&infd, &to, &config(verify))?;
+        }
+
+        let (outfd, direct) = open_dest(&to, true)?;
+        if direct {
+            // Direct reads need an aligned buffer; a misaligned one is
+            // rejected.
+            let mut buf = vec![0; DIRECT_IO_ALIGN * 2 + 1];
+            let off = buf.as_ptr().align_offset(DIRECT_IO_ALIGN) + 1;
+            assert!(outfd.read_at(&mut buf[off..off + DIRECT_IO_ALIGN], 0).is_err());
+            assert_eq!(123, read_full(&outfd, AlignedBuf::new(4096).as_mut_slice(), 2 * VERIFY_BUFFER as u64, true)?);
+        }
+        assert!(contents_match(&infd, &outfd, direct, VERIFY_BUFFER)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_mismatch() -> Result<()> {
+        let dir = TempDir::new_in(".")?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mut data = vec![0xcc; VERIFY_BUFFER + 5000];
+        fs::write(&from, &data)?;
+        data[VERIFY_BUFFER + 10] = 0;
+        fs::write(&to, &data)?;
+
+        let infd = File::open(&from)?;
+        verify_copy(&infd, &to, &config(Verify::Off))?;
+        for verify in [Verify::Cached, Verify::Direct] {
+            let err = verify_copy(&infd, &to, &config(verify)).unwrap_err();
+            assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::VerificationFailed(_))));
+        }
+
+        // Length differences are also caught.
+        fs::write(&to, &data[..100])?;
+        assert!(verify_copy(&infd, &to, &config(Verify::Direct)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_hash_sparse() -> Result<()> {
+        let dir = TempDir::new_in(".")?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        let mb = 1024 * 1024;
+        let data = vec![0xcc; 64 * 1024];
+
+        File::create(&from)?.set_len(4 * mb)?;
+        File::options().write(true).open(&from)?.write_all_at(&data, mb)?;
+        // A dense copy matches its sparse source.
+        let mut dense = vec![0; 4 * mb as usize];
+        dense[mb as usize..mb as usize + data.len()].copy_from_slice(&data);
+        fs::write(&to, &dense)?;
+
+        let infd = File::open(&from)?;
+        verify_copy(&infd, &to, &config(Verify::Hash))?;
+
+        // Data where the source has a hole.
+        File::options().write(true).open(&to)?.write_all_at(b"x", 3 * mb)?;
+        let err = verify_copy(&infd, &to, &config(Verify::Hash)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<XcpError>(), Some(XcpError::VerificationFailed(_))));
+
+        // A sparse copy with differing data.
+        File::create(&to)?.set_len(4 * mb)?;
+        File::options().write(true).open(&to)?.write_all_at(&data[1..], mb + 1)?;
+        assert!(verify_copy(&infd, &to, &config(Verify::Hash)).is_err());
+        File::options().write(true).open(&to)?.write_all_at(&data[..1], mb)?;
+        verify_copy(&infd, &to, &config(Verify::Hash))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_union() {
+        assert_eq!(vec![0..10, 20..40], union(vec![0..5, 20..30], vec![3..10, 25..40]));
+        assert_eq!(vec![0..10, 20..25], union(vec![0..5, 20..25], vec![5..10, 22..23]));
+        assert_eq!(vec![0..5, 8..9], union(vec![], vec![0..5, 8..9]));
+    }
+}
@@ -14,29 +14,46 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+mod metrics;
 mod options;
 mod progress;
 
-use std::path::PathBuf;
-use std::{result, thread};
+use std::path::{Path, PathBuf};
+use std::{process, result, thread};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use glob::{glob, Paths};
+use indicatif::HumanBytes;
+use libfs::{filesystem_type, is_devnull};
 use libxcp::config::{Config, Reflink};
 use libxcp::drivers::load_driver;
 use libxcp::errors::{Result, XcpError};
 use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
-use log::{error, info, warn};
+use libxcp::pack::{index_path, pack_dir};
+use libxcp::quoting::quote_path;
+use libxcp::status::{read_status, CopyStatus, RunState, StatusFileUpdater};
+use log::{error, info, log_enabled, warn, Level};
 
-use crate::options::Opts;
+use crate::options::{ExitCodes, Opts};
+use crate::progress::Progress;
+
+/// How often the --status-file is rewritten.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
 
 fn init_logging(opts: &Opts) -> Result<()> {
     use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
 
+    // Keep stdout for the JSON records.
+    let mode = if opts.json {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
     TermLogger::init(
         opts.log_level(),
         Config::default(),
-        TerminalMode::Mixed,
+        mode,
         ColorChoice::Auto,
     ).or_else(
         |_| SimpleLogger::init(opts.log_level(), Config::default())
@@ -75,6 +92,98 @@ fn expand_sources(source_list: &[String], opts: &Opts) -> Result<Vec<PathBuf>> {
     }
 }
 
+// Summary of read throughput when copying to /dev/null.
+fn read_summary(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { (bytes as f64 / secs) as u64 } else { bytes };
+    format!("Read {} bytes ({}) in {:.2}s, {}/s",
+            bytes, HumanBytes(bytes), secs, HumanBytes(rate))
+}
+
+/// Report the filesystem types involved, to help diagnose why
+/// reflinks or accelerated copies weren't used.
+fn log_filesystems(sources: &[PathBuf], dest: &Path) {
+    // The destination may not exist yet.
+    let dest_fs = dest.ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+    for path in sources.iter().map(PathBuf::as_path).chain([dest_fs]) {
+        match filesystem_type(path) {
+            Ok(Some(fstype)) => info!("Filesystem of {} is {}", quote_path(path), fstype),
+            Ok(None) => {}
+            Err(e) => info!("Failed to find filesystem of {}: {}", quote_path(path), e),
+        }
+    }
+}
+
+// Final totals for --summary. The rate is of the total size, as for
+// the progress bar.
+fn copy_summary(progress: &Progress, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let rate = if secs > 0.0 { (progress.total as f64 / secs) as u64 } else { progress.total };
+    format!("Copied {} files, {} bytes ({}), {} bytes written ({}) in {:.2}s, {}/s",
+            progress.files, progress.total, HumanBytes(progress.total),
+            progress.copied, HumanBytes(progress.copied), secs, HumanBytes(rate))
+}
+
+fn coverage_summary(progress: &Progress) -> String {
+    let total = progress.files + progress.skipped;
+    let pct = if total == 0 {
+        100.0
+    } else {
+        progress.skipped as f64 * 100.0 / total as f64
+    };
+    format!("Copied {} files, skipped {} already present ({:.1}% coverage)",
+            progress.files, progress.skipped, pct)
+}
+
+// The exit status of a --keep-going run, from the collected outcomes.
+fn exit_code(progress: &Progress, codes: &ExitCodes) -> i32 {
+    if progress.errors > 0 {
+        codes.errors as i32
+    } else if progress.skipped > 0 {
+        codes.skipped as i32
+    } else {
+        0
+    }
+}
+
+fn status_summary(status: &CopyStatus, now: u64) -> String {
+    let state = match status.state {
+        _ if status.is_stale() => format!("Stale (process {} has exited)", status.pid),
+        RunState::Running => format!("Running (process {})", status.pid),
+        RunState::Finished => "Finished".to_string(),
+    };
+    let pct = if status.total == 0 {
+        100.0
+    } else {
+        status.copied as f64 * 100.0 / status.total as f64
+    };
+    format!("{}: copied {} of {} ({:.1}%), {} files, {} skipped, {} errors; updated {}s ago",
+            state, HumanBytes(status.copied), HumanBytes(status.total), pct,
+            status.files, status.skipped, status.errors, now.saturating_sub(status.updated))
+}
+
+fn show_status(path: &Path) -> Result<()> {
+    let status = read_status(path)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    println!("{}", status_summary(&status, now));
+    Ok(())
+}
+
+fn pack(sources: &[PathBuf], dest: &Path, opts: &Opts) -> Result<()> {
+    let source = match sources {
+        [source] => source,
+        _ => return Err(XcpError::InvalidArguments("--pack requires a single source directory".to_string()).into()),
+    };
+    if opts.no_clobber && dest.exists() {
+        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", dest.to_path_buf()).into());
+    }
+    let entries = pack_dir(source, dest, &Config::from(opts))?;
+    info!("Packed {} files into {}, index {}", entries.len(), quote_path(dest), quote_path(&index_path(dest)));
+    Ok(())
+}
+
 fn opts_check(opts: &Opts) {
     #[cfg(any(target_os = "linux", target_os = "android"))]
     if opts.reflink == Reflink::Never {
@@ -87,6 +196,10 @@ fn main() -> Result<()> {
     init_logging(&opts)?;
     opts_check(&opts);
 
+    if let Some(path) = &opts.status {
+        return show_status(path);
+    }
+
     let (dest, source_patterns) = opts
         .paths
         .split_last()
@@ -94,9 +207,13 @@ fn main() -> Result<()> {
         .map(|(d, s)| (PathBuf::from(d), s))?;
 
     let sources = expand_sources(source_patterns, &opts)?;
+    let discard = is_devnull(&dest);
+    if opts.pack {
+        return pack(&sources, &dest, &opts);
+    }
     if sources.is_empty() {
         return Err(XcpError::InvalidSource("No source files found.").into());
-    } else if !dest.is_dir() {
+    } else if !dest.is_dir() && !discard {
         if sources.len() == 1 && sources[0].is_dir() && dest.exists() {
             return Err(XcpError::InvalidDestination("Cannot copy a directory to a file.").into());
         } else if sources.len() > 1 {
@@ -106,7 +223,7 @@ fn main() -> Result<()> {
 
     // Sanity-check all sources up-front
     for source in &sources {
-        info!("Copying source {:?} to {:?}", source, dest);
+        info!("Copying source {} to {}", quote_path(source), quote_path(&dest));
         if !source.exists() {
             return Err(XcpError::InvalidSource("Source does not exist.").into());
         }
@@ -120,7 +237,7 @@ fn main() -> Result<()> {
 
         let sourcedir = source
             .components()
-            .last()
+            .next_back()
             .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
 
         let target_base = if dest.exists() && dest.is_dir() && !opts.no_target_directory {
@@ -134,6 +251,9 @@ fn main() -> Result<()> {
         }
     }
 
+    if log_enabled!(Level::Info) {
+        log_filesystems(&sources, &dest);
+    }
 
     // ========== Start copy ============
 
@@ -142,7 +262,10 @@ fn main() -> Result<()> {
 
     let updater = ChannelUpdater::new(&config);
     let stat_rx = updater.rx_channel();
-    let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
+    let stats: Arc<dyn StatusUpdater> = match &opts.status_file {
+        Some(path) => Arc::new(StatusFileUpdater::new(path, Arc::new(updater), STATUS_INTERVAL)?),
+        None => Arc::new(updater),
+    };
 
     let handle = thread::spawn(move || -> Result<()> {
         driver.copy(sources, &dest, stats)
@@ -151,27 +274,65 @@ fn main() -> Result<()> {
 
     // ========== Collect output and display ============
 
-    let pb = progress::create_bar(&opts, 0)?;
+    let renderer = progress::create_renderer(&opts)?;
+    let mut progress = Progress::default();
+    let mut failures = Vec::new();
+    let start = Instant::now();
 
     // Gather the results as we go; our end of the channel has been
     // moved to the driver call and will end when drained.
     for stat in stat_rx {
+        progress.update(&stat);
+        renderer.event(&stat, &progress);
         match stat {
-            StatusUpdate::Copied(v) => pb.inc(v),
-            StatusUpdate::Size(v) => pb.inc_size(v),
+            StatusUpdate::Error(e) if opts.keep_going => {
+                error!("Received error: {}", e);
+                failures.push(e);
+            }
             StatusUpdate::Error(e) => {
-                // FIXME: Optional continue?
                 error!("Received error: {}", e);
+                renderer.finish(&progress);
+                if let Some(target) = &opts.metrics {
+                    metrics::write(target, &progress, start.elapsed())?;
+                }
                 return Err(e.into());
             }
+            StatusUpdate::Skipped(ref path) => info!("Skipped {}", quote_path(path)),
+            _ => {}
         }
+        renderer.render(&progress);
     }
 
     handle.join()
         .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
 
     info!("Copy complete");
-    pb.end();
+    renderer.finish(&progress);
+
+    if discard && !opts.json {
+        println!("{}", read_summary(progress.total, start.elapsed()));
+    }
+    if opts.ignore_existing && !opts.json {
+        println!("{}", coverage_summary(&progress));
+    }
+    if opts.summary {
+        println!("{}", copy_summary(&progress, start.elapsed()));
+    }
+    if let Some(target) = &opts.metrics {
+        metrics::write(target, &progress, start.elapsed())?;
+    }
+    if opts.keep_going {
+        if !failures.is_empty() {
+            error!("Errors during copy ({}):", failures.len());
+            for e in &failures {
+                error!("  {}", e);
+            }
+        }
+        let code = exit_code(&progress, &opts.exit_codes);
+        if code != 0 {
+            process::exit(code);
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,85 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! End-of-run metrics in the Prometheus text exposition format,
+//! e.g. for the node-exporter textfile collector.
+
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+use libxcp::errors::Result;
+
+use crate::progress::Progress;
+
+/// Render the run metrics as Prometheus text.
+pub fn render(progress: &Progress, elapsed: Duration) -> String {
+    let metrics = [
+        ("files_copied_total", "counter", "Files copied, including reflinks.", progress.files.to_string()),
+        ("bytes_copied_total", "counter", "Bytes of data copied.", progress.copied.to_string()),
+        ("reflinks_total", "counter", "Files reflinked rather than copied.", progress.reflinked.to_string()),
+        ("errors_total", "counter", "Errors encountered.", progress.errors.to_string()),
+        ("duration_seconds", "gauge", "Duration of the run.", format!("{:.3}", elapsed.as_secs_f64())),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        // Writing to a String cannot fail.
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    out
+}
+
+/// Write the run metrics to `target`, or stdout if it is `-`.
+pub fn write(target: &str, progress: &Progress, elapsed: Duration) -> Result<()> {
+    let text = render(progress, elapsed);
+    if target == "-" {
+        print!("{}", text);
+    } else {
+        fs::write(target, text)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let progress = Progress {
+            copied: 4096,
+            files: 3,
+            reflinked: 1,
+            ..Progress::default()
+        };
+        let text = render(&progress, Duration::from_millis(1500));
+
+        let samples = text.lines()
+            .filter(|l| !l.starts_with('#'))
+            .collect::<Vec<&str>>();
+        assert_eq!(vec![
+            "files_copied_total 3",
+            "bytes_copied_total 4096",
+            "reflinks_total 1",
+            "errors_total 0",
+            "duration_seconds 1.500",
+        ], samples);
+        assert!(text.contains("# TYPE duration_seconds gauge\n"));
+    }
+}
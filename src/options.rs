@@ -14,14 +14,23 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::result;
+use std::str::FromStr;
+use std::time::Duration;
+
 use clap::{ArgAction, Parser};
 
-use libxcp::config::{Config, Reflink, Backup};
+use libfs::{group_id, user_id};
+use libxcp::config::{Config, MIN_BLOCK_SIZE, Reflink, Backup, Preallocate, Encrypted, HardLinks, IdMap, ModeTransform, Optimize, Overwrite, Pattern, Perms, SameFile, Sparse, Symlinks, Verify, XattrFilter};
 use log::LevelFilter;
 use unbytify::unbytify;
 
 use libxcp::drivers::Drivers;
-use libxcp::errors::Result;
+use libxcp::errors::{Result, XcpError};
+use libxcp::hash::Digest;
 
 #[derive(Clone, Debug, Parser)]
 #[command(
@@ -43,10 +52,18 @@ pub struct Opts {
     /// Dereference symlinks in source
     ///
     /// Follow symlinks, possibly recursively, when copying source
-    /// files.
-    #[arg(short = 'L', long)]
+    /// files. This is the same as '--symlinks=follow'.
+    #[arg(short = 'L', long, conflicts_with = "symlinks")]
     pub dereference: bool,
 
+    /// How to handle symlinks in the source.
+    ///
+    /// 'preserve' (the default) recreates them pointing at the same
+    /// target, 'follow' copies the files they point to, and 'skip'
+    /// doesn't copy them.
+    #[arg(long)]
+    pub symlinks: Option<Symlinks>,
+
     /// Number of parallel workers.
     ///
     /// Default is 4; if the value is negative or 0 it uses the number
@@ -56,15 +73,69 @@ pub struct Opts {
 
     /// Block size for operations.
     ///
-    /// Accepts standard size modifiers like "M" and "GB". Actual
-    /// usage internally depends on the driver.
-    #[arg(long,  default_value = "1MB", value_parser=unbytify)]
+    /// Accepts standard size modifiers like "M" and "GB". This sets
+    /// both the chunk size of each copy call and the granularity of
+    /// progress updates; larger blocks may be faster on fast storage,
+    /// smaller blocks give smoother progress. The minimum is 4KB.
+    /// Actual usage internally depends on the driver.
+    #[arg(long,  default_value = "1MB", value_parser=parse_block_size)]
     pub block_size: u64,
 
     /// Do not overwrite an existing file
     #[arg(short, long)]
     pub no_clobber: bool,
 
+    /// Prompt before overwriting an existing file.
+    ///
+    /// Files are only overwritten if the answer is 'y' or 'yes'. If
+    /// standard input is not a terminal this behaves as
+    /// '--no-clobber'. Disables the progress bar.
+    #[arg(short, long, conflicts_with_all = ["no_clobber", "ignore_existing"])]
+    pub interactive: bool,
+
+    /// Stay on the source filesystems.
+    ///
+    /// Directories that are mount points for other filesystems are
+    /// created empty, but their contents are not copied.
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+
+    /// Only copy files missing from the destination.
+    ///
+    /// Existing files are skipped rather than overwritten, and a
+    /// summary of how much of the source was already present is
+    /// printed at the end.
+    #[arg(long, conflicts_with = "no_clobber")]
+    pub ignore_existing: bool,
+
+    /// Skip files smaller than this size.
+    ///
+    /// Accepts size modifiers like "M" and "GB".
+    #[arg(long, value_parser=unbytify)]
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this size.
+    ///
+    /// Accepts size modifiers like "M" and "GB".
+    #[arg(long, value_parser=unbytify)]
+    pub max_size: Option<u64>,
+
+    /// Check the destination has enough free inodes before copying.
+    ///
+    /// Counts the source entries first, which requires an extra walk
+    /// of the source; the copy is refused if there is not enough
+    /// space for them.
+    #[arg(long)]
+    pub check_inodes: bool,
+
+    /// Pack the files in a source directory into a single file.
+    ///
+    /// The regular files are concatenated in sorted order into the
+    /// destination, with an index of their offsets written to
+    /// `<dest>.idx`. This is not a standard archive format.
+    #[arg(long)]
+    pub pack: bool,
+
     /// Use .gitignore if present.
     ///
     /// NOTE: This is fairly basic at the moment, and only honours a
@@ -73,6 +144,22 @@ pub struct Opts {
     #[arg(long)]
     pub gitignore: bool,
 
+    /// Exclude paths matching PATTERN from directory copies.
+    ///
+    /// Shell-style globs matched against the path relative to the
+    /// source, as in .gitignore; e.g. '*.tmp' or 'node_modules'. A
+    /// pattern without a '/' matches at any depth. Excluded
+    /// directories are not descended into. May be repeated.
+    #[arg(long, value_name = "PATTERN", value_parser = Pattern::glob)]
+    pub exclude: Vec<Pattern>,
+
+    /// Exclude paths matching the regular expression REGEX.
+    ///
+    /// As for '--exclude', but a regular expression matched anywhere
+    /// in the relative path unless anchored; e.g. '\.o$'.
+    #[arg(long, value_name = "REGEX", value_parser = Pattern::regex)]
+    pub exclude_regex: Vec<Pattern>,
+
     /// Expand file patterns.
     ///
     /// Glob (expand) filename patterns natively (note; the shell may still do its own expansion first)
@@ -83,6 +170,24 @@ pub struct Opts {
     #[arg(long)]
     pub no_progress: bool,
 
+    /// Print progress as newline-delimited JSON.
+    ///
+    /// One object is written to stdout for each file started,
+    /// completed or skipped, each error and each progress update,
+    /// followed by a summary record. Log messages are written to
+    /// stderr instead.
+    #[arg(long)]
+    pub json: bool,
+
+    /// Print a one-line summary once the copy is complete.
+    ///
+    /// The line gives the number of files, their total size, the bytes
+    /// of data actually written (not counting holes in sparse files or
+    /// reflinked data), the elapsed time and the average rate; e.g. for
+    /// logs of batch jobs run with '--no-progress'.
+    #[arg(long, conflicts_with = "json")]
+    pub summary: bool,
+
     /// Do not copy the file permissions.
     #[arg(long)]
     pub no_perms: bool,
@@ -91,6 +196,106 @@ pub struct Opts {
     #[arg(long)]
     pub no_timestamps: bool,
 
+    /// Modify the preserved file permissions.
+    ///
+    /// A comma-separated list of symbolic changes applied to the
+    /// source mode, as for rsync's `--chmod`; e.g. 'go-w' removes
+    /// group and other write access.
+    #[arg(long, value_name = "SPEC")]
+    pub chmod: Option<ModeTransform>,
+
+    /// How to set the mode of copied files.
+    ///
+    /// 'exact' (the default) copies the source mode, 'umask' applies
+    /// the current umask to it, as `cp` does without '-p', and
+    /// 'default' uses 0644 for files and 0755 for directories.
+    #[arg(long, default_value = "exact", value_name = "MODE")]
+    pub perms: Perms,
+
+    /// Skip the copy if the destination already has this content.
+    ///
+    /// The expected SHA-256 digest of the destination, as printed by
+    /// `sha256sum`; i.e. 64 hex digits, optionally prefixed with
+    /// 'sha256:'. The source is not read when the destination matches.
+    #[arg(long, value_name = "SHA256")]
+    pub expect_hash: Option<Digest>,
+
+    /// Preserve only the listed attributes.
+    ///
+    /// A comma-separated list as for `cp --preserve`; one or more of
+    /// 'mode', 'ownership', 'timestamps', 'links', 'xattr', 'context',
+    /// 'acl' or 'all'. Attributes not listed are not copied. 'links'
+    /// is the same as '--hard-links=preserve'. Setting the owner
+    /// requires root or CAP_CHOWN; otherwise only the group is
+    /// preserved where possible.
+    #[arg(long, value_name = "ATTR_LIST")]
+    pub preserve: Option<Preserve>,
+
+    /// Preserve the SELinux security context.
+    ///
+    /// As for `cp --preserve=context`; the 'security.selinux'
+    /// attribute is copied even if other attributes are not. Failing
+    /// to set it is only a warning.
+    #[arg(long)]
+    pub preserve_context: bool,
+
+    /// Preserve POSIX ACLs.
+    ///
+    /// The access ACLs of files and directories, and the default ACLs
+    /// of directories, are copied even if other attributes are
+    /// not. Failing to set them is only a warning.
+    #[arg(long)]
+    pub preserve_acl: bool,
+
+    /// Only copy extended attributes matching PATTERN.
+    ///
+    /// PATTERN is an attribute name, or a prefix ending in '*' such
+    /// as 'user.*'. May be given more than once; by default all
+    /// attributes are copied.
+    #[arg(long, value_name = "PATTERN")]
+    pub xattr_include: Vec<String>,
+
+    /// Don't copy extended attributes matching PATTERN.
+    ///
+    /// As for '--xattr-include'; e.g. 'security.*' to skip security
+    /// labels. Exclusions are applied after inclusions.
+    #[arg(long, value_name = "PATTERN")]
+    pub xattr_exclude: Vec<String>,
+
+    /// Remap file owners; implies preserving ownership.
+    ///
+    /// A comma-separated list of 'FROM:TO' user IDs or names, as for
+    /// rsync's --usermap; e.g. '1000:1500,alice:bob'. Unlisted users
+    /// are unchanged.
+    #[arg(long, value_name = "LIST", value_parser = parse_usermap)]
+    pub usermap: Option<IdMap>,
+
+    /// Remap file groups; implies preserving ownership.
+    ///
+    /// As for --usermap, with group IDs or names.
+    #[arg(long, value_name = "LIST", value_parser = parse_groupmap)]
+    pub groupmap: Option<IdMap>,
+
+    /// Re-read and compare each file after copying.
+    ///
+    /// 'off' (the default), 'cached', 'direct' or 'hash'. 'direct'
+    /// reads the destination with O_DIRECT, bypassing the page cache,
+    /// so the comparison is with what reached the disk; it falls back
+    /// to 'cached' on filesystems without direct I/O. 'hash' compares
+    /// SHA-256 hashes of the files, reading one at a time and
+    /// skipping holes.
+    #[arg(long, default_value = "off")]
+    pub verify: Verify,
+
+    /// Check that reflinked files share their data with the source.
+    ///
+    /// After each reflink the physical extents of the source and
+    /// destination are compared, and the copy fails if they are not
+    /// shared or the filesystem can't report them. Use with
+    /// '--reflink=always' where scripts depend on deduplication.
+    #[arg(long)]
+    pub verify_reflink: bool,
+
     /// Driver to use, defaults to 'file-parallel'.
     ///
     /// Currently there are 2; the default "parfile", which
@@ -128,19 +333,390 @@ pub struct Opts {
     /// Backup options
     ///
     /// Whether to create backups of overwritten files. Current
-    /// options are 'none'/'off', or 'numbered', or 'auto'. Numbered
-    /// backups follow the semantics of `cp` numbered backups
-    /// (e.g. `file.txt.~123~`). 'auto' will only create a numbered
-    /// backup if a previous backups exists. Default is 'none'.
+    /// options are 'none'/'off', 'numbered', 'auto' or
+    /// 'simple'/'never'. Numbered backups follow the semantics of
+    /// `cp` numbered backups (e.g. `file.txt.~123~`). 'auto' will
+    /// only create a numbered backup if a previous backups
+    /// exists. Simple backups append `~` to the name
+    /// (e.g. `file.txt~`), replacing any previous simple
+    /// backup. Default is 'none'.
     #[arg(long, default_value = "none")]
     pub backup: Backup,
 
+    /// Preallocation strategy for destination files.
+    ///
+    /// 'never' (the default) only sets the file length, 'always'
+    /// reserves all blocks up front, and 'keep-size' reserves the
+    /// blocks before setting the length. Preallocation can reduce
+    /// fragmentation on ext4 and XFS, and avoids running out of space
+    /// part way through a file, but will make sparse copies fully
+    /// allocated. 'auto' reserves blocks for all but sparse sources.
+    #[arg(long, default_value = "never")]
+    pub preallocate: Preallocate,
+
+    /// Control the creation of sparse files.
+    ///
+    /// As for `cp --sparse`; 'auto' (the default) copies the holes of
+    /// sparse sources, 'always' also leaves any blocks of zeros as
+    /// holes, and 'never' writes the holes as zeros.
+    #[arg(long, default_value = "auto", value_name = "WHEN")]
+    pub sparse: Sparse,
+
+    /// Per-file timeout in seconds.
+    ///
+    /// Abort the copy of any single file that takes longer than
+    /// this. The timeout is checked between blocks (see
+    /// '--block-size'), and at least every 16MiB. A file stuck on
+    /// unresponsive storage is reported as timed out and left behind.
+    #[arg(long)]
+    pub file_timeout: Option<u64>,
+
+    /// Retry reads failing with an I/O error N times.
+    ///
+    /// On flaky media a read can fail partway through a file. Each
+    /// failed batch (see '--block-size') is retried after a short
+    /// delay; the final error reports the offset of the failure.
+    #[arg(long, default_value = "0", value_name = "N")]
+    pub read_retries: u32,
+
+    /// Only copy the N most recently modified files.
+    ///
+    /// Files in all sources are ordered by modification time after
+    /// any other filters (e.g. '--exclude' and '--min-size') and only
+    /// the newest N are copied; older ones are skipped. Useful for
+    /// archiving the latest rotated logs.
+    #[arg(long)]
+    pub newest: Option<usize>,
+
+    /// Maximum number of open file descriptors.
+    ///
+    /// Limits the total descriptors held by concurrent file copies
+    /// (each holds at least 2). Defaults to a value derived from the
+    /// process open-file limit (see 'ulimit -n').
+    #[arg(long)]
+    pub max_open_fds: Option<usize>,
+
+    /// Maximum memory used for copy buffers.
+    ///
+    /// Limits the total size of the buffers used by concurrent
+    /// workers for user-space copies and '--verify'; workers wait
+    /// for memory rather than exceeding it. Accepts size modifiers
+    /// like "M" and "GB".
+    #[arg(long, value_parser=unbytify)]
+    pub max_buffer_memory: Option<u64>,
+
+    /// Maximum copy rate in bytes per second.
+    ///
+    /// The limit is shared by all workers, so applies to the copy as
+    /// a whole. Accepts size modifiers like "M" and "GB"; e.g. '10M'
+    /// for 10MB/s.
+    #[arg(long, value_name = "BYTES", value_parser=unbytify)]
+    pub bwlimit: Option<u64>,
+
+    /// Handling of encrypted sources without a key.
+    ///
+    /// Files encrypted with fscrypt cannot be read while their key is
+    /// not loaded. 'error' (the default) aborts the copy with an
+    /// explanation, 'skip' warns and skips such files.
+    #[arg(long, default_value = "error")]
+    pub encrypted: Encrypted,
+
+    /// Read through holes when copying to /dev/null.
+    ///
+    /// Copying to /dev/null reads the sources and reports the read
+    /// throughput. By default holes in sparse files are skipped, as
+    /// in a normal copy; this forces them to be read too.
+    #[arg(long)]
+    pub read_holes: bool,
+
+    /// Create files in the source directory order.
+    ///
+    /// Copies entries in the order the source filesystem lists them,
+    /// so the destination directory layout mirrors the source (useful
+    /// for reproducing filesystem images). Uses a single worker with
+    /// the 'parfile' driver, so is slower for large trees.
+    #[arg(long)]
+    pub readdir_order: bool,
+
+    /// Create files in sorted order, one at a time.
+    ///
+    /// Walks the source sorted by name and creates the destination
+    /// entries sequentially, so repeated copies of a tree create
+    /// files in the same order (e.g. for reproducible inode numbers
+    /// in filesystem images). Uses a single worker with the
+    /// 'parfile' driver.
+    #[arg(long, conflicts_with = "readdir_order")]
+    pub reproducible: bool,
+
+    /// Start writeback every N bytes written.
+    ///
+    /// Flushes data to disk periodically during the copy of large
+    /// files rather than all at the end, smoothing out I/O
+    /// latency. Accepts size modifiers like "M" and "GB". Use with
+    /// '--fsync' for durable copies.
+    #[arg(long, value_parser=unbytify)]
+    pub sync_every: Option<u64>,
+
+    /// Copy file data with direct I/O, bypassing the page cache.
+    ///
+    /// Uses O_DIRECT where the filesystem supports it. Sparse files
+    /// are copied in full.
+    #[arg(long)]
+    pub direct_io: bool,
+
+    /// Drop copied files from the page cache.
+    ///
+    /// Keeps copies of large amounts of data from evicting everything
+    /// else from the cache. Written data is flushed to disk before
+    /// it is dropped.
+    #[arg(long)]
+    pub drop_cache: bool,
+
+    /// Only copy regular files (and directories).
+    ///
+    /// Symlinks, FIFOs, sockets and device nodes are skipped; use
+    /// '-v' to list them.
+    #[arg(long)]
+    pub regular_only: bool,
+
+    /// Handling of sources that are the same file as the destination.
+    ///
+    /// A destination may be the same file as its source via a hard
+    /// link or a bind mount; copying would destroy it. 'error' (the
+    /// default) aborts the copy, 'skip' skips such files.
+    #[arg(long, default_value = "error")]
+    pub same_file: SameFile,
+
+    /// Tune the copy for the filesystems involved.
+    ///
+    /// 'auto' picks a profile from the source and destination
+    /// filesystem types, which may override '--reflink',
+    /// '--preallocate' and '--block-size'; e.g. same-filesystem btrfs
+    /// copies prefer reflinks, and tmpfs destinations use large
+    /// blocks. 'off' (the default) uses the options as given.
+    #[arg(long, default_value = "off")]
+    pub optimize: Optimize,
+
+    /// How to handle source files with multiple hard links.
+    ///
+    /// Each copy is an independent file, breaking any links to it
+    /// from elsewhere. 'copy' (the default) does so silently, 'warn'
+    /// warns for each such file, and 'skip' doesn't copy them.
+    /// 'preserve' copies the file once and recreates other links to
+    /// it within the copy as hard links.
+    #[arg(long, default_value = "copy")]
+    pub hard_links: HardLinks,
+
+    /// What to do when a destination file exists.
+    ///
+    /// 'always' (the default) replaces it, and 'rename' copies to the
+    /// first free name of the form 'file (1).txt' instead, e.g. when
+    /// several sources have the same name. 'never' skips the file,
+    /// and 'if-newer' only replaces it if the source was modified
+    /// more recently.
+    #[arg(long, default_value = "always", conflicts_with = "no_clobber")]
+    pub overwrite: Overwrite,
+
+    /// Resume interrupted copies.
+    ///
+    /// Existing destination files are compared with the source by
+    /// their data extents, and the copy restarts after the last
+    /// complete region. Assumes the source has not changed, unless
+    /// '--verify' is given, in which case the existing data is
+    /// checked first.
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Update existing files in place, writing only changed blocks.
+    ///
+    /// Uses an rsync-style rolling checksum to find the blocks of
+    /// the source that differ from the destination. Both files are
+    /// read in full, so this only helps when writes are expensive,
+    /// e.g. for large, mostly unchanged, images.
+    #[arg(long, conflicts_with = "resume")]
+    pub delta: bool,
+
+    /// Lock destination files while copying.
+    ///
+    /// Takes an advisory lock on each destination, so concurrent
+    /// invocations using this option copying to the same file wait
+    /// for each other rather than corrupting it.
+    #[arg(long)]
+    pub lock: bool,
+
+    /// Write files to a temporary name and rename them into place.
+    ///
+    /// Destinations are only replaced once the copy is complete, so
+    /// an interrupted copy never leaves a partially written file.
+    #[arg(long, conflicts_with_all = ["resume", "delta", "lock"])]
+    pub atomic: bool,
+
+    /// Remove N leading components from destination paths.
+    ///
+    /// As for `tar --strip-components`; e.g. with 1, the contents of
+    /// a source directory are copied directly into the destination.
+    /// Files with too few components are skipped.
+    #[arg(long, value_name = "N", default_value = "0")]
+    pub strip_components: usize,
+
+    /// Write Prometheus-format metrics to this file on completion.
+    ///
+    /// Use '-' for stdout. Metrics are files, bytes, reflinks and
+    /// errors totals and the run duration.
+    #[arg(long)]
+    pub metrics: Option<String>,
+
+    /// Continue copying the remaining files after an error.
+    ///
+    /// Unreadable files and directories in a tree are skipped too. The
+    /// failures are listed once the copy completes, and the exit
+    /// status reflects the outcome; see --exit-codes.
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Exit statuses to use with --keep-going.
+    ///
+    /// A comma-separated list of 'skipped=N' and 'errors=N'. The exit
+    /// status is 0 if every file was copied, the 'errors' status if
+    /// any copy failed, and otherwise the 'skipped' status if any file
+    /// was skipped. Defaults to 'skipped=1,errors=2'.
+    #[arg(long, value_name = "LIST", requires = "keep_going", default_value = "skipped=1,errors=2")]
+    pub exit_codes: ExitCodes,
+
+    /// Periodically write the copy progress to this file.
+    ///
+    /// The file is replaced atomically, and can be read by another
+    /// invocation with --status.
+    #[arg(long, value_name = "PATH")]
+    pub status_file: Option<PathBuf>,
+
+    /// Display the progress recorded in a status file and exit.
+    ///
+    /// See --status-file. Status files left by runs that exited
+    /// without finishing are reported as stale.
+    #[arg(long, value_name = "PATH")]
+    pub status: Option<PathBuf>,
+
     /// Path list.
     ///
     /// Source and destination files, or multiple source(s) to a directory.
     pub paths: Vec<String>,
 }
 
+/// File attributes to preserve, parsed from a `cp`-style list.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Preserve {
+    pub mode: bool,
+    pub ownership: bool,
+    pub timestamps: bool,
+    pub links: bool,
+    pub xattr: bool,
+    pub context: bool,
+    pub acl: bool,
+}
+
+impl FromStr for Preserve {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut preserve = Preserve::default();
+        for attr in s.split(',') {
+            match attr.trim().to_lowercase().as_str() {
+                "mode" => preserve.mode = true,
+                "ownership" => preserve.ownership = true,
+                "timestamps" => preserve.timestamps = true,
+                "links" => preserve.links = true,
+                "xattr" => preserve.xattr = true,
+                "context" => preserve.context = true,
+                "acl" => preserve.acl = true,
+                "all" => preserve = Preserve {
+                    mode: true,
+                    ownership: true,
+                    timestamps: true,
+                    links: true,
+                    xattr: true,
+                    context: true,
+                    acl: true,
+                },
+                _ => return Err(XcpError::InvalidArguments(format!("Unexpected value for 'preserve': {}", attr))),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+/// Exit statuses for the outcomes of a `--keep-going` run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExitCodes {
+    pub skipped: u8,
+    pub errors: u8,
+}
+
+impl Default for ExitCodes {
+    fn default() -> Self {
+        ExitCodes {
+            skipped: 1,
+            errors: 2,
+        }
+    }
+}
+
+impl FromStr for ExitCodes {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut codes = ExitCodes::default();
+        for mapping in s.split(',') {
+            let invalid = || XcpError::InvalidArguments(format!("Unexpected value for 'exit-codes': {}", mapping));
+            let (outcome, code) = mapping.split_once('=').ok_or_else(invalid)?;
+            let code = code.trim().parse().map_err(|_| invalid())?;
+            match outcome.trim().to_lowercase().as_str() {
+                "skipped" => codes.skipped = code,
+                "errors" => codes.errors = code,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(codes)
+    }
+}
+
+fn parse_block_size(s: &str) -> result::Result<u64, XcpError> {
+    let size = unbytify(s)
+        .map_err(|e| XcpError::InvalidArguments(format!("Invalid block size '{}': {}", s, e)))?;
+    if size < MIN_BLOCK_SIZE {
+        return Err(XcpError::InvalidArguments(format!("Block size must be at least {} bytes: {}", MIN_BLOCK_SIZE, s)));
+    }
+    Ok(size)
+}
+
+// Parse a list of FROM:TO id mappings, resolving names with `lookup`.
+fn parse_id_map(s: &str, lookup: fn(&str) -> result::Result<Option<u32>, libfs::Error>) -> result::Result<IdMap, XcpError> {
+    let resolve = |id: &str| -> result::Result<u32, XcpError> {
+        if let Ok(n) = id.parse() {
+            return Ok(n);
+        }
+        lookup(id)
+            .map_err(|e| XcpError::InvalidArguments(format!("Failed to look up '{}': {}", id, e)))?
+            .ok_or_else(|| XcpError::InvalidArguments(format!("Unknown user or group: {}", id)))
+    };
+
+    let mut map = HashMap::new();
+    for mapping in s.split(',') {
+        let (from, to) = mapping.split_once(':')
+            .ok_or_else(|| XcpError::InvalidArguments(format!("Unexpected mapping, expected FROM:TO: {}", mapping)))?;
+        map.insert(resolve(from.trim())?, resolve(to.trim())?);
+    }
+    Ok(IdMap(map))
+}
+
+fn parse_usermap(s: &str) -> result::Result<IdMap, XcpError> {
+    parse_id_map(s, user_id)
+}
+
+fn parse_groupmap(s: &str) -> result::Result<IdMap, XcpError> {
+    parse_id_map(s, group_id)
+}
+
 impl Opts {
     pub fn from_args() -> Result<Opts> {
         Ok(Opts::parse())
@@ -154,6 +730,68 @@ impl Opts {
             _ => LevelFilter::Trace,
         }
     }
+
+    /// Whether to skip copying permissions, from either
+    /// `--no-perms` or `--preserve`.
+    pub fn no_perms(&self) -> bool {
+        self.no_perms || self.preserve.is_some_and(|p| !p.mode)
+    }
+
+    /// Whether to copy extended attributes; `--no-perms` skips them
+    /// as well as the mode.
+    pub fn preserve_xattrs(&self) -> bool {
+        !self.no_perms && self.preserve.map_or(true, |p| p.xattr)
+    }
+
+    /// Whether to copy the security context, from either
+    /// `--preserve-context` or `--preserve`.
+    pub fn preserve_context(&self) -> bool {
+        self.preserve_context || self.preserve.is_some_and(|p| p.context)
+    }
+
+    /// Whether to copy ACLs, from either `--preserve-acl` or
+    /// `--preserve`.
+    pub fn preserve_acl(&self) -> bool {
+        self.preserve_acl || self.preserve.is_some_and(|p| p.acl)
+    }
+
+    /// The hard link handling, from either `--hard-links` or
+    /// `--preserve`.
+    pub fn hard_links(&self) -> HardLinks {
+        if self.preserve.is_some_and(|p| p.links) {
+            HardLinks::Preserve
+        } else {
+            self.hard_links
+        }
+    }
+
+    /// The symlink handling, from either `--symlinks` or
+    /// `--dereference`.
+    pub fn symlinks(&self) -> Symlinks {
+        match self.symlinks {
+            Some(symlinks) => symlinks,
+            None if self.dereference => Symlinks::Follow,
+            None => Symlinks::Preserve,
+        }
+    }
+
+    /// Whether to copy ownership, from either `--preserve` or a
+    /// `--usermap`/`--groupmap`.
+    pub fn preserve_ownership(&self) -> bool {
+        self.preserve.is_some_and(|p| p.ownership) || self.usermap.is_some() || self.groupmap.is_some()
+    }
+
+    /// Whether to prompt before overwriting; `--interactive` only
+    /// prompts if standard input is a terminal.
+    pub fn prompts(&self) -> bool {
+        self.interactive && io::stdin().is_terminal()
+    }
+
+    /// Whether to skip copying timestamps, from either
+    /// `--no-timestamps` or `--preserve`.
+    pub fn no_timestamps(&self) -> bool {
+        self.no_timestamps || self.preserve.is_some_and(|p| !p.timestamps)
+    }
 }
 
 impl From<&Opts> for Config {
@@ -165,19 +803,179 @@ impl From<&Opts> for Config {
                 opts.workers
             },
             block_size: if opts.no_progress {
-                usize::max_value() as u64
+                usize::MAX as u64
             } else {
                 opts.block_size
             },
             gitignore: opts.gitignore,
-            no_clobber: opts.no_clobber,
-            no_perms: opts.no_perms,
-            no_timestamps: opts.no_timestamps,
-            dereference: opts.dereference,
+            exclude: opts.exclude.iter().chain(&opts.exclude_regex).cloned().collect(),
+            no_clobber: opts.no_clobber || (opts.interactive && !opts.prompts()),
+            interactive: opts.prompts(),
+            no_perms: opts.no_perms(),
+            preserve_xattrs: opts.preserve_xattrs(),
+            preserve_context: opts.preserve_context(),
+            preserve_acl: opts.preserve_acl(),
+            no_timestamps: opts.no_timestamps(),
+            symlinks: opts.symlinks(),
             no_target_directory: opts.no_target_directory,
             fsync: opts.fsync,
             reflink: opts.reflink,
             backup: opts.backup,
+            preallocate: opts.preallocate,
+            sparse: opts.sparse,
+            file_timeout: opts.file_timeout.map(Duration::from_secs),
+            read_retries: opts.read_retries,
+            max_open_fds: opts.max_open_fds,
+            max_buffer_memory: opts.max_buffer_memory,
+            max_bytes_per_sec: opts.bwlimit,
+            encrypted: opts.encrypted,
+            read_holes: opts.read_holes,
+            readdir_order: opts.readdir_order,
+            reproducible: opts.reproducible,
+            sync_every: opts.sync_every,
+            drop_cache: opts.drop_cache,
+            regular_only: opts.regular_only,
+            same_file: opts.same_file,
+            overwrite: opts.overwrite,
+            hard_links: opts.hard_links(),
+            resume: opts.resume,
+            delta: opts.delta,
+            lock: opts.lock,
+            atomic: opts.atomic,
+            strip_components: opts.strip_components,
+            ignore_existing: opts.ignore_existing,
+            min_size: opts.min_size,
+            max_size: opts.max_size,
+            newest: opts.newest,
+            check_inodes: opts.check_inodes,
+            one_file_system: opts.one_file_system,
+            chmod: opts.chmod,
+            perms: opts.perms,
+            expect_hash: opts.expect_hash,
+            keep_going: opts.keep_going,
+            basic_io: false,
+            direct_io: opts.direct_io,
+            no_extent_map: false,
+            optimize: opts.optimize,
+            xattr_filter: XattrFilter {
+                include: opts.xattr_include.clone(),
+                exclude: opts.xattr_exclude.clone(),
+            },
+            timestamp_granularity: None,
+            preserve_ownership: opts.preserve_ownership(),
+            uid_map: opts.usermap.clone().unwrap_or_default(),
+            gid_map: opts.groupmap.clone().unwrap_or_default(),
+            verify: opts.verify,
+            verify_reflink: opts.verify_reflink,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        let opts = Opts::try_parse_from(["xcp"].iter().chain(args).chain(&["from", "to"])).unwrap();
+        Config::from(&opts)
+    }
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(1024 * 1024, config(&[]).block_size);
+        assert_eq!(4 * 1024 * 1024, config(&["--block-size=4M"]).block_size);
+        assert_eq!(MIN_BLOCK_SIZE, config(&["--block-size=4096"]).block_size);
+        assert_eq!(u64::MAX, config(&["--no-progress", "--block-size=4M"]).block_size);
+
+        let parse = |size: &str| Opts::try_parse_from(["xcp", "--block-size", size, "from", "to"]);
+        assert!(parse("0").is_err());
+        assert!(parse("100").is_err());
+        assert!(parse("lots").is_err());
+    }
+
+    #[test]
+    fn test_parse_preserve() {
+        let preserve = Preserve::from_str("mode,timestamps").unwrap();
+        assert_eq!(Preserve { mode: true, timestamps: true, ..Preserve::default() }, preserve);
+
+        let all = Preserve::from_str("all").unwrap();
+        assert!(all.mode && all.ownership && all.timestamps && all.links && all.xattr && all.acl);
+
+        assert!(Preserve::from_str("mode,bogus").is_err());
+        assert!(Preserve::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_preserve_config() {
+        let conf = config(&["--preserve=mode,timestamps"]);
+        assert!(!conf.no_perms);
+        assert!(!conf.no_timestamps);
+
+        let conf = config(&["--preserve=timestamps"]);
+        assert!(conf.no_perms);
+        assert!(!conf.no_timestamps);
+        assert_eq!(HardLinks::Copy, conf.hard_links);
+
+        let conf = config(&["--preserve=links"]);
+        assert_eq!(HardLinks::Preserve, conf.hard_links);
+
+        let conf = config(&["--preserve=mode"]);
+        assert!(!conf.no_perms);
+        assert!(!conf.preserve_xattrs);
+        assert!(conf.no_timestamps);
+
+        let conf = config(&["--preserve=xattr"]);
+        assert!(conf.no_perms);
+        assert!(conf.preserve_xattrs);
+
+        let conf = config(&["--no-perms"]);
+        assert!(!conf.preserve_xattrs);
+
+        let conf = config(&["--preserve=mode,context"]);
+        assert!(!conf.preserve_xattrs);
+        assert!(conf.preserve_context);
+        assert!(config(&["--preserve-context"]).preserve_context);
+        assert!(config(&["--preserve=all"]).preserve_context);
+
+        let conf = config(&["--preserve=mode,acl"]);
+        assert!(!conf.preserve_xattrs);
+        assert!(conf.preserve_acl);
+        assert!(config(&["--preserve-acl"]).preserve_acl);
+        assert!(!config(&[]).preserve_acl);
+
+        // The default preserves everything.
+        let conf = config(&[]);
+        assert!(!conf.no_perms);
+        assert!(conf.preserve_xattrs);
+        assert!(!conf.no_timestamps);
+    }
+
+    #[test]
+    fn test_parse_exit_codes() {
+        assert_eq!(ExitCodes { skipped: 1, errors: 2 }, ExitCodes::from_str("skipped=1,errors=2").unwrap());
+        assert_eq!(ExitCodes { skipped: 0, errors: 2 }, ExitCodes::from_str("skipped=0").unwrap());
+        assert_eq!(ExitCodes { skipped: 1, errors: 10 }, ExitCodes::from_str("Errors = 10").unwrap());
+        for bad in ["", "skipped", "skipped=x", "errors=256", "failed=3"] {
+            assert!(ExitCodes::from_str(bad).is_err(), "Parsed {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_id_map() {
+        let map = parse_usermap("1000:1500, root:42").unwrap();
+        assert_eq!(1500, map.map(1000));
+        assert_eq!(42, map.map(0));
+        assert_eq!(7, map.map(7));
+
+        for bad in ["", "1000", "1000:", "no-such-user-xcp:1"] {
+            assert!(parse_usermap(bad).is_err(), "Parsed {:?}", bad);
         }
+
+        let conf = config(&["--groupmap=5:6"]);
+        assert!(conf.preserve_ownership);
+        assert_eq!(6, conf.gid_map.map(5));
+        assert_eq!(5, conf.uid_map.map(5));
+        assert!(!config(&[]).preserve_ownership);
+        assert!(config(&["--preserve=ownership"]).preserve_ownership);
     }
 }
@@ -14,69 +14,367 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::options::Opts;
+
+//! Progress display. [Progress] aggregates the status updates
+//! received from the copy driver, and a [ProgressRenderer] is
+//! responsible only for displaying that state. Any renderer can be
+//! used with the collected state. The progress bar shows the
+//! recent copy rate and the time remaining at that rate; see
+//! [Throughput].
+//!
+//! With `--json` progress is written to stdout as newline-delimited
+//! JSON, one object per event. Each has an `event` field, one of:
+//!
+//! * `started`: `path`, the source file.
+//! * `progress`: `bytes_done` and `bytes_total` so far.
+//! * `completed`: `files_done` so far and `reflinked`.
+//! * `skipped`: `path`, the source entry.
+//! * `error`: `message`.
+//! * `summary`: the final record; `status` (`ok` or `failed`),
+//!   `bytes_done`, `bytes_total`, `files`, `reflinked`, `skipped`,
+//!   `errors` and `elapsed_secs`.
+//!
+//! Fields may be added to these in future, but not removed.
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use libxcp::errors::Result;
+use indicatif::HumanBytes;
+use libxcp::feedback::{StatusUpdate, Throughput};
 
-struct NoopBar;
+use crate::options::Opts;
 
-struct VisualBar {
-    bar: indicatif::ProgressBar,
+/// Aggregated progress of a copy operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Bytes copied so far.
+    pub copied: u64,
+    /// Total bytes to copy, as discovered so far.
+    pub total: u64,
+    /// Number of entries skipped.
+    pub skipped: u64,
+    /// Number of files completed, including reflinks.
+    pub files: u64,
+    /// Number of files reflinked rather than copied.
+    pub reflinked: u64,
+    /// Number of errors received. Handling them is left to the
+    /// caller.
+    pub errors: u64,
 }
 
-pub trait ProgressBar {
-    #[allow(unused)]
-    fn set_size(&self, size: u64);
-    fn inc_size(&self, size: u64);
-    fn inc(&self, size: u64);
-    fn end(&self);
+impl Progress {
+    /// Apply a status update to the state.
+    pub fn update(&mut self, update: &StatusUpdate) {
+        match update {
+            StatusUpdate::Started(_) => {}
+            StatusUpdate::Copied(v) => self.copied += v,
+            StatusUpdate::Size(v) => self.total += v,
+            StatusUpdate::Skipped(_) => self.skipped += 1,
+            StatusUpdate::Completed { reflinked } => {
+                self.files += 1;
+                if *reflinked {
+                    self.reflinked += 1;
+                }
+            }
+            StatusUpdate::Error(_) => self.errors += 1,
+        }
+    }
 }
 
-
-impl ProgressBar for NoopBar {
-    fn set_size(&self, _size: u64) {
+/// Display of [Progress] state.
+pub trait ProgressRenderer {
+    /// Report an individual update, after it has been applied to
+    /// `progress`; called before [render](Self::render).
+    fn event(&self, _update: &StatusUpdate, _progress: &Progress) {
     }
-    fn inc_size(&self, _size: u64) {
+    /// Display the current state; called after each update.
+    fn render(&self, progress: &Progress);
+    /// Display the final state once the copy is complete.
+    fn finish(&self, progress: &Progress);
+}
+
+/// Renderer that displays nothing.
+struct QuietRenderer;
+
+impl ProgressRenderer for QuietRenderer {
+    fn render(&self, _progress: &Progress) {
     }
-    fn inc(&self, _size: u64) {
+    fn finish(&self, _progress: &Progress) {
     }
-    fn end(&self) {
+}
+
+/// The period the displayed copy rate is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+// E.g. "01:05" or "2:00:00".
+fn format_remaining(remaining: Duration) -> String {
+    let secs = remaining.as_secs_f64().round() as u64;
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, mins, secs)
+    } else {
+        format!("{:02}:{:02}", mins, secs)
     }
 }
 
-impl ProgressBar for VisualBar {
-    fn set_size(&self, size: u64) {
-        self.bar.set_length(size);
+// E.g. "120.00 MiB/s, 00:42 remaining".
+fn rate_message(rate: f64, eta: Option<Duration>) -> String {
+    let rate = format!("{}/s", HumanBytes(rate as u64));
+    match eta {
+        Some(eta) => format!("{}, {} remaining", rate, format_remaining(eta)),
+        None => rate,
     }
+}
+
+/// Terminal progress-bar renderer.
+struct BarRenderer {
+    bar: indicatif::ProgressBar,
+    throughput: RefCell<Throughput>,
+}
 
-    fn inc_size(&self, size: u64) {
-        self.bar.inc_length(size);
+impl BarRenderer {
+    fn new(target: indicatif::ProgressDrawTarget) -> Result<Self> {
+        let bar = indicatif::ProgressBar::with_draw_target(Some(0), target).with_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({msg})")?
+                .progress_chars("#>-"),
+        );
+        bar.set_message(rate_message(0.0, None));
+        let mut throughput = Throughput::new(RATE_WINDOW);
+        throughput.record(Instant::now(), 0);
+        Ok(Self { bar, throughput: RefCell::new(throughput) })
     }
+}
 
-    fn inc(&self, size: u64) {
-        self.bar.inc(size);
+impl ProgressRenderer for BarRenderer {
+    fn render(&self, progress: &Progress) {
+        self.bar.set_length(progress.total);
+        self.bar.set_position(progress.copied);
+        let mut throughput = self.throughput.borrow_mut();
+        throughput.record(Instant::now(), progress.copied);
+        self.bar.set_message(rate_message(throughput.rate(), throughput.eta(progress.total)));
     }
 
-    fn end(&self) {
+    fn finish(&self, progress: &Progress) {
+        self.render(progress);
         self.bar.finish();
     }
 }
 
-impl VisualBar {
-    fn new(size: u64) -> Result<Self> {
-        let bar = indicatif::ProgressBar::new(size).with_style(
-            indicatif::ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
-                .progress_chars("#>-"),
-        );
-        Ok(Self { bar })
+// Quote a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // Writing to a String cannot fail.
+            c if c.is_control() => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_path(path: &Path) -> String {
+    json_string(&path.to_string_lossy())
+}
+
+/// The JSON record for an update; see the [module](self)
+/// documentation.
+fn json_event(update: &StatusUpdate, progress: &Progress) -> String {
+    match update {
+        StatusUpdate::Started(path) =>
+            format!(r#"{{"event":"started","path":{}}}"#, json_path(path)),
+        StatusUpdate::Copied(_) | StatusUpdate::Size(_) =>
+            format!(r#"{{"event":"progress","bytes_done":{},"bytes_total":{}}}"#, progress.copied, progress.total),
+        StatusUpdate::Completed { reflinked } =>
+            format!(r#"{{"event":"completed","files_done":{},"reflinked":{}}}"#, progress.files, reflinked),
+        StatusUpdate::Skipped(path) =>
+            format!(r#"{{"event":"skipped","path":{}}}"#, json_path(path)),
+        StatusUpdate::Error(e) =>
+            format!(r#"{{"event":"error","message":{}}}"#, json_string(&e.to_string())),
+    }
+}
+
+fn json_summary(progress: &Progress, elapsed: Duration) -> String {
+    let status = if progress.errors > 0 { "failed" } else { "ok" };
+    format!(concat!(r#"{{"event":"summary","status":"{}","bytes_done":{},"bytes_total":{},"files":{},"#,
+                    r#""reflinked":{},"skipped":{},"errors":{},"elapsed_secs":{:.3}}}"#),
+            status, progress.copied, progress.total, progress.files,
+            progress.reflinked, progress.skipped, progress.errors, elapsed.as_secs_f64())
+}
+
+/// Newline-delimited JSON renderer, writing to stdout.
+struct JsonRenderer {
+    start: Instant,
+}
+
+impl JsonRenderer {
+    fn emit(&self, record: &str) {
+        // As with print!, but a closed pipe isn't fatal to the copy.
+        let _ = writeln!(io::stdout().lock(), "{}", record);
+    }
+}
+
+impl ProgressRenderer for JsonRenderer {
+    fn event(&self, update: &StatusUpdate, progress: &Progress) {
+        self.emit(&json_event(update, progress));
+    }
+
+    fn render(&self, _progress: &Progress) {
+    }
+
+    fn finish(&self, progress: &Progress) {
+        self.emit(&json_summary(progress, self.start.elapsed()));
     }
 }
 
-pub fn create_bar(opts: &Opts, size: u64) -> Result<Box<dyn ProgressBar>> {
-    if opts.no_progress {
-        Ok(Box::new(NoopBar {}))
+pub fn create_renderer(opts: &Opts) -> Result<Box<dyn ProgressRenderer>> {
+    if opts.json {
+        Ok(Box::new(JsonRenderer { start: Instant::now() }))
+    } else if opts.no_progress || opts.prompts() {
+        // The bar would redraw over any overwrite prompts.
+        Ok(Box::new(QuietRenderer {}))
     } else {
-        Ok(Box::new(VisualBar::new(size)?))
+        Ok(Box::new(BarRenderer::new(indicatif::ProgressDrawTarget::stderr())?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use libxcp::errors::XcpError;
+
+    #[derive(Default)]
+    struct RecordingRenderer {
+        frames: RefCell<Vec<Progress>>,
+        finished: RefCell<Option<Progress>>,
+    }
+
+    impl ProgressRenderer for RecordingRenderer {
+        fn render(&self, progress: &Progress) {
+            self.frames.borrow_mut().push(*progress);
+        }
+        fn finish(&self, progress: &Progress) {
+            *self.finished.borrow_mut() = Some(*progress);
+        }
+    }
+
+    fn feed(renderer: &dyn ProgressRenderer, updates: Vec<StatusUpdate>) -> Progress {
+        let mut progress = Progress::default();
+        for update in updates {
+            progress.update(&update);
+            renderer.render(&progress);
+        }
+        renderer.finish(&progress);
+        progress
+    }
+
+    #[test]
+    fn test_progress_aggregates_updates() {
+        let mut progress = Progress::default();
+        progress.update(&StatusUpdate::Size(100));
+        progress.update(&StatusUpdate::Copied(40));
+        progress.update(&StatusUpdate::Size(50));
+        progress.update(&StatusUpdate::Copied(60));
+        progress.update(&StatusUpdate::Skipped("fifo".into()));
+        progress.update(&StatusUpdate::Completed { reflinked: false });
+        progress.update(&StatusUpdate::Completed { reflinked: true });
+        progress.update(&StatusUpdate::Error(XcpError::CopyError("counted".to_string())));
+
+        assert_eq!(Progress {
+            copied: 100,
+            total: 150,
+            skipped: 1,
+            files: 2,
+            reflinked: 1,
+            errors: 1,
+        }, progress);
+    }
+
+    #[test]
+    fn test_renderer_receives_each_state() {
+        let renderer = RecordingRenderer::default();
+        feed(&renderer, vec![
+            StatusUpdate::Size(10),
+            StatusUpdate::Copied(4),
+            StatusUpdate::Copied(6),
+        ]);
+
+        assert_eq!(vec![
+            Progress { copied: 0, total: 10, skipped: 0, ..Progress::default() },
+            Progress { copied: 4, total: 10, skipped: 0, ..Progress::default() },
+            Progress { copied: 10, total: 10, skipped: 0, ..Progress::default() },
+        ], *renderer.frames.borrow());
+        assert_eq!(Some(Progress { copied: 10, total: 10, skipped: 0, ..Progress::default() }), *renderer.finished.borrow());
+    }
+
+    #[test]
+    fn test_bar_renderer() -> Result<()> {
+        let renderer = BarRenderer::new(indicatif::ProgressDrawTarget::hidden())?;
+        let mut progress = Progress::default();
+        for update in [StatusUpdate::Size(1024), StatusUpdate::Copied(512), StatusUpdate::Size(1024)] {
+            progress.update(&update);
+            renderer.render(&progress);
+        }
+
+        assert_eq!(Some(2048), renderer.bar.length());
+        assert_eq!(512, renderer.bar.position());
+        assert!(!renderer.bar.is_finished());
+
+        renderer.finish(&progress);
+        assert!(renderer.bar.is_finished());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_records() {
+        assert_eq!(r#""a \"b\"\\c\n\u0001""#, json_string("a \"b\"\\c\n\u{1}"));
+
+        let mut progress = Progress::default();
+        let mut records = Vec::new();
+        for update in [
+            StatusUpdate::Size(100),
+            StatusUpdate::Started("dir/a file".into()),
+            StatusUpdate::Copied(100),
+            StatusUpdate::Completed { reflinked: false },
+            StatusUpdate::Skipped("fifo".into()),
+            StatusUpdate::Error(XcpError::CopyError("failed".to_string())),
+        ] {
+            progress.update(&update);
+            records.push(json_event(&update, &progress));
+        }
+        assert_eq!(vec![
+            r#"{"event":"progress","bytes_done":0,"bytes_total":100}"#,
+            r#"{"event":"started","path":"dir/a file"}"#,
+            r#"{"event":"progress","bytes_done":100,"bytes_total":100}"#,
+            r#"{"event":"completed","files_done":1,"reflinked":false}"#,
+            r#"{"event":"skipped","path":"fifo"}"#,
+            r#"{"event":"error","message":"Error during copy: failed"}"#,
+        ], records);
+
+        assert_eq!(
+            r#"{"event":"summary","status":"failed","bytes_done":100,"bytes_total":100,"files":1,"reflinked":0,"skipped":1,"errors":1,"elapsed_secs":1.500}"#,
+            json_summary(&progress, Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_rate_message() {
+        assert_eq!("0 B/s", rate_message(0.0, None));
+        assert_eq!("120.00 MiB/s, 00:42 remaining", rate_message(120.0 * 1024.0 * 1024.0, Some(Duration::from_secs(42))));
+        assert_eq!("1.00 KiB/s, 1:01:05 remaining", rate_message(1024.0, Some(Duration::from_millis(3_664_600))));
+        assert_eq!("59:59", format_remaining(Duration::from_secs(3599)));
     }
 }
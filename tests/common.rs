@@ -14,8 +14,9 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::fs::{create_dir_all, set_permissions, write, File, Permissions};
-use std::os::unix::fs::{symlink, PermissionsExt};
+use std::fs::{create_dir_all, hard_link, metadata, read_dir, read_link, set_permissions, write, File, Permissions};
+use std::os::unix::fs::{chown, symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::process::Command;
 use std::os::unix::net::UnixListener;
 use cfg_if::cfg_if;
 use test_case::test_case;
@@ -100,6 +101,127 @@ fn dest_file_exists(drv: &str) {
     assert!(stderr.contains("Destination file exists"));
 }
 
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn interactive_without_terminal(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    // Standard input is not a terminal, so this is --no-clobber.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--interactive",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Destination file exists"));
+    assert!(file_contains(&dest_path, "old").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn interactive_declined_is_skipped(drv: &str) {
+    use rustix::pty::{grantpt, openpt, ptsname, unlockpt, OpenptFlags};
+    use std::ffi::OsStr;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    // Prompts need a terminal, so answer from a pseudo-terminal.
+    let master = File::from(openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY).unwrap());
+    grantpt(&master).unwrap();
+    unlockpt(&master).unwrap();
+    let name = ptsname(&master, Vec::new()).unwrap();
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(OsStr::from_bytes(name.as_bytes()))
+        .unwrap();
+    (&master).write_all(b"n\n").unwrap();
+
+    let out = get_command().unwrap()
+        .args([
+            "--driver", drv,
+            "--interactive",
+            "--keep-going",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .stdin(tty)
+        .output().unwrap();
+    println!("STDERR: {}", String::from_utf8_lossy(&out.stderr));
+
+    // Declined, so skipped rather than failed.
+    assert_eq!(Some(1), out.status.code());
+    assert!(file_contains(&dest_path, "old").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_summary_line(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    create_file(&source.join("a.txt"), &"a".repeat(1000)).unwrap();
+    create_file(&source.join("b.txt"), &"b".repeat(24)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--no-progress",
+        "--summary",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert_eq!(1, stdout.lines().count());
+    assert!(stdout.contains("Copied 2 files, 1024 bytes (1.00 KiB), 1024 bytes written (1.00 KiB) in "));
+    assert!(stdout.trim_end().ends_with("/s"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_empty_file(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("empty.txt");
+    let dest_path = dir.path().join("dest.txt");
+    File::create(&source_path).unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o640)).unwrap();
+    set_time_past(&source_path).unwrap();
+    create_file(&dest_path, "existing data").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--block-size", "4096",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let from = source_path.metadata().unwrap();
+    let to = dest_path.metadata().unwrap();
+    assert_eq!(0, to.len());
+    assert_eq!(0o640, to.permissions().mode() & 0o777);
+    assert!(timestamps_same(&from.modified().unwrap(), &to.modified().unwrap()));
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn source_same_as_dest(drv: &str) {
@@ -290,6 +412,7 @@ fn same_file_no_overwrite(drv: &str) {
     .unwrap();
 
     assert!(! out.status.success());
+    assert!(file_contains(&source_path, "falskjdfa;lskdjfa").unwrap());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
@@ -440,6 +563,33 @@ fn file_copy_perms(drv: &str) {
     }
 }
 
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_chmod(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "This is a test file.").unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o666)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--chmod",
+        "go-w,u+x",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+    assert_eq!(0o744, dest_path.metadata().unwrap().permissions().mode() & 0o7777);
+    assert_eq!(0o666, source_path.metadata().unwrap().permissions().mode() & 0o7777);
+}
+
 #[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 #[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
@@ -1093,9 +1243,9 @@ fn test_socket_file(drv: &str) {
     ]).unwrap();
     assert!(out.status.success());
 
-    assert!(to.exists());
-    let ftype = to.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    // Sockets are skipped.
+    assert!(!to.exists());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Skipping socket"));
 }
 
 #[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
@@ -1122,9 +1272,9 @@ fn test_sockets_dir(drv: &str) {
     ]).unwrap();
     assert!(out.status.success());
 
-    assert!(to.exists());
-    let ftype = to.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    assert!(to_dir.is_dir());
+    assert!(!to.exists());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Skipping socket"));
 }
 
 #[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
@@ -1138,7 +1288,7 @@ fn unreadable_file_error(drv: &str) {
 
     create_file(&source_path, text).unwrap();
 
-    let perms = Permissions::from_mode(0);
+    let perms = Permissions::from_mode(0o0);
     set_permissions(&source_path, perms).unwrap();
 
     let out = run(&[
@@ -1164,7 +1314,7 @@ fn dest_file_exists_not_writable(drv: &str) {
         create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
         File::create(&dest_path).unwrap();
     }
-    set_permissions(&dest_path, Permissions::from_mode(0)).unwrap();
+    set_permissions(&dest_path, Permissions::from_mode(0o0)).unwrap();
 
     let out = run(&[
         "--driver",
@@ -1371,3 +1521,1144 @@ fn test_deep_symlinks(drv: &str) {
     let stderr = String::from_utf8(out.stderr).unwrap();
     assert!(stderr.contains("Too many levels of symbolic links"));
 }
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_newest_sources(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("logs");
+    let dest = dir.path().join("archive");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&dest).unwrap();
+
+    for (n, age) in [(1, 400), (2, 100), (3, 300), (4, 200)] {
+        let file = source.join(format!("app.log.{}", n));
+        create_file(&file, &format!("log {}", n)).unwrap();
+        set_time_ago(&file, age).unwrap();
+    }
+
+    let pattern = source.join("app.log.*");
+    let out = run(&[
+        "--driver", drv,
+        "--glob",
+        "--newest", "2",
+        pattern.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest.join("app.log.1").exists());
+    assert!(dest.join("app.log.2").exists());
+    assert!(!dest.join("app.log.3").exists());
+    assert!(dest.join("app.log.4").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_newest_after_filters(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("logs");
+    let dest = dir.path().join("archive");
+    create_dir_all(source.join("old")).unwrap();
+
+    // The newest file is too small, and the next is excluded, so the
+    // two newest of the rest are copied from within the tree.
+    for (name, age, size) in [("a.log", 100, 1), ("b.log", 200, 100), ("c.log", 300, 100),
+                              ("old/d.log", 400, 100), ("old/e.log", 500, 100)] {
+        let file = source.join(name);
+        create_file(&file, &"x".repeat(size)).unwrap();
+        set_time_ago(&file, age).unwrap();
+    }
+    // The directory itself is newer than any of its files.
+    set_time_ago(&source, 0).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--newest", "2",
+        "--min-size", "10",
+        "--exclude", "b.log",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(!dest.join("a.log").exists());
+    assert!(!dest.join("b.log").exists());
+    assert!(dest.join("c.log").exists());
+    assert!(dest.join("old/d.log").exists());
+    assert!(!dest.join("old/e.log").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_wide_tree_with_fd_limit(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+
+    for n in 0..64 {
+        create_file(&source.join(format!("file{}.txt", n)), &format!("file {}", n)).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "--workers", "8",
+        "--max-open-fds", "4",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    for n in 0..64 {
+        assert!(file_contains(&dest.join(format!("file{}.txt", n)), &format!("file {}", n)).unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_to_devnull_reports_throughput(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("a.txt"), &"a".repeat(1000)).unwrap();
+    create_file(&source.join("sub/b.txt"), &"b".repeat(24)).unwrap();
+
+    let null_mode = metadata("/dev/null").unwrap().permissions().mode();
+
+    let out = run(&[
+        "--driver", drv,
+        "--no-progress",
+        "-r",
+        source.to_str().unwrap(),
+        "/dev/null",
+    ]).unwrap();
+
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Read 1024 bytes"));
+    assert!(stdout.contains("/s"));
+
+    let meta = metadata("/dev/null").unwrap();
+    assert!(meta.file_type().is_char_device());
+    assert_eq!(null_mode, meta.permissions().mode());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
+fn copy_regular_only(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(source.join("sub")).unwrap();
+
+    create_file(&source.join("file.txt"), "data").unwrap();
+    create_file(&source.join("sub/nested.txt"), "nested").unwrap();
+    symlink("file.txt", source.join("link")).unwrap();
+    let _sock = UnixListener::bind(source.join("sub/sock")).unwrap();
+    let out = Command::new("mkfifo")
+        .arg(source.join("fifo"))
+        .output().unwrap();
+    assert!(out.status.success());
+
+    let out = run(&[
+        "--driver", drv,
+        "--regular-only",
+        "-vv",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest.join("file.txt"), "data").unwrap());
+    assert!(file_contains(&dest.join("sub/nested.txt"), "nested").unwrap());
+    for skipped in ["link", "fifo", "sub/sock"] {
+        assert!(dest.join(skipped).symlink_metadata().is_err());
+    }
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Skipped"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_onto_hard_link(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&dest).unwrap();
+    create_file(&source.join("file.txt"), "original").unwrap();
+    create_file(&source.join("other.txt"), "other").unwrap();
+    create_dir_all(dest.join("source")).unwrap();
+    hard_link(source.join("file.txt"), dest.join("source/file.txt")).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("same file"));
+    assert!(file_contains(&source.join("file.txt"), "original").unwrap());
+
+    let out = run(&[
+        "--driver", drv,
+        "--same-file", "skip",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&source.join("file.txt"), "original").unwrap());
+    assert!(file_contains(&dest.join("source/other.txt"), "other").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_metrics(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    let metrics = dir.path().join("xcp.prom");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("one.txt"), "one").unwrap();
+    create_file(&source.join("two.txt"), "two!").unwrap();
+    write(source.join("sub/three.bin"), rand_data(3 * 1024 * 1024)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--reflink", "never",
+        "--metrics", metrics.to_str().unwrap(),
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let text = std::fs::read_to_string(&metrics).unwrap();
+    let values = text.lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| {
+            let (name, value) = l.split_once(' ').unwrap();
+            (name.to_string(), value.parse::<f64>().unwrap())
+        })
+        .collect::<std::collections::HashMap<String, f64>>();
+
+    assert_eq!(5, values.len());
+    assert_eq!(3.0, values["files_copied_total"]);
+    assert_eq!((3 + 4 + 3 * 1024 * 1024) as f64, values["bytes_copied_total"]);
+    assert_eq!(0.0, values["reflinks_total"]);
+    assert_eq!(0.0, values["errors_total"]);
+    assert!(values["duration_seconds"] >= 0.0);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn concurrent_copies_with_lock(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let len = 1024 * 1024;
+    let sources = ["a.bin", "b.bin"].map(|name| {
+        let path = dir.path().join(name);
+        write(&path, rand_data(len)).unwrap();
+        path
+    });
+    let dest = dir.path().join("dest.bin");
+
+    let children = sources.iter()
+        .map(|source| {
+            get_command().unwrap()
+                .args([
+                    "--driver", drv,
+                    "--lock",
+                    "--reflink", "never",
+                    "--block-size", "4KB",
+                    "--no-progress",
+                    source.to_str().unwrap(),
+                    dest.to_str().unwrap(),
+                ])
+                .spawn().unwrap()
+        })
+        .collect::<Vec<_>>();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    // The copies were serialised, so the result is one source intact.
+    assert!(sources.iter().any(|source| files_match(source, &dest)));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn output_escapes_control_chars(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    let name = "evil\nname\x1b[31m";
+    create_file(&source.join(name), "data").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-v",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join(name), "data").unwrap());
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("evil\\nname\\x1b[31m"));
+    assert!(!stdout.contains(name));
+    assert!(!stdout.contains("\x1b[31m"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_strip_components(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(source.join("sub/deeper")).unwrap();
+    create_dir_all(&dest).unwrap();
+    create_file(&source.join("top.txt"), "top").unwrap();
+    create_file(&source.join("sub/nested.txt"), "nested").unwrap();
+    create_file(&source.join("sub/deeper/deep.txt"), "deep").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--strip-components", "1",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(!dest.join("source").exists());
+    assert!(file_contains(&dest.join("top.txt"), "top").unwrap());
+    assert!(file_contains(&dest.join("sub/nested.txt"), "nested").unwrap());
+    assert!(file_contains(&dest.join("sub/deeper/deep.txt"), "deep").unwrap());
+
+    // Files with too few components are skipped.
+    let dest2 = dir.path().join("dest2");
+    create_dir_all(&dest2).unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "--strip-components", "2",
+        "-r",
+        source.to_str().unwrap(),
+        dest2.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(!dest2.join("top.txt").exists());
+    assert!(file_contains(&dest2.join("nested.txt"), "nested").unwrap());
+    assert!(file_contains(&dest2.join("deeper/deep.txt"), "deep").unwrap());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Skipping"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_ignore_existing(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(source.join("sub")).unwrap();
+    create_dir_all(dest.join("sub")).unwrap();
+    for name in ["a.txt", "b.txt", "sub/c.txt", "sub/d.txt"] {
+        create_file(&source.join(name), name).unwrap();
+    }
+    // Half the tree is already present, with different content.
+    for name in ["b.txt", "sub/c.txt"] {
+        create_file(&dest.join(name), "existing").unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "--ignore-existing",
+        "-r",
+        "-T",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest.join("a.txt"), "a.txt").unwrap());
+    assert!(file_contains(&dest.join("sub/d.txt"), "sub/d.txt").unwrap());
+    assert!(file_contains(&dest.join("b.txt"), "existing").unwrap());
+    assert!(file_contains(&dest.join("sub/c.txt"), "existing").unwrap());
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("Copied 2 files, skipped 2 already present (50.0% coverage)"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_expect_hash(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new content").unwrap();
+    create_file(&dest_path, "deployed").unwrap();
+
+    // SHA-256 of "deployed"; the destination is left untouched.
+    let out = run(&[
+        "--driver", drv,
+        "--expect-hash", "sha256:c1fa83ed9f3b817e225d7994e58324f4e7da3e260281dfc89bc2ff16953c4304",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "deployed").unwrap());
+
+    // SHA-256 of "something else"; the copy goes ahead.
+    let out = run(&[
+        "--driver", drv,
+        "--expect-hash", "f41f3fa625ff120ddca7ef456bf66371ecea23c129f4e4c32367101edb516cf8",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+
+    let out = run(&[
+        "--driver", drv,
+        "--expect-hash", "not-a-hash",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_keep_going_exit_codes(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        create_file(&source.join(name), name).unwrap();
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "--keep-going",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert_eq!(Some(0), out.status.code());
+
+    // A directory in the way of one file makes its copy fail.
+    let dest = dir.path().join("dest2");
+    create_dir_all(dest.join("b.txt")).unwrap();
+    let out = run(&[
+        "--driver", drv,
+        "--keep-going",
+        "--exit-codes", "errors=7",
+        "-r",
+        "-T",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert_eq!(Some(7), out.status.code());
+    assert!(file_contains(&dest.join("a.txt"), "a.txt").unwrap());
+    assert!(file_contains(&dest.join("c.txt"), "c.txt").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_status_file(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let status_path = dir.path().join("status");
+    create_file(&source_path, "This is a test file.").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--status-file", status_path.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+
+    let out = run(&["--status", status_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.starts_with("Finished: copied 20 B of 20 B (100.0%), 1 files, 0 skipped, 0 errors"), "{}", stdout);
+
+    // A run that died part-way.
+    write(&status_path, "xcp-status 1\npid=999999999\nstate=running\nupdated=0\ntotal=100\ncopied=50\n").unwrap();
+    let out = run(&["--status", status_path.to_str().unwrap()]).unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.starts_with("Stale (process 999999999 has exited): copied 50 B of 100 B (50.0%)"), "{}", stdout);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_usermap(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "This is a test file.").unwrap();
+    if let Err(e) = chown(&source_path, Some(1000), Some(1000)) {
+        // Requires root.
+        println!("Skipping: unable to chown source: {}", e);
+        return;
+    }
+
+    let out = run(&[
+        "--driver", drv,
+        "--usermap", "1000:2000,3000:4000",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+
+    let meta = dest_path.metadata().unwrap();
+    assert_eq!(2000, meta.uid());
+    // No group mapping; preserved as-is.
+    assert_eq!(1000, meta.gid());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_rename_on_conflict(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(dir.path().join("a")).unwrap();
+    create_dir_all(dir.path().join("b")).unwrap();
+    create_dir_all(&dest).unwrap();
+    let a = dir.path().join("a/file.txt");
+    let b = dir.path().join("b/file.txt");
+    create_file(&a, "from a").unwrap();
+    create_file(&b, "from b").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--overwrite", "rename",
+        a.to_str().unwrap(),
+        b.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    // Either may be copied first.
+    let mut contents = ["file.txt", "file (1).txt"]
+        .map(|name| std::fs::read_to_string(dest.join(name)).unwrap());
+    contents.sort();
+    assert_eq!(["from a", "from b"], contents);
+    assert!(!dest.join("file (2).txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_verify(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let sparse_path = dir.path().join("sparse.bin");
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+    // Not a multiple of the direct I/O alignment.
+    write(&source_path, rand_data(1024 * 1024 + 4321)).unwrap();
+    File::create(&sparse_path).unwrap().set_len(3 * 1024 * 1024 + 1).unwrap();
+
+    for verify in ["cached", "direct"] {
+        let out = run(&[
+            "--driver", drv,
+            "--verify", verify,
+            source_path.to_str().unwrap(),
+            sparse_path.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(files_match(&source_path, &dest.join("source.bin")));
+        assert!(files_match(&sparse_path, &dest.join("sparse.bin")));
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_multiply_linked_file(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(&source).unwrap();
+    create_file(&source.join("file.txt"), "content").unwrap();
+    hard_link(source.join("file.txt"), dir.path().join("link.txt")).unwrap();
+
+    let dest = dir.path().join("warned");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--hard-links", "warn",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stdout).contains("has 2 hard links"));
+    assert!(file_contains(&dest.join("file.txt"), "content").unwrap());
+
+    let dest = dir.path().join("silent");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("hard links"));
+
+    let dest = dir.path().join("skipped");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--hard-links", "skip",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(dest.is_dir());
+    assert!(!dest.join("file.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_delta_in_place(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.img");
+    let dest_path = dir.path().join("dest.img");
+    let mut data = rand_data(4 * 1024 * 1024);
+    write(&dest_path, &data).unwrap();
+    let ino = metadata(&dest_path).unwrap().ino();
+    for b in &mut data[2 * 1024 * 1024..2 * 1024 * 1024 + 1000] {
+        *b = !*b;
+    }
+    data.truncate(3 * 1024 * 1024 + 17);
+    write(&source_path, &data).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--delta",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+    // Updated in place, not replaced.
+    assert_eq!(ino, metadata(&dest_path).unwrap().ino());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_optimize_auto(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    write(source.join("file.bin"), rand_data(3 * 1024 * 1024 + 7)).unwrap();
+    File::create(source.join("sparse.bin")).unwrap().set_len(4 * 1024 * 1024).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--optimize", "auto",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source.join("file.bin"), &dest.join("file.bin")));
+    assert!(files_match(&source.join("sparse.bin"), &dest.join("sparse.bin")));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn copy_symlinks_modes(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
+
+    let skipped = dir.path().join("skipped");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--symlinks", "skip",
+        source_path.to_str().unwrap(),
+        skipped.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&skipped.join("file.txt"), "orig").unwrap());
+    assert!(skipped.join("link.txt").symlink_metadata().is_err());
+
+    let followed = dir.path().join("followed");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--symlinks", "follow",
+        source_path.to_str().unwrap(),
+        followed.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    let link = followed.join("link.txt");
+    assert!(!link.symlink_metadata().unwrap().file_type().is_symlink());
+    assert!(file_contains(&link, "orig").unwrap());
+
+    let preserved = dir.path().join("preserved");
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        source_path.to_str().unwrap(),
+        preserved.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert_eq!(Some("file.txt"), read_link(preserved.join("link.txt")).unwrap().to_str());
+
+    let out = run(&[
+        "--dereference",
+        "--symlinks", "skip",
+        source_path.to_str().unwrap(),
+        dir.path().join("conflict").to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_preserve_hard_links(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(source.join("sub")).unwrap();
+    create_file(&source.join("file.txt"), "content").unwrap();
+    hard_link(source.join("file.txt"), source.join("sub/link1.txt")).unwrap();
+    hard_link(source.join("file.txt"), source.join("sub/link2.txt")).unwrap();
+    // Outside the copied tree.
+    hard_link(source.join("file.txt"), dir.path().join("outside.txt")).unwrap();
+    create_file(&source.join("other.txt"), "other").unwrap();
+
+    let dest = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--hard-links", "preserve",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let meta = metadata(dest.join("file.txt")).unwrap();
+    assert_eq!(3, meta.nlink());
+    for link in ["sub/link1.txt", "sub/link2.txt"] {
+        assert!(file_contains(&dest.join(link), "content").unwrap());
+        assert_eq!(meta.ino(), metadata(dest.join(link)).unwrap().ino());
+    }
+    assert_eq!(1, metadata(dest.join("other.txt")).unwrap().nlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_overwrite_never_and_if_newer(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    create_dir_all(&dest).unwrap();
+    create_file(&source.join("newer.txt"), "new").unwrap();
+    create_file(&source.join("older.txt"), "new").unwrap();
+    create_file(&source.join("missing.txt"), "new").unwrap();
+    create_file(&dest.join("newer.txt"), "old").unwrap();
+    create_file(&dest.join("older.txt"), "old").unwrap();
+    set_time_ago(&dest.join("newer.txt"), 3600).unwrap();
+    set_time_ago(&source.join("older.txt"), 3600).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-T",
+        "--overwrite", "never",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join("newer.txt"), "old").unwrap());
+    assert!(file_contains(&dest.join("older.txt"), "old").unwrap());
+    assert!(file_contains(&dest.join("missing.txt"), "new").unwrap());
+
+    let out = run(&[
+        "--driver", drv,
+        "-r", "-T",
+        "--overwrite", "if-newer",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join("newer.txt"), "new").unwrap());
+    assert!(file_contains(&dest.join("older.txt"), "old").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_bwlimit(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024)).unwrap();
+
+    let start = std::time::Instant::now();
+    let out = run(&[
+        "--driver", drv,
+        "--reflink", "never",
+        "--bwlimit", "512K",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+    // The bucket starts empty, so 1MB takes about 2s.
+    assert!(start.elapsed().as_millis() >= 1500, "{:?}", start.elapsed());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_drop_cache(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--drop-cache",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_direct_io(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(1024 * 1024 + 1000)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "--direct-io",
+        "--reflink", "never",
+        "--block-size", "64K",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_size_limits(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    write(source.join("small.bin"), rand_data(100)).unwrap();
+    write(source.join("medium.bin"), rand_data(10 * 1024)).unwrap();
+    write(source.join("large.bin"), rand_data(100 * 1024)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--min-size", "1K",
+        "--max-size", "64K",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(!dest.join("small.bin").exists());
+    assert!(files_match(&source.join("medium.bin"), &dest.join("medium.bin")));
+    assert!(!dest.join("large.bin").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_exclude_patterns(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(source.join("src")).unwrap();
+    create_dir_all(source.join("node_modules/pkg")).unwrap();
+    create_file(&source.join("src/main.c"), "main").unwrap();
+    create_file(&source.join("src/main.o"), "object").unwrap();
+    create_file(&source.join("scratch.tmp"), "scratch").unwrap();
+    create_file(&source.join("node_modules/pkg/index.js"), "js").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--exclude", "*.tmp",
+        "--exclude", "node_modules",
+        "--exclude-regex", r"\.o$",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest.join("src/main.c"), "main").unwrap());
+    assert!(!dest.join("src/main.o").exists());
+    assert!(!dest.join("scratch.tmp").exists());
+    assert!(!dest.join("node_modules").exists());
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--exclude-regex", "(",
+        source.to_str().unwrap(),
+        dir.path().join("other").to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_json_progress(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    write(source.join("a.bin"), rand_data(4096)).unwrap();
+    write(source.join("b.bin"), rand_data(1024)).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--json",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert!(lines.iter().all(|l| l.starts_with(r#"{"event":""#) && l.ends_with('}')), "{}", stdout);
+    assert_eq!(2, lines.iter().filter(|l| l.starts_with(r#"{"event":"started""#)).count());
+    assert_eq!(2, lines.iter().filter(|l| l.starts_with(r#"{"event":"completed""#)).count());
+    let summary = lines.last().unwrap();
+    assert!(summary.starts_with(r#"{"event":"summary","status":"ok","bytes_done":5120,"bytes_total":5120,"files":2,"#), "{}", summary);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_atomic(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    let dest = dir.path().join("dest");
+    create_dir_all(&source).unwrap();
+    write(source.join("a.bin"), rand_data(64 * 1024)).unwrap();
+    create_file(&source.join("b.txt"), "b").unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--atomic",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(files_match(&source.join("a.bin"), &dest.join("a.bin")));
+    assert!(file_contains(&dest.join("b.txt"), "b").unwrap());
+    assert_eq!(2, read_dir(&dest).unwrap().count());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_perms_modes(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("script.sh");
+    create_file(&source, "#!/bin/sh\n").unwrap();
+    set_permissions(&source, Permissions::from_mode(0o777)).unwrap();
+
+    // The umask is inherited by xcp. Read it without changing it, as
+    // other tests may be creating files.
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let umask = status.lines()
+        .find_map(|l| l.strip_prefix("Umask:"))
+        .map_or(0o022, |m| u32::from_str_radix(m.trim(), 8).unwrap());
+
+    for (perms, expected) in [("exact", 0o777), ("umask", 0o777 & !umask), ("default", 0o644)] {
+        let dest = dir.path().join(format!("script.{}", perms));
+        let out = run(&[
+            "--driver", drv,
+            "--perms", perms,
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(expected, metadata(&dest).unwrap().permissions().mode() & 0o7777, "{}", perms);
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_simple_backup(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let backup_path = dir.path().join("dest.txt~");
+    create_file(&dest_path, "first").unwrap();
+
+    for content in ["second", "third"] {
+        create_file(&source_path, content).unwrap();
+        let out = run(&[
+            "--driver", drv,
+            "--backup=simple",
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert!(out.status.success());
+        assert!(file_contains(&dest_path, content).unwrap());
+    }
+
+    // Only one simple backup is kept, holding the last replaced content.
+    assert!(file_contains(&backup_path, "second").unwrap());
+    assert!(!dir.path().join("dest.txt.~1~").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_file_trailing_slash(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    create_file(&source_path, "content").unwrap();
+    let dest_dir = dir.path().join("dest");
+    create_dir_all(&dest_dir).unwrap();
+
+    let out = run(&[
+        "--driver", drv,
+        source_path.to_str().unwrap(),
+        &format!("{}/", dest_dir.to_str().unwrap()),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_dir.join("source.txt"), "content").unwrap());
+
+    // As with cp, a trailing slash requires an existing directory.
+    let file_dest = dir.path().join("file.txt");
+    create_file(&file_dest, "original").unwrap();
+    for dest in [dir.path().join("missing"), file_dest.clone()] {
+        let out = run(&[
+            "--driver", drv,
+            source_path.to_str().unwrap(),
+            &format!("{}/", dest.to_str().unwrap()),
+        ])
+        .unwrap();
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("not a directory"));
+    }
+    assert!(!dir.path().join("missing").exists());
+    assert!(file_contains(&file_dest, "original").unwrap());
+
+    // A directory is copied to a new one, as with `cp -r`.
+    let source_dir = dir.path().join("tree");
+    create_dir_all(&source_dir).unwrap();
+    create_file(&source_dir.join("file.txt"), "in tree").unwrap();
+    let new_dir = dir.path().join("newdir");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        source_dir.to_str().unwrap(),
+        &format!("{}/", new_dir.to_str().unwrap()),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&new_dir.join("file.txt"), "in tree").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_directory_metadata(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    let sub = source_path.join("sub");
+    create_dir_all(&sub).unwrap();
+    create_file(&sub.join("file.txt"), "content").unwrap();
+
+    // Set after the contents are created, and read-only so the
+    // copy must defer it.
+    set_time_past(&sub).unwrap();
+    set_time_past(&source_path).unwrap();
+    set_permissions(&sub, Permissions::from_mode(0o555)).unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o750)).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let dest_sub = dest_base.join("sub");
+    assert!(file_contains(&dest_sub.join("file.txt"), "content").unwrap());
+    for (from, to, mode) in [(&source_path, &dest_base, 0o750), (&sub, &dest_sub, 0o555)] {
+        let smeta = from.metadata().unwrap();
+        let dmeta = to.metadata().unwrap();
+        assert_eq!(mode, dmeta.permissions().mode() & 0o7777);
+        assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+    }
+
+    set_permissions(&sub, Permissions::from_mode(0o755)).unwrap();
+    set_permissions(&dest_sub, Permissions::from_mode(0o755)).unwrap();
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_keep_going_walk_errors(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source = dir.path().join("source");
+    create_dir_all(&source).unwrap();
+    create_file(&source.join("a.txt"), "a").unwrap();
+    create_file(&source.join("c.txt"), "c").unwrap();
+    // Can't be dereferenced, so fails in the walk.
+    symlink("missing", source.join("b.txt")).unwrap();
+
+    let dest = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "--dereference",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert!(!out.status.success());
+
+    let dest = dir.path().join("dest2");
+    let out = run(&[
+        "--driver", drv,
+        "--dereference",
+        "--keep-going",
+        "-r",
+        source.to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ]).unwrap();
+    assert_eq!(Some(2), out.status.code());
+    assert!(file_contains(&dest.join("a.txt"), "a").unwrap());
+    assert!(file_contains(&dest.join("c.txt"), "c").unwrap());
+    assert!(!dest.join("b.txt").exists());
+
+    let output = String::from_utf8_lossy(&out.stdout).to_string() + &String::from_utf8_lossy(&out.stderr);
+    assert!(output.contains("Errors during copy (1):"), "{}", output);
+}
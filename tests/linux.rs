@@ -19,8 +19,10 @@ mod util;
 #[cfg(all(target_os = "linux", feature = "use_linux"))]
 mod test {
     use std::{process::Command, fs::{File, OpenOptions}, io::SeekFrom};
+    use std::fs::{create_dir_all, read_dir};
     use std::io::{Seek, Write};
-    use libfs::{map_extents, sync};
+    use std::os::unix::fs::MetadataExt;
+    use libfs::{map_extents, preallocate, sync};
     use test_case::test_case;
 
     use crate::util::*;
@@ -37,14 +39,14 @@ mod test {
         {
             let mut infd = File::create(&source_path).unwrap();
             let data = rand_data(size);
-            infd.write(&data).unwrap();
+            infd.write_all(&data).unwrap();
         }
 
         {
             let infd = File::open(&source_path).unwrap();
             let inext = map_extents(&infd).unwrap().unwrap();
             // Single file, extent not shared.
-            assert_eq!(false, inext[0].shared);
+            assert!(!inext[0].shared);
         }
 
         let out = run(&[
@@ -65,8 +67,8 @@ mod test {
             // Extents should be shared.
             let inext = map_extents(&infd).unwrap().unwrap();
             let outext = map_extents(&outfd).unwrap().unwrap();
-            assert_eq!(true, inext[0].shared);
-            assert_eq!(true, outext[0].shared);
+            assert!(inext[0].shared);
+            assert!(outext[0].shared);
         }
 
         {
@@ -77,7 +79,7 @@ mod test {
                 .open(&dest_path).unwrap();
             outfd.seek(SeekFrom::Start(0)).unwrap();
             let data = rand_data(size);
-            outfd.write(&data).unwrap();
+            outfd.write_all(&data).unwrap();
             // brtfs at least seems to need this to force CoW and
             // de-share the extents.
             sync(&outfd).unwrap();
@@ -89,8 +91,8 @@ mod test {
             // First extent should now be un-shared.
             let inext = map_extents(&infd).unwrap().unwrap();
             let outext = map_extents(&outfd).unwrap().unwrap();
-            assert_eq!(false, inext[0].shared);
-            assert_eq!(false, outext[0].shared);
+            assert!(!inext[0].shared);
+            assert!(!outext[0].shared);
         }
 
     }
@@ -274,4 +276,664 @@ mod test {
         println!("Compare trees...");
         compare_trees(&src, &dest).unwrap();
     }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
+    fn test_preallocate_strategies(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let slen = create_sparse(&from, 0, 0).unwrap();
+
+        for strategy in ["never", "always", "keep-size"] {
+            let to = dir.path().join(format!("{}.bin", strategy));
+            let out = run(&[
+                "--driver", drv,
+                "--preallocate", strategy,
+                from.to_str().unwrap(),
+                to.to_str().unwrap(),
+            ]).unwrap();
+            assert!(out.status.success());
+            assert_eq!(slen, to.metadata().unwrap().len());
+            assert!(files_match(&from, &to));
+
+            let extents = map_extents(&File::open(&to).unwrap()).unwrap().unwrap();
+            println!("Strategy {}: {} extents", strategy, extents.len());
+            if strategy == "never" {
+                assert!(probably_sparse(&to).unwrap());
+            } else {
+                assert!(!probably_sparse(&to).unwrap());
+                assert!(extents.last().unwrap().end >= slen);
+            }
+        }
+    }
+
+    #[cfg(feature = "parblock")]
+    #[test]
+    #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
+    fn parblock_skips_unwritten_extents() {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("prealloc.bin");
+        let to = dir.path().join("copy.bin");
+        let len = 4 * 1024 * 1024;
+
+        {
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&rand_data(64 * 1024)).unwrap();
+            assert!(preallocate(&fd, len, false).unwrap());
+        }
+        // Fully allocated, but mostly unwritten.
+        assert!(!probably_sparse(&from).unwrap());
+        let extents = map_extents(&File::open(&from).unwrap()).unwrap().unwrap();
+        assert!(extents.last().unwrap().unwritten);
+
+        let out = run(&[
+            "--driver", "parblock",
+            "--reflink=never",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert_eq!(len, to.metadata().unwrap().len());
+        assert!(files_match(&from, &to));
+        assert!(probably_sparse(&to).unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+    fn copy_file_capabilities(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("server");
+        let to = dir.path().join("server.copy");
+        create_file(&from, "#!/bin/sh\n").unwrap();
+
+        // VFS_CAP_REVISION_2 with cap_net_bind_service (10) permitted
+        // and effective.
+        let mut cap = Vec::with_capacity(20);
+        cap.extend_from_slice(&(0x0200_0000u32 | 1).to_le_bytes());
+        cap.extend_from_slice(&(1u32 << 10).to_le_bytes());
+        cap.extend_from_slice(&[0; 12]);
+        if let Err(e) = xattr::set(&from, "security.capability", &cap) {
+            // Requires CAP_SETFCAP (i.e. root).
+            println!("Skipping: unable to set file capabilities: {}", e);
+            return;
+        }
+
+        let out = run(&[
+            "--driver", drv,
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert_eq!(Some(cap), xattr::get(&to, "security.capability").unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_onto_bind_mount_alias(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let alias = dir.path().join("alias");
+        create_dir_all(&source).unwrap();
+        create_dir_all(&alias).unwrap();
+        create_file(&source.join("file.txt"), "original").unwrap();
+
+        let out = Command::new("mount")
+            .args(["--bind", source.to_str().unwrap(), alias.to_str().unwrap()])
+            .output().unwrap();
+        if !out.status.success() {
+            println!("Skipping: unable to create bind mount: {}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+
+        let out = run(&[
+            "--driver", drv,
+            source.join("file.txt").to_str().unwrap(),
+            alias.join("file.txt").to_str().unwrap(),
+        ]).unwrap();
+
+        let umount = Command::new("umount").arg(&alias).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(!out.status.success());
+        assert!(file_contains(&source.join("file.txt"), "original").unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_to_fs_without_inodes(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let image = dir.path().join("tiny.img");
+        let mnt = dir.path().join("mnt");
+        create_dir_all(&source).unwrap();
+        create_dir_all(&mnt).unwrap();
+        for i in 0..16 {
+            create_file(&source.join(format!("file{}.txt", i)), "data").unwrap();
+        }
+
+        // A filesystem with only a handful of free inodes.
+        File::create(&image).unwrap().set_len(4 * 1024 * 1024).unwrap();
+        let mkfs = Command::new("mkfs.ext4")
+            .args(["-q", "-N", "16", image.to_str().unwrap()])
+            .output();
+        let mounted = mkfs.is_ok_and(|out| out.status.success()) && Command::new("mount")
+            .args(["-o", "loop", image.to_str().unwrap(), mnt.to_str().unwrap()])
+            .output().unwrap()
+            .status.success();
+        if !mounted {
+            println!("Skipping: unable to create a loop filesystem");
+            return;
+        }
+
+        let checked = run(&[
+            "--driver", drv,
+            "--check-inodes",
+            "-r",
+            source.to_str().unwrap(),
+            mnt.to_str().unwrap(),
+        ]).unwrap();
+        let copied_before_check = mnt.join("source").exists();
+        let unchecked = run(&[
+            "--driver", drv,
+            "-r",
+            source.to_str().unwrap(),
+            mnt.to_str().unwrap(),
+        ]).unwrap();
+
+        let umount = Command::new("umount").arg(&mnt).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(!checked.status.success());
+        assert!(String::from_utf8_lossy(&checked.stderr).contains("Insufficient free inodes"));
+        assert!(!copied_before_check);
+
+        assert!(!unchecked.status.success());
+        assert!(String::from_utf8_lossy(&unchecked.stderr).contains("No free inodes"));
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_one_file_system_mount_point(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let mnt = source.join("mnt");
+        let dest = dir.path().join("dest");
+        create_dir_all(&mnt).unwrap();
+        create_file(&source.join("file.txt"), "data").unwrap();
+
+        let out = Command::new("mount")
+            .args(["-t", "tmpfs", "tmpfs", mnt.to_str().unwrap()])
+            .output().unwrap();
+        if !out.status.success() {
+            println!("Skipping: unable to mount tmpfs: {}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+        create_file(&mnt.join("mounted.txt"), "mounted").unwrap();
+        create_dir_all(mnt.join("subdir")).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--one-file-system",
+            "-r",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+
+        let umount = Command::new("umount").arg(&mnt).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(out.status.success());
+        assert!(file_contains(&dest.join("file.txt"), "data").unwrap());
+        // The mount point is created, but empty.
+        assert!(dest.join("mnt").is_dir());
+        assert_eq!(0, read_dir(dest.join("mnt")).unwrap().count());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_reproducible(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        create_dir_all(source.join("sub")).unwrap();
+        for n in 0..32 {
+            create_file(&source.join(format!("file{:02}", (n * 7) % 32)), "data").unwrap();
+            create_file(&source.join(format!("sub/file{:02}", (n * 5) % 32)), "data").unwrap();
+        }
+
+        // The order of each file's inode number in the copy; i.e. the
+        // order the files were created in.
+        let creation_order = |dest: &std::path::Path| {
+            let mut files = walkdir::WalkDir::new(dest).min_depth(1).into_iter()
+                .map(|e| e.unwrap())
+                .map(|e| (e.metadata().unwrap().ino(), e.path().strip_prefix(dest).unwrap().to_path_buf()))
+                .collect::<Vec<_>>();
+            files.sort();
+            files.into_iter().map(|(_, p)| p).collect::<Vec<_>>()
+        };
+
+        let mut runs = Vec::new();
+        for name in ["dest1", "dest2"] {
+            let dest = dir.path().join(name);
+            let out = run(&[
+                "--driver", drv,
+                "--workers", "8",
+                "--reproducible",
+                "-r",
+                source.to_str().unwrap(),
+                dest.to_str().unwrap(),
+            ]).unwrap();
+            assert!(out.status.success());
+            runs.push(creation_order(&dest));
+        }
+        assert_eq!(65, runs[0].len());
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+    fn copy_xattr_namespace_filter(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("file.txt");
+        create_file(&from, "data").unwrap();
+        xattr::set(&from, "user.foo", b"app").unwrap();
+        if let Err(e) = xattr::set(&from, "security.bar", b"label") {
+            // Requires CAP_SYS_ADMIN (i.e. root).
+            println!("Skipping: unable to set security xattr: {}", e);
+            return;
+        }
+
+        let to = dir.path().join("filtered.txt");
+        let out = run(&[
+            "--driver", drv,
+            "--xattr-include", "user.*",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(Some(b"app".to_vec()), xattr::get(&to, "user.foo").unwrap());
+        assert_eq!(None, xattr::get(&to, "security.bar").unwrap());
+
+        let to = dir.path().join("excluded.txt");
+        let out = run(&[
+            "--driver", drv,
+            "--xattr-exclude", "user.*",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(None, xattr::get(&to, "user.foo").unwrap());
+        assert_eq!(Some(b"label".to_vec()), xattr::get(&to, "security.bar").unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+    fn copy_preserve_xattr(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("file.txt");
+        create_file(&from, "data").unwrap();
+        xattr::set(&from, "user.foo", b"app").unwrap();
+
+        let to = dir.path().join("mode.txt");
+        let out = run(&[
+            "--driver", drv,
+            "--preserve", "mode",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(None, xattr::get(&to, "user.foo").unwrap());
+
+        let to = dir.path().join("xattr.txt");
+        let out = run(&[
+            "--driver", drv,
+            "--preserve", "xattr",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(Some(b"app".to_vec()), xattr::get(&to, "user.foo").unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_to_full_fs(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source.bin");
+        let mnt = dir.path().join("mnt");
+        create_dir_all(&mnt).unwrap();
+        File::create(&source).unwrap()
+            .write_all(&rand_data(4 * 1024 * 1024)).unwrap();
+
+        let out = Command::new("mount")
+            .args(["-t", "tmpfs", "-o", "size=1m", "tmpfs", mnt.to_str().unwrap()])
+            .output().unwrap();
+        if !out.status.success() {
+            println!("Skipping: unable to mount tmpfs: {}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+
+        let dest = mnt.join("dest.bin");
+        let out = run(&[
+            "--driver", drv,
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        let dest_exists = dest.exists();
+
+        let umount = Command::new("umount").arg(&mnt).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains("No space left"));
+        assert!(!dest_exists);
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_cross_device_reflink(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let mnt = dir.path().join("mnt");
+        create_dir_all(&mnt).unwrap();
+        let dest = dir.path().join("dest.bin");
+
+        let out = Command::new("mount")
+            .args(["-t", "tmpfs", "tmpfs", mnt.to_str().unwrap()])
+            .output().unwrap();
+        if !out.status.success() {
+            println!("Skipping: unable to mount tmpfs: {}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+        let source = mnt.join("source.bin");
+        let data = rand_data(1024 * 1024);
+        File::create(&source).unwrap().write_all(&data).unwrap();
+
+        // The reflink fails with EXDEV; auto quietly copies instead.
+        let auto = run(&[
+            "--driver", drv,
+            "--reflink=auto",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        let always = run(&[
+            "--driver", drv,
+            "--reflink=always",
+            source.to_str().unwrap(),
+            dir.path().join("always.bin").to_str().unwrap(),
+        ]).unwrap();
+        let copied = files_match(&source, &dest);
+
+        let umount = Command::new("umount").arg(&mnt).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(auto.status.success());
+        assert!(auto.stderr.is_empty(), "{}", String::from_utf8_lossy(&auto.stderr));
+        assert!(copied);
+        assert!(!always.status.success());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_special_files(drv: &str) {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("dest");
+        create_dir_all(&source).unwrap();
+        create_file(&source.join("file.txt"), "data").unwrap();
+        let _sock = UnixListener::bind(source.join("sock")).unwrap();
+        let out = Command::new("mkfifo").arg(source.join("fifo")).output().unwrap();
+        assert!(out.status.success());
+        // The same device as /dev/null; needs CAP_MKNOD.
+        let devices = Command::new("mknod")
+            .args([source.join("null").to_str().unwrap(), "c", "1", "3"])
+            .output().unwrap()
+            .status.success();
+
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(file_contains(&dest.join("file.txt"), "data").unwrap());
+        assert!(dest.join("fifo").symlink_metadata().unwrap().file_type().is_fifo());
+        assert!(!dest.join("sock").exists());
+        assert!(String::from_utf8_lossy(&out.stdout).contains("Skipping socket"));
+        if devices {
+            let meta = dest.join("null").symlink_metadata().unwrap();
+            assert!(meta.file_type().is_char_device());
+            assert_eq!(source.join("null").metadata().unwrap().rdev(), meta.rdev());
+        } else {
+            println!("Skipping device node check: mknod not permitted");
+        }
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_xattr", ignore = "No FS support")]
+    fn copy_security_context(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("file.txt");
+        let to = dir.path().join("file.copy");
+        create_file(&from, "labelled").unwrap();
+
+        let context = b"system_u:object_r:httpd_sys_content_t:s0\0";
+        if let Err(e) = xattr::set(&from, "security.selinux", context) {
+            // Requires SELinux, or root without an LSM.
+            println!("Skipping: unable to set security context: {}", e);
+            return;
+        }
+
+        // The context is copied even when other security attributes aren't.
+        let out = run(&[
+            "--driver", drv,
+            "--preserve-context",
+            "--xattr-exclude", "security.*",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(Some(context.to_vec()), xattr::get(&to, "security.selinux").unwrap());
+    }
+
+    // A POSIX ACL in its xattr form: a version header followed by
+    // (tag, permissions, id) entries, sorted by tag.
+    fn acl_xattr(user: u32, perm: u16) -> Vec<u8> {
+        const UNDEFINED_ID: u32 = u32::MAX;
+        let entries = [
+            (0x01u16, 7u16, UNDEFINED_ID), // ACL_USER_OBJ
+            (0x02, perm, user),            // ACL_USER
+            (0x04, 5, UNDEFINED_ID),       // ACL_GROUP_OBJ
+            (0x10, 5, UNDEFINED_ID),       // ACL_MASK
+            (0x20, 5, UNDEFINED_ID),       // ACL_OTHER
+        ];
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_acl", ignore = "No FS support")]
+    fn copy_preserve_acl(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source");
+        let dest = dir.path().join("dest");
+        create_dir_all(source.join("sub")).unwrap();
+        create_file(&source.join("file.txt"), "shared").unwrap();
+        create_file(&source.join("sub/plain.txt"), "private").unwrap();
+
+        let access = acl_xattr(1234, 4);
+        if let Err(e) = xattr::set(source.join("file.txt"), "system.posix_acl_access", &access) {
+            println!("Skipping: unable to set ACL: {}", e);
+            return;
+        }
+        let default = acl_xattr(1234, 5);
+        xattr::set(source.join("sub"), "system.posix_acl_default", &default).unwrap();
+
+        // ACLs are copied even when other attributes aren't.
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            "--preserve=mode,acl",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        let acl = |path: &str, name: &str| xattr::get(dest.join(path), name).unwrap();
+        assert_eq!(xattr::get(source.join("file.txt"), "system.posix_acl_access").unwrap(),
+                   acl("file.txt", "system.posix_acl_access"));
+        assert_eq!(xattr::get(source.join("sub"), "system.posix_acl_default").unwrap(),
+                   acl("sub", "system.posix_acl_default"));
+        // The default ACL is set after the contents are copied, so it
+        // isn't inherited by them.
+        assert_eq!(None, acl("sub/plain.txt", "system.posix_acl_access"));
+
+        // Without the option, the default ACL isn't copied.
+        let plain = dir.path().join("plain");
+        let out = run(&[
+            "--driver", drv,
+            "-r",
+            "--preserve=mode",
+            source.to_str().unwrap(),
+            plain.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert_eq!(None, xattr::get(plain.join("sub"), "system.posix_acl_default").unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    fn copy_atomic_to_full_fs(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let source = dir.path().join("source.bin");
+        let mnt = dir.path().join("mnt");
+        create_dir_all(&mnt).unwrap();
+        File::create(&source).unwrap()
+            .write_all(&rand_data(4 * 1024 * 1024)).unwrap();
+
+        let out = Command::new("mount")
+            .args(["-t", "tmpfs", "-o", "size=1m", "tmpfs", mnt.to_str().unwrap()])
+            .output().unwrap();
+        if !out.status.success() {
+            println!("Skipping: unable to mount tmpfs: {}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+
+        // A failed copy leaves the existing destination intact.
+        let dest = mnt.join("dest.bin");
+        create_file(&dest, "original").unwrap();
+        let out = run(&[
+            "--driver", drv,
+            "--atomic",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        let original = file_contains(&dest, "original").unwrap();
+        let entries = read_dir(&mnt).unwrap().count();
+
+        let umount = Command::new("umount").arg(&mnt).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(!out.status.success());
+        assert!(original);
+        assert_eq!(1, entries);
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn copy_sparse_modes(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let mb = 1024 * 1024;
+
+        // Fully allocated, but mostly zeros.
+        let dense = dir.path().join("dense.bin");
+        let mut data = vec![0u8; 4 * mb];
+        data[..4096].copy_from_slice(&rand_data(4096));
+        data[3 * mb..3 * mb + 10000].copy_from_slice(&rand_data(10000));
+        File::create(&dense).unwrap().write_all(&data).unwrap();
+        sync(&File::open(&dense).unwrap()).unwrap();
+        assert!(!probably_sparse(&dense).unwrap());
+
+        let to = dir.path().join("dense.copy");
+        let out = run(&[
+            "--driver", drv,
+            "--sparse", "always",
+            dense.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert!(files_match(&dense, &to));
+        assert!(to.metadata().unwrap().blocks() * 512 < mb as u64);
+
+        // A sparse source is filled in.
+        let sparse = dir.path().join("sparse.bin");
+        let len = create_sparse(&sparse, 0, 0).unwrap();
+        let to = dir.path().join("sparse.copy");
+        let out = run(&[
+            "--driver", drv,
+            "--sparse", "never",
+            sparse.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert!(files_match(&sparse, &to));
+        assert!(to.metadata().unwrap().blocks() * 512 >= len);
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn copy_sparse_reflink_fallback(drv: &str) {
+        let dir = tempdir_rel().unwrap();
+        let mnt = dir.path().join("mnt");
+        create_dir_all(&mnt).unwrap();
+        let dest = dir.path().join("dest.bin");
+
+        let out = Command::new("mount")
+            .args(["-t", "tmpfs", "tmpfs", mnt.to_str().unwrap()])
+            .output().unwrap();
+        if !out.status.success() {
+            println!("Skipping: unable to mount tmpfs: {}", String::from_utf8_lossy(&out.stderr));
+            return;
+        }
+        let source = mnt.join("source.bin");
+        create_sparse(&source, 0, 4096 * 64).unwrap();
+        let source_sparse = probably_sparse(&source).unwrap();
+
+        // The reflink across filesystems fails, so auto falls back to
+        // copying, which should still preserve the holes.
+        let out = run(&[
+            "--driver", drv,
+            "--reflink=auto",
+            source.to_str().unwrap(),
+            dest.to_str().unwrap(),
+        ]).unwrap();
+        let copied = files_match(&source, &dest);
+
+        let umount = Command::new("umount").arg(&mnt).output().unwrap();
+        assert!(umount.status.success());
+
+        assert!(out.status.success());
+        assert!(source_sparse);
+        assert!(copied);
+        assert!(probably_sparse(&dest).unwrap());
+    }
 }
+
@@ -69,6 +69,14 @@ pub fn set_time_past(file: &Path) -> Result<(), Error> {
     Ok(())
 }
 
+pub fn set_time_ago(file: &Path, secs: u64) -> Result<(), Error> {
+    let past = SystemTime::now().checked_sub(Duration::from_secs(secs)).unwrap();
+    let ft = FileTimes::new()
+        .set_modified(past);
+    File::open(file)?.set_times(ft)?;
+    Ok(())
+}
+
 pub fn timestamps_same(from: &SystemTime, to: &SystemTime) -> bool {
     let from_s = from.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
     let to_s = to.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;